@@ -0,0 +1,36 @@
+use anchor_lang::prelude::*;
+
+declare_id!("CwZf4ex9Pvimuvj7x6iuu9CRs4nYmGU3KFLAwsRE519h");
+
+// Minimal completion callback used by the escrow-payment tests. It logs the
+// notice the escrow passes so tests can check the payload.
+#[program]
+pub mod completion_callback {
+    use super::*;
+
+    pub fn on_escrow_completed(
+        _ctx: Context<OnEscrowCompleted>,
+        notice: CompletionNotice,
+    ) -> Result<()> {
+        msg!(
+            "Relayed completion of {} for {} lamports",
+            notice.payment_agreement,
+            notice.amount
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct OnEscrowCompleted {}
+
+// Mirrors escrow_payment::account::CompletionNotice
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct CompletionNotice {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
@@ -1,7 +1,7 @@
 use anchor_lang::prelude::*;
 
 #[account]
-#[derive(InitSpace)]
+#[derive(InitSpace, Default)]
 pub struct PaymentAgreement {
     #[max_len(32)]
     pub name: String,
@@ -12,7 +12,8 @@ pub struct PaymentAgreement {
 
     pub amount: u64,
 
-    // Optional expiration timestamp (Unix timestamp)
+    // Optional expiration timestamp (Unix timestamp). Inclusive: the agreement
+    // counts as expired from this second onwards.
     pub expiration_timestamp: Option<i64>,
 
     // If both parties have approved, the payment can be executed
@@ -23,10 +24,959 @@ pub struct PaymentAgreement {
     pub payer_requested_cancel: bool,
     pub receiver_requested_cancel: bool,
 
+    // Terminal state, replacing the former is_completed, is_cancelled and
+    // is_referee_intervened flags. The layout changed with it, so accounts
+    // written by earlier versions must be settled and closed (or refunded by
+    // the admin) before upgrading, then recreated.
+    pub status: AgreementStatus,
+
+    // Unix timestamp at which the agreement was created
+    pub created_at: i64,
+    // When the agreement reached its terminal status, None while pending.
+    // Added after created_at, so older accounts must be recreated as for status.
+    pub settled_at: Option<i64>,
+
+    // Receiver must approve before this timestamp or the offer lapses
+    pub acceptance_expiry: Option<i64>,
+
+    // N-of-M approval, empty means the default payer + receiver 2-of-2
+    #[max_len(5)]
+    pub approvers: Vec<Pubkey>,
+    pub approval_threshold: u8,
+    // Bit i is set once approvers[i] has approved
+    pub approvals_bitmap: u8,
+
+    // Set when either party opens a dispute for the referee to resolve
+    pub dispute_opened_at: Option<i64>,
+
+    // Mint a receipt token to the payer on completion
+    pub mint_receipt: bool,
+
+    // Canonical bump of this PDA, stored so CPI callers can sign without searching
+    pub bump: u8,
+
+    // Receiver must respond by this time or enforce_sla escalates
+    pub sla_deadline: Option<i64>,
+    // Set when an SLA breach without a referee unlocks the payer's reclaim
+    pub sla_breached: bool,
+
+    // sha256(amount_le_bytes || salt) for offers whose amount stays hidden
+    // until the receiver accepts, funded on reveal
+    pub amount_commitment: Option<[u8; 32]>,
+    pub amount_revealed: bool,
+
+    // Sealed-bid mode: the receiver's commitment to the minimum amount they
+    // accept, revealed after the payer's amount
+    pub receiver_terms_commitment: Option<[u8; 32]>,
+    pub receiver_terms_revealed: bool,
+
+    // Paid to the referee out of the escrow when they intervene
+    pub referee_fee: Option<RefereeFee>,
+
+    // Mutual cancellation can't execute before created_at + this many seconds
+    pub min_duration_seconds: Option<i64>,
+
+    // When enabled, a receiver's submit_delivery stands in for their approval
+    pub delivery_completion: bool,
+    pub delivery_submitted: bool,
+    // Hash or reference to the delivered work
+    pub delivery_proof: [u8; 32],
+
+    // By default paying a closed receiver recreates it as a system account.
+    // When set, completion is rejected instead so the payer can decide.
+    pub require_receiver_exists: bool,
+
+    // Bit i is set once approvers[i] has voted to cancel
+    pub rejections_bitmap: u8,
+    // Set when every approver, or every panel referee, has voted and neither
+    // side reached the threshold
+    pub tie_detected_at: Option<i64>,
+    // How resolve_tie settles a deadlock, None leaves it to the parties
+    pub tie_break: Option<TieBreak>,
+
+    // Arbitration panel used instead of the single referee, settles once
+    // required_votes referees agree
+    #[max_len(3)]
+    pub referees: Vec<Pubkey>,
+    pub required_votes: u8,
+    // Bit i is set once referees[i] has voted that way
+    pub complete_votes_bitmap: u8,
+    pub cancel_votes_bitmap: u8,
+
+    // Partial releases pay out of `amount`, which always holds what is still escrowed
+    pub released_amount: u64,
+    pub release_count: u32,
+    // When set, each release needs both parties to approve it
+    pub per_release_dual_approval: bool,
+    pub pending_release: Option<PendingRelease>,
+    // Release k may not happen before allowed_release_timestamps[k]
+    #[max_len(10)]
+    pub allowed_release_timestamps: Vec<i64>,
+
+    // Slot of the transaction that completed the agreement, 0 until then
+    pub completion_slot: u64,
+
+    // Depositing one of these tokens grants the receiver's approval, once
+    pub proof_token_mint: Option<Pubkey>,
+    pub proof_consumed: bool,
+
+    // Kept by the receiver when both parties cancel
+    pub nonrefundable_deposit: u64,
+
+    // Approves on the payer's side instead of the funding payer, refunds still go to the payer
+    pub payer_approver: Option<Pubkey>,
+
+    // Refund an expired escrow in chunks rather than all at once
+    pub expiry_refund_schedule: Option<ExpiryRefundSchedule>,
+    pub refund_started_at: Option<i64>,
+    pub refunded_amount: u64,
+
+    // Weighted mode: approver_weights[i] is the weight of approvers[i], and the
+    // agreement completes once approved_weight reaches weight_threshold
+    #[max_len(5)]
+    pub approver_weights: Vec<u16>,
+    pub weight_threshold: u16,
+    pub approved_weight: u32,
+
+    // Referee fee escrowed on top of `amount`. Paid to the referee on
+    // intervention, otherwise returned to the payer when the agreement settles.
+    pub reserved_referee_fee: u64,
+
+    // Reduced amount the receiver offered to settle for, awaiting the payer
+    pub proposed_deliverable_amount: Option<u64>,
+
+    // Program notified through CPI whenever the agreement completes
+    pub completion_callback: Option<Pubkey>,
+
+    // Up-front portion both parties are agreeing to pay while holding the rest
+    pub pending_partial_completion: Option<PendingRelease>,
+
+    // Handing the payer role to another key also needs the receiver's signature
+    pub ownership_transfer_needs_receiver: bool,
+
+    // From this time anyone may complete the agreement unless a dispute is open
+    pub auto_complete_timestamp: Option<i64>,
+
+    // One entry per partial release, in order
+    #[max_len(10)]
+    pub release_history: Vec<ReleaseRecord>,
+
+    // SPL mint escrowed instead of lamports, None for native SOL agreements.
+    // Token agreements hold `amount` in the PDA's associated token account.
+    pub mint: Option<Pubkey>,
+
+    // Approval pays fee_bps of the reference account's growth instead of the
+    // full amount, capped by the escrow, and refunds the rest to the payer
+    pub performance_fee: Option<PerformanceFee>,
+
+    // Reference account lamports captured at creation
+    pub reference_baseline: u64,
+
+    // Grace period after expiration during which the receiver can still
+    // approve before the payer may withdraw
+    pub dispute_window_seconds: u64,
+
+    // Free-form description for invoices, beyond what fits in the name
+    #[max_len(200)]
+    pub memo: Option<String>,
+
+    // Staged payout of `amount`, each released once both parties approve it.
+    // Empty for agreements paid out in one go.
+    #[max_len(10)]
+    pub milestones: Vec<Milestone>,
+
+    // Receives expired refunds instead of the payer. The rent still goes
+    // back to the payer when the account closes.
+    pub fallback_receiver: Option<Pubkey>,
+
+    // Layout version, see CURRENT_SCHEMA_VERSION. Fields added after it must be
+    // fixed-size, so older and shorter accounts still decode once padded. A
+    // bool read from stale padding is cleared by migrate_agreement.
+    pub version: u8,
+
+    // Share of the payout sent to fee_collector on mutual completion, 0 for none
+    pub fee_basis_points: u16,
+    pub fee_collector: Pubkey,
+
+    // Set by the referee to freeze the parties while a dispute is resolved.
+    // Referee rulings still go through, and expiry refunds only stop when
+    // pause_blocks_withdrawal is also set.
+    pub is_paused: bool,
+    pub pause_blocks_withdrawal: bool,
+
+    // With require_ack set, neither party can approve until the receiver has
+    // called receiver_acknowledge to consent to the obligation
+    pub require_ack: bool,
+    pub receiver_acknowledged: bool,
+
+    // Last time either party changed the agreement, from which the abandon
+    // timeout is counted
+    pub last_activity_ts: i64,
+
+    // Hash of an off-chain document the agreement is bound to, such as the
+    // SHA-256 of a signed contract. Never interpreted on-chain, all zeros for none.
+    pub document_hash: [u8; 32],
+
+    // Who settle_expired pays once the agreement expires unsettled
+    pub expiry_favors: ExpiryBeneficiary,
+
+    // Whose approval settles an agreement without an approver set
+    pub completion_mode: CompletionMode,
+
+    // Folded into the address so a payer can reuse a name, 0 for none
+    pub nonce: u64,
+
+    // Bump of the vault holding the escrowed lamports, see VAULT_SEED. Unused
+    // by token agreements, which escrow in a token account instead.
+    pub vault_bump: u8,
+
+    // Where refunds of the escrow go instead of the payer, set to the
+    // FundingSource owner when a delegate pulled the funds. Default for none.
+    pub refund_to: Pubkey,
+
+    // The payer the address was derived from. transfer_payer_role only moves
+    // `payer`, so the agreement keeps its address under the original key.
+    pub original_payer: Pubkey,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, InitSpace)]
+pub struct Milestone {
+    pub amount: u64,
+    pub released: bool,
+    pub payer_approved: bool,
+    pub receiver_approved: bool,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, InitSpace)]
+pub struct ReleaseRecord {
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+// A partial release awaiting the second party's approval
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, InitSpace)]
+pub struct PendingRelease {
+    pub amount: u64,
+    pub payer_approved: bool,
+    pub receiver_approved: bool,
+}
+
+// The first chunk is refunded on the first withdrawal after expiry, each
+// following one `interval_seconds` later
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub struct ExpiryRefundSchedule {
+    pub chunks: u8,
+    pub interval_seconds: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct ApproverWeight {
+    pub approver: Pubkey,
+    pub weight: u16,
+}
+
+// PaymentAgreement layouts:
+//   v1: every field up to and including fallback_receiver, with no version byte.
+//   v2: v1 followed by `version: u8`.
+//   v3: v2 followed by `fee_basis_points: u16` and `fee_collector: Pubkey`.
+//   v4: v3 followed by `is_paused: bool` and `pause_blocks_withdrawal: bool`.
+//   v5: v4 followed by `require_ack: bool` and `receiver_acknowledged: bool`.
+//   v6: v5 followed by `last_activity_ts: i64`.
+//   v7: v6 followed by `document_hash: [u8; 32]`.
+//   v8: v7 followed by `expiry_favors: ExpiryBeneficiary`.
+//   v9: v8 followed by `completion_mode: CompletionMode`.
+//   v10: v9 followed by `nonce: u64`.
+//   v11: v10 followed by `vault_bump: u8`. Older native agreements hold their
+//        escrow on the agreement account itself until migrated.
+//   v12: v11 followed by `refund_to: Pubkey`.
+//   v13: v12 followed by `original_payer: Pubkey`.
+// Each version is allocated at its own INIT_SPACE, so the account size tells
+// them apart. migrate_agreement rewrites older accounts as the current one.
+pub const CURRENT_SCHEMA_VERSION: u8 = 13;
+
+// Bytes v3 appended after the version byte
+pub const V3_FIELDS_SPACE: usize = 2 + 32;
+
+// Bytes v4 appended after the v3 fields
+pub const V4_FIELDS_SPACE: usize = 1 + 1;
+
+// Bytes v5 appended after the v4 fields
+pub const V5_FIELDS_SPACE: usize = 1 + 1;
+
+// Bytes v6 appended after the v5 fields
+pub const V6_FIELDS_SPACE: usize = 8;
+
+// Bytes v7 appended after the v6 fields
+pub const V7_FIELDS_SPACE: usize = 32;
+
+// Bytes v8 appended after the v7 fields
+pub const V8_FIELDS_SPACE: usize = 1;
+
+// Bytes v9 appended after the v8 fields
+pub const V9_FIELDS_SPACE: usize = 1;
+
+// Bytes v10 appended after the v9 fields
+pub const V10_FIELDS_SPACE: usize = 8;
+
+// Bytes v11 appended after the v10 fields
+pub const V11_FIELDS_SPACE: usize = 1;
+
+// Bytes v12 appended after the v11 fields
+pub const V12_FIELDS_SPACE: usize = 32;
+
+// Bytes v13 appended after the v12 fields
+pub const V13_FIELDS_SPACE: usize = 32;
+
+// Agreements live at [PAYMENT_AGREEMENT_SEED, original_payer, name, nonce_seed(nonce)],
+// so a name is only unique per payer and nonce, and two payers may reuse it
+// freely. Exported in the IDL so clients derive the address from it instead of
+// copying the literal.
+#[constant]
+pub const PAYMENT_AGREEMENT_SEED: &[u8] = b"payment_agreement";
+
+// Native agreements escrow their lamports in a program-owned PDA with no data
+// at [VAULT_SEED, agreement]. It holds its own rent-exempt minimum plus exactly
+// amount + reserved_referee_fee, and the agreement account only its rent.
+#[constant]
+pub const VAULT_SEED: &[u8] = b"vault";
+
+// Marks the start of a nonce seed. Seeds are concatenated when deriving the
+// address, and 0xff never appears in a UTF-8 name, so no name can spell out
+// another agreement's name followed by its nonce.
+pub const NONCE_SEED_MARKER: u8 = 0xff;
+
+// Last agreement seed. Nonce 0 adds no bytes, so agreements created without a
+// nonce keep their [PAYMENT_AGREEMENT_SEED, payer, name] address.
+pub fn nonce_seed(nonce: u64) -> Vec<u8> {
+    if nonce == 0 {
+        Vec::new()
+    } else {
+        let mut seed = vec![NONCE_SEED_MARKER];
+        seed.extend_from_slice(&nonce.to_le_bytes());
+        seed
+    }
+}
+
+// Key for any index over agreements. Always scope by payer and nonce so another
+// payer, or the same payer under another nonce, reusing the name can't collide
+// with or shadow the entry.
+pub fn scoped_name_key(payer: &Pubkey, name: &str, nonce: u64) -> [u8; 32] {
+    anchor_lang::solana_program::hash::hashv(&[
+        payer.as_ref(),
+        name.as_bytes(),
+        &nonce.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PdaInfo {
+    pub seed_prefix: Vec<u8>,
+    pub payer: Pubkey,
+    pub name: String,
+    pub nonce: u64,
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct AgreementSummary {
+    pub status: AgreementStatus,
+    pub amount: u64,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub referee: Option<Pubkey>,
+    // Always false for agreements without an expiration
+    pub is_expired: bool,
+    pub memo: Option<String>,
+    pub created_at: i64,
+    pub settled_at: Option<i64>,
+    pub document_hash: Option<[u8; 32]>,
+}
+
+pub const MAX_APPROVERS: usize = 5;
+
+pub const MAX_REFEREES: usize = 3;
+
+// Smallest native escrow, in lamports (0.001 SOL). Anything less can't
+// meaningfully settle and only clutters indexers. Token agreements are
+// denominated in the mint's units and only have to be non-zero.
+pub const MIN_ESCROW_AMOUNT: u64 = 1_000_000;
+
+// Native escrows above this many lamports must name a referee or a referee
+// panel to arbitrate disputes. Token agreements are not covered.
+pub const MANDATORY_REFEREE_THRESHOLD: u64 = 100 * 1_000_000_000;
+
+// Longest memo in bytes, matching the max_len on PaymentAgreement::memo
+pub const MAX_MEMO_LEN: usize = 200;
+
+pub const MAX_RELEASE_SCHEDULE: usize = 10;
+
+pub const MAX_MILESTONES: usize = 10;
+
+// Partial releases per agreement. The history is stored inline, so it has to
+// be bounded to fit the account allocated at creation.
+pub const MAX_RELEASE_HISTORY: usize = 10;
+
+// Whether the vault still holds what it owes. `amount` shrinks with each
+// partial release, so it is always the outstanding obligation.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IntegrityStatus {
+    pub balance: u64,
+    pub rent_exempt_minimum: u64,
+    pub outstanding_amount: u64,
+    pub rent_exempt: bool,
+    // Lamports missing to cover rent plus the outstanding amount, 0 when healthy
+    pub shortfall: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RefereeDecision {
+    Complete,
+    Cancel,
+}
+
+// Upper bound on a percentage referee fee, 20%
+pub const MAX_REFEREE_FEE_BPS: u16 = 2000;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum TieBreak {
+    FavorPayer,
+    FavorReceiver,
+    // Clear the votes so the approvers or referees have to vote again
+    RequireUnanimity,
+    // Half to the receiver, the odd lamport goes back to the payer
+    Split5050,
+}
+
+// Side an unsettled agreement settles to on expiry. Receiver suits
+// "pay unless disputed" deals.
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default, InitSpace,
+)]
+pub enum ExpiryBeneficiary {
+    #[default]
+    Payer,
+    Receiver,
+}
+
+// Which approvals complete an agreement. The one-sided modes suit trusted
+// counterparties and work as a release-on-demand escrow.
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default, InitSpace,
+)]
+pub enum CompletionMode {
+    // Both payer and receiver approve
+    #[default]
+    MutualApproval,
+    // The receiver's confirmation alone completes
+    ReceiverConfirm,
+    // The payer's release alone completes
+    PayerRelease,
+}
+
+// Only Pending agreements accept approvals, cancellations and withdrawals
+#[derive(
+    AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default, InitSpace,
+)]
+pub enum AgreementStatus {
+    #[default]
+    Pending,
+    Completed,
+    Cancelled,
+    RefereeCompleted,
+    RefereeCancelled,
+    // Refunded to the payer after expiration
+    Expired,
+}
+
+impl AgreementStatus {
+    pub fn is_completed(self) -> bool {
+        matches!(self, Self::Completed | Self::RefereeCompleted)
+    }
+
+    // An expiry refund returns the escrow just like a cancellation
+    pub fn is_cancelled(self) -> bool {
+        matches!(
+            self,
+            Self::Cancelled | Self::RefereeCancelled | Self::Expired
+        )
+    }
+
+    pub fn is_referee_ruling(self) -> bool {
+        matches!(self, Self::RefereeCompleted | Self::RefereeCancelled)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum RefereeFee {
+    // Flat fee in lamports
+    Fixed { lamports: u64 },
+    // Share of the escrowed amount in basis points, rounded down
+    Bps { bps: u16 },
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub struct PerformanceFee {
+    // Share of the reference account's balance growth owed, in basis points
+    pub fee_bps: u16,
+    // Account whose lamport balance is measured against the creation baseline
+    pub reference_account: Pubkey,
+}
+
+// Upper bound on agreements closed per batch to stay within compute limits
+pub const MAX_BATCH_CLOSE: usize = 10;
+
+// Upper bound on agreements created per batch. Each entry adds its parameters
+// and its accounts to the transaction, which has to stay under the packet size.
+pub const MAX_BATCH_CREATE: usize = 5;
+
+// One plain agreement in create_payment_agreements_batch, without a referee or
+// options
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AgreementParams {
+    pub name: String,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub expiration_timestamp: Option<i64>,
+}
+
+// Optional creation-time settings, omitted entirely for a plain escrow.
+// Every field is an Option so clients only need to set what they use.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct AgreementOptions {
+    pub acceptance_expiry: Option<i64>,
+    pub approvers: Option<Vec<Pubkey>>,
+    pub approval_threshold: Option<u8>,
+    pub mint_receipt: Option<bool>,
+    pub sla_seconds: Option<i64>,
+    pub amount_commitment: Option<[u8; 32]>,
+    pub referee_fee: Option<RefereeFee>,
+    pub min_duration_seconds: Option<i64>,
+    pub delivery_completion: Option<bool>,
+    pub require_receiver_exists: Option<bool>,
+    pub tie_break: Option<TieBreak>,
+    // Alternative to the absolute expiration_timestamp, never both
+    pub expiration_duration_seconds: Option<i64>,
+    pub referees: Option<Vec<Pubkey>>,
+    pub required_votes: Option<u8>,
+    // Succeed as a no-op when an agreement with identical terms already exists
+    pub idempotent: Option<bool>,
+    pub per_release_dual_approval: Option<bool>,
+    pub proof_token_mint: Option<Pubkey>,
+    pub allowed_release_timestamps: Option<Vec<i64>>,
+    pub nonrefundable_deposit: Option<u64>,
+    pub payer_approver: Option<Pubkey>,
+    pub expiry_refund_schedule: Option<ExpiryRefundSchedule>,
+    pub approver_weights: Option<Vec<ApproverWeight>>,
+    pub weight_threshold: Option<u16>,
+    pub reserve_referee_fee: Option<bool>,
+    pub completion_callback: Option<Pubkey>,
+    pub ownership_transfer_needs_receiver: Option<bool>,
+    pub auto_complete_timestamp: Option<i64>,
+    pub performance_fee: Option<PerformanceFee>,
+    pub dispute_window_seconds: Option<u64>,
+    pub memo: Option<String>,
+    // Milestone amounts, which must add up to the agreement amount
+    pub milestones: Option<Vec<u64>>,
+    pub fallback_receiver: Option<Pubkey>,
+    // Protocol fee on mutual completion, set together or not at all
+    pub fee_basis_points: Option<u16>,
+    pub fee_collector: Option<Pubkey>,
+    pub require_ack: Option<bool>,
+    pub document_hash: Option<[u8; 32]>,
+    // Accept a receiver that is off-curve or not owned by the System Program,
+    // in which case receiver_account may be omitted
+    pub allow_program_receiver: Option<bool>,
+    pub expiry_favors: Option<ExpiryBeneficiary>,
+    pub completion_mode: Option<CompletionMode>,
+    // Lets the payer reuse a name, see nonce_seed
+    pub nonce: Option<u64>,
+}
+
+// Longest minimum commitment period an agreement may require
+pub const MAX_MIN_DURATION_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+#[account]
+#[derive(InitSpace)]
+pub struct Config {
+    pub admin: Pubkey,
+
+    // Number of payment agreements that have not reached a terminal state
+    pub total_active_agreements: u64,
+
+    // Cap on total_active_agreements, 0 means unlimited
+    pub max_total_active: u64,
+
+    // Seconds after created_at before a referee may intervene
+    pub min_seconds_before_intervention: i64,
+
+    // Receives dust swept from settled agreements
+    pub treasury: Pubkey,
+
+    // Fallback arbiter for disputes stuck on an unresponsive referee
+    pub default_referee: Option<Pubkey>,
+
+    // Seconds a dispute may sit unresolved before escalating to the default referee
+    pub referee_inactivity_window: i64,
+
+    // Seconds a tied vote must stand before resolve_tie may apply the tie-break
+    pub tie_break_timeout: i64,
+
+    // When false a missing or non-executable completion callback is logged and
+    // skipped so it can't hold up a payout
+    pub callback_failures_fatal: bool,
+
+    // Seconds without activity after which the referee may confirm a
+    // one-sided cancel request
+    pub abandon_timeout: i64,
+
+    // Shortest time between creation and expiration, so the receiver always
+    // has a window to respond before the payer can reclaim
+    pub min_agreement_lifetime: i64,
+}
+
+// Program-wide usage totals for dashboards. Volumes are u128 so cumulative
+// lamport totals never overflow in practice.
+#[account]
+#[derive(InitSpace)]
+pub struct Stats {
+    pub created_volume: u128,
+    // Paid out to receivers, including partial releases
+    pub completed_volume: u128,
+    // Returned to payers on cancellation
+    pub cancelled_volume: u128,
+    // Returned to payers after expiry
+    pub expired_refund_volume: u128,
+
+    pub created_count: u64,
+    pub completed_count: u64,
+    pub cancelled_count: u64,
+    pub expired_refund_count: u64,
+
+    // Agreements that have not reached a terminal state
+    pub active_count: u64,
+}
+
+// Fields left as None keep their current value
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ConfigUpdate {
+    pub max_total_active: Option<u64>,
+    pub min_seconds_before_intervention: Option<i64>,
+    pub treasury: Option<Pubkey>,
+    pub default_referee: Option<Pubkey>,
+    pub referee_inactivity_window: Option<i64>,
+    pub tie_break_timeout: Option<i64>,
+    pub callback_failures_fatal: Option<bool>,
+    pub abandon_timeout: Option<i64>,
+    pub min_agreement_lifetime: Option<i64>,
+}
+
+// Default intervention window applied when the config is initialized
+pub const MIN_SECONDS_BEFORE_INTERVENTION: i64 = 24 * 60 * 60;
+
+// Default inactivity window before a dispute can escalate to the default referee
+pub const REFEREE_INACTIVITY_WINDOW: i64 = 14 * 24 * 60 * 60;
+
+// Default wait between detecting a tied vote and applying the tie-break
+pub const TIE_BREAK_TIMEOUT: i64 = 3 * 24 * 60 * 60;
+
+// Default inactivity before a one-sided cancel can be confirmed by the referee
+pub const ABANDON_TIMEOUT: i64 = 30 * 24 * 60 * 60;
+
+// Default minimum lifetime of an agreement with an expiration
+pub const MIN_AGREEMENT_LIFETIME: i64 = 60 * 60;
+
+// Program-owned lamport pool an owner pre-funds so an approved delegate can
+// create agreements that pull their escrow from it
+#[account]
+#[derive(InitSpace)]
+pub struct FundingSource {
+    pub owner: Pubkey,
+    pub delegate: Pubkey,
+    // Lamports the delegate may still pull
+    pub allowance: u64,
+    pub bump: u8,
+}
+
+pub const MAX_CONTRIBUTORS: usize = 10;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct Contribution {
+    pub contributor: Pubkey,
+    pub amount: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct CrowdfundAgreement {
+    #[max_len(32)]
+    pub name: String,
+
+    pub creator: Pubkey,
+    pub receiver: Pubkey,
+
+    // The escrow pays the receiver once total_contributed reaches this amount
+    pub target_amount: u64,
+    pub total_contributed: u64,
+
+    // After this timestamp an unfunded escrow lets contributors reclaim
+    pub expiration_timestamp: i64,
+
+    #[max_len(10)]
+    pub contributions: Vec<Contribution>,
+
     pub is_completed: bool,
-    pub is_cancelled: bool,
+}
 
-    pub is_referee_intervened: bool,
+pub const MAX_SPLIT_RECEIVERS: usize = 10;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, InitSpace)]
+pub struct SplitShare {
+    pub receiver: Pubkey,
+    pub amount: u64,
+}
+
+// One escrowed amount divided among several receivers. The payer releases
+// every share at once, closing the account, or pays ranges of shares across
+// several transactions first.
+#[account]
+#[derive(InitSpace)]
+pub struct SplitPaymentAgreement {
+    #[max_len(32)]
+    pub name: String,
+
+    pub payer: Pubkey,
+
+    // Always the sum of the shares
+    pub amount: u64,
+
+    #[max_len(10)]
+    pub shares: Vec<SplitShare>,
+
+    // Bit i is set once shares[i] has been paid by settle_split_range
+    pub paid_bitmap: u16,
+}
+
+// Subscription-style escrow funded with every period up front. The receiver
+// claims one period at a time, at most once per interval.
+#[account]
+#[derive(InitSpace)]
+pub struct RecurringAgreement {
+    #[max_len(32)]
+    pub name: String,
+
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+
+    pub per_period_amount: u64,
+    pub interval_seconds: u64,
+    pub num_periods: u32,
+    pub periods_claimed: u32,
+
+    // Creation time until the first claim, so the first period also has to
+    // wait a full interval
+    pub last_claim_ts: i64,
+}
+
+// Where each lamport of the escrow goes on completion. Fee kinds the
+// agreement does not use are reported as zero.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct PayoutBreakdown {
+    pub receiver_share: u64,
+    pub referee_fee: u64,
+    pub platform_fee: u64,
+    pub insurance: u64,
+    pub burn: u64,
+    // Lamports in the vault above the escrow and its rent-exempt minimum, left
+    // for sweep_dust
+    pub dust: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PermissionAction {
+    Approve,
+    Reject,
+    Cancel,
+    Withdraw,
+    Intervene,
+    SubmitDelivery,
+    RevealPayerAmount,
+    OpenDispute,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct PermissionCheck {
+    pub allowed: bool,
+    // Error code the instruction would fail with, 0 when allowed
+    pub denial_code: u32,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WithdrawBlockReason {
+    None,
+    NoExpiration,
+    NotExpired,
+    AlreadyCompleted,
+    AlreadyCancelled,
+    ReceiverApproved,
+    RefundNotVested,
+    DisputeWindowActive,
+    Paused,
+    // Expiry pays the receiver through settle_expired instead
+    ExpiryFavorsReceiver,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct WithdrawEligibility {
+    pub can_withdraw: bool,
+    pub reason: WithdrawBlockReason,
+}
+
+#[event]
+pub struct AgreementCreated {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AgreementApproved {
+    pub payment_agreement: Pubkey,
+    pub approver: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+// Amount is what the receiver was paid. referee_forced marks a completion
+// imposed by a referee rather than reached by the parties.
+#[event]
+pub struct AgreementCompleted {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub referee_forced: bool,
+    // Start of the agreement, `timestamp` is when it settled
+    pub created_at: i64,
+    pub timestamp: i64,
+    pub completion_slot: u64,
+}
+
+// Amount is what was returned to the payer
+#[event]
+pub struct AgreementCancelled {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    // Start of the agreement, `timestamp` is when it settled
+    pub created_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReceiverAcknowledged {
+    pub payment_agreement: Pubkey,
+    pub receiver: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReceiverDeclined {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RefereeIntervened {
+    pub payment_agreement: Pubkey,
+    pub referee: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub completed: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReceiptMinted {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub name: String,
+    pub amount: u64,
+}
+
+// Payload passed to a completion callback program, prefixed with the Anchor
+// discriminator of `on_escrow_completed`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct CompletionNotice {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+// Instruction the escrow invokes on a completion callback program
+pub const COMPLETION_CALLBACK_IX: &str = "on_escrow_completed";
+
+#[event]
+pub struct EmergencyRefund {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub admin: Pubkey,
+    pub refunded_lamports: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ResetToPending {
+    pub payment_agreement: Pubkey,
+    pub payer: Pubkey,
+    pub receiver: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct OwnershipTransferred {
+    pub old_payment_agreement: Pubkey,
+    pub new_payment_agreement: Pubkey,
+    pub old_payer: Pubkey,
+    pub new_payer: Pubkey,
+}
+
+#[event]
+pub struct PayerRoleTransferred {
+    pub payment_agreement: Pubkey,
+    pub old_payer: Pubkey,
+    pub new_payer: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct RefereeVoteCast {
+    pub payment_agreement: Pubkey,
+    pub referee: Pubkey,
+    pub decision: RefereeDecision,
+    pub complete_votes: u8,
+    pub cancel_votes: u8,
+    pub threshold_reached: bool,
+}
+
+#[event]
+pub struct RefereeRulingExecuted {
+    pub payment_agreement: Pubkey,
+    pub decision: RefereeDecision,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
 }
 
 #[error_code]
@@ -67,9 +1017,395 @@ pub enum ErrorCode {
     #[msg("Referee cannot be the same as receiver.")]
     RefereeCannotBeReceiver,
 
-    #[msg("Expiration timestamp must be in the future.")]
+    #[msg("Expiration timestamp must be later than the current time.")]
     ExpirationMustBeInFuture,
 
     #[msg("Payment agreement has not expired yet.")]
     PaymentAgreementNotExpired,
+
+    #[msg("The payment agreement account is not owned by this program.")]
+    InvalidAgreementAccount,
+
+    #[msg("Amount must be greater than zero.")]
+    InvalidAmount,
+
+    #[msg("Arithmetic overflow while updating amounts.")]
+    AmountOverflow,
+
+    #[msg("The maximum number of contributors has been reached.")]
+    ContributorLimitReached,
+
+    #[msg("The funding period has ended.")]
+    FundingPeriodEnded,
+
+    #[msg("No contribution to reclaim.")]
+    NothingToReclaim,
+
+    #[msg("The maximum number of active agreements has been reached.")]
+    GlobalCapReached,
+
+    #[msg("Arithmetic overflow while updating counters.")]
+    CounterOverflow,
+
+    #[msg("The receiver has already approved this payment agreement.")]
+    ReceiverHasApproved,
+
+    #[msg("The referee cannot intervene this soon after creation.")]
+    TooEarlyForIntervention,
+
+    #[msg("Intervention window must not be negative.")]
+    InvalidInterventionWindow,
+
+    #[msg("Acceptance expiry must not be after the expiration timestamp.")]
+    AcceptanceExpiryAfterExpiration,
+
+    #[msg("The acceptance window for this offer has lapsed.")]
+    AcceptanceWindowLapsed,
+
+    #[msg("Invalid treasury account provided.")]
+    InvalidTreasury,
+
+    #[msg("The payment agreement is not settled yet.")]
+    AgreementNotSettled,
+
+    #[msg("Approvers must be unique and at most 5.")]
+    InvalidApprovers,
+
+    #[msg("Approval threshold must be between 1 and the number of approvers.")]
+    InvalidApprovalThreshold,
+
+    #[msg("The payment agreement has no referee.")]
+    NoRefereeAssigned,
+
+    #[msg("A dispute is already open.")]
+    DisputeAlreadyOpen,
+
+    #[msg("No dispute is open.")]
+    NoDisputeOpen,
+
+    #[msg("The referee inactivity window has not elapsed.")]
+    InactivityWindowNotElapsed,
+
+    #[msg("No default referee is configured.")]
+    NoDefaultReferee,
+
+    #[msg("Receipt mint accounts are missing from remaining accounts.")]
+    MissingReceiptAccounts,
+
+    #[msg("Invalid receipt mint accounts provided.")]
+    InvalidReceiptAccounts,
+
+    #[msg("The approval mode cannot change once approvals exist.")]
+    CannotChangeModeAfterApproval,
+
+    #[msg("SLA window must be greater than zero.")]
+    InvalidSla,
+
+    #[msg("The payment agreement has no SLA to enforce.")]
+    NoSlaConfigured,
+
+    #[msg("The receiver response SLA has not elapsed.")]
+    SlaNotElapsed,
+
+    #[msg("The receiver has already responded.")]
+    ReceiverAlreadyResponded,
+
+    #[msg("Too many accounts in a single batch.")]
+    BatchTooLarge,
+
+    #[msg("Amount must be zero when it is hidden behind a commitment.")]
+    CommittedAmountMustBeZero,
+
+    #[msg("The payment agreement has no amount commitment.")]
+    NoAmountCommitment,
+
+    #[msg("The amount has already been revealed.")]
+    AmountAlreadyRevealed,
+
+    #[msg("The amount has not been revealed yet.")]
+    AmountNotRevealed,
+
+    #[msg("The receiver has not accepted yet.")]
+    ReceiverHasNotAccepted,
+
+    #[msg("Revealed value does not match the commitment.")]
+    CommitmentMismatch,
+
+    #[msg("Referee fee exceeds the allowed maximum.")]
+    InvalidRefereeFee,
+
+    #[msg("Minimum duration must be positive and no longer than the allowed maximum.")]
+    InvalidMinimumDuration,
+
+    #[msg("The minimum escrow duration has not been met yet.")]
+    MinimumDurationNotMet,
+
+    #[msg("Delivery completion is not enabled for this agreement.")]
+    DeliveryCompletionNotEnabled,
+
+    #[msg("Delivery has already been submitted.")]
+    DeliveryAlreadySubmitted,
+
+    #[msg("Migrated escrow balance does not match the new amount.")]
+    MigrationBalanceMismatch,
+
+    #[msg("The receiver account does not exist.")]
+    ReceiverDoesNotExist,
+
+    #[msg("This approver has already voted.")]
+    AlreadyVoted,
+
+    #[msg("No tied vote has been detected.")]
+    NoTieDetected,
+
+    #[msg("The tie-break timeout has not elapsed yet.")]
+    TieBreakTimeoutNotElapsed,
+
+    #[msg("No tie-break policy is configured.")]
+    NoTieBreakPolicy,
+
+    #[msg("Signer is not the funding source's approved delegate.")]
+    InvalidFundingDelegate,
+
+    #[msg("Amount exceeds the funding source's remaining allowance.")]
+    FundingAllowanceExceeded,
+
+    #[msg("The receiver has already committed to terms.")]
+    ReceiverTermsAlreadyCommitted,
+
+    #[msg("The receiver has not committed to any terms.")]
+    NoReceiverTermsCommitment,
+
+    #[msg("The receiver's terms have already been revealed.")]
+    ReceiverTermsAlreadyRevealed,
+
+    #[msg("The receiver's terms have not been revealed yet.")]
+    ReceiverTermsNotRevealed,
+
+    #[msg("The revealed amount is below the receiver's sealed minimum.")]
+    SealedTermsNotMet,
+
+    #[msg("Provide either an expiration timestamp or a duration, not both.")]
+    ConflictingExpiration,
+
+    #[msg("The escrow holds less than the agreed amount.")]
+    InsufficientEscrowBalance,
+
+    #[msg("Invalid referee panel.")]
+    InvalidReferees,
+
+    #[msg("Required votes must be between 1 and the number of referees.")]
+    InvalidRequiredVotes,
+
+    #[msg("A payment agreement with this name already exists.")]
+    AgreementAlreadyExists,
+
+    #[msg("An existing payment agreement has different terms.")]
+    ConflictingExistingAgreement,
+
+    #[msg("Release amount must be positive and no more than the escrowed amount.")]
+    InvalidReleaseAmount,
+
+    #[msg("Release index does not match the next release.")]
+    ReleaseIndexMismatch,
+
+    #[msg("Release amount does not match the pending release.")]
+    ReleaseAmountMismatch,
+
+    #[msg("Proof token does not match the agreement's proof mint.")]
+    InvalidProofToken,
+
+    #[msg("The proof token for this agreement has already been deposited.")]
+    ProofAlreadyUsed,
+
+    #[msg("Release schedule is too long or not in ascending order.")]
+    InvalidReleaseSchedule,
+
+    #[msg("This release is not scheduled yet.")]
+    ReleaseTooEarly,
+
+    #[msg("Non-refundable deposit must be less than the amount.")]
+    InvalidDeposit,
+
+    #[msg("Payer approver must differ from both the payer and the receiver.")]
+    InvalidPayerApprover,
+
+    #[msg("Expiry refund schedule needs at least one chunk and a positive interval.")]
+    InvalidRefundSchedule,
+
+    #[msg("The next refund chunk has not vested yet.")]
+    RefundNotVested,
+
+    #[msg("Approver weights must be positive, unique and not combined with an approver list.")]
+    InvalidApproverWeights,
+
+    #[msg("Weight threshold must be between 1 and the total approver weight.")]
+    InvalidWeightThreshold,
+
+    #[msg("Deliverable amount must be positive and no more than the amount.")]
+    InvalidDeliverableAmount,
+
+    #[msg("The receiver has not proposed a partial acceptance.")]
+    NoPartialAcceptance,
+
+    #[msg("Deliverable amount does not match the receiver's proposal.")]
+    DeliverableAmountMismatch,
+
+    #[msg("Completion callback program was not passed or is not executable.")]
+    CompletionCallbackUnavailable,
+
+    #[msg("Immediate portion must be positive and less than the escrowed amount.")]
+    InvalidImmediateAmount,
+
+    #[msg("New payer must differ from the current payer, the receiver and any referee.")]
+    InvalidNewPayer,
+
+    #[msg("The receiver must consent to transferring this agreement.")]
+    ReceiverConsentRequired,
+
+    #[msg("Auto-complete needs a referee and a time before the expiration.")]
+    InvalidAutoCompleteTimestamp,
+
+    #[msg("This agreement has no auto-complete time.")]
+    NoAutoComplete,
+
+    #[msg("The auto-complete time has not been reached yet.")]
+    AutoCompleteNotReached,
+
+    #[msg("The auto-complete time has passed, a dispute can no longer be opened.")]
+    AutoCompletePassed,
+
+    #[msg("Release history is full, approve the agreement to settle the remainder.")]
+    ReleaseHistoryFull,
+
+    #[msg(
+        "Token agreements must use the token instructions, native ones the native instructions."
+    )]
+    WrongInstructionForAsset,
+
+    #[msg("Mint does not match the agreement's token mint.")]
+    InvalidMint,
+
+    #[msg("Performance fee needs 1 to 10000 bps and a reference account of its own.")]
+    InvalidPerformanceFee,
+
+    #[msg("The performance fee reference account was not provided.")]
+    MissingReferenceAccount,
+
+    #[msg("The dispute window after expiration has not elapsed yet.")]
+    DisputeWindowActive,
+
+    #[msg("Payer balance does not cover the escrowed amount plus the account's rent.")]
+    InsufficientFundsForAmountAndRent,
+
+    #[msg("Split shares must be positive and sum exactly to the escrowed amount.")]
+    SplitSumMismatch,
+
+    #[msg("Split needs 1 to 10 distinct receivers, none of them the payer.")]
+    InvalidSplitReceivers,
+
+    #[msg("Fixed referee fee must be less than the payment amount.")]
+    RefereeFeeTooLarge,
+
+    #[msg("Memo must be at most 200 bytes of UTF-8.")]
+    InvalidMemo,
+
+    #[msg("Agreements above the threshold amount require a referee.")]
+    RefereeRequiredForLargeAmount,
+
+    #[msg("Milestones must be 1 to 10 positive amounts adding up to the agreement amount.")]
+    InvalidMilestones,
+
+    #[msg("No milestone exists at this index.")]
+    InvalidMilestoneIndex,
+
+    #[msg("Milestone has already been released.")]
+    MilestoneAlreadyReleased,
+
+    #[msg("Fallback receiver is missing, does not match, or is the payer.")]
+    InvalidFallbackReceiver,
+
+    #[msg("Amount is below the minimum escrow amount.")]
+    AmountBelowMinimum,
+
+    #[msg("Protocol fee needs 1 to 10000 basis points and a fee collector.")]
+    InvalidProtocolFee,
+
+    #[msg("Fee collector account is missing or does not match.")]
+    InvalidFeeCollector,
+
+    #[msg("Signer has already approved this agreement.")]
+    AlreadyApproved,
+
+    #[msg("The referee has already ruled on this agreement.")]
+    RefereeAlreadyIntervened,
+
+    #[msg("Recurring agreements need a positive amount, interval and number of periods.")]
+    InvalidRecurringTerms,
+
+    #[msg("The current period has not elapsed yet.")]
+    PeriodNotElapsed,
+
+    #[msg("The agreement is paused by the referee.")]
+    AgreementPaused,
+
+    #[msg("The agreement is not paused.")]
+    AgreementNotPaused,
+
+    #[msg("The receiver has not acknowledged the agreement yet.")]
+    ReceiverHasNotAcknowledged,
+
+    #[msg("The referee cannot change once approvals exist.")]
+    RefereeChangeAfterApproval,
+
+    #[msg("Exactly one party must have requested the cancellation.")]
+    NoOneSidedCancelRequest,
+
+    #[msg("The abandon timeout has not elapsed since the last activity.")]
+    AbandonTimeoutNotElapsed,
+
+    #[msg("The document hash must not be all zeros.")]
+    InvalidDocumentHash,
+
+    #[msg("The split range is empty or past the last share.")]
+    InvalidSplitRange,
+
+    #[msg("This split share has already been paid.")]
+    SplitShareAlreadyPaid,
+
+    #[msg("The receiver must be a System Program account.")]
+    ReceiverNotSystemAccount,
+
+    #[msg("Expiry can only favor the receiver on a native agreement with an expiration.")]
+    InvalidExpiryBeneficiary,
+
+    #[msg("This agreement settles in favor of the receiver on expiry.")]
+    ExpiryFavorsReceiver,
+
+    #[msg("The expiration is sooner than the minimum agreement lifetime.")]
+    ExpirationTooSoon,
+
+    #[msg("A one-sided completion mode needs a native agreement without approvers.")]
+    InvalidCompletionMode,
+
+    #[msg("The receiver account must be passed to check its owner.")]
+    MissingReceiverAccount,
+
+    #[msg("The receiver must be a wallet address, not a program-derived one.")]
+    ReceiverNotOnCurve,
+
+    #[msg("Token agreements can't be created under a nonce.")]
+    NonceOnTokenAgreement,
+
+    #[msg("The vault account does not belong to the agreement.")]
+    InvalidVault,
+
+    #[msg("The account refunds are owed to must be passed.")]
+    MissingRefundAccount,
+
+    #[msg("Arithmetic overflow while computing a settlement, fee or split.")]
+    ArithmeticOverflow,
+
+    #[msg("The escrow holds less than it owes, so the debit would eat into its rent.")]
+    EscrowBalanceMismatch,
 }
@@ -0,0 +1,297 @@
+// Off-chain helpers that derive the program's PDAs and build the account
+// metas of the common payment agreement instructions, so Rust clients don't
+// repeat the seed logic. Only compiled with the `client` feature.
+use crate::account::{nonce_seed, PAYMENT_AGREEMENT_SEED, VAULT_SEED};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::AccountMeta;
+use anchor_lang::system_program;
+
+// Account metas for one instruction, with the agreement PDA they target and
+// the vault escrowing its lamports
+pub struct AgreementAccounts {
+    pub metas: Vec<AccountMeta>,
+    pub payment_agreement: Pubkey,
+    pub bump: u8,
+    pub vault: Pubkey,
+}
+
+// Agreements created without a nonce use nonce 0. `payer` is the original
+// payer, which keeps deriving the address after transfer_payer_role.
+pub fn payment_agreement_pda(payer: &Pubkey, name: &str, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            PAYMENT_AGREEMENT_SEED,
+            payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(nonce),
+        ],
+        &crate::ID,
+    )
+}
+
+pub fn vault_pda(payment_agreement: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, payment_agreement.as_ref()], &crate::ID)
+}
+
+pub fn config_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"config"], &crate::ID)
+}
+
+pub fn stats_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"stats"], &crate::ID)
+}
+
+// receiver_account is checked to be a wallet, and may only be None when the
+// agreement sets allow_program_receiver
+pub fn create_payment_agreement(
+    payer: Pubkey,
+    name: &str,
+    nonce: u64,
+    referee: Option<Pubkey>,
+    receiver_account: Option<Pubkey>,
+) -> AgreementAccounts {
+    let (payment_agreement, bump) = payment_agreement_pda(&payer, name, nonce);
+    let vault = vault_pda(&payment_agreement).0;
+    let metas = crate::accounts::CreatePaymentAgreement {
+        payment_agreement,
+        vault,
+        payer,
+        referee,
+        receiver_account,
+        config: config_pda().0,
+        stats: stats_pda().0,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+
+    AgreementAccounts {
+        metas,
+        payment_agreement,
+        bump,
+        vault,
+    }
+}
+
+pub fn approve_payment_agreement(
+    payer: Pubkey,
+    receiver: Pubkey,
+    signer: Pubkey,
+    name: &str,
+    nonce: u64,
+) -> AgreementAccounts {
+    let (payment_agreement, bump) = payment_agreement_pda(&payer, name, nonce);
+    let vault = vault_pda(&payment_agreement).0;
+    let metas = crate::accounts::ApprovePaymentAgreement {
+        payment_agreement,
+        vault,
+        signer,
+        payer,
+        receiver,
+        config: config_pda().0,
+        stats: stats_pda().0,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+
+    AgreementAccounts {
+        metas,
+        payment_agreement,
+        bump,
+        vault,
+    }
+}
+
+pub fn cancel_payment_agreement(
+    payer: Pubkey,
+    signer: Pubkey,
+    name: &str,
+    nonce: u64,
+) -> AgreementAccounts {
+    let (payment_agreement, bump) = payment_agreement_pda(&payer, name, nonce);
+    let vault = vault_pda(&payment_agreement).0;
+    let metas = crate::accounts::CancelPaymentAgreement {
+        payment_agreement,
+        vault,
+        signer,
+        payer,
+        config: config_pda().0,
+        stats: stats_pda().0,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+
+    AgreementAccounts {
+        metas,
+        payment_agreement,
+        bump,
+        vault,
+    }
+}
+
+pub fn withdraw_expired_funds(payer: Pubkey, name: &str, nonce: u64) -> AgreementAccounts {
+    let (payment_agreement, bump) = payment_agreement_pda(&payer, name, nonce);
+    let vault = vault_pda(&payment_agreement).0;
+    let metas = crate::accounts::WithdrawExpiredFunds {
+        payment_agreement,
+        vault,
+        payer,
+        config: config_pda().0,
+        stats: stats_pda().0,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+
+    AgreementAccounts {
+        metas,
+        payment_agreement,
+        bump,
+        vault,
+    }
+}
+
+pub fn settle_expired(
+    payer: Pubkey,
+    receiver: Pubkey,
+    signer: Pubkey,
+    name: &str,
+    nonce: u64,
+) -> AgreementAccounts {
+    let (payment_agreement, bump) = payment_agreement_pda(&payer, name, nonce);
+    let vault = vault_pda(&payment_agreement).0;
+    let metas = crate::accounts::SettleExpired {
+        payment_agreement,
+        vault,
+        signer,
+        payer,
+        receiver,
+        config: config_pda().0,
+        stats: stats_pda().0,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None);
+
+    AgreementAccounts {
+        metas,
+        payment_agreement,
+        bump,
+        vault,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta_flags(metas: &[AccountMeta]) -> Vec<(Pubkey, bool, bool)> {
+        metas
+            .iter()
+            .map(|meta| (meta.pubkey, meta.is_signer, meta.is_writable))
+            .collect()
+    }
+
+    #[test]
+    fn payment_agreement_pda_uses_the_exported_seed() {
+        let payer = Pubkey::new_unique();
+        let (address, bump) = payment_agreement_pda(&payer, "invoice", 0);
+
+        let expected = Pubkey::create_program_address(
+            &[PAYMENT_AGREEMENT_SEED, payer.as_ref(), b"invoice", &[bump]],
+            &crate::ID,
+        )
+        .unwrap();
+        assert_eq!(address, expected);
+
+        let (address, bump) = payment_agreement_pda(&payer, "invoice", 7);
+        let expected = Pubkey::create_program_address(
+            &[
+                PAYMENT_AGREEMENT_SEED,
+                payer.as_ref(),
+                b"invoice",
+                &[0xff],
+                &7u64.to_le_bytes(),
+                &[bump],
+            ],
+            &crate::ID,
+        )
+        .unwrap();
+        assert_eq!(address, expected);
+    }
+
+    #[test]
+    fn vault_pda_uses_the_exported_seed() {
+        let (payment_agreement, _) = payment_agreement_pda(&Pubkey::new_unique(), "invoice", 0);
+        let (address, bump) = vault_pda(&payment_agreement);
+
+        let expected = Pubkey::create_program_address(
+            &[VAULT_SEED, payment_agreement.as_ref(), &[bump]],
+            &crate::ID,
+        )
+        .unwrap();
+        assert_eq!(address, expected);
+    }
+
+    #[test]
+    fn create_metas_follow_the_accounts_struct() {
+        let payer = Pubkey::new_unique();
+        let referee = Pubkey::new_unique();
+        let accounts = create_payment_agreement(payer, "invoice", 0, Some(referee), None);
+
+        assert_eq!(
+            meta_flags(&accounts.metas),
+            vec![
+                (accounts.payment_agreement, false, true),
+                (accounts.vault, false, true),
+                (payer, true, true),
+                (referee, false, false),
+                // An omitted optional account is passed as the program id
+                (crate::ID, false, false),
+                (config_pda().0, false, true),
+                (stats_pda().0, false, true),
+                (system_program::ID, false, false),
+            ]
+        );
+        assert_eq!(
+            (accounts.payment_agreement, accounts.bump),
+            payment_agreement_pda(&payer, "invoice", 0)
+        );
+    }
+
+    #[test]
+    fn approve_metas_follow_the_accounts_struct() {
+        let payer = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
+        let accounts = approve_payment_agreement(payer, receiver, receiver, "invoice", 0);
+
+        assert_eq!(
+            meta_flags(&accounts.metas),
+            vec![
+                (accounts.payment_agreement, false, true),
+                (accounts.vault, false, true),
+                (receiver, true, true),
+                (payer, false, true),
+                (receiver, false, true),
+                (config_pda().0, false, true),
+                (stats_pda().0, false, true),
+                (system_program::ID, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn withdraw_metas_follow_the_accounts_struct() {
+        let payer = Pubkey::new_unique();
+        let accounts = withdraw_expired_funds(payer, "invoice", 0);
+
+        assert_eq!(
+            meta_flags(&accounts.metas),
+            vec![
+                (accounts.payment_agreement, false, true),
+                (accounts.vault, false, true),
+                (payer, true, true),
+                (config_pda().0, false, true),
+                (stats_pda().0, false, true),
+                (system_program::ID, false, false),
+            ]
+        );
+    }
+}
@@ -1,402 +1,8233 @@
-use crate::account::{ErrorCode, PaymentAgreement};
+use crate::account::{
+    nonce_seed, AgreementApproved, AgreementCancelled, AgreementCompleted, AgreementCreated,
+    AgreementOptions, AgreementParams, AgreementStatus, AgreementSummary, ApproverWeight,
+    CompletionMode, CompletionNotice, Config, ConfigUpdate, Contribution, CrowdfundAgreement,
+    EmergencyRefund, ErrorCode, ExpiryBeneficiary, FundingSource, IntegrityStatus, Milestone,
+    OwnershipTransferred, PayerRoleTransferred, PaymentAgreement, PayoutBreakdown, PdaInfo,
+    PendingRelease, PerformanceFee, PermissionAction, PermissionCheck, ReceiptMinted,
+    ReceiverAcknowledged, ReceiverDeclined, RecurringAgreement, RefereeDecision, RefereeFee,
+    RefereeIntervened, RefereeRulingExecuted, RefereeVoteCast, ReleaseRecord, ResetToPending,
+    SplitPaymentAgreement, SplitShare, Stats, TieBreak, WithdrawBlockReason, WithdrawEligibility,
+    ABANDON_TIMEOUT, COMPLETION_CALLBACK_IX, CURRENT_SCHEMA_VERSION, MANDATORY_REFEREE_THRESHOLD,
+    MAX_APPROVERS, MAX_BATCH_CLOSE, MAX_BATCH_CREATE, MAX_CONTRIBUTORS, MAX_MEMO_LEN,
+    MAX_MILESTONES, MAX_MIN_DURATION_SECONDS, MAX_REFEREES, MAX_REFEREE_FEE_BPS,
+    MAX_RELEASE_HISTORY, MAX_RELEASE_SCHEDULE, MAX_SPLIT_RECEIVERS, MIN_AGREEMENT_LIFETIME,
+    MIN_ESCROW_AMOUNT, MIN_SECONDS_BEFORE_INTERVENTION, PAYMENT_AGREEMENT_SEED,
+    REFEREE_INACTIVITY_WINDOW, TIE_BREAK_TIMEOUT, V10_FIELDS_SPACE, V11_FIELDS_SPACE,
+    V12_FIELDS_SPACE, V13_FIELDS_SPACE, V3_FIELDS_SPACE, V4_FIELDS_SPACE, V5_FIELDS_SPACE,
+    V6_FIELDS_SPACE, V7_FIELDS_SPACE, V8_FIELDS_SPACE, V9_FIELDS_SPACE, VAULT_SEED,
+};
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke;
 use anchor_lang::system_program;
+use anchor_spl::associated_token::{get_associated_token_address, AssociatedToken};
+use anchor_spl::token::{self, CloseAccount, Mint, MintTo, Token, TokenAccount, Transfer};
+use std::ops::{Deref, DerefMut};
 
 #[derive(Accounts)]
-#[instruction(name: String, receiver: Pubkey, amount: u64, expiration_timestamp: Option<i64>)]
+#[instruction(
+    name: String,
+    receiver: Pubkey,
+    amount: u64,
+    expiration_timestamp: Option<i64>,
+    options: Option<AgreementOptions>
+)]
 pub struct CreatePaymentAgreement<'info> {
+    // init_if_needed so an idempotent retry can find the agreement it already created
     #[account(
-        init,
+        init_if_needed,
         payer = payer,
         space = 8 + PaymentAgreement::INIT_SPACE,
-        seeds = [b"payment_agreement", payer.key().as_ref(), name.as_bytes()],
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payer.key().as_ref(),
+            name.as_bytes(),
+            &nonce_seed(options_nonce(&options))
+        ],
         bump
     )]
     pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 0,
+        seeds = [VAULT_SEED, payment_agreement.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Lamport-only PDA, created here with no data
+    pub vault: UncheckedAccount<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
     /// CHECK: Optional referee account
     pub referee: Option<UncheckedAccount<'info>>,
+    /// CHECK: Receiver account, only read for its owner. Required unless
+    /// allow_program_receiver is set.
+    #[account(address = receiver @ ErrorCode::InvalidReceiver)]
+    pub receiver_account: Option<UncheckedAccount<'info>>,
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(name: String)]
-pub struct ApprovePaymentAgreement<'info> {
+#[instruction(name: String, receiver: Pubkey)]
+pub struct CreateTokenPaymentAgreement<'info> {
     #[account(
-        mut,
-        seeds = [b"payment_agreement", payer.key().as_ref(), name.as_bytes()],
+        init,
+        payer = payer,
+        space = 8 + PaymentAgreement::INIT_SPACE,
+        // Token agreements have no options and so no nonce
+        seeds = [PAYMENT_AGREEMENT_SEED, payer.key().as_ref(), name.as_bytes()],
         bump
     )]
     pub payment_agreement: Account<'info, PaymentAgreement>,
 
     #[account(mut)]
-    pub signer: Signer<'info>,
+    pub payer: Signer<'info>,
 
-    /// CHECK: This account is validated against the stored payer in the payment agreement
-    pub payer: AccountInfo<'info>,
+    pub mint: Account<'info, Mint>,
 
-    #[account(mut)]
-    /// CHECK: This account is validated against the stored receiver in the payment agreement
-    pub receiver: AccountInfo<'info>,
+    #[account(mut, token::mint = mint, token::authority = payer)]
+    pub payer_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Receiver account, only read for its owner. Token agreements take
+    /// no options, so it is always required.
+    #[account(address = receiver @ ErrorCode::InvalidReceiver)]
+    pub receiver_account: Option<UncheckedAccount<'info>>,
+
+    // Owned by the agreement PDA, which signs every transfer out
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = mint,
+        associated_token::authority = payment_agreement
+    )]
+    pub escrow_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
 
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
 #[instruction(name: String)]
-pub struct CancelPaymentAgreement<'info> {
+pub struct ApproveTokenPaymentAgreement<'info> {
     #[account(
         mut,
-        seeds = [b"payment_agreement", payer.key().as_ref(), name.as_bytes()],
-        bump
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
     )]
     pub payment_agreement: Account<'info, PaymentAgreement>,
 
-    #[account(mut)]
     pub signer: Signer<'info>,
 
     #[account(mut)]
-    /// CHECK: This account is validated against the stored payer in the payment agreement
+    /// CHECK: Validated against the stored payer, receives the token account rent
     pub payer: AccountInfo<'info>,
 
-    pub system_program: Program<'info, System>,
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = payment_agreement
+    )]
+    pub escrow_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = mint, token::authority = receiver)]
+    pub receiver_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub token_program: Program<'info, Token>,
 }
 
+// Shared by mutual cancellation and expiry, both refund the payer
 #[derive(Accounts)]
 #[instruction(name: String)]
-pub struct WithdrawExpiredFunds<'info> {
+pub struct RefundTokenPaymentAgreement<'info> {
     #[account(
         mut,
-        seeds = [b"payment_agreement", payer.key().as_ref(), name.as_bytes()],
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
         bump,
-        close = payer
+        has_one = payer @ ErrorCode::InvalidPayer
     )]
     pub payment_agreement: Account<'info, PaymentAgreement>,
 
+    pub signer: Signer<'info>,
+
     #[account(mut)]
-    pub payer: Signer<'info>,
+    /// CHECK: Validated against the stored payer, receives the token account rent
+    pub payer: AccountInfo<'info>,
 
-    pub system_program: Program<'info, System>,
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint,
+        associated_token::authority = payment_agreement
+    )]
+    pub escrow_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = mint, token::authority = payer)]
+    pub payer_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub token_program: Program<'info, Token>,
 }
 
-pub fn create_payment_agreement(
-    ctx: Context<CreatePaymentAgreement>,
+#[derive(Accounts)]
+#[instruction(
     name: String,
     receiver: Pubkey,
     amount: u64,
     expiration_timestamp: Option<i64>,
-) -> Result<()> {
-    // Validate name length
-    require!(name.len() > 0 && name.len() <= 32, ErrorCode::InvalidName);
+    options: Option<AgreementOptions>
+)]
+pub struct CreatePaymentAgreementFromSource<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PaymentAgreement::INIT_SPACE,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payer.key().as_ref(),
+            name.as_bytes(),
+            &nonce_seed(options_nonce(&options))
+        ],
+        bump
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
 
-    // Prevent self-payment
-    require!(
-        ctx.accounts.payer.key() != receiver,
-        ErrorCode::PayerCannotBeReceiver
-    );
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(
+        init,
+        payer = payer,
+        space = 0,
+        seeds = [VAULT_SEED, payment_agreement.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Lamport-only PDA, created here with no data
+    pub vault: UncheckedAccount<'info>,
+    // The source's delegate, pays only the rent of the agreement and its vault
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"funding_source", funding_source.owner.as_ref()],
+        bump = funding_source.bump
+    )]
+    pub funding_source: Account<'info, FundingSource>,
+    /// CHECK: Optional referee account
+    pub referee: Option<UncheckedAccount<'info>>,
+    /// CHECK: Receiver account, only read for its owner. Required unless
+    /// allow_program_receiver is set.
+    #[account(address = receiver @ ErrorCode::InvalidReceiver)]
+    pub receiver_account: Option<UncheckedAccount<'info>>,
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
 
-    // Get referee from optional account
-    let referee = match &ctx.accounts.referee {
-        Some(referee_account) => Some(referee_account.key()),
-        None => None,
-    };
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+    pub system_program: Program<'info, System>,
+}
 
-    // If referee is provided, ensure it's not the same as payer or receiver
-    if let Some(referee_key) = referee {
-        require!(
-            referee_key != ctx.accounts.payer.key(),
-            ErrorCode::RefereeCannotBePayer
-        );
-        require!(referee_key != receiver, ErrorCode::RefereeCannotBeReceiver);
-    }
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ApprovePaymentAgreement<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error
+    #[account(mut)]
+    /// CHECK: Owner checked, decoded and matched against its PDA by load_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
 
-    // If expiration is provided, ensure it's in the future
-    if let Some(expiration) = expiration_timestamp {
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        require!(
-            expiration > current_timestamp,
-            ErrorCode::ExpirationMustBeInFuture
-        );
-    }
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
 
-    let payment_agreement = &mut ctx.accounts.payment_agreement;
+    #[account(mut)]
+    pub signer: Signer<'info>,
 
-    //Check payer balance
-    let payer_balance = ctx.accounts.payer.to_account_info().lamports();
-    require!(payer_balance >= amount, ErrorCode::InsufficientFunds);
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
 
-    payment_agreement.name = name;
-    payment_agreement.payer = ctx.accounts.payer.key();
-    payment_agreement.receiver = receiver;
-    payment_agreement.referee = referee;
-    payment_agreement.amount = amount;
-    payment_agreement.expiration_timestamp = expiration_timestamp;
-    payment_agreement.payer_approved = false;
-    payment_agreement.receiver_approved = false;
-    payment_agreement.payer_requested_cancel = false;
-    payment_agreement.receiver_requested_cancel = false;
-    payment_agreement.is_completed = false;
-    payment_agreement.is_cancelled = false;
-    payment_agreement.is_referee_intervened = false;
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
 
-    system_program::transfer(
-        CpiContext::new(
-            ctx.accounts.system_program.to_account_info(),
-            system_program::Transfer {
-                from: ctx.accounts.payer.to_account_info(),
-                to: ctx.accounts.payment_agreement.to_account_info(),
-            },
-        ),
-        amount,
-    )?;
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
 
-    Ok(())
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
 }
 
-pub fn approve_payment_agreement(
-    ctx: Context<ApprovePaymentAgreement>,
-    _name: String,
-) -> Result<()> {
-    // Check if both parties have approved and get necessary data
-    let (should_complete, transfer_amount) = {
-        let payment_agreement = &mut ctx.accounts.payment_agreement;
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CancelPaymentAgreement<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error
+    #[account(mut)]
+    /// CHECK: Owner checked, decoded and matched against its PDA by load_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
 
-        require!(
-            ctx.accounts.signer.key() == payment_agreement.payer
-                || ctx.accounts.signer.key() == payment_agreement.receiver,
-            ErrorCode::Unauthorized
-        );
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
 
-        // Validate that passed accounts match stored accounts
-        require!(
-            ctx.accounts.payer.key() == payment_agreement.payer,
-            ErrorCode::InvalidPayer
-        );
-        require!(
-            ctx.accounts.receiver.key() == payment_agreement.receiver,
-            ErrorCode::InvalidReceiver
-        );
+    #[account(mut)]
+    pub signer: Signer<'info>,
 
-        require!(
-            !payment_agreement.is_completed,
-            ErrorCode::AgreementAlreadyCompleted
-        );
-        require!(
-            !payment_agreement.is_cancelled,
-            ErrorCode::AgreementAlreadyCancelled
-        );
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
 
-        if ctx.accounts.signer.key() == payment_agreement.payer {
-            payment_agreement.payer_approved = true;
-        } else if ctx.accounts.signer.key() == payment_agreement.receiver {
-            payment_agreement.receiver_approved = true;
-        }
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
 
-        let should_complete =
-            payment_agreement.payer_approved && payment_agreement.receiver_approved;
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
 
-        if should_complete {
-            payment_agreement.is_completed = true;
-        }
+    pub system_program: Program<'info, System>,
+}
 
-        (should_complete, payment_agreement.amount)
-    };
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct WithdrawExpiredFunds<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error and
+    // closed by the handler once the refund is complete
+    #[account(mut)]
+    /// CHECK: Owner checked, decoded and matched against its PDA by load_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
 
-    // Now do the transfer if needed
-    if should_complete {
-        // Transfer lamports from PDA to receiver
-        ctx.accounts
-            .payment_agreement
-            .sub_lamports(transfer_amount)?;
-        ctx.accounts.receiver.add_lamports(transfer_amount)?;
-    }
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
 
-    Ok(())
-}
+    #[account(mut)]
+    pub payer: Signer<'info>,
 
-pub fn cancel_payment_agreement(ctx: Context<CancelPaymentAgreement>, _name: String) -> Result<()> {
-    // Handle cancellation logic and get necessary data
-    let (should_cancel, transfer_amount) = {
-        let payment_agreement = &mut ctx.accounts.payment_agreement;
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
 
-        require!(
-            ctx.accounts.signer.key() == payment_agreement.payer
-                || ctx.accounts.signer.key() == payment_agreement.receiver,
-            ErrorCode::Unauthorized
-        );
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
 
-        // Validate that passed payer account matches stored payer
-        require!(
-            ctx.accounts.payer.key() == payment_agreement.payer,
-            ErrorCode::InvalidPayer
-        );
+    pub system_program: Program<'info, System>,
+}
 
-        require!(
-            !payment_agreement.is_completed,
-            ErrorCode::AgreementAlreadyCompleted
-        );
-        require!(
-            !payment_agreement.is_cancelled,
-            ErrorCode::AgreementAlreadyCancelled
-        );
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct SettleExpired<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error and
+    // closed by the handler once the agreement is settled in full
+    #[account(mut)]
+    /// CHECK: Owner checked, decoded and matched against its PDA by load_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
 
-        if ctx.accounts.signer.key() == payment_agreement.payer {
-            payment_agreement.payer_requested_cancel = true;
-        } else if ctx.accounts.signer.key() == payment_agreement.receiver {
-            payment_agreement.receiver_requested_cancel = true;
-        }
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
 
-        let should_cancel =
-            payment_agreement.payer_requested_cancel && payment_agreement.receiver_requested_cancel;
+    // The payer or the receiver, whichever the expiry favors
+    pub signer: Signer<'info>,
 
-        if should_cancel {
-            payment_agreement.is_cancelled = true;
-        }
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
 
-        (should_cancel, payment_agreement.amount)
-    };
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
 
-    // Return funds to payer if cancelled
-    if should_cancel {
-        // Transfer lamports from PDA to payer
-        ctx.accounts
-            .payment_agreement
-            .sub_lamports(transfer_amount)?;
-        ctx.accounts.payer.add_lamports(transfer_amount)?;
-    }
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
 
-    Ok(())
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
 }
 
-pub fn referee_intervene_complete_payment_agreement(
-    ctx: Context<ApprovePaymentAgreement>,
-    _name: String,
-) -> Result<()> {
+#[derive(Accounts)]
+pub struct MigrateAgreement<'info> {
+    // Left unchecked so older layouts that no longer decode can still be read
+    #[account(mut, owner = crate::ID @ ErrorCode::InvalidAgreementAccount)]
+    /// CHECK: The discriminator and layout are checked by the handler
+    pub payment_agreement: UncheckedAccount<'info>,
+
+    // Created by the handler for native agreements from before the vault
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    // Anyone may migrate, the caller covers the rent for the added bytes
+    #[account(mut)]
+    pub fee_payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ReadPaymentAgreement<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error
+    #[account(owner = crate::ID @ ErrorCode::InvalidAgreementAccount)]
+    /// CHECK: Decoded and matched against its PDA by read_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
+
+    #[account(seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA holding the escrow, see VAULT_SEED
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: The original payer, only used to derive the payment agreement PDA
+    pub payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CheckPermission<'info> {
+    // Left unchecked so a foreign-owned account fails with a readable error
+    #[account(owner = crate::ID @ ErrorCode::InvalidAgreementAccount)]
+    /// CHECK: Decoded and matched against its PDA by read_agreement
+    pub payment_agreement: UncheckedAccount<'info>,
+
+    /// CHECK: The original payer, only used to derive the payment agreement PDA
+    pub payer: AccountInfo<'info>,
+
+    #[account(seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ResetPaymentAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+
+    pub receiver: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ChangeReferee<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+
+    pub receiver: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct UpdateDocumentHash<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+
+    pub receiver: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct DisputePaymentAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub signer: Signer<'info>,
+
+    /// CHECK: Matched against the stored payer by has_one
+    pub payer: AccountInfo<'info>,
+
+    #[account(seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct SetApprovalMode<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+
+    pub receiver: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct EnforceSla<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    /// CHECK: Matched against the stored payer by has_one
+    pub payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct SubmitDelivery<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub receiver: Signer<'info>,
+
+    /// CHECK: Matched against the stored payer by has_one
+    pub payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ProveAndApprove<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    // Whoever holds the proof token, typically the carrier
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
+
+    pub proof_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = proof_mint,
+        token::authority = depositor
+    )]
+    pub depositor_token_account: Account<'info, TokenAccount>,
+
+    // Program-controlled account the proof token is locked into
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        seeds = [b"proof_vault", payment_agreement.key().as_ref()],
+        bump,
+        token::mint = proof_mint,
+        token::authority = payment_agreement
+    )]
+    pub proof_vault: Account<'info, TokenAccount>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String, new_name: String, new_receiver: Pubkey)]
+pub struct MigrateToNewTerms<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer,
+        close = payer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PaymentAgreement::INIT_SPACE,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payer.key().as_ref(),
+            new_name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump
+    )]
+    pub new_payment_agreement: Account<'info, PaymentAgreement>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 0,
+        seeds = [VAULT_SEED, new_payment_agreement.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Lamport-only PDA, created here with no data
+    pub new_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The current receiver must consent to the new terms
+    pub receiver: Signer<'info>,
+
+    /// CHECK: Optional referee for the new terms
+    pub new_referee: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: New receiver account, only read for its owner. Migration takes no
+    /// options, so it is always required.
+    #[account(address = new_receiver @ ErrorCode::InvalidReceiver)]
+    pub receiver_account: Option<UncheckedAccount<'info>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct TransferOwnership<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer,
+        close = payer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    // Same name, re-seeded under the new payer
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PaymentAgreement::INIT_SPACE,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            new_payer.key().as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump
+    )]
+    pub new_payment_agreement: Account<'info, PaymentAgreement>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 0,
+        seeds = [VAULT_SEED, new_payment_agreement.key().as_ref()],
+        bump
+    )]
+    /// CHECK: Lamport-only PDA, created here with no data
+    pub new_vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: Only used as the new payer key and PDA seed
+    pub new_payer: UncheckedAccount<'info>,
+
+    // Required when the agreement asks for the receiver's consent
+    pub receiver: Option<Signer<'info>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct TransferPayerRole<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+
+    // Required when the agreement asks for the receiver's consent
+    pub receiver: Option<Signer<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ResolveTie<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct SealedReceiverTerms<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub receiver: Signer<'info>,
+
+    /// CHECK: Matched against the stored payer by has_one
+    pub payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct RefereeVote<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    pub signer: Signer<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the payment agreement
+    pub receiver: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+}
+
+// The agreement PDAs are passed as writable remaining accounts, one per entry
+// and in the same order, followed by the vault and then the receiver account
+// of each entry
+#[derive(Accounts)]
+pub struct CreatePaymentAgreementsBatch<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(mut, seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// The agreements are passed as writable remaining accounts, each followed by
+// its vault
+#[derive(Accounts)]
+pub struct CloseTerminalAgreementsBatch<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CloseSettledAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer,
+        close = payer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct AdminEmergencyRefund<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer,
+        close = payer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored payer in the payment agreement
+    pub payer: AccountInfo<'info>,
+
+    #[account(mut, seeds = [b"config"], bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct RevealPayerAmount<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ExtendExpiration<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct TopUpPaymentAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [
+            PAYMENT_AGREEMENT_SEED,
+            payment_agreement.original_payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce)
+        ],
+        bump,
+        has_one = payer @ ErrorCode::InvalidPayer
+    )]
+    pub payment_agreement: Account<'info, PaymentAgreement>,
+
+    // Holds the escrowed lamports, see VAULT_SEED
+    #[account(mut, seeds = [VAULT_SEED, payment_agreement.key().as_ref()], bump)]
+    /// CHECK: Lamport-only PDA, its address is checked by the seeds
+    pub vault: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(mut, seeds = [b"stats"], bump)]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenFundingSource<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + FundingSource::INIT_SPACE,
+        seeds = [b"funding_source", owner.key().as_ref()],
+        bump
+    )]
+    pub funding_source: Account<'info, FundingSource>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseFundingSource<'info> {
+    #[account(
+        mut,
+        seeds = [b"funding_source", owner.key().as_ref()],
+        bump = funding_source.bump,
+        has_one = owner @ ErrorCode::Unauthorized,
+        close = owner
+    )]
+    pub funding_source: Account<'info, FundingSource>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Config::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::EscrowPayment>,
+
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(admin.key())
+            @ ErrorCode::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeStats<'info> {
+    #[account(seeds = [b"config"], bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Stats::INIT_SPACE,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub config: Account<'info, Config>,
+
+    pub admin: Signer<'info>,
+}
+
+// The agreements are passed as writable remaining accounts, each followed by
+// its vault
+#[derive(Accounts)]
+pub struct SweepDust<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump,
+        has_one = admin @ ErrorCode::Unauthorized,
+        has_one = treasury @ ErrorCode::InvalidTreasury
+    )]
+    pub config: Account<'info, Config>,
+
+    pub admin: Signer<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the treasury stored in the config
+    pub treasury: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeReceiptMint<'info> {
+    #[account(seeds = [b"config"], bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // Program-wide receipt mint, the config PDA is its mint authority
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"receipt_mint"],
+        bump,
+        mint::decimals = 0,
+        mint::authority = config
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateCrowdfundAgreement<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + CrowdfundAgreement::INIT_SPACE,
+        seeds = [b"crowdfund", creator.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub crowdfund_agreement: Account<'info, CrowdfundAgreement>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct Contribute<'info> {
+    #[account(
+        mut,
+        seeds = [b"crowdfund", creator.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub crowdfund_agreement: Account<'info, CrowdfundAgreement>,
+
+    #[account(mut)]
+    pub contributor: Signer<'info>,
+
+    /// CHECK: This account is only used to derive the crowdfund PDA
+    pub creator: AccountInfo<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver in the crowdfund agreement
+    pub receiver: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ReclaimContribution<'info> {
+    #[account(
+        mut,
+        seeds = [b"crowdfund", creator.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub crowdfund_agreement: Account<'info, CrowdfundAgreement>,
+
+    #[account(mut)]
+    pub contributor: Signer<'info>,
+
+    /// CHECK: This account is only used to derive the crowdfund PDA
+    pub creator: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateSplitPaymentAgreement<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + SplitPaymentAgreement::INIT_SPACE,
+        seeds = [b"split_payment", payer.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub split_payment_agreement: Account<'info, SplitPaymentAgreement>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// Receivers are passed as writable remaining accounts, in share order
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CompleteSplitPaymentAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [b"split_payment", payer.key().as_ref(), name.as_bytes()],
+        bump,
+        close = payer
+    )]
+    pub split_payment_agreement: Account<'info, SplitPaymentAgreement>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// Receivers for shares[start..end] are passed as writable remaining accounts,
+// in share order
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct SettleSplitRange<'info> {
+    #[account(
+        mut,
+        seeds = [b"split_payment", payer.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub split_payment_agreement: Account<'info, SplitPaymentAgreement>,
+
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateRecurringAgreement<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + RecurringAgreement::INIT_SPACE,
+        seeds = [b"recurring", payer.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub recurring_agreement: Account<'info, RecurringAgreement>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct ClaimPeriod<'info> {
+    #[account(
+        mut,
+        seeds = [b"recurring", payer.key().as_ref(), name.as_bytes()],
+        bump
+    )]
+    pub recurring_agreement: Account<'info, RecurringAgreement>,
+
+    #[account(mut)]
+    pub receiver: Signer<'info>,
+
+    #[account(mut)]
+    /// CHECK: Only used to derive the PDA and to receive the rent after the last period
+    pub payer: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CancelRecurringAgreement<'info> {
+    #[account(
+        mut,
+        seeds = [b"recurring", payer.key().as_ref(), name.as_bytes()],
+        bump,
+        close = payer
+    )]
+    pub recurring_agreement: Account<'info, RecurringAgreement>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(mut)]
+    /// CHECK: This account is validated against the stored receiver
+    pub receiver: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_config(
+    ctx: Context<InitializeConfig>,
+    max_total_active: u64,
+    treasury: Pubkey,
+) -> Result<()> {
+    let config = &mut ctx.accounts.config;
+
+    config.admin = ctx.accounts.admin.key();
+    config.total_active_agreements = 0;
+    config.max_total_active = max_total_active;
+    config.min_seconds_before_intervention = MIN_SECONDS_BEFORE_INTERVENTION;
+    config.treasury = treasury;
+    config.default_referee = None;
+    config.referee_inactivity_window = REFEREE_INACTIVITY_WINDOW;
+    config.tie_break_timeout = TIE_BREAK_TIMEOUT;
+    config.callback_failures_fatal = false;
+    config.abandon_timeout = ABANDON_TIMEOUT;
+    config.min_agreement_lifetime = MIN_AGREEMENT_LIFETIME;
+
+    Ok(())
+}
+
+// Totals start from zero, agreements created before this are not counted
+pub fn initialize_stats(_ctx: Context<InitializeStats>) -> Result<()> {
+    Ok(())
+}
+
+pub fn update_config(ctx: Context<UpdateConfig>, update: ConfigUpdate) -> Result<()> {
+    let config = &mut ctx.accounts.config;
+
+    if let Some(max_total_active) = update.max_total_active {
+        config.max_total_active = max_total_active;
+    }
+
+    if let Some(window) = update.min_seconds_before_intervention {
+        require!(window >= 0, ErrorCode::InvalidInterventionWindow);
+        config.min_seconds_before_intervention = window;
+    }
+
+    if let Some(treasury) = update.treasury {
+        config.treasury = treasury;
+    }
+
+    if let Some(default_referee) = update.default_referee {
+        config.default_referee = Some(default_referee);
+    }
+
+    if let Some(window) = update.referee_inactivity_window {
+        require!(window >= 0, ErrorCode::InvalidInterventionWindow);
+        config.referee_inactivity_window = window;
+    }
+
+    if let Some(timeout) = update.tie_break_timeout {
+        require!(timeout >= 0, ErrorCode::InvalidInterventionWindow);
+        config.tie_break_timeout = timeout;
+    }
+
+    if let Some(fatal) = update.callback_failures_fatal {
+        config.callback_failures_fatal = fatal;
+    }
+
+    if let Some(timeout) = update.abandon_timeout {
+        require!(timeout >= 0, ErrorCode::InvalidInterventionWindow);
+        config.abandon_timeout = timeout;
+    }
+
+    if let Some(lifetime) = update.min_agreement_lifetime {
+        require!(lifetime >= 0, ErrorCode::InvalidInterventionWindow);
+        config.min_agreement_lifetime = lifetime;
+    }
+
+    Ok(())
+}
+
+pub fn sweep_dust(ctx: Context<SweepDust>) -> Result<u64> {
+    let pairs = ctx.remaining_accounts.chunks_exact(2);
+    require!(
+        pairs.remainder().is_empty(),
+        ErrorCode::InvalidAgreementAccount
+    );
+
+    let rent = Rent::get()?;
+    let mut total_swept: u64 = 0;
+
+    for accounts in pairs {
+        let (account_info, vault) = (&accounts[0], &accounts[1]);
+        require!(
+            account_info.owner == &crate::ID,
+            ErrorCode::InvalidAgreementAccount
+        );
+
+        let payment_agreement = {
+            let data = account_info.try_borrow_data()?;
+            PaymentAgreement::try_deserialize(&mut &data[..])?
+        };
+
+        // Only settled agreements hold orphaned lamports, never an active escrow
+        require!(
+            payment_agreement.status != AgreementStatus::Pending,
+            ErrorCode::AgreementNotSettled
+        );
+
+        let (vault_address, _) =
+            Pubkey::find_program_address(&[VAULT_SEED, account_info.key.as_ref()], &crate::ID);
+        require_keys_eq!(vault.key(), vault_address, ErrorCode::InvalidVault);
+
+        // Anything above the rent-exempt minimum is dust, except chunks of a
+        // gradual expiry refund that the vault still owes the payer. Token
+        // agreements have no vault to sweep.
+        let owed = if payment_agreement.refund_started_at.is_some() {
+            payment_agreement
+                .amount
+                .checked_add(payment_agreement.reserved_referee_fee)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+        } else {
+            0
+        };
+        let agreement_dust = account_info
+            .lamports()
+            .saturating_sub(rent.minimum_balance(account_info.data_len()));
+        let vault_dust = if vault.owner == &crate::ID {
+            vault
+                .lamports()
+                .saturating_sub(rent.minimum_balance(0).saturating_add(owed))
+        } else {
+            0
+        };
+
+        for (account, dust) in [(account_info, agreement_dust), (vault, vault_dust)] {
+            if dust == 0 {
+                continue;
+            }
+
+            account.sub_lamports(dust)?;
+            ctx.accounts.treasury.add_lamports(dust)?;
+
+            total_swept = total_swept
+                .checked_add(dust)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+    }
+
+    Ok(total_swept)
+}
+
+pub fn initialize_receipt_mint(_ctx: Context<InitializeReceiptMint>) -> Result<()> {
+    Ok(())
+}
+
+// Mints one receipt token to the payer. Expects remaining accounts
+// [receipt_mint, payer_token_account, token_program].
+fn mint_completion_receipt<'info>(
+    config: &Account<'info, Config>,
+    config_bump: u8,
+    payer: Pubkey,
+    remaining_accounts: &'info [AccountInfo<'info>],
+) -> Result<()> {
+    let (expected_mint, _) = Pubkey::find_program_address(&[b"receipt_mint"], &crate::ID);
+
+    // Looked up by key like the fee collector, so the receipt accounts can sit
+    // anywhere among the reference and fee accounts. The receipt goes to the
+    // payer's associated token account.
+    let find_account = |key: Pubkey| {
+        remaining_accounts
+            .iter()
+            .find(|account| account.key() == key)
+            .ok_or(ErrorCode::MissingReceiptAccounts)
+    };
+    let receipt_mint = find_account(expected_mint)?;
+    let token_program = find_account(token::ID)?;
+    let payer_token_account = find_account(get_associated_token_address(&payer, &expected_mint))?;
+
+    require_keys_eq!(
+        *payer_token_account.owner,
+        token::ID,
+        ErrorCode::InvalidReceiptAccounts
+    );
+
+    // The receipt must land in a token account the payer actually owns
+    let token_account = {
+        let data = payer_token_account.try_borrow_data()?;
+        TokenAccount::try_deserialize(&mut &data[..])?
+    };
+    require_keys_eq!(
+        token_account.owner,
+        payer,
+        ErrorCode::InvalidReceiptAccounts
+    );
+    require_keys_eq!(
+        token_account.mint,
+        expected_mint,
+        ErrorCode::InvalidReceiptAccounts
+    );
+
+    let signer_seeds: &[&[&[u8]]] = &[&[b"config".as_ref(), &[config_bump]]];
+    token::mint_to(
+        CpiContext::new_with_signer(
+            token_program.clone(),
+            MintTo {
+                mint: receipt_mint.clone(),
+                to: payer_token_account.clone(),
+                authority: config.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        1,
+    )?;
+
+    Ok(())
+}
+
+// Every state-changing instruction checks this first, so once an earlier
+// instruction in the same transaction settles the agreement, anything after
+// it that would move funds is rejected
+fn require_pending(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require_unsettled(payment_agreement)?;
+    require_native(payment_agreement)?;
+
+    Ok(())
+}
+
+// Party-driven instructions are frozen while the referee has the agreement
+// paused, the referee's own instructions are not
+fn require_not_paused(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(!payment_agreement.is_paused, ErrorCode::AgreementPaused);
+
+    Ok(())
+}
+
+fn require_acknowledged(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        !payment_agreement.require_ack || payment_agreement.receiver_acknowledged,
+        ErrorCode::ReceiverHasNotAcknowledged
+    );
+
+    Ok(())
+}
+
+fn require_unsettled(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        !payment_agreement.status.is_completed(),
+        ErrorCode::AgreementAlreadyCompleted
+    );
+    require!(
+        payment_agreement.status == AgreementStatus::Pending,
+        ErrorCode::AgreementAlreadyCancelled
+    );
+
+    Ok(())
+}
+
+// The native instructions move lamports, which a token agreement doesn't hold
+fn require_native(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        payment_agreement.mint.is_none(),
+        ErrorCode::WrongInstructionForAsset
+    );
+
+    Ok(())
+}
+
+// Called whenever an agreement leaves the pending state
+fn release_active_slot(config: &mut Config, stats: &mut Stats) -> Result<()> {
+    config.total_active_agreements = config
+        .total_active_agreements
+        .checked_sub(1)
+        .ok_or(ErrorCode::CounterOverflow)?;
+    // Agreements created before the stats account existed were never counted
+    stats.active_count = stats.active_count.saturating_sub(1);
+
+    Ok(())
+}
+
+// Who approves on the payer's side, the funder unless a separate approver is set
+fn payer_side_approver(payment_agreement: &PaymentAgreement) -> Pubkey {
+    payment_agreement
+        .payer_approver
+        .unwrap_or(payment_agreement.payer)
+}
+
+// Repeated right before a referee ruling is recorded and paid out, so the
+// payout can't fire on a settled agreement even if the checks above it are
+// reordered, and a ruling is never recorded twice
+fn require_no_ruling(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require_unsettled(payment_agreement)?;
+    require!(
+        !payment_agreement.status.is_referee_ruling(),
+        ErrorCode::RefereeAlreadyIntervened
+    );
+
+    Ok(())
+}
+
+// Re-read right before a completion payout leaves the escrow, so the transfer
+// never trusts a flag captured earlier if something in between changes state
+fn require_completed(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        payment_agreement.status.is_completed(),
+        ErrorCode::BothPartiesMustApprove
+    );
+
+    Ok(())
+}
+
+// Whether the party approvals settle an agreement without an approver set. A
+// submitted delivery counts as the receiver's approval.
+fn approvals_complete(payment_agreement: &PaymentAgreement) -> bool {
+    let receiver_approved =
+        payment_agreement.receiver_approved || payment_agreement.delivery_submitted;
+    match payment_agreement.completion_mode {
+        CompletionMode::MutualApproval => payment_agreement.payer_approved && receiver_approved,
+        CompletionMode::ReceiverConfirm => receiver_approved,
+        CompletionMode::PayerRelease => payment_agreement.payer_approved,
+    }
+}
+
+// Every party action on an open agreement calls this, so the abandon timeout
+// only runs while neither side is doing anything
+fn record_activity(payment_agreement: &mut PaymentAgreement) -> Result<()> {
+    payment_agreement.last_activity_ts = Clock::get()?.unix_timestamp;
+
+    Ok(())
+}
+
+// Moves the agreement into a terminal status and stamps when that happened
+fn settle(payment_agreement: &mut PaymentAgreement, status: AgreementStatus) -> Result<()> {
+    payment_agreement.status = status;
+    payment_agreement.settled_at = Some(Clock::get()?.unix_timestamp);
+
+    Ok(())
+}
+
+// Every completion path goes through here so the settling slot is always recorded
+fn mark_completed(payment_agreement: &mut PaymentAgreement) -> Result<()> {
+    let clock = Clock::get()?;
+    payment_agreement.status = AgreementStatus::Completed;
+    payment_agreement.settled_at = Some(clock.unix_timestamp);
+    payment_agreement.completion_slot = clock.slot;
+
+    Ok(())
+}
+
+// Notifies the completion callback, if one is set. The callback program is
+// found by key in the remaining accounts and every account after it is
+// forwarded. Only a missing or non-executable program can be skipped, an error
+// returned by the callback itself still aborts the transaction.
+fn notify_completion(
+    payment_agreement: &(impl Key + Deref<Target = PaymentAgreement>),
+    config: &Config,
+    amount: u64,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    let Some(callback) = payment_agreement.completion_callback else {
+        return Ok(());
+    };
+
+    let position = remaining_accounts
+        .iter()
+        .position(|account| account.key() == callback && account.executable);
+    let Some(position) = position else {
+        require!(
+            !config.callback_failures_fatal,
+            ErrorCode::CompletionCallbackUnavailable
+        );
+        msg!("Completion callback {} unavailable, skipping", callback);
+        return Ok(());
+    };
+
+    let notice = CompletionNotice {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        receiver: payment_agreement.receiver,
+        amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    };
+    let preimage = format!("global:{}", COMPLETION_CALLBACK_IX);
+    let mut data = hashv(&[preimage.as_bytes()]).to_bytes()[..8].to_vec();
+    notice
+        .serialize(&mut data)
+        .map_err(|_| anchor_lang::error::ErrorCode::InstructionDidNotSerialize)?;
+
+    let callback_accounts = &remaining_accounts[position + 1..];
+    let instruction = Instruction {
+        program_id: callback,
+        accounts: callback_accounts
+            .iter()
+            .map(|account| AccountMeta {
+                pubkey: account.key(),
+                is_signer: account.is_signer,
+                is_writable: account.is_writable,
+            })
+            .collect(),
+        data,
+    };
+    invoke(&instruction, callback_accounts)?;
+
+    Ok(())
+}
+
+// Once the history is full the remainder can still be settled in one go
+// through the normal approval flow
+fn require_release_slot(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        payment_agreement.release_history.len() < MAX_RELEASE_HISTORY,
+        ErrorCode::ReleaseHistoryFull
+    );
+
+    Ok(())
+}
+
+// Pays `amount` out of the escrow's books and appends it to the history
+fn record_release(payment_agreement: &mut PaymentAgreement, amount: u64) -> Result<()> {
+    require_release_slot(payment_agreement)?;
+
+    payment_agreement.amount = payment_agreement
+        .amount
+        .checked_sub(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    payment_agreement.released_amount = payment_agreement
+        .released_amount
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    payment_agreement.release_count = payment_agreement
+        .release_count
+        .checked_add(1)
+        .ok_or(ErrorCode::CounterOverflow)?;
+    payment_agreement.release_history.push(ReleaseRecord {
+        amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+fn emit_completed(
+    payment_agreement: &(impl Key + Deref<Target = PaymentAgreement>),
+    amount: u64,
+    referee_forced: bool,
+) -> Result<()> {
+    emit!(AgreementCompleted {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        receiver: payment_agreement.receiver,
+        amount,
+        referee_forced,
+        created_at: payment_agreement.created_at,
+        timestamp: Clock::get()?.unix_timestamp,
+        completion_slot: payment_agreement.completion_slot,
+    });
+
+    Ok(())
+}
+
+fn emit_cancelled(
+    payment_agreement: &(impl Key + Deref<Target = PaymentAgreement>),
+    amount: u64,
+) -> Result<()> {
+    emit!(AgreementCancelled {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        receiver: payment_agreement.receiver,
+        amount,
+        created_at: payment_agreement.created_at,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+fn add_volume(total: &mut u128, amount: u64) -> Result<()> {
+    *total = total
+        .checked_add(amount as u128)
+        .ok_or(ErrorCode::AmountOverflow)?;
+
+    Ok(())
+}
+
+fn increment_count(count: &mut u64) -> Result<()> {
+    *count = count.checked_add(1).ok_or(ErrorCode::CounterOverflow)?;
+
+    Ok(())
+}
+
+fn record_completed(stats: &mut Stats, amount: u64) -> Result<()> {
+    increment_count(&mut stats.completed_count)?;
+    add_volume(&mut stats.completed_volume, amount)
+}
+
+fn record_cancelled(stats: &mut Stats, amount: u64) -> Result<()> {
+    increment_count(&mut stats.cancelled_count)?;
+    add_volume(&mut stats.cancelled_volume, amount)
+}
+
+// Lamports of the performance fee's reference account, passed in remaining accounts
+fn reference_balance(
+    performance_fee: &PerformanceFee,
+    remaining_accounts: &[AccountInfo],
+) -> Result<u64> {
+    let reference = remaining_accounts
+        .iter()
+        .find(|account| account.key() == performance_fee.reference_account)
+        .ok_or(ErrorCode::MissingReferenceAccount)?;
+
+    Ok(reference.lamports())
+}
+
+fn capture_reference_baseline(
+    payment_agreement: &mut Account<PaymentAgreement>,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    if let Some(performance_fee) = payment_agreement.performance_fee {
+        // The agreement's own balance is the escrow, not a performance measure
+        require!(
+            performance_fee.reference_account != payment_agreement.key(),
+            ErrorCode::InvalidPerformanceFee
+        );
+        payment_agreement.reference_baseline =
+            reference_balance(&performance_fee, remaining_accounts)?;
+    }
+
+    Ok(())
+}
+
+// fee_bps of the growth over the baseline, never more than the cap. A balance
+// at or below the baseline owes nothing.
+fn performance_fee_amount(balance: u64, baseline: u64, fee_bps: u16, cap: u64) -> u64 {
+    let growth = balance.saturating_sub(baseline);
+    (growth as u128 * fee_bps as u128 / 10_000).min(cap as u128) as u64
+}
+
+// Portion of the escrow the receiver is paid on completion
+fn completion_payout(
+    payment_agreement: &PaymentAgreement,
+    remaining_accounts: &[AccountInfo],
+) -> Result<u64> {
+    match payment_agreement.performance_fee {
+        Some(performance_fee) => Ok(performance_fee_amount(
+            reference_balance(&performance_fee, remaining_accounts)?,
+            payment_agreement.reference_baseline,
+            performance_fee.fee_bps,
+            payment_agreement.amount,
+        )),
+        None => Ok(payment_agreement.amount),
+    }
+}
+
+// Rounds down, so the receiver keeps any fraction of a lamport
+fn protocol_fee_amount(payout: u64, fee_basis_points: u16) -> Result<u64> {
+    bps_of(payout, fee_basis_points)
+}
+
+// Errors instead of truncating when bps above 10_000 push the result past u64
+fn bps_of(amount: u64, bps: u16) -> Result<u64> {
+    let scaled = (amount as u128)
+        .checked_mul(bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        / 10_000;
+    u64::try_from(scaled).map_err(|_| error!(ErrorCode::ArithmeticOverflow))
+}
+
+fn fee_collector_account<'a, 'info>(
+    payment_agreement: &PaymentAgreement,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<&'a AccountInfo<'info>> {
+    let fee_collector = remaining_accounts
+        .iter()
+        .find(|account| account.key() == payment_agreement.fee_collector)
+        .ok_or(ErrorCode::InvalidFeeCollector)?;
+
+    Ok(fee_collector)
+}
+
+// Refunds go back to whoever funded the escrow. That is the payer, unless the
+// agreement pulled from a FundingSource and recorded its owner in refund_to,
+// who is then found by key in the remaining accounts. Returns the account
+// credited.
+fn credit_refund(
+    payment_agreement: &PaymentAgreement,
+    payer: &AccountInfo,
+    remaining_accounts: &[AccountInfo],
+    amount: u64,
+) -> Result<Pubkey> {
+    if payment_agreement.refund_to == Pubkey::default() {
+        payer.add_lamports(amount)?;
+        return Ok(payer.key());
+    }
+
+    let refund_account = remaining_accounts
+        .iter()
+        .find(|account| account.key() == payment_agreement.refund_to)
+        .ok_or(ErrorCode::MissingRefundAccount)?;
+    refund_account.add_lamports(amount)?;
+
+    Ok(refund_account.key())
+}
+
+// Large native escrows can't be left without anyone to arbitrate them
+fn require_referee_coverage(payment_agreement: &PaymentAgreement) -> Result<()> {
+    require!(
+        payment_agreement.mint.is_some()
+            || payment_agreement.amount <= MANDATORY_REFEREE_THRESHOLD
+            || payment_agreement.referee.is_some()
+            || !payment_agreement.referees.is_empty(),
+        ErrorCode::RefereeRequiredForLargeAmount
+    );
+
+    Ok(())
+}
+
+// Portion of the escrow owed to an intervening referee, never more than the amount
+fn referee_fee_amount(payment_agreement: &PaymentAgreement) -> Result<u64> {
+    let amount = payment_agreement.amount;
+    match payment_agreement.referee_fee {
+        Some(RefereeFee::Fixed { lamports }) => Ok(lamports.min(amount)),
+        Some(RefereeFee::Bps { bps }) => bps_of(amount, bps),
+        None => Ok(0),
+    }
+}
+
+// Hands back the reserved referee fee when the agreement settles without the referee
+fn take_referee_reserve(payment_agreement: &mut PaymentAgreement) -> u64 {
+    std::mem::take(&mut payment_agreement.reserved_referee_fee)
+}
+
+// Single source of the completion payout math, shared by the settle paths
+// and get_payout_breakdown
+fn payout_breakdown(
+    payment_agreement: &PaymentAgreement,
+    via_referee: bool,
+    escrow_lamports: u64,
+    minimum_balance: u64,
+) -> Result<PayoutBreakdown> {
+    // A reserved fee sits on top of the amount rather than coming out of it
+    let reserved = payment_agreement.reserved_referee_fee;
+    let referee_fee = match (via_referee, reserved) {
+        (false, _) => 0,
+        (true, 0) => referee_fee_amount(payment_agreement)?,
+        (true, reserved) => reserved,
+    };
+    let receiver_share = if reserved > 0 {
+        payment_agreement.amount
+    } else {
+        payment_agreement
+            .amount
+            .checked_sub(referee_fee)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+    };
+    // The protocol fee only applies to mutual completion
+    let platform_fee = if via_referee {
+        0
+    } else {
+        protocol_fee_amount(receiver_share, payment_agreement.fee_basis_points)?
+    };
+    let dust = escrow_lamports
+        .saturating_sub(minimum_balance)
+        .saturating_sub(payment_agreement.amount)
+        .saturating_sub(reserved);
+
+    Ok(PayoutBreakdown {
+        receiver_share: receiver_share
+            .checked_sub(platform_fee)
+            .ok_or(ErrorCode::ArithmeticOverflow)?,
+        referee_fee,
+        platform_fee,
+        dust,
+        ..PayoutBreakdown::default()
+    })
+}
+
+// Commitments are sha256(value_le_bytes || salt)
+fn commitment_matches(value: u64, salt: &[u8; 32], commitment: &[u8; 32]) -> bool {
+    hashv(&[&value.to_le_bytes(), salt]).to_bytes() == *commitment
+}
+
+// Approvers in a bitmap, each counted by weight in weighted mode
+fn tally(payment_agreement: &PaymentAgreement, bitmap: u8) -> u32 {
+    if payment_agreement.approver_weights.is_empty() {
+        return bitmap.count_ones();
+    }
+
+    payment_agreement
+        .approver_weights
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| bitmap & (1 << index) != 0)
+        .map(|(_, weight)| *weight as u32)
+        .sum()
+}
+
+// What tally must reach for approvals or rejections to decide the agreement
+fn approval_quorum(payment_agreement: &PaymentAgreement) -> u32 {
+    if payment_agreement.approver_weights.is_empty() {
+        payment_agreement.approval_threshold as u32
+    } else {
+        payment_agreement.weight_threshold as u32
+    }
+}
+
+// Marks the start of a deadlock once every approver has voted and neither
+// side reached the threshold
+fn record_tie(payment_agreement: &mut PaymentAgreement) -> Result<()> {
+    if payment_agreement.approvers.is_empty() || payment_agreement.tie_detected_at.is_some() {
+        return Ok(());
+    }
+
+    let threshold = approval_quorum(payment_agreement);
+    let approvals = tally(payment_agreement, payment_agreement.approvals_bitmap);
+    let rejections = tally(payment_agreement, payment_agreement.rejections_bitmap);
+    let voted =
+        (payment_agreement.approvals_bitmap | payment_agreement.rejections_bitmap).count_ones();
+
+    if voted as usize == payment_agreement.approvers.len()
+        && approvals < threshold
+        && rejections < threshold
+    {
+        payment_agreement.tie_detected_at = Some(Clock::get()?.unix_timestamp);
+    }
+
+    Ok(())
+}
+
+// The same for a referee panel, once every referee has voted and neither
+// decision reached required_votes
+fn record_panel_tie(payment_agreement: &mut PaymentAgreement) -> Result<()> {
+    if payment_agreement.referees.is_empty() || payment_agreement.tie_detected_at.is_some() {
+        return Ok(());
+    }
+
+    let complete_votes = payment_agreement.complete_votes_bitmap.count_ones();
+    let cancel_votes = payment_agreement.cancel_votes_bitmap.count_ones();
+    let required_votes = payment_agreement.required_votes as u32;
+
+    if (complete_votes + cancel_votes) as usize == payment_agreement.referees.len()
+        && complete_votes < required_votes
+        && cancel_votes < required_votes
+    {
+        payment_agreement.tie_detected_at = Some(Clock::get()?.unix_timestamp);
+    }
+
+    Ok(())
+}
+
+// Creation escrowed the amount, but re-verify before paying out so a
+// shortfall surfaces as a clear error rather than a failed lamport debit
+fn require_escrow_balance(escrow: &AccountInfo, amount: u64) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(escrow.data_len());
+    require!(
+        escrow.lamports() >= minimum_balance.saturating_add(amount),
+        ErrorCode::InsufficientEscrowBalance
+    );
+
+    Ok(())
+}
+
+// Every debit from an escrow PDA goes through here, so an accounting bug that
+// left it short fails the instruction instead of eating into its rent
+fn debit_escrow(escrow: &AccountInfo, amount: u64) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(escrow.data_len());
+    debit_above_rent(escrow, minimum_balance, amount)
+}
+
+fn debit_above_rent(escrow: &AccountInfo, minimum_balance: u64, amount: u64) -> Result<()> {
+    require!(
+        integrity_status(escrow.lamports(), minimum_balance, amount).shortfall == 0,
+        ErrorCode::EscrowBalanceMismatch
+    );
+    escrow.sub_lamports(amount)?;
+
+    Ok(())
+}
+
+// Empties a vault into `destination` as its agreement closes. By then only its
+// rent and any dust are left, the escrow itself has been paid out. Token
+// agreements have no vault, so there is nothing to move.
+fn close_vault(vault: &AccountInfo, destination: &AccountInfo) -> Result<()> {
+    let lamports = vault.lamports();
+    if lamports > 0 {
+        vault.sub_lamports(lamports)?;
+        destination.add_lamports(lamports)?;
+    }
+
+    Ok(())
+}
+
+fn integrity_status(
+    balance: u64,
+    minimum_balance: u64,
+    outstanding_amount: u64,
+) -> IntegrityStatus {
+    IntegrityStatus {
+        balance,
+        rent_exempt_minimum: minimum_balance,
+        outstanding_amount,
+        rent_exempt: balance >= minimum_balance,
+        shortfall: minimum_balance
+            .saturating_add(outstanding_amount)
+            .saturating_sub(balance),
+    }
+}
+
+// A closed account has no lamports, crediting it would recreate it
+fn require_receiver_exists(
+    payment_agreement: &PaymentAgreement,
+    receiver: &AccountInfo,
+) -> Result<()> {
+    if payment_agreement.require_receiver_exists {
+        require!(receiver.lamports() > 0, ErrorCode::ReceiverDoesNotExist);
+    }
+
+    Ok(())
+}
+
+// A panel replaces the single referee and may not include either party
+fn validate_referees(
+    referees: &[Pubkey],
+    required_votes: u8,
+    payer: Pubkey,
+    receiver: Pubkey,
+    referee: Option<Pubkey>,
+) -> Result<()> {
+    if referees.is_empty() {
+        require!(required_votes == 0, ErrorCode::InvalidRequiredVotes);
+        return Ok(());
+    }
+
+    require!(
+        referee.is_none() && referees.len() <= MAX_REFEREES,
+        ErrorCode::InvalidReferees
+    );
+    for (i, panel_referee) in referees.iter().enumerate() {
+        require!(*panel_referee != payer, ErrorCode::RefereeCannotBePayer);
+        require!(
+            *panel_referee != receiver,
+            ErrorCode::RefereeCannotBeReceiver
+        );
+        require!(
+            !referees[i + 1..].contains(panel_referee),
+            ErrorCode::InvalidReferees
+        );
+    }
+    require!(
+        required_votes > 0 && required_votes as usize <= referees.len(),
+        ErrorCode::InvalidRequiredVotes
+    );
+
+    Ok(())
+}
+
+// Core term checks shared by creation and migration
+fn validate_terms(
+    name: &str,
+    payer: Pubkey,
+    receiver: Pubkey,
+    referee: Option<Pubkey>,
+    expiration_timestamp: Option<i64>,
+) -> Result<()> {
+    // Validate name length
+    require!(!name.is_empty() && name.len() <= 32, ErrorCode::InvalidName);
+
+    // Prevent self-payment
+    require!(payer != receiver, ErrorCode::PayerCannotBeReceiver);
+
+    // If referee is provided, ensure it's not the same as payer or receiver
+    if let Some(referee_key) = referee {
+        require!(referee_key != payer, ErrorCode::RefereeCannotBePayer);
+        require!(referee_key != receiver, ErrorCode::RefereeCannotBeReceiver);
+    }
+
+    // An agreement that would already be expired on creation is rejected
+    if let Some(expiration) = expiration_timestamp {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            !is_expired(expiration, current_timestamp),
+            ErrorCode::ExpirationMustBeInFuture
+        );
+    }
+
+    Ok(())
+}
+
+// An expiration exactly min_lifetime after creation is allowed
+fn meets_min_lifetime(expiration: i64, created_at: i64, min_lifetime: i64) -> bool {
+    expiration >= created_at.saturating_add(min_lifetime)
+}
+
+// Expiry is inclusive, so creation and withdrawal agree on the boundary second
+fn is_expired(expiration: i64, current_timestamp: i64) -> bool {
+    current_timestamp >= expiration
+}
+
+// An empty approver set means the default payer + receiver 2-of-2
+fn validate_approvers(approvers: &[Pubkey], approval_threshold: u8) -> Result<()> {
+    if approvers.is_empty() {
+        require!(approval_threshold == 0, ErrorCode::InvalidApprovalThreshold);
+        return Ok(());
+    }
+
+    require!(
+        approvers.len() <= MAX_APPROVERS,
+        ErrorCode::InvalidApprovers
+    );
+    for (i, approver) in approvers.iter().enumerate() {
+        require!(
+            !approvers[i + 1..].contains(approver),
+            ErrorCode::InvalidApprovers
+        );
+    }
+    require!(
+        approval_threshold > 0 && approval_threshold as usize <= approvers.len(),
+        ErrorCode::InvalidApprovalThreshold
+    );
+
+    Ok(())
+}
+
+fn validate_milestones(milestones: &[u64], amount: u64) -> Result<()> {
+    require!(
+        milestones.len() <= MAX_MILESTONES && milestones.iter().all(|amount| *amount > 0),
+        ErrorCode::InvalidMilestones
+    );
+    let total = milestones
+        .iter()
+        .try_fold(0u64, |total, amount| total.checked_add(*amount))
+        .ok_or(ErrorCode::InvalidMilestones)?;
+    require!(total == amount, ErrorCode::InvalidMilestones);
+
+    Ok(())
+}
+
+fn validate_approver_weights(weighted: &[ApproverWeight], weight_threshold: u16) -> Result<()> {
+    require!(
+        weighted.len() <= MAX_APPROVERS,
+        ErrorCode::InvalidApproverWeights
+    );
+    for (i, entry) in weighted.iter().enumerate() {
+        require!(entry.weight > 0, ErrorCode::InvalidApproverWeights);
+        require!(
+            !weighted[i + 1..]
+                .iter()
+                .any(|other| other.approver == entry.approver),
+            ErrorCode::InvalidApproverWeights
+        );
+    }
+
+    let total_weight: u32 = weighted.iter().map(|entry| entry.weight as u32).sum();
+    require!(
+        weight_threshold > 0 && weight_threshold as u32 <= total_weight,
+        ErrorCode::InvalidWeightThreshold
+    );
+
+    Ok(())
+}
+
+// Nonce an agreement is created under, read by the creation account seeds
+fn options_nonce(options: &Option<AgreementOptions>) -> u64 {
+    options
+        .as_ref()
+        .and_then(|options| options.nonce)
+        .unwrap_or(0)
+}
+
+// Validates the terms and writes a fresh pending agreement. Funding is left
+// to the caller.
+#[allow(clippy::too_many_arguments)]
+fn init_payment_agreement(
+    payment_agreement: &mut PaymentAgreement,
+    config: &mut Config,
+    stats: &mut Stats,
+    payer: Pubkey,
+    referee: Option<Pubkey>,
+    bump: u8,
+    name: String,
+    receiver: Pubkey,
+    amount: u64,
+    expiration_timestamp: Option<i64>,
+    options: Option<AgreementOptions>,
+    mint: Option<Pubkey>,
+) -> Result<()> {
+    let options = options.unwrap_or_default();
+
+    // A relative duration is converted to an absolute expiration from now
+    let expiration_timestamp = match options.expiration_duration_seconds {
+        Some(duration) => {
+            require!(
+                expiration_timestamp.is_none(),
+                ErrorCode::ConflictingExpiration
+            );
+            require!(duration > 0, ErrorCode::ExpirationMustBeInFuture);
+            Some(
+                Clock::get()?
+                    .unix_timestamp
+                    .checked_add(duration)
+                    .ok_or(ErrorCode::AmountOverflow)?,
+            )
+        }
+        None => expiration_timestamp,
+    };
+
+    validate_terms(&name, payer, receiver, referee, expiration_timestamp)?;
+
+    // Stops a payer from creating and reclaiming before the receiver can react
+    if let Some(expiration) = expiration_timestamp {
+        require!(
+            meets_min_lifetime(
+                expiration,
+                Clock::get()?.unix_timestamp,
+                config.min_agreement_lifetime,
+            ),
+            ErrorCode::ExpirationTooSoon
+        );
+    }
+
+    // The acceptance window must close no later than the overall expiration
+    if let Some(acceptance_expiry) = options.acceptance_expiry {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            acceptance_expiry > current_timestamp,
+            ErrorCode::ExpirationMustBeInFuture
+        );
+        if let Some(expiration) = expiration_timestamp {
+            require!(
+                acceptance_expiry <= expiration,
+                ErrorCode::AcceptanceExpiryAfterExpiration
+            );
+        }
+    }
+
+    // Auto-completion is blocked by opening a dispute, which needs a referee.
+    // It must also fall before expiry or the payer could reclaim first.
+    if let Some(auto_complete_timestamp) = options.auto_complete_timestamp {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            referee.is_some()
+                && auto_complete_timestamp > current_timestamp
+                && !matches!(
+                    expiration_timestamp,
+                    Some(expiration) if auto_complete_timestamp >= expiration
+                ),
+            ErrorCode::InvalidAutoCompleteTimestamp
+        );
+    }
+
+    if let Some(performance_fee) = options.performance_fee {
+        require!(
+            performance_fee.fee_bps > 0
+                && performance_fee.fee_bps <= 10_000
+                && performance_fee.reference_account != payer,
+            ErrorCode::InvalidPerformanceFee
+        );
+    }
+
+    // Paying the receiver on expiry needs an expiration, and settle_expired
+    // only moves lamports
+    if options.expiry_favors == Some(ExpiryBeneficiary::Receiver) {
+        require!(
+            expiration_timestamp.is_some() && mint.is_none(),
+            ErrorCode::InvalidExpiryBeneficiary
+        );
+    }
+
+    // Validate the optional N-of-M approver set, or the weighted one which
+    // fills the same approver list
+    let weighted = options.approver_weights.unwrap_or_default();
+    let (approvers, approval_threshold, approver_weights, weight_threshold) = if weighted.is_empty()
+    {
+        let approvers = options.approvers.unwrap_or_default();
+        let approval_threshold = options.approval_threshold.unwrap_or(0);
+        validate_approvers(&approvers, approval_threshold)?;
+        (approvers, approval_threshold, Vec::new(), 0)
+    } else {
+        require!(
+            options.approvers.is_none() && options.approval_threshold.is_none(),
+            ErrorCode::InvalidApproverWeights
+        );
+        let weight_threshold = options.weight_threshold.unwrap_or(0);
+        validate_approver_weights(&weighted, weight_threshold)?;
+        (
+            weighted.iter().map(|entry| entry.approver).collect(),
+            0,
+            weighted.iter().map(|entry| entry.weight).collect(),
+            weight_threshold,
+        )
+    };
+
+    // An approver set decides by quorum, and the token approval keeps mutual
+    // approval, so the one-sided modes only apply to plain native agreements
+    let completion_mode = options.completion_mode.unwrap_or_default();
+    if completion_mode != CompletionMode::MutualApproval {
+        require!(
+            approvers.is_empty() && mint.is_none(),
+            ErrorCode::InvalidCompletionMode
+        );
+    }
+
+    // The token accounts sign with the agreement seeds without a nonce
+    if mint.is_some() {
+        require!(
+            options.nonce.unwrap_or(0) == 0,
+            ErrorCode::NonceOnTokenAgreement
+        );
+    }
+
+    // Borsh already rejects invalid UTF-8, so only the length is left to check
+    if let Some(memo) = &options.memo {
+        require!(memo.len() <= MAX_MEMO_LEN, ErrorCode::InvalidMemo);
+    }
+
+    // All zeros is how an agreement without a document is stored
+    if let Some(document_hash) = options.document_hash {
+        require!(document_hash != [0; 32], ErrorCode::InvalidDocumentHash);
+    }
+
+    // Validate the optional referee panel
+    let referees = options.referees.unwrap_or_default();
+    let required_votes = options.required_votes.unwrap_or(0);
+    validate_referees(&referees, required_votes, payer, receiver, referee)?;
+
+    // Validate the optional release schedule
+    let allowed_release_timestamps = options.allowed_release_timestamps.unwrap_or_default();
+    require!(
+        allowed_release_timestamps.len() <= MAX_RELEASE_SCHEDULE
+            && allowed_release_timestamps
+                .windows(2)
+                .all(|pair| pair[0] <= pair[1]),
+        ErrorCode::InvalidReleaseSchedule
+    );
+
+    // Milestones split a known amount, so they can't be used with a hidden one
+    let milestones = options.milestones.unwrap_or_default();
+    if !milestones.is_empty() {
+        require!(
+            options.amount_commitment.is_none(),
+            ErrorCode::InvalidMilestones
+        );
+        validate_milestones(&milestones, amount)?;
+    }
+
+    require!(
+        options.fallback_receiver != Some(payer),
+        ErrorCode::InvalidFallbackReceiver
+    );
+
+    let protocol_fee = (options.fee_basis_points, options.fee_collector);
+    let (fee_basis_points, fee_collector) = match protocol_fee {
+        (Some(fee_basis_points), Some(fee_collector)) => {
+            require!(
+                fee_basis_points > 0 && fee_basis_points <= 10_000,
+                ErrorCode::InvalidProtocolFee
+            );
+            (fee_basis_points, fee_collector)
+        }
+        (None, None) => (0, Pubkey::default()),
+        _ => return err!(ErrorCode::InvalidProtocolFee),
+    };
+
+    if let Some(payer_approver) = options.payer_approver {
+        require!(
+            payer_approver != payer && payer_approver != receiver,
+            ErrorCode::InvalidPayerApprover
+        );
+    }
+
+    if let Some(schedule) = options.expiry_refund_schedule {
+        require!(
+            schedule.chunks > 0 && schedule.interval_seconds > 0,
+            ErrorCode::InvalidRefundSchedule
+        );
+    }
+
+    let nonrefundable_deposit = options.nonrefundable_deposit.unwrap_or(0);
+    require!(
+        nonrefundable_deposit == 0 || nonrefundable_deposit < amount,
+        ErrorCode::InvalidDeposit
+    );
+
+    if let Some(sla_seconds) = options.sla_seconds {
+        require!(sla_seconds > 0, ErrorCode::InvalidSla);
+    }
+
+    // A hidden amount is funded on reveal, nothing is escrowed up front
+    if options.amount_commitment.is_some() {
+        require!(amount == 0, ErrorCode::CommittedAmountMustBeZero);
+    } else {
+        require!(
+            amount >= MIN_ESCROW_AMOUNT || (mint.is_some() && amount > 0),
+            ErrorCode::AmountBelowMinimum
+        );
+    }
+
+    if let Some(min_duration_seconds) = options.min_duration_seconds {
+        require!(
+            min_duration_seconds > 0 && min_duration_seconds <= MAX_MIN_DURATION_SECONDS,
+            ErrorCode::InvalidMinimumDuration
+        );
+        // A commitment period outlasting the agreement could never be satisfied
+        if let Some(expiration) = expiration_timestamp {
+            let current_timestamp = Clock::get()?.unix_timestamp;
+            require!(
+                current_timestamp.saturating_add(min_duration_seconds) <= expiration,
+                ErrorCode::InvalidMinimumDuration
+            );
+        }
+    }
+
+    // Reserving the fee needs a fee, a referee to pay it to, and a known amount
+    let reserve_referee_fee = options.reserve_referee_fee.unwrap_or(false);
+    if reserve_referee_fee {
+        require!(
+            options.referee_fee.is_some()
+                && referee.is_some()
+                && options.amount_commitment.is_none(),
+            ErrorCode::InvalidRefereeFee
+        );
+    }
+
+    match options.referee_fee {
+        Some(RefereeFee::Bps { bps }) => {
+            require!(bps <= MAX_REFEREE_FEE_BPS, ErrorCode::InvalidRefereeFee);
+        }
+        Some(RefereeFee::Fixed { lamports }) => {
+            // The receiver must be left something after the referee is paid
+            require!(
+                options.amount_commitment.is_some() || lamports < amount,
+                ErrorCode::RefereeFeeTooLarge
+            );
+        }
+        None => {}
+    }
+
+    // Enforce the global cap on active agreements
+    require!(
+        config.max_total_active == 0 || config.total_active_agreements < config.max_total_active,
+        ErrorCode::GlobalCapReached
+    );
+    config.total_active_agreements = config
+        .total_active_agreements
+        .checked_add(1)
+        .ok_or(ErrorCode::CounterOverflow)?;
+    increment_count(&mut stats.active_count)?;
+    increment_count(&mut stats.created_count)?;
+    // Volumes are in lamports, token amounts would skew them
+    if mint.is_none() {
+        add_volume(&mut stats.created_volume, amount)?;
+    }
+
+    payment_agreement.name = name;
+    payment_agreement.payer = payer;
+    payment_agreement.original_payer = payer;
+    payment_agreement.receiver = receiver;
+    payment_agreement.referee = referee;
+    payment_agreement.amount = amount;
+    payment_agreement.expiration_timestamp = expiration_timestamp;
+    payment_agreement.payer_approved = false;
+    payment_agreement.receiver_approved = false;
+    payment_agreement.payer_requested_cancel = false;
+    payment_agreement.receiver_requested_cancel = false;
+    payment_agreement.status = AgreementStatus::Pending;
+    payment_agreement.settled_at = None;
+    let created_at = Clock::get()?.unix_timestamp;
+    payment_agreement.created_at = created_at;
+    payment_agreement.last_activity_ts = created_at;
+    payment_agreement.sla_deadline = options
+        .sla_seconds
+        .map(|sla_seconds| created_at.saturating_add(sla_seconds));
+    payment_agreement.sla_breached = false;
+    payment_agreement.completion_slot = 0;
+    payment_agreement.proof_token_mint = options.proof_token_mint;
+    payment_agreement.proof_consumed = false;
+    payment_agreement.nonrefundable_deposit = nonrefundable_deposit;
+    payment_agreement.payer_approver = options.payer_approver;
+    payment_agreement.expiry_refund_schedule = options.expiry_refund_schedule;
+    payment_agreement.refund_started_at = None;
+    payment_agreement.refunded_amount = 0;
+    payment_agreement.amount_commitment = options.amount_commitment;
+    payment_agreement.amount_revealed = false;
+    payment_agreement.receiver_terms_commitment = None;
+    payment_agreement.receiver_terms_revealed = false;
+    payment_agreement.referee_fee = options.referee_fee;
+    payment_agreement.proposed_deliverable_amount = None;
+    payment_agreement.completion_callback = options.completion_callback;
+    payment_agreement.reserved_referee_fee = 0;
+    if reserve_referee_fee {
+        payment_agreement.reserved_referee_fee = referee_fee_amount(payment_agreement)?;
+    }
+    payment_agreement.min_duration_seconds = options.min_duration_seconds;
+    payment_agreement.delivery_completion = options.delivery_completion.unwrap_or(false);
+    payment_agreement.delivery_submitted = false;
+    payment_agreement.delivery_proof = [0; 32];
+    payment_agreement.require_receiver_exists = options.require_receiver_exists.unwrap_or(false);
+    payment_agreement.rejections_bitmap = 0;
+    payment_agreement.tie_detected_at = None;
+    payment_agreement.tie_break = options.tie_break;
+    payment_agreement.acceptance_expiry = options.acceptance_expiry;
+    payment_agreement.approvers = approvers;
+    payment_agreement.approval_threshold = approval_threshold;
+    payment_agreement.approvals_bitmap = 0;
+    payment_agreement.approver_weights = approver_weights;
+    payment_agreement.weight_threshold = weight_threshold;
+    payment_agreement.approved_weight = 0;
+    payment_agreement.referees = referees;
+    payment_agreement.required_votes = required_votes;
+    payment_agreement.complete_votes_bitmap = 0;
+    payment_agreement.cancel_votes_bitmap = 0;
+    payment_agreement.released_amount = 0;
+    payment_agreement.release_count = 0;
+    payment_agreement.per_release_dual_approval =
+        options.per_release_dual_approval.unwrap_or(false);
+    payment_agreement.pending_release = None;
+    payment_agreement.pending_partial_completion = None;
+    payment_agreement.release_history = Vec::new();
+    payment_agreement.mint = mint;
+    payment_agreement.ownership_transfer_needs_receiver =
+        options.ownership_transfer_needs_receiver.unwrap_or(false);
+    payment_agreement.auto_complete_timestamp = options.auto_complete_timestamp;
+    payment_agreement.performance_fee = options.performance_fee;
+    payment_agreement.reference_baseline = 0;
+    payment_agreement.dispute_window_seconds = options.dispute_window_seconds.unwrap_or(0);
+    payment_agreement.memo = options.memo;
+    payment_agreement.version = CURRENT_SCHEMA_VERSION;
+    payment_agreement.fee_basis_points = fee_basis_points;
+    payment_agreement.fee_collector = fee_collector;
+    payment_agreement.is_paused = false;
+    payment_agreement.pause_blocks_withdrawal = false;
+    payment_agreement.require_ack = options.require_ack.unwrap_or(false);
+    payment_agreement.receiver_acknowledged = false;
+    payment_agreement.document_hash = options.document_hash.unwrap_or_default();
+    payment_agreement.expiry_favors = options.expiry_favors.unwrap_or_default();
+    payment_agreement.completion_mode = completion_mode;
+    payment_agreement.nonce = options.nonce.unwrap_or(0);
+    payment_agreement.fallback_receiver = options.fallback_receiver;
+    payment_agreement.milestones = milestones
+        .into_iter()
+        .map(|amount| Milestone {
+            amount,
+            released: false,
+            payer_approved: false,
+            receiver_approved: false,
+        })
+        .collect();
+    payment_agreement.allowed_release_timestamps = allowed_release_timestamps;
+    payment_agreement.dispute_opened_at = None;
+    payment_agreement.mint_receipt = options.mint_receipt.unwrap_or(false);
+    payment_agreement.bump = bump;
+
+    require_referee_coverage(payment_agreement)?;
+
+    Ok(())
+}
+
+// A program-owned or off-curve receiver may have no way to sign for or move
+// the funds, so unless the options allow one the receiver account must be
+// passed and be a System Program wallet
+fn require_wallet_receiver(
+    receiver: Pubkey,
+    receiver_account: Option<&AccountInfo>,
+    options: &Option<AgreementOptions>,
+) -> Result<()> {
+    let allow_program_receiver = options
+        .as_ref()
+        .and_then(|options| options.allow_program_receiver)
+        .unwrap_or(false);
+    if allow_program_receiver {
+        return Ok(());
+    }
+
+    require!(is_on_curve(&receiver), ErrorCode::ReceiverNotOnCurve);
+    let receiver_account = receiver_account.ok_or(ErrorCode::MissingReceiverAccount)?;
+    require_keys_eq!(receiver_account.key(), receiver, ErrorCode::InvalidReceiver);
+    require!(
+        receiver_account.owner == &system_program::ID,
+        ErrorCode::ReceiverNotSystemAccount
+    );
+
+    Ok(())
+}
+
+// Pubkey::is_on_curve panics on-chain, where the curve syscall does the check
+#[allow(deprecated)]
+fn is_on_curve(key: &Pubkey) -> bool {
+    #[cfg(target_os = "solana")]
+    {
+        // Curve id 0 is edwards25519, a valid point returns 0
+        let mut result = 0u8;
+        unsafe {
+            anchor_lang::solana_program::syscalls::sol_curve_validate_point(
+                0,
+                key.as_ref().as_ptr(),
+                &mut result,
+            ) == 0
+        }
+    }
+    #[cfg(not(target_os = "solana"))]
+    key.is_on_curve()
+}
+
+// Whether an existing agreement was created with exactly these terms, with the
+// options normalized the way init_payment_agreement stores them
+fn matches_creation_terms(
+    existing: &PaymentAgreement,
+    receiver: Pubkey,
+    amount: u64,
+    referee: Option<Pubkey>,
+    expiration_timestamp: Option<i64>,
+    options: &AgreementOptions,
+) -> bool {
+    // A relative expiration was resolved against the original creation time
+    let expiration_matches = match options.expiration_duration_seconds {
+        Some(duration) => {
+            expiration_timestamp.is_none()
+                && existing.expiration_timestamp == existing.created_at.checked_add(duration)
+        }
+        None => existing.expiration_timestamp == expiration_timestamp,
+    };
+    let sla_matches = existing.sla_deadline
+        == options
+            .sla_seconds
+            .map(|sla_seconds| existing.created_at.saturating_add(sla_seconds));
+
+    let weighted = options.approver_weights.as_deref().unwrap_or(&[]);
+    let approvers_match = if weighted.is_empty() {
+        existing.approver_weights.is_empty()
+            && existing.approvers == options.approvers.as_deref().unwrap_or(&[])
+            && existing.approval_threshold == options.approval_threshold.unwrap_or(0)
+    } else {
+        let (approvers, weights): (Vec<Pubkey>, Vec<u16>) = weighted
+            .iter()
+            .map(|entry| (entry.approver, entry.weight))
+            .unzip();
+        existing.approvers == approvers
+            && existing.approver_weights == weights
+            && existing.weight_threshold == options.weight_threshold.unwrap_or(0)
+    };
+
+    let milestones = options.milestones.as_deref().unwrap_or(&[]);
+    let milestones_match = existing.milestones.len() == milestones.len()
+        && existing
+            .milestones
+            .iter()
+            .zip(milestones)
+            .all(|(milestone, amount)| milestone.amount == *amount);
+
+    existing.receiver == receiver
+        && existing.amount == amount
+        && existing.referee == referee
+        && expiration_matches
+        && sla_matches
+        && approvers_match
+        && milestones_match
+        && existing.fee_basis_points == options.fee_basis_points.unwrap_or(0)
+        && existing.fee_collector == options.fee_collector.unwrap_or_default()
+        && existing.completion_mode == options.completion_mode.unwrap_or_default()
+        && existing.document_hash == options.document_hash.unwrap_or_default()
+        && existing.expiry_favors == options.expiry_favors.unwrap_or_default()
+        && existing.memo == options.memo
+        && existing.referee_fee == options.referee_fee
+        && (existing.reserved_referee_fee > 0) == options.reserve_referee_fee.unwrap_or(false)
+        && existing.referees == options.referees.as_deref().unwrap_or(&[])
+        && existing.required_votes == options.required_votes.unwrap_or(0)
+        && existing.tie_break == options.tie_break
+        && existing.acceptance_expiry == options.acceptance_expiry
+        && existing.auto_complete_timestamp == options.auto_complete_timestamp
+        && existing.allowed_release_timestamps
+            == options.allowed_release_timestamps.as_deref().unwrap_or(&[])
+        && existing.amount_commitment == options.amount_commitment
+        && existing.nonrefundable_deposit == options.nonrefundable_deposit.unwrap_or(0)
+        && existing.payer_approver == options.payer_approver
+        && existing.fallback_receiver == options.fallback_receiver
+        && existing.expiry_refund_schedule == options.expiry_refund_schedule
+        && existing.min_duration_seconds == options.min_duration_seconds
+        && existing.performance_fee == options.performance_fee
+        && existing.completion_callback == options.completion_callback
+        && existing.proof_token_mint == options.proof_token_mint
+        && existing.dispute_window_seconds == options.dispute_window_seconds.unwrap_or(0)
+        && existing.mint_receipt == options.mint_receipt.unwrap_or(false)
+        && existing.delivery_completion == options.delivery_completion.unwrap_or(false)
+        && existing.require_ack == options.require_ack.unwrap_or(false)
+        && existing.require_receiver_exists == options.require_receiver_exists.unwrap_or(false)
+        && existing.per_release_dual_approval == options.per_release_dual_approval.unwrap_or(false)
+        && existing.ownership_transfer_needs_receiver
+            == options.ownership_transfer_needs_receiver.unwrap_or(false)
+}
+
+pub fn create_payment_agreement(
+    ctx: Context<CreatePaymentAgreement>,
+    name: String,
+    receiver: Pubkey,
+    amount: u64,
+    expiration_timestamp: Option<i64>,
+    options: Option<AgreementOptions>,
+) -> Result<()> {
+    // Get referee from optional account
+    let referee = ctx
+        .accounts
+        .referee
+        .as_ref()
+        .map(|referee_account| referee_account.key());
+
+    require_wallet_receiver(receiver, ctx.accounts.receiver_account.as_deref(), &options)?;
+
+    // An initialized agreement always has a payer
+    let existing = &ctx.accounts.payment_agreement;
+    if existing.payer != Pubkey::default() {
+        let idempotent = options
+            .as_ref()
+            .and_then(|options| options.idempotent)
+            .unwrap_or(false);
+        require!(idempotent, ErrorCode::AgreementAlreadyExists);
+
+        // A retry of a creation that already landed is a no-op
+        require!(
+            matches_creation_terms(
+                existing,
+                receiver,
+                amount,
+                referee,
+                expiration_timestamp,
+                &options.unwrap_or_default(),
+            ),
+            ErrorCode::ConflictingExistingAgreement
+        );
+        return Ok(());
+    }
+
+    init_payment_agreement(
+        &mut ctx.accounts.payment_agreement,
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.stats,
+        ctx.accounts.payer.key(),
+        referee,
+        ctx.bumps.payment_agreement,
+        name,
+        receiver,
+        amount,
+        expiration_timestamp,
+        options,
+        None,
+    )?;
+    ctx.accounts.payment_agreement.vault_bump = ctx.bumps.vault;
+    capture_reference_baseline(&mut ctx.accounts.payment_agreement, ctx.remaining_accounts)?;
+
+    // Any reserved referee fee is escrowed alongside the amount
+    let escrowed = amount
+        .checked_add(ctx.accounts.payment_agreement.reserved_referee_fee)
+        .ok_or(ErrorCode::AmountOverflow)?;
+
+    // `init` has already charged the payer the rent of both accounts, so the
+    // rest of their balance must cover the escrow. Report both parts on a
+    // shortfall rather than letting the transfer fail.
+    let payer_balance = ctx.accounts.payer.to_account_info().lamports();
+    if payer_balance < escrowed {
+        let rent = Rent::get()?;
+        let rent = rent
+            .minimum_balance(8 + PaymentAgreement::INIT_SPACE)
+            .saturating_add(rent.minimum_balance(0));
+        msg!(
+            "Payer needs {} lamports to escrow plus {} for rent",
+            escrowed,
+            rent
+        );
+        return err!(ErrorCode::InsufficientFundsForAmountAndRent);
+    }
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+            },
+        ),
+        escrowed,
+    )?;
+
+    emit!(AgreementCreated {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver,
+        amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+// Creates and funds several plain agreements at once. Any entry failing
+// validation aborts the transaction, so either all of them exist or none do.
+pub fn create_payment_agreements_batch<'info>(
+    ctx: Context<'_, '_, 'info, 'info, CreatePaymentAgreementsBatch<'info>>,
+    agreements: Vec<AgreementParams>,
+) -> Result<()> {
+    require!(
+        !agreements.is_empty() && agreements.len() <= MAX_BATCH_CREATE,
+        ErrorCode::BatchTooLarge
+    );
+    require!(
+        ctx.remaining_accounts.len() == agreements.len() * 3,
+        ErrorCode::InvalidAgreementAccount
+    );
+
+    let payer = ctx.accounts.payer.key();
+    let (agreement_accounts, rest) = ctx.remaining_accounts.split_at(agreements.len());
+    let (vault_accounts, receiver_accounts) = rest.split_at(agreements.len());
+
+    for (((params, account_info), vault), receiver_account) in agreements
+        .into_iter()
+        .zip(agreement_accounts)
+        .zip(vault_accounts)
+        .zip(receiver_accounts)
+    {
+        // Batch entries have no options, so a program receiver is never allowed
+        require_wallet_receiver(params.receiver, Some(receiver_account), &None)?;
+
+        // A name over the 32-byte seed limit has no address
+        let (address, bump) = Pubkey::try_find_program_address(
+            &[
+                PAYMENT_AGREEMENT_SEED,
+                payer.as_ref(),
+                params.name.as_bytes(),
+            ],
+            &crate::ID,
+        )
+        .ok_or(ErrorCode::InvalidName)?;
+        require_keys_eq!(
+            account_info.key(),
+            address,
+            ErrorCode::InvalidAgreementAccount
+        );
+        // Also rejects a name repeated within the batch
+        require!(
+            account_info.owner == &system_program::ID && account_info.data_is_empty(),
+            ErrorCode::AgreementAlreadyExists
+        );
+
+        let (vault_address, vault_bump) =
+            Pubkey::find_program_address(&[VAULT_SEED, address.as_ref()], &crate::ID);
+        require_keys_eq!(vault.key(), vault_address, ErrorCode::InvalidVault);
+
+        let mut payment_agreement = PaymentAgreement::default();
+        init_payment_agreement(
+            &mut payment_agreement,
+            &mut ctx.accounts.config,
+            &mut ctx.accounts.stats,
+            payer,
+            None,
+            bump,
+            params.name,
+            params.receiver,
+            params.amount,
+            params.expiration_timestamp,
+            None,
+            None,
+        )?;
+        payment_agreement.vault_bump = vault_bump;
+
+        let bump = [bump];
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            PAYMENT_AGREEMENT_SEED,
+            payer.as_ref(),
+            payment_agreement.name.as_bytes(),
+            &bump,
+        ]];
+        create_program_account(
+            account_info,
+            8 + PaymentAgreement::INIT_SPACE,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            signer_seeds,
+        )?;
+        payment_agreement.try_serialize(&mut &mut account_info.try_borrow_mut_data()?[..])?;
+
+        let vault_bump = [vault_bump];
+        let vault_seeds: &[&[&[u8]]] = &[&[VAULT_SEED, address.as_ref(), &vault_bump]];
+        create_program_account(
+            vault,
+            0,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            vault_seeds,
+        )?;
+
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: vault.clone(),
+                },
+            ),
+            params.amount,
+        )?;
+
+        emit!(AgreementCreated {
+            payment_agreement: address,
+            payer,
+            receiver: params.receiver,
+            amount: params.amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+    }
+
+    Ok(())
+}
+
+// Allocates a program-owned PDA the way `init` does. An address that was sent
+// lamports ahead of time can't be created, so it is topped up to the
+// rent-exempt minimum, allocated and assigned instead.
+fn create_program_account<'info>(
+    account_info: &AccountInfo<'info>,
+    space: usize,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    let existing = account_info.lamports();
+
+    if existing == 0 {
+        return system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                system_program::CreateAccount {
+                    from: payer.to_account_info(),
+                    to: account_info.clone(),
+                },
+                signer_seeds,
+            ),
+            rent,
+            space as u64,
+            &crate::ID,
+        );
+    }
+
+    if existing < rent {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: account_info.clone(),
+                },
+            ),
+            rent - existing,
+        )?;
+    }
+    system_program::allocate(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Allocate {
+                account_to_allocate: account_info.clone(),
+            },
+            signer_seeds,
+        ),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Assign {
+                account_to_assign: account_info.clone(),
+            },
+            signer_seeds,
+        ),
+        &crate::ID,
+    )
+}
+
+// Token agreements escrow `amount` of an SPL mint in an associated token
+// account owned by the agreement PDA. They are plain payer + receiver
+// agreements without the optional features of native ones.
+pub fn create_token_payment_agreement(
+    ctx: Context<CreateTokenPaymentAgreement>,
+    name: String,
+    receiver: Pubkey,
+    amount: u64,
+    expiration_timestamp: Option<i64>,
+) -> Result<()> {
+    require_wallet_receiver(receiver, ctx.accounts.receiver_account.as_deref(), &None)?;
+
+    init_payment_agreement(
+        &mut ctx.accounts.payment_agreement,
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.stats,
+        ctx.accounts.payer.key(),
+        None,
+        ctx.bumps.payment_agreement,
+        name,
+        receiver,
+        amount,
+        expiration_timestamp,
+        None,
+        Some(ctx.accounts.mint.key()),
+    )?;
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.payer_token_account.to_account_info(),
+                to: ctx.accounts.escrow_token_account.to_account_info(),
+                authority: ctx.accounts.payer.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    emit!(AgreementCreated {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver,
+        amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+pub fn approve_token_payment_agreement(
+    ctx: Context<ApproveTokenPaymentAgreement>,
+    _name: String,
+) -> Result<()> {
+    let should_complete = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+        require!(
+            payment_agreement.mint == Some(ctx.accounts.mint.key()),
+            ErrorCode::InvalidMint
+        );
+
+        require_unsettled(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+        require_acknowledged(payment_agreement)?;
+
+        if signer == payer_side_approver(payment_agreement) {
+            require!(
+                !payment_agreement.payer_approved,
+                ErrorCode::AlreadyApproved
+            );
+            payment_agreement.payer_approved = true;
+        } else if signer == payment_agreement.receiver {
+            require!(
+                !payment_agreement.receiver_approved,
+                ErrorCode::AlreadyApproved
+            );
+            payment_agreement.receiver_approved = true;
+        } else {
+            return err!(ErrorCode::Unauthorized);
+        }
+
+        let should_complete =
+            payment_agreement.payer_approved && payment_agreement.receiver_approved;
+
+        if should_complete {
+            require!(
+                ctx.accounts.escrow_token_account.amount >= payment_agreement.amount,
+                ErrorCode::InsufficientEscrowBalance
+            );
+            mark_completed(payment_agreement)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            increment_count(&mut ctx.accounts.stats.completed_count)?;
+        }
+
+        should_complete
+    };
+
+    if should_complete {
+        require_completed(&ctx.accounts.payment_agreement)?;
+        let paid = settle_token_escrow(
+            &ctx.accounts.payment_agreement,
+            &ctx.accounts.escrow_token_account,
+            &ctx.accounts.receiver_token_account,
+            &ctx.accounts.payer,
+            &ctx.accounts.token_program,
+        )?;
+        emit_completed(&ctx.accounts.payment_agreement, paid, false)?;
+    }
+
+    emit!(AgreementApproved {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        approver: ctx.accounts.signer.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: ctx.accounts.receiver.key(),
+        amount: ctx.accounts.payment_agreement.amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+pub fn cancel_token_payment_agreement(
+    ctx: Context<RefundTokenPaymentAgreement>,
+    _name: String,
+) -> Result<()> {
+    let should_cancel = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            payment_agreement.mint == Some(ctx.accounts.mint.key()),
+            ErrorCode::InvalidMint
+        );
+
+        require_unsettled(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        if ctx.accounts.signer.key() == payment_agreement.payer {
+            payment_agreement.payer_requested_cancel = true;
+        } else if ctx.accounts.signer.key() == payment_agreement.receiver {
+            payment_agreement.receiver_requested_cancel = true;
+        } else {
+            return err!(ErrorCode::Unauthorized);
+        }
+
+        let should_cancel =
+            payment_agreement.payer_requested_cancel && payment_agreement.receiver_requested_cancel;
+
+        if should_cancel {
+            settle(payment_agreement, AgreementStatus::Cancelled)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            increment_count(&mut ctx.accounts.stats.cancelled_count)?;
+        }
+
+        should_cancel
+    };
+
+    if should_cancel {
+        let refunded = settle_token_escrow(
+            &ctx.accounts.payment_agreement,
+            &ctx.accounts.escrow_token_account,
+            &ctx.accounts.payer_token_account,
+            &ctx.accounts.payer,
+            &ctx.accounts.token_program,
+        )?;
+        emit_cancelled(&ctx.accounts.payment_agreement, refunded)?;
+    }
+
+    Ok(())
+}
+
+// Refunds an expired token agreement in full and closes it
+pub fn withdraw_expired_token_funds(
+    ctx: Context<RefundTokenPaymentAgreement>,
+    _name: String,
+) -> Result<()> {
+    {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.signer.key() == payment_agreement.payer
+                && ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            payment_agreement.mint == Some(ctx.accounts.mint.key()),
+            ErrorCode::InvalidMint
+        );
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        if let Some(error) = withdraw_block_error(expired_withdrawal_block(
+            payment_agreement,
+            current_timestamp,
+        )) {
+            return Err(error!(error));
+        }
+
+        payment_agreement.refund_started_at = Some(current_timestamp);
+        settle(payment_agreement, AgreementStatus::Expired)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        increment_count(&mut ctx.accounts.stats.expired_refund_count)?;
+    }
+
+    let refunded = settle_token_escrow(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.escrow_token_account,
+        &ctx.accounts.payer_token_account,
+        &ctx.accounts.payer,
+        &ctx.accounts.token_program,
+    )?;
+    emit_cancelled(&ctx.accounts.payment_agreement, refunded)?;
+
+    ctx.accounts
+        .payment_agreement
+        .close(ctx.accounts.payer.to_account_info())?;
+
+    Ok(())
+}
+
+// Empties the agreement's token account into `destination` and closes it,
+// returning its rent to the payer. The agreement PDA signs both with its own
+// seeds. Returns the number of tokens moved.
+fn settle_token_escrow<'info>(
+    payment_agreement: &Account<'info, PaymentAgreement>,
+    escrow_token_account: &Account<'info, TokenAccount>,
+    destination: &Account<'info, TokenAccount>,
+    payer: &AccountInfo<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<u64> {
+    let bump = [payment_agreement.bump];
+    let signer_seeds: &[&[&[u8]]] = &[&[
+        PAYMENT_AGREEMENT_SEED,
+        payment_agreement.original_payer.as_ref(),
+        payment_agreement.name.as_bytes(),
+        &bump,
+    ]];
+
+    // Anything sent to the escrow on top of the amount goes along with it,
+    // the account can only be closed once empty
+    let balance = escrow_token_account.amount;
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            Transfer {
+                from: escrow_token_account.to_account_info(),
+                to: destination.to_account_info(),
+                authority: payment_agreement.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        balance,
+    )?;
+
+    token::close_account(CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        CloseAccount {
+            account: escrow_token_account.to_account_info(),
+            destination: payer.clone(),
+            authority: payment_agreement.to_account_info(),
+        },
+        signer_seeds,
+    ))?;
+
+    Ok(balance)
+}
+
+pub fn create_payment_agreement_from_source(
+    ctx: Context<CreatePaymentAgreementFromSource>,
+    name: String,
+    receiver: Pubkey,
+    amount: u64,
+    expiration_timestamp: Option<i64>,
+    options: Option<AgreementOptions>,
+) -> Result<()> {
+    let referee = ctx.accounts.referee.as_ref().map(|referee| referee.key());
+
+    require!(
+        ctx.accounts.funding_source.delegate == ctx.accounts.payer.key(),
+        ErrorCode::InvalidFundingDelegate
+    );
+    require_wallet_receiver(receiver, ctx.accounts.receiver_account.as_deref(), &options)?;
+
+    init_payment_agreement(
+        &mut ctx.accounts.payment_agreement,
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.stats,
+        ctx.accounts.payer.key(),
+        referee,
+        ctx.bumps.payment_agreement,
+        name,
+        receiver,
+        amount,
+        expiration_timestamp,
+        options,
+        None,
+    )?;
+    ctx.accounts.payment_agreement.vault_bump = ctx.bumps.vault;
+    // The escrow is the owner's, so refunds go back to them, not the delegate
+    ctx.accounts.payment_agreement.refund_to = ctx.accounts.funding_source.owner;
+    capture_reference_baseline(&mut ctx.accounts.payment_agreement, ctx.remaining_accounts)?;
+
+    // Any reserved referee fee is escrowed alongside the amount
+    let escrowed = amount
+        .checked_add(ctx.accounts.payment_agreement.reserved_referee_fee)
+        .ok_or(ErrorCode::AmountOverflow)?;
+
+    // The payer must stay within the source's allowance
+    let funding_source = &mut ctx.accounts.funding_source;
+    funding_source.allowance = funding_source
+        .allowance
+        .checked_sub(escrowed)
+        .ok_or(ErrorCode::FundingAllowanceExceeded)?;
+
+    // Never dip into the source's rent-exempt reserve
+    let source_info = funding_source.to_account_info();
+    let minimum_balance = Rent::get()?.minimum_balance(source_info.data_len());
+    require!(
+        source_info.lamports().saturating_sub(minimum_balance) >= escrowed,
+        ErrorCode::InsufficientFunds
+    );
+
+    // Pull the escrow from the program-owned source
+    ctx.accounts.funding_source.sub_lamports(escrowed)?;
+    ctx.accounts.vault.add_lamports(escrowed)?;
+
+    emit!(AgreementCreated {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver,
+        amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+pub fn open_funding_source(
+    ctx: Context<OpenFundingSource>,
+    deposit: u64,
+    delegate: Pubkey,
+    allowance: u64,
+) -> Result<()> {
+    let funding_source = &mut ctx.accounts.funding_source;
+
+    funding_source.owner = ctx.accounts.owner.key();
+    funding_source.delegate = delegate;
+    funding_source.allowance = allowance;
+    funding_source.bump = ctx.bumps.funding_source;
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.owner.to_account_info(),
+                to: ctx.accounts.funding_source.to_account_info(),
+            },
+        ),
+        deposit,
+    )?;
+
+    Ok(())
+}
+
+pub fn close_funding_source(_ctx: Context<CloseFundingSource>) -> Result<()> {
+    Ok(())
+}
+
+pub fn approve_payment_agreement<'info>(
+    ctx: Context<'_, '_, 'info, 'info, ApprovePaymentAgreement<'info>>,
+    name: String,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    // Check if both parties have approved and get necessary data
+    let (should_complete, transfer_amount, to_payer) = {
+        let payment_agreement = &mut payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        // With an approver set only its members may approve
+        if payment_agreement.approvers.is_empty() {
+            require!(
+                signer == payer_side_approver(payment_agreement)
+                    || signer == payment_agreement.receiver,
+                ErrorCode::Unauthorized
+            );
+        } else {
+            require!(
+                payment_agreement.approvers.contains(&signer),
+                ErrorCode::Unauthorized
+            );
+        }
+
+        // Validate that passed accounts match stored accounts. On a mismatch
+        // the provided and stored keys are logged as Left and Right.
+        require_keys_eq!(
+            ctx.accounts.payer.key(),
+            payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require_keys_eq!(
+            ctx.accounts.receiver.key(),
+            payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+        require_acknowledged(payment_agreement)?;
+
+        if signer == payer_side_approver(payment_agreement) {
+            // The payer can only release once the hidden amount is funded
+            require!(
+                payment_agreement.amount_commitment.is_none() || payment_agreement.amount_revealed,
+                ErrorCode::AmountNotRevealed
+            );
+            require!(
+                !payment_agreement.payer_approved,
+                ErrorCode::AlreadyApproved
+            );
+            payment_agreement.payer_approved = true;
+        } else if signer == payment_agreement.receiver {
+            // A receiver who missed the acceptance window can no longer accept
+            if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+                let current_timestamp = Clock::get()?.unix_timestamp;
+                require!(
+                    current_timestamp <= acceptance_expiry,
+                    ErrorCode::AcceptanceWindowLapsed
+                );
+            }
+            require!(
+                !payment_agreement.receiver_approved,
+                ErrorCode::AlreadyApproved
+            );
+            payment_agreement.receiver_approved = true;
+        }
+
+        if let Some(index) = payment_agreement
+            .approvers
+            .iter()
+            .position(|approver| *approver == signer)
+        {
+            require!(
+                payment_agreement.rejections_bitmap & (1 << index) == 0,
+                ErrorCode::AlreadyVoted
+            );
+            require!(
+                payment_agreement.approvals_bitmap & (1 << index) == 0,
+                ErrorCode::AlreadyApproved
+            );
+            payment_agreement.approvals_bitmap |= 1 << index;
+        }
+
+        let should_complete = if payment_agreement.approvers.is_empty() {
+            approvals_complete(payment_agreement)
+        } else {
+            payment_agreement.approved_weight =
+                tally(payment_agreement, payment_agreement.approvals_bitmap);
+            payment_agreement.approved_weight >= approval_quorum(payment_agreement)
+        };
+
+        let mut payout = 0;
+        if should_complete {
+            require_escrow_balance(
+                &ctx.accounts.vault.to_account_info(),
+                payment_agreement.amount,
+            )?;
+            require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+            // Sealed terms must be revealed and met before funds move
+            require!(
+                payment_agreement.receiver_terms_commitment.is_none()
+                    || payment_agreement.receiver_terms_revealed,
+                ErrorCode::ReceiverTermsNotRevealed
+            );
+            payout = completion_payout(payment_agreement, ctx.remaining_accounts)?;
+            mark_completed(payment_agreement)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            record_completed(&mut ctx.accounts.stats, payout)?;
+            add_volume(
+                &mut ctx.accounts.stats.cancelled_volume,
+                payment_agreement
+                    .amount
+                    .checked_sub(payout)
+                    .ok_or(ErrorCode::ArithmeticOverflow)?,
+            )?;
+        } else {
+            record_tie(payment_agreement)?;
+        }
+
+        // Anything the receiver is not owed goes back with the reserve
+        let to_payer = if should_complete {
+            payment_agreement
+                .amount
+                .checked_sub(payout)
+                .and_then(|refund| refund.checked_add(take_referee_reserve(payment_agreement)))
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+        } else {
+            0
+        };
+
+        (should_complete, payout, to_payer)
+    };
+
+    // Now do the transfer if needed
+    if should_complete {
+        let protocol_fee =
+            protocol_fee_amount(transfer_amount, payment_agreement.fee_basis_points)?;
+        let total = transfer_amount
+            .checked_add(to_payer)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let receiver_amount = transfer_amount
+            .checked_sub(protocol_fee)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        // Transfer lamports from PDA to receiver
+        require_completed(&payment_agreement)?;
+        debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+        ctx.accounts.receiver.add_lamports(receiver_amount)?;
+        credit_refund(
+            &payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            to_payer,
+        )?;
+
+        if protocol_fee > 0 {
+            fee_collector_account(&payment_agreement, ctx.remaining_accounts)?
+                .add_lamports(protocol_fee)?;
+        }
+
+        if payment_agreement.mint_receipt {
+            issue_receipt(&ctx, &payment_agreement)?;
+        }
+
+        notify_completion(
+            &payment_agreement,
+            &ctx.accounts.config,
+            transfer_amount,
+            ctx.remaining_accounts,
+        )?;
+        emit_completed(&payment_agreement, transfer_amount, false)?;
+    }
+
+    emit!(AgreementApproved {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        approver: ctx.accounts.signer.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: ctx.accounts.receiver.key(),
+        amount: payment_agreement.amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    // The payout and any refund already left the vault above, so closing both
+    // only returns their rent to the payer who funded it
+    if should_complete {
+        payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+        close_vault(
+            &ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.payer.to_account_info(),
+        )?;
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// Pays part of the escrow to the receiver. The payer releases alone unless
+// per_release_dual_approval is set, in which case both parties call this
+// for the same release before funds move.
+pub fn release_partial_payment(
+    ctx: Context<ApprovePaymentAgreement>,
+    name: String,
+    release_index: u32,
+    amount: u64,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let mut reserve = 0;
+    let should_release = {
+        let payment_agreement = &mut payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        let is_payer = signer == payer_side_approver(payment_agreement);
+        let is_receiver = signer == payment_agreement.receiver;
+        require!(
+            is_payer || (is_receiver && payment_agreement.per_release_dual_approval),
+            ErrorCode::Unauthorized
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+        require_acknowledged(payment_agreement)?;
+
+        require_release_slot(payment_agreement)?;
+        require!(
+            release_index == payment_agreement.release_count,
+            ErrorCode::ReleaseIndexMismatch
+        );
+        require!(
+            amount > 0 && amount <= payment_agreement.amount,
+            ErrorCode::InvalidReleaseAmount
+        );
+
+        // Releases past the end of the schedule are not time-gated
+        if let Some(release_at) = payment_agreement
+            .allowed_release_timestamps
+            .get(release_index as usize)
+        {
+            require!(
+                Clock::get()?.unix_timestamp >= *release_at,
+                ErrorCode::ReleaseTooEarly
+            );
+        }
+
+        let should_release = if payment_agreement.per_release_dual_approval {
+            let pending = payment_agreement
+                .pending_release
+                .get_or_insert(PendingRelease {
+                    amount,
+                    payer_approved: false,
+                    receiver_approved: false,
+                });
+            require!(pending.amount == amount, ErrorCode::ReleaseAmountMismatch);
+
+            if is_payer {
+                pending.payer_approved = true;
+            } else {
+                pending.receiver_approved = true;
+            }
+            pending.payer_approved && pending.receiver_approved
+        } else {
+            true
+        };
+
+        if should_release {
+            require_escrow_balance(
+                &ctx.accounts.vault.to_account_info(),
+                payment_agreement.amount,
+            )?;
+
+            payment_agreement.pending_release = None;
+            record_release(payment_agreement, amount)?;
+
+            // Releasing the last lamport completes the agreement
+            if payment_agreement.amount == 0 {
+                mark_completed(payment_agreement)?;
+                release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+                record_completed(&mut ctx.accounts.stats, amount)?;
+                reserve = take_referee_reserve(payment_agreement);
+            } else {
+                add_volume(&mut ctx.accounts.stats.completed_volume, amount)?;
+            }
+        }
+
+        should_release
+    };
+
+    if should_release {
+        let total = amount
+            .checked_add(reserve)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+        ctx.accounts.receiver.add_lamports(amount)?;
+        credit_refund(
+            &payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            reserve,
+        )?;
+
+        if payment_agreement.status.is_completed() {
+            notify_completion(
+                &payment_agreement,
+                &ctx.accounts.config,
+                amount,
+                ctx.remaining_accounts,
+            )?;
+            emit_completed(&payment_agreement, amount, false)?;
+
+            // Anything still escrowed, such as a top-up, goes back with the rent
+            payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+            close_vault(
+                &ctx.accounts.vault.to_account_info(),
+                &ctx.accounts.payer.to_account_info(),
+            )?;
+        }
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// Releases one milestone once both parties have approved it. The last one
+// completes the agreement and closes it, returning the rent to the payer.
+pub fn approve_milestone(
+    ctx: Context<ApprovePaymentAgreement>,
+    name: String,
+    index: u32,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let mut reserve = 0;
+    let released = {
+        let payment_agreement = &mut payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        let is_payer = signer == payer_side_approver(payment_agreement);
+        require!(
+            is_payer || signer == payment_agreement.receiver,
+            ErrorCode::Unauthorized
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+        require_acknowledged(payment_agreement)?;
+
+        let milestone = payment_agreement
+            .milestones
+            .get_mut(index as usize)
+            .ok_or(ErrorCode::InvalidMilestoneIndex)?;
+        require!(!milestone.released, ErrorCode::MilestoneAlreadyReleased);
+
+        if is_payer {
+            require!(!milestone.payer_approved, ErrorCode::AlreadyApproved);
+            milestone.payer_approved = true;
+        } else {
+            require!(!milestone.receiver_approved, ErrorCode::AlreadyApproved);
+            milestone.receiver_approved = true;
+        }
+
+        let milestone = *milestone;
+        if milestone.payer_approved && milestone.receiver_approved {
+            // Partial releases may already have paid out part of the escrow
+            require!(
+                milestone.amount <= payment_agreement.amount,
+                ErrorCode::InvalidReleaseAmount
+            );
+            require_escrow_balance(
+                &ctx.accounts.vault.to_account_info(),
+                payment_agreement.amount,
+            )?;
+
+            payment_agreement.milestones[index as usize].released = true;
+            record_release(payment_agreement, milestone.amount)?;
+
+            if payment_agreement
+                .milestones
+                .iter()
+                .all(|milestone| milestone.released)
+            {
+                mark_completed(payment_agreement)?;
+                release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+                record_completed(&mut ctx.accounts.stats, milestone.amount)?;
+                reserve = take_referee_reserve(payment_agreement);
+            } else {
+                add_volume(&mut ctx.accounts.stats.completed_volume, milestone.amount)?;
+            }
+            milestone.amount
+        } else {
+            0
+        }
+    };
+
+    if released > 0 {
+        let total = released
+            .checked_add(reserve)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+        ctx.accounts.receiver.add_lamports(released)?;
+        credit_refund(
+            &payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            reserve,
+        )?;
+
+        if payment_agreement.status.is_completed() {
+            notify_completion(
+                &payment_agreement,
+                &ctx.accounts.config,
+                released,
+                ctx.remaining_accounts,
+            )?;
+            emit_completed(&payment_agreement, released, false)?;
+
+            // Anything still escrowed, such as a top-up, goes back with the rent
+            payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+            close_vault(
+                &ctx.accounts.vault.to_account_info(),
+                &ctx.accounts.payer.to_account_info(),
+            )?;
+        }
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// "Pay part now, the rest on final sign-off". Once both parties have called
+// this with the same immediate amount it is paid to the receiver, and the
+// approvals reset so the held remainder needs a fresh approval round. Until
+// then the remainder can still be refunded through cancellation or expiry.
+pub fn complete_partial_hold_rest(
+    ctx: Context<ApprovePaymentAgreement>,
+    name: String,
+    immediate_amount: u64,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let should_release = {
+        let payment_agreement = &mut payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        let is_payer = signer == payer_side_approver(payment_agreement);
+        let is_receiver = signer == payment_agreement.receiver;
+        require!(is_payer || is_receiver, ErrorCode::Unauthorized);
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        // An approver quorum can't be bypassed by the two parties alone
+        require!(
+            payment_agreement.approvers.is_empty(),
+            ErrorCode::Unauthorized
+        );
+        require!(
+            immediate_amount > 0 && immediate_amount < payment_agreement.amount,
+            ErrorCode::InvalidImmediateAmount
+        );
+        require_release_slot(payment_agreement)?;
+
+        let pending = payment_agreement
+            .pending_partial_completion
+            .get_or_insert(PendingRelease {
+                amount: immediate_amount,
+                payer_approved: false,
+                receiver_approved: false,
+            });
+        require!(
+            pending.amount == immediate_amount,
+            ErrorCode::ReleaseAmountMismatch
+        );
+
+        if is_payer {
+            pending.payer_approved = true;
+        } else {
+            pending.receiver_approved = true;
+        }
+        let should_release = pending.payer_approved && pending.receiver_approved;
+
+        if should_release {
+            require_escrow_balance(
+                &ctx.accounts.vault.to_account_info(),
+                payment_agreement.amount,
+            )?;
+            require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+
+            payment_agreement.pending_partial_completion = None;
+            record_release(payment_agreement, immediate_amount)?;
+            add_volume(&mut ctx.accounts.stats.completed_volume, immediate_amount)?;
+
+            // The remainder is held until both parties approve again
+            payment_agreement.payer_approved = false;
+            payment_agreement.receiver_approved = false;
+        }
+
+        should_release
+    };
+
+    if should_release {
+        debit_escrow(&ctx.accounts.vault.to_account_info(), immediate_amount)?;
+        ctx.accounts.receiver.add_lamports(immediate_amount)?;
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// Mint the completion receipt and record its details
+fn issue_receipt<'info>(
+    ctx: &Context<'_, '_, 'info, 'info, ApprovePaymentAgreement<'info>>,
+    payment_agreement: &LoadedAgreement,
+) -> Result<()> {
+    mint_completion_receipt(
+        &ctx.accounts.config,
+        ctx.bumps.config,
+        payment_agreement.payer,
+        ctx.remaining_accounts,
+    )?;
+
+    emit!(ReceiptMinted {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        name: payment_agreement.name.clone(),
+        amount: payment_agreement.amount,
+    });
+
+    Ok(())
+}
+
+pub fn cancel_payment_agreement(ctx: Context<CancelPaymentAgreement>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    // Handle cancellation logic and get necessary data
+    let (should_cancel, transfer_amount, deposit) = {
+        let payment_agreement = &mut payment_agreement;
+
+        require!(
+            ctx.accounts.signer.key() == payment_agreement.payer
+                || ctx.accounts.signer.key() == payment_agreement.receiver,
+            ErrorCode::Unauthorized
+        );
+
+        // Validate that passed payer account matches stored payer, logging
+        // both keys on a mismatch
+        require_keys_eq!(
+            ctx.accounts.payer.key(),
+            payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        if ctx.accounts.signer.key() == payment_agreement.payer {
+            payment_agreement.payer_requested_cancel = true;
+        } else if ctx.accounts.signer.key() == payment_agreement.receiver {
+            payment_agreement.receiver_requested_cancel = true;
+        }
+
+        let should_cancel =
+            payment_agreement.payer_requested_cancel && payment_agreement.receiver_requested_cancel;
+
+        if should_cancel {
+            // Enforce the minimum commitment period before funds can go back
+            if let Some(min_duration_seconds) = payment_agreement.min_duration_seconds {
+                let current_timestamp = Clock::get()?.unix_timestamp;
+                require!(
+                    current_timestamp
+                        >= payment_agreement
+                            .created_at
+                            .saturating_add(min_duration_seconds),
+                    ErrorCode::MinimumDurationNotMet
+                );
+            }
+
+            settle(payment_agreement, AgreementStatus::Cancelled)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+        }
+
+        // Partial releases may already have paid out more than the deposit
+        let deposit = payment_agreement
+            .nonrefundable_deposit
+            .min(payment_agreement.amount);
+        let reserve = if should_cancel {
+            take_referee_reserve(payment_agreement)
+        } else {
+            0
+        };
+
+        let refund = payment_agreement
+            .amount
+            .checked_sub(deposit)
+            .and_then(|refund| refund.checked_add(reserve))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (should_cancel, refund, deposit)
+    };
+
+    // Return funds to payer if cancelled
+    if should_cancel {
+        // Transfer lamports from PDA to payer
+        debit_escrow(&ctx.accounts.vault.to_account_info(), transfer_amount)?;
+        credit_refund(
+            &payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            transfer_amount,
+        )?;
+
+        // The receiver keeps the deposit, passed as the first remaining account
+        if deposit > 0 {
+            let receiver = ctx
+                .remaining_accounts
+                .first()
+                .ok_or(ErrorCode::InvalidReceiver)?;
+            require_keys_eq!(
+                receiver.key(),
+                payment_agreement.receiver,
+                ErrorCode::InvalidReceiver
+            );
+            debit_escrow(&ctx.accounts.vault.to_account_info(), deposit)?;
+            receiver.add_lamports(deposit)?;
+        }
+
+        emit_cancelled(&payment_agreement, transfer_amount)?;
+
+        // Closing hands the leftover rent of both accounts back to the payer, who
+        // paid it at creation. The escrowed amount was already moved out above.
+        payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+        close_vault(
+            &ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.payer.to_account_info(),
+        )?;
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// The receiver refuses the payment, everything goes straight back to the payer
+// and the agreement closes
+pub fn decline_payment_agreement(ctx: Context<CancelPaymentAgreement>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let transfer_amount = {
+        let payment_agreement = &mut payment_agreement;
+
+        require!(
+            ctx.accounts.signer.key() == payment_agreement.receiver,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+
+        settle(payment_agreement, AgreementStatus::Cancelled)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+
+        // Declining forfeits any non-refundable deposit as well
+        payment_agreement
+            .amount
+            .checked_add(take_referee_reserve(payment_agreement))
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+    };
+
+    debit_escrow(&ctx.accounts.vault.to_account_info(), transfer_amount)?;
+    credit_refund(
+        &payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        transfer_amount,
+    )?;
+
+    emit!(ReceiverDeclined {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: ctx.accounts.signer.key(),
+        amount: transfer_amount,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    emit_cancelled(&payment_agreement, transfer_amount)?;
+
+    // Nothing is left to settle, so both accounts close to the payer in the
+    // same instruction, like a mutual cancel
+    payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// An approver's vote to cancel, the counterpart of approving in N-of-M mode
+pub fn reject_payment_agreement(ctx: Context<CancelPaymentAgreement>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let (should_cancel, transfer_amount) = {
+        let payment_agreement = &mut payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        let mut return_amount = 0;
+        let index = payment_agreement
+            .approvers
+            .iter()
+            .position(|approver| *approver == signer)
+            .ok_or(ErrorCode::Unauthorized)?;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        require!(
+            (payment_agreement.approvals_bitmap | payment_agreement.rejections_bitmap)
+                & (1 << index)
+                == 0,
+            ErrorCode::AlreadyVoted
+        );
+        payment_agreement.rejections_bitmap |= 1 << index;
+
+        let should_cancel = tally(payment_agreement, payment_agreement.rejections_bitmap)
+            >= approval_quorum(payment_agreement);
+
+        if should_cancel {
+            settle(payment_agreement, AgreementStatus::Cancelled)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+            let reserve = take_referee_reserve(payment_agreement);
+            return_amount = payment_agreement
+                .amount
+                .checked_add(reserve)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        } else {
+            record_tie(payment_agreement)?;
+        }
+
+        (should_cancel, return_amount)
+    };
+
+    if should_cancel {
+        debit_escrow(&ctx.accounts.vault.to_account_info(), transfer_amount)?;
+        credit_refund(
+            &payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            transfer_amount,
+        )?;
+
+        emit_cancelled(&payment_agreement, transfer_amount)?;
+
+        payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+        close_vault(
+            &ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.payer.to_account_info(),
+        )?;
+    }
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// Permissionless crank that settles a tied vote once the timeout has passed
+pub fn resolve_tie(ctx: Context<ResolveTie>, _name: String) -> Result<()> {
+    let (to_receiver, to_payer) = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+
+        let tie_detected_at = payment_agreement
+            .tie_detected_at
+            .ok_or(ErrorCode::NoTieDetected)?;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp
+                >= tie_detected_at.saturating_add(ctx.accounts.config.tie_break_timeout),
+            ErrorCode::TieBreakTimeoutNotElapsed
+        );
+
+        let tie_break = payment_agreement
+            .tie_break
+            .ok_or(ErrorCode::NoTieBreakPolicy)?;
+        let amount = payment_agreement.amount;
+
+        let (to_receiver, to_payer) = match tie_break {
+            TieBreak::FavorPayer => (0, amount),
+            TieBreak::FavorReceiver => (amount, 0),
+            TieBreak::Split5050 => (amount / 2, amount - amount / 2),
+            TieBreak::RequireUnanimity => {
+                // Start the vote over, funds stay escrowed
+                payment_agreement.approvals_bitmap = 0;
+                payment_agreement.approved_weight = 0;
+                payment_agreement.rejections_bitmap = 0;
+                payment_agreement.complete_votes_bitmap = 0;
+                payment_agreement.cancel_votes_bitmap = 0;
+                payment_agreement.tie_detected_at = None;
+                return Ok(());
+            }
+        };
+
+        let stats = &mut ctx.accounts.stats;
+        if to_receiver > 0 {
+            require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+            mark_completed(payment_agreement)?;
+            record_completed(stats, to_receiver)?;
+            add_volume(&mut stats.cancelled_volume, to_payer)?;
+        } else {
+            settle(payment_agreement, AgreementStatus::Cancelled)?;
+            record_cancelled(stats, to_payer)?;
+        }
+        release_active_slot(&mut ctx.accounts.config, stats)?;
+
+        let to_payer = to_payer
+            .checked_add(take_referee_reserve(payment_agreement))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (to_receiver, to_payer)
+    };
+
+    let total = to_receiver
+        .checked_add(to_payer)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    ctx.accounts.receiver.add_lamports(to_receiver)?;
+    credit_refund(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        to_payer,
+    )?;
+
+    if ctx.accounts.payment_agreement.status.is_completed() {
+        notify_completion(
+            &ctx.accounts.payment_agreement,
+            &ctx.accounts.config,
+            to_receiver,
+            ctx.remaining_accounts,
+        )?;
+        emit_completed(&ctx.accounts.payment_agreement, to_receiver, false)?;
+    } else {
+        emit_cancelled(&ctx.accounts.payment_agreement, to_payer)?;
+    }
+
+    // Anything still escrowed, such as a top-up, goes back with the rent
+    ctx.accounts
+        .payment_agreement
+        .close(ctx.accounts.payer.to_account_info())?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    Ok(())
+}
+
+// Decodes an agreement taken unchecked by the read-only instructions, failing
+// like the seeds constraint it replaces when it isn't at the expected PDA.
+// `payer` is the one the address derives from, the original payer.
+fn read_agreement(
+    account_info: &AccountInfo,
+    payer: &Pubkey,
+    name: &str,
+) -> Result<PaymentAgreement> {
+    let payment_agreement = {
+        let data = account_info.try_borrow_data()?;
+        PaymentAgreement::try_deserialize(&mut &data[..])?
+    };
+    require_agreement_address(account_info, &payment_agreement, payer, name)?;
+
+    Ok(payment_agreement)
+}
+
+fn require_agreement_address(
+    account_info: &AccountInfo,
+    payment_agreement: &PaymentAgreement,
+    payer: &Pubkey,
+    name: &str,
+) -> Result<()> {
+    let expected = Pubkey::create_program_address(
+        &[
+            PAYMENT_AGREEMENT_SEED,
+            payer.as_ref(),
+            name.as_bytes(),
+            &nonce_seed(payment_agreement.nonce),
+            &[payment_agreement.bump],
+        ],
+        &crate::ID,
+    )
+    .map_err(|_| anchor_lang::error::ErrorCode::ConstraintSeeds)?;
+    require_keys_eq!(
+        account_info.key(),
+        expected,
+        anchor_lang::error::ErrorCode::ConstraintSeeds
+    );
+
+    Ok(())
+}
+
+// An agreement taken as an owner-checked UncheckedAccount by the instructions
+// that settle it, so a foreign-owned account fails with InvalidAgreementAccount
+// rather than Anchor's generic owner error. Anchor doesn't write it back, the
+// handler calls exit once done.
+struct LoadedAgreement<'a, 'info> {
+    info: &'a AccountInfo<'info>,
+    agreement: PaymentAgreement,
+}
+
+impl<'info> LoadedAgreement<'_, 'info> {
+    // Same as Account::close
+    fn close(&self, destination: AccountInfo<'info>) -> Result<()> {
+        let lamports = self.info.lamports();
+        self.info.sub_lamports(lamports)?;
+        destination.add_lamports(lamports)?;
+        self.info.assign(&System::id());
+        self.info.realloc(0, false)?;
+
+        Ok(())
+    }
+
+    // Writes the agreement back unless it was closed
+    fn exit(&self) -> Result<()> {
+        if self.info.owner != &crate::ID {
+            return Ok(());
+        }
+
+        let mut data = self.info.try_borrow_mut_data()?;
+        let mut writer: &mut [u8] = &mut data;
+        self.agreement.try_serialize(&mut writer)
+    }
+}
+
+impl Key for LoadedAgreement<'_, '_> {
+    fn key(&self) -> Pubkey {
+        *self.info.key
+    }
+}
+
+impl Deref for LoadedAgreement<'_, '_> {
+    type Target = PaymentAgreement;
+
+    fn deref(&self) -> &PaymentAgreement {
+        &self.agreement
+    }
+}
+
+impl DerefMut for LoadedAgreement<'_, '_> {
+    fn deref_mut(&mut self) -> &mut PaymentAgreement {
+        &mut self.agreement
+    }
+}
+
+// Unlike read_agreement, derives the address from the stored original payer,
+// so the settling instructions keep working after transfer_payer_role
+fn load_agreement<'a, 'info>(
+    account_info: &'a AccountInfo<'info>,
+    name: &str,
+) -> Result<LoadedAgreement<'a, 'info>> {
+    // Checked in the same order as Account, so a closed agreement still reads
+    // as not initialized
+    if account_info.owner == &System::id() && account_info.lamports() == 0 {
+        return err!(anchor_lang::error::ErrorCode::AccountNotInitialized);
+    }
+    require_keys_eq!(
+        *account_info.owner,
+        crate::ID,
+        ErrorCode::InvalidAgreementAccount
+    );
+
+    let agreement = {
+        let data = account_info.try_borrow_data()?;
+        PaymentAgreement::try_deserialize(&mut &data[..])?
+    };
+    require_agreement_address(account_info, &agreement, &agreement.original_payer, name)?;
+
+    Ok(LoadedAgreement {
+        agreement,
+        info: account_info,
+    })
+}
+
+pub fn get_pda_info(ctx: Context<ReadPaymentAgreement>, name: String) -> Result<PdaInfo> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+
+    Ok(PdaInfo {
+        seed_prefix: PAYMENT_AGREEMENT_SEED.to_vec(),
+        payer: ctx.accounts.payer.key(),
+        name: payment_agreement.name.clone(),
+        nonce: payment_agreement.nonce,
+        bump: payment_agreement.bump,
+    })
+}
+
+// Rewrites an agreement stored in an older layout into the current one.
+// Permissionless, and a no-op for agreements that are already current.
+pub fn migrate_agreement(ctx: Context<MigrateAgreement>) -> Result<()> {
+    let account_info = ctx.accounts.payment_agreement.to_account_info();
+    let upgraded = upgrade_agreement_data(&account_info.try_borrow_data()?)?;
+    let Some(mut upgraded) = upgraded else {
+        return Ok(());
+    };
+
+    let space = 8 + PaymentAgreement::INIT_SPACE;
+    let rent = Rent::get()?;
+    let extra_rent = rent
+        .minimum_balance(space)
+        .saturating_sub(rent.minimum_balance(account_info.data_len()));
+    if extra_rent > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.fee_payer.to_account_info(),
+                    to: account_info.clone(),
+                },
+            ),
+            extra_rent,
+        )?;
+    }
+    account_info.realloc(space, false)?;
+
+    // Native agreements from before the vault escrow on the account itself.
+    // Everything above its rent moves into a new vault, whose rent the caller
+    // covers like the added bytes. Later ones already have their vault.
+    let vault = ctx.accounts.vault.to_account_info();
+    if upgraded.mint.is_none() && *vault.owner != crate::ID {
+        let agreement_key = account_info.key();
+        let vault_bump = [ctx.bumps.vault];
+        create_program_account(
+            &vault,
+            0,
+            &ctx.accounts.fee_payer,
+            &ctx.accounts.system_program,
+            &[&[VAULT_SEED, agreement_key.as_ref(), &vault_bump]],
+        )?;
+        let escrowed = account_info
+            .lamports()
+            .saturating_sub(rent.minimum_balance(space));
+        account_info.sub_lamports(escrowed)?;
+        vault.add_lamports(escrowed)?;
+        upgraded.vault_bump = ctx.bumps.vault;
+    }
+
+    let mut data = account_info.try_borrow_mut_data()?;
+    upgraded.try_serialize(&mut &mut data[..])?;
+
+    Ok(())
+}
+
+// Decodes a stored agreement, returning it upgraded to CURRENT_SCHEMA_VERSION,
+// or None when it already uses the current layout. Older layouts are shorter
+// and miss the trailing fields, which are read from padding and then reset.
+// Padding can't be trusted to be zero, the unused tail of an account may hold
+// stale bytes from a longer earlier serialization.
+fn upgrade_agreement_data(data: &[u8]) -> Result<Option<PaymentAgreement>> {
+    require!(
+        data.len() > 8 && data[..8] == *PaymentAgreement::DISCRIMINATOR,
+        ErrorCode::InvalidAgreementAccount
+    );
+    let space = 8 + PaymentAgreement::INIT_SPACE;
+    if data.len() >= space {
+        return Ok(None);
+    }
+
+    let mut padded = data[8..].to_vec();
+    padded.resize(PaymentAgreement::INIT_SPACE, 0);
+    let mut agreement = decode_padded_agreement(&mut padded)?;
+
+    // v1 has no version byte, later versions record their own
+    let v2_space = space
+        - V3_FIELDS_SPACE
+        - V4_FIELDS_SPACE
+        - V5_FIELDS_SPACE
+        - V6_FIELDS_SPACE
+        - V7_FIELDS_SPACE
+        - V8_FIELDS_SPACE
+        - V9_FIELDS_SPACE
+        - V10_FIELDS_SPACE
+        - V11_FIELDS_SPACE
+        - V12_FIELDS_SPACE
+        - V13_FIELDS_SPACE;
+    let version = if data.len() < v2_space {
+        1
+    } else {
+        agreement.version
+    };
+    if version < 3 {
+        agreement.fee_basis_points = 0;
+        agreement.fee_collector = Pubkey::default();
+    }
+    if version < 4 {
+        agreement.is_paused = false;
+        agreement.pause_blocks_withdrawal = false;
+    }
+    if version < 5 {
+        agreement.require_ack = false;
+        agreement.receiver_acknowledged = false;
+    }
+    // Older agreements count their inactivity from creation
+    if version < 6 {
+        agreement.last_activity_ts = agreement.created_at;
+    }
+    if version < 7 {
+        agreement.document_hash = [0; 32];
+    }
+    if version < 8 {
+        agreement.expiry_favors = ExpiryBeneficiary::Payer;
+    }
+    if version < 9 {
+        agreement.completion_mode = CompletionMode::MutualApproval;
+    }
+    // Agreements from before nonces sit at the nonce 0 address
+    if version < 10 {
+        agreement.nonce = 0;
+    }
+    // Set by migrate_agreement once the escrow has moved into the vault
+    if version < 11 {
+        agreement.vault_bump = 0;
+    }
+    if version < 12 {
+        agreement.refund_to = Pubkey::default();
+    }
+    // Payers couldn't change in place before, so the stored one derived the address
+    if version < 13 {
+        agreement.original_payer = agreement.payer;
+    }
+    agreement.version = CURRENT_SCHEMA_VERSION;
+
+    Ok(Some(agreement))
+}
+
+// Counts the bytes consumed, so a failed decode shows where it stopped
+struct CountingReader<'a> {
+    data: &'a [u8],
+    read: usize,
+}
+
+impl std::io::Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = &self.data[self.read..];
+        let read = remaining.len().min(buf.len());
+        buf[..read].copy_from_slice(&remaining[..read]);
+        self.read += read;
+        Ok(read)
+    }
+}
+
+// A bool added since the account was written may land on a stale byte that is
+// neither 0 nor 1. Those fields are reset by the caller anyway, so the offending
+// byte is cleared and decoding retried, at most once per bool added since v3.
+fn decode_padded_agreement(padded: &mut [u8]) -> Result<PaymentAgreement> {
+    for _ in 0..=V4_FIELDS_SPACE + V5_FIELDS_SPACE + V8_FIELDS_SPACE + V9_FIELDS_SPACE {
+        let mut reader = CountingReader {
+            data: padded,
+            read: 0,
+        };
+        match PaymentAgreement::deserialize_reader(&mut reader) {
+            Ok(agreement) => return Ok(agreement),
+            Err(_) if reader.read > 0 && padded[reader.read - 1] > 1 => {
+                padded[reader.read - 1] = 0;
+            }
+            Err(_) => break,
+        }
+    }
+
+    err!(ErrorCode::InvalidAgreementAccount)
+}
+
+// Read-only snapshot for clients that simulate instead of decoding the account
+pub fn get_agreement_summary(
+    ctx: Context<ReadPaymentAgreement>,
+    name: String,
+) -> Result<AgreementSummary> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+    let current_timestamp = Clock::get()?.unix_timestamp;
+
+    Ok(AgreementSummary {
+        status: payment_agreement.status,
+        amount: payment_agreement.amount,
+        payer: payment_agreement.payer,
+        receiver: payment_agreement.receiver,
+        referee: payment_agreement.referee,
+        is_expired: payment_agreement
+            .expiration_timestamp
+            .is_some_and(|expiration| is_expired(expiration, current_timestamp)),
+        memo: payment_agreement.memo.clone(),
+        created_at: payment_agreement.created_at,
+        settled_at: payment_agreement.settled_at,
+        document_hash: (payment_agreement.document_hash != [0; 32])
+            .then_some(payment_agreement.document_hash),
+    })
+}
+
+// Read-only health check for monitoring, reports a shortfall instead of failing
+pub fn verify_integrity(
+    ctx: Context<ReadPaymentAgreement>,
+    name: String,
+) -> Result<IntegrityStatus> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+    let vault = &ctx.accounts.vault;
+    let minimum_balance = Rent::get()?.minimum_balance(vault.data_len());
+
+    Ok(integrity_status(
+        vault.lamports(),
+        minimum_balance,
+        payment_agreement
+            .amount
+            .checked_add(payment_agreement.reserved_referee_fee)
+            .ok_or(ErrorCode::ArithmeticOverflow)?,
+    ))
+}
+
+pub fn get_payout_breakdown(
+    ctx: Context<ReadPaymentAgreement>,
+    name: String,
+    via_referee: bool,
+) -> Result<PayoutBreakdown> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+    let vault = &ctx.accounts.vault;
+    let minimum_balance = Rent::get()?.minimum_balance(vault.data_len());
+
+    payout_breakdown(
+        &payment_agreement,
+        via_referee,
+        vault.lamports(),
+        minimum_balance,
+    )
+}
+
+pub fn reset_to_pending(ctx: Context<ResetPaymentAgreement>, _name: String) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    // Both parties must co-sign the reset
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::InvalidReceiver
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    // Back to the freshly-created state, funds stay escrowed
+    payment_agreement.payer_approved = false;
+    payment_agreement.receiver_approved = false;
+    payment_agreement.payer_requested_cancel = false;
+    payment_agreement.receiver_requested_cancel = false;
+    payment_agreement.delivery_submitted = false;
+    payment_agreement.delivery_proof = [0; 32];
+    payment_agreement.approvals_bitmap = 0;
+    payment_agreement.approved_weight = 0;
+    payment_agreement.rejections_bitmap = 0;
+    payment_agreement.tie_detected_at = None;
+    payment_agreement.pending_release = None;
+    payment_agreement.pending_partial_completion = None;
+    payment_agreement.proposed_deliverable_amount = None;
+
+    emit!(ResetToPending {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        receiver: payment_agreement.receiver,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    Ok(())
+}
+
+pub fn set_approval_mode(
+    ctx: Context<SetApprovalMode>,
+    _name: String,
+    approvers: Vec<Pubkey>,
+    approval_threshold: u8,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    // Both parties must agree to the new approval rules
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::InvalidReceiver
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    // Existing approvals could cross the new threshold, so refuse the switch
+    require!(
+        !payment_agreement.payer_approved
+            && !payment_agreement.receiver_approved
+            && payment_agreement.approvals_bitmap == 0
+            && payment_agreement.rejections_bitmap == 0,
+        ErrorCode::CannotChangeModeAfterApproval
+    );
+
+    validate_approvers(&approvers, approval_threshold)?;
+
+    payment_agreement.approvers = approvers;
+    payment_agreement.approval_threshold = approval_threshold;
+    // Switching modes always lands in plain N-of-M
+    payment_agreement.approver_weights = Vec::new();
+    payment_agreement.weight_threshold = 0;
+
+    Ok(())
+}
+
+// Replaces an unavailable referee, agreed by both parties before anyone has
+// approved and before the current referee has acted
+pub fn change_referee(
+    ctx: Context<ChangeReferee>,
+    _name: String,
+    new_referee: Option<Pubkey>,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::InvalidReceiver
+    );
+
+    require_pending(payment_agreement)?;
+    require_not_paused(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    require!(
+        !payment_agreement.payer_approved
+            && !payment_agreement.receiver_approved
+            && payment_agreement.approvals_bitmap == 0
+            && payment_agreement.rejections_bitmap == 0,
+        ErrorCode::RefereeChangeAfterApproval
+    );
+    // A panel votes instead of a single referee
+    require!(
+        payment_agreement.referees.is_empty(),
+        ErrorCode::InvalidReferees
+    );
+
+    if let Some(referee) = new_referee {
+        require!(
+            referee != payment_agreement.payer,
+            ErrorCode::RefereeCannotBePayer
+        );
+        require!(
+            referee != payment_agreement.receiver,
+            ErrorCode::RefereeCannotBeReceiver
+        );
+    } else {
+        require!(
+            payment_agreement.auto_complete_timestamp.is_none(),
+            ErrorCode::InvalidAutoCompleteTimestamp
+        );
+    }
+
+    payment_agreement.referee = new_referee;
+    require_referee_coverage(payment_agreement)?;
+
+    Ok(())
+}
+
+// Rebinds a pending agreement to an amended document, or unbinds it with None.
+// Both parties sign so neither can swap the document alone.
+pub fn update_document_hash(
+    ctx: Context<UpdateDocumentHash>,
+    _name: String,
+    document_hash: Option<[u8; 32]>,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::InvalidReceiver
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    if let Some(document_hash) = document_hash {
+        require!(document_hash != [0; 32], ErrorCode::InvalidDocumentHash);
+    }
+    payment_agreement.document_hash = document_hash.unwrap_or_default();
+
+    Ok(())
+}
+
+pub fn reveal_payer_amount(
+    ctx: Context<RevealPayerAmount>,
+    _name: String,
+    amount: u64,
+    salt: [u8; 32],
+) -> Result<()> {
+    {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::Unauthorized
+        );
+
+        require_pending(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        let commitment = payment_agreement
+            .amount_commitment
+            .ok_or(ErrorCode::NoAmountCommitment)?;
+        require!(
+            !payment_agreement.amount_revealed,
+            ErrorCode::AmountAlreadyRevealed
+        );
+
+        // Reveal only once the receiver has committed to accepting, either
+        // openly or with sealed terms of their own
+        require!(
+            payment_agreement.receiver_approved
+                || payment_agreement.receiver_terms_commitment.is_some(),
+            ErrorCode::ReceiverHasNotAccepted
+        );
+
+        require!(amount >= MIN_ESCROW_AMOUNT, ErrorCode::AmountBelowMinimum);
+        require!(
+            commitment_matches(amount, &salt, &commitment),
+            ErrorCode::CommitmentMismatch
+        );
+
+        payment_agreement.amount = amount;
+        payment_agreement.amount_revealed = true;
+        require_referee_coverage(payment_agreement)?;
+    }
+
+    // Fund the escrow with the revealed amount
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    Ok(())
+}
+
+// Moves the expiration later, or sets one on an agreement that had none
+pub fn extend_expiration(
+    ctx: Context<ExtendExpiration>,
+    _name: String,
+    new_expiration_timestamp: i64,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::Unauthorized
+    );
+
+    require_unsettled(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    require!(
+        !is_expired(new_expiration_timestamp, Clock::get()?.unix_timestamp)
+            && !matches!(
+                payment_agreement.expiration_timestamp,
+                Some(expiration) if new_expiration_timestamp <= expiration
+            ),
+        ErrorCode::ExpirationMustBeInFuture
+    );
+
+    payment_agreement.expiration_timestamp = Some(new_expiration_timestamp);
+
+    Ok(())
+}
+
+// Adds `delta` to a pending agreement's escrow, funded by the payer
+pub fn increase_payment_amount(
+    ctx: Context<TopUpPaymentAgreement>,
+    _name: String,
+    delta: u64,
+) -> Result<()> {
+    {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::Unauthorized
+        );
+
+        require_pending(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        // A hidden amount is set by the reveal, which would discard a top-up
+        require!(
+            payment_agreement.amount_commitment.is_none() || payment_agreement.amount_revealed,
+            ErrorCode::AmountNotRevealed
+        );
+
+        require!(delta > 0, ErrorCode::InvalidAmount);
+        payment_agreement.amount = payment_agreement
+            .amount
+            .checked_add(delta)
+            .ok_or(ErrorCode::AmountOverflow)?;
+        require_referee_coverage(payment_agreement)?;
+        add_volume(&mut ctx.accounts.stats.created_volume, delta)?;
+    }
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+            },
+        ),
+        delta,
+    )?;
+
+    Ok(())
+}
+
+// Lowers a pending agreement's escrow to `new_amount`, refunding the difference
+// to the payer. Only allowed before the receiver has accepted the current terms.
+pub fn decrease_payment_amount(
+    ctx: Context<TopUpPaymentAgreement>,
+    _name: String,
+    new_amount: u64,
+) -> Result<()> {
+    let refund = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::Unauthorized
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        require!(
+            payment_agreement.amount_commitment.is_none() || payment_agreement.amount_revealed,
+            ErrorCode::AmountNotRevealed
+        );
+        require!(
+            !payment_agreement.receiver_approved && !payment_agreement.delivery_submitted,
+            ErrorCode::ReceiverHasApproved
+        );
+        // Milestones have to keep adding up to the escrowed amount
+        require!(
+            payment_agreement.milestones.is_empty(),
+            ErrorCode::InvalidMilestones
+        );
+
+        require!(
+            new_amount >= MIN_ESCROW_AMOUNT,
+            ErrorCode::AmountBelowMinimum
+        );
+        require!(
+            new_amount < payment_agreement.amount,
+            ErrorCode::InvalidAmount
+        );
+
+        let refund = payment_agreement.amount - new_amount;
+        payment_agreement.amount = new_amount;
+
+        // Agreements created before the stats account existed aren't in the volume
+        let stats = &mut ctx.accounts.stats;
+        stats.created_volume = stats.created_volume.saturating_sub(refund as u128);
+
+        refund
+    };
+
+    debit_escrow(&ctx.accounts.vault.to_account_info(), refund)?;
+    credit_refund(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        refund,
+    )?;
+
+    Ok(())
+}
+
+// The receiver's sealed acceptance, a commitment to the minimum amount they'll take
+pub fn commit_receiver_terms(
+    ctx: Context<SealedReceiverTerms>,
+    _name: String,
+    commitment: [u8; 32],
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    // Sealed terms only make sense against a hidden amount
+    require!(
+        payment_agreement.amount_commitment.is_some(),
+        ErrorCode::NoAmountCommitment
+    );
+    require!(
+        payment_agreement.receiver_terms_commitment.is_none(),
+        ErrorCode::ReceiverTermsAlreadyCommitted
+    );
+
+    // Same deadline as an explicit acceptance
+    if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp <= acceptance_expiry,
+            ErrorCode::AcceptanceWindowLapsed
+        );
+    }
+
+    payment_agreement.receiver_terms_commitment = Some(commitment);
+
+    Ok(())
+}
+
+pub fn reveal_receiver_terms(
+    ctx: Context<SealedReceiverTerms>,
+    _name: String,
+    min_amount: u64,
+    salt: [u8; 32],
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    let commitment = payment_agreement
+        .receiver_terms_commitment
+        .ok_or(ErrorCode::NoReceiverTermsCommitment)?;
+    require!(
+        !payment_agreement.receiver_terms_revealed,
+        ErrorCode::ReceiverTermsAlreadyRevealed
+    );
+
+    // The payer reveals first so neither side can adapt to the other's number
+    require!(
+        payment_agreement.amount_revealed,
+        ErrorCode::AmountNotRevealed
+    );
+    require!(
+        commitment_matches(min_amount, &salt, &commitment),
+        ErrorCode::CommitmentMismatch
+    );
+    require!(
+        payment_agreement.amount >= min_amount,
+        ErrorCode::SealedTermsNotMet
+    );
+
+    payment_agreement.receiver_terms_revealed = true;
+
+    Ok(())
+}
+
+pub fn submit_delivery(
+    ctx: Context<SubmitDelivery>,
+    _name: String,
+    delivery_proof: [u8; 32],
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+    require_not_paused(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    require!(
+        payment_agreement.delivery_completion,
+        ErrorCode::DeliveryCompletionNotEnabled
+    );
+    require!(
+        !payment_agreement.delivery_submitted,
+        ErrorCode::DeliveryAlreadySubmitted
+    );
+
+    // Same deadline as an explicit acceptance
+    if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp <= acceptance_expiry,
+            ErrorCode::AcceptanceWindowLapsed
+        );
+    }
+
+    payment_agreement.delivery_submitted = true;
+    payment_agreement.delivery_proof = delivery_proof;
+
+    Ok(())
+}
+
+// The receiver consents to the obligation. Needed before anyone can approve
+// an agreement created with require_ack, harmless otherwise.
+pub fn receiver_acknowledge(ctx: Context<SubmitDelivery>, _name: String) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_unsettled(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    // Same deadline as an explicit acceptance
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+        require!(
+            current_timestamp <= acceptance_expiry,
+            ErrorCode::AcceptanceWindowLapsed
+        );
+    }
+
+    payment_agreement.receiver_acknowledged = true;
+
+    emit!(ReceiverAcknowledged {
+        payment_agreement: payment_agreement.key(),
+        receiver: payment_agreement.receiver,
+        timestamp: current_timestamp,
+    });
+
+    Ok(())
+}
+
+// Locks the proof token in the agreement's vault and records the receiver's
+// approval. Completes right away if the payer has already approved.
+pub fn prove_and_approve<'info>(
+    ctx: Context<'_, '_, 'info, 'info, ProveAndApprove<'info>>,
+    _name: String,
+) -> Result<()> {
+    let (should_complete, transfer_amount, reserve) = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        require_acknowledged(payment_agreement)?;
+
+        require!(
+            payment_agreement.proof_token_mint == Some(ctx.accounts.proof_mint.key()),
+            ErrorCode::InvalidProofToken
+        );
+        require!(
+            !payment_agreement.proof_consumed,
+            ErrorCode::ProofAlreadyUsed
+        );
+
+        // Same deadline as an explicit acceptance
+        if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+            let current_timestamp = Clock::get()?.unix_timestamp;
+            require!(
+                current_timestamp <= acceptance_expiry,
+                ErrorCode::AcceptanceWindowLapsed
+            );
+        }
+
+        payment_agreement.proof_consumed = true;
+        payment_agreement.receiver_approved = true;
+
+        let should_complete =
+            payment_agreement.approvers.is_empty() && approvals_complete(payment_agreement);
+
+        if should_complete {
+            require_escrow_balance(
+                &ctx.accounts.vault.to_account_info(),
+                payment_agreement.amount,
+            )?;
+            require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+            require!(
+                payment_agreement.receiver_terms_commitment.is_none()
+                    || payment_agreement.receiver_terms_revealed,
+                ErrorCode::ReceiverTermsNotRevealed
+            );
+            mark_completed(payment_agreement)?;
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+            record_completed(&mut ctx.accounts.stats, payment_agreement.amount)?;
+        }
+
+        let reserve = if should_complete {
+            take_referee_reserve(payment_agreement)
+        } else {
+            0
+        };
+
+        (should_complete, payment_agreement.amount, reserve)
+    };
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.depositor_token_account.to_account_info(),
+                to: ctx.accounts.proof_vault.to_account_info(),
+                authority: ctx.accounts.depositor.to_account_info(),
+            },
+        ),
+        1,
+    )?;
+
+    if should_complete {
+        let total = transfer_amount
+            .checked_add(reserve)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+        ctx.accounts.receiver.add_lamports(transfer_amount)?;
+        credit_refund(
+            &ctx.accounts.payment_agreement,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+            reserve,
+        )?;
+
+        if ctx.accounts.payment_agreement.mint_receipt {
+            let payment_agreement = &ctx.accounts.payment_agreement;
+            mint_completion_receipt(
+                &ctx.accounts.config,
+                ctx.bumps.config,
+                payment_agreement.payer,
+                ctx.remaining_accounts,
+            )?;
+
+            emit!(ReceiptMinted {
+                payment_agreement: payment_agreement.key(),
+                payer: payment_agreement.payer,
+                name: payment_agreement.name.clone(),
+                amount: payment_agreement.amount,
+            });
+        }
+
+        notify_completion(
+            &ctx.accounts.payment_agreement,
+            &ctx.accounts.config,
+            transfer_amount,
+            ctx.remaining_accounts,
+        )?;
+        emit_completed(&ctx.accounts.payment_agreement, transfer_amount, false)?;
+
+        // Anything still escrowed, such as a top-up, goes back with the rent
+        ctx.accounts
+            .payment_agreement
+            .close(ctx.accounts.payer.to_account_info())?;
+        close_vault(
+            &ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.payer.to_account_info(),
+        )?;
+    }
+
+    Ok(())
+}
+
+// The receiver offers to settle for less than the full scope. Nothing moves
+// until the payer confirms the same figure.
+pub fn partial_accept(
+    ctx: Context<SubmitDelivery>,
+    _name: String,
+    deliverable_amount: u64,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    // An approver quorum can't be bypassed by the two parties alone
+    require!(
+        payment_agreement.approvers.is_empty(),
+        ErrorCode::Unauthorized
+    );
+    require!(
+        deliverable_amount > 0 && deliverable_amount <= payment_agreement.amount,
+        ErrorCode::InvalidDeliverableAmount
+    );
+
+    // Same deadline as an explicit acceptance
+    if let Some(acceptance_expiry) = payment_agreement.acceptance_expiry {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp <= acceptance_expiry,
+            ErrorCode::AcceptanceWindowLapsed
+        );
+    }
+
+    payment_agreement.proposed_deliverable_amount = Some(deliverable_amount);
+
+    Ok(())
+}
+
+// Pays the deliverable amount to the receiver and refunds the rest to the
+// payer in one settlement. The payer restates the amount so a changed
+// proposal can't be confirmed by accident.
+pub fn confirm_partial_accept(
+    ctx: Context<ApprovePaymentAgreement>,
+    name: String,
+    deliverable_amount: u64,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let (to_receiver, to_payer) = {
+        let payment_agreement = &mut payment_agreement;
+
+        require!(
+            ctx.accounts.signer.key() == payer_side_approver(payment_agreement),
+            ErrorCode::Unauthorized
+        );
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+        record_activity(payment_agreement)?;
+
+        let proposed = payment_agreement
+            .proposed_deliverable_amount
+            .ok_or(ErrorCode::NoPartialAcceptance)?;
+        require!(
+            proposed == deliverable_amount,
+            ErrorCode::DeliverableAmountMismatch
+        );
+
+        require_escrow_balance(
+            &ctx.accounts.vault.to_account_info(),
+            payment_agreement.amount,
+        )?;
+        require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+
+        let refund = payment_agreement
+            .amount
+            .checked_sub(deliverable_amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        mark_completed(payment_agreement)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_completed(&mut ctx.accounts.stats, deliverable_amount)?;
+        add_volume(&mut ctx.accounts.stats.cancelled_volume, refund)?;
+
+        let to_payer = refund
+            .checked_add(take_referee_reserve(payment_agreement))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (deliverable_amount, to_payer)
+    };
+
+    let total = to_receiver
+        .checked_add(to_payer)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    ctx.accounts.receiver.add_lamports(to_receiver)?;
+    credit_refund(
+        &payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        to_payer,
+    )?;
+
+    notify_completion(
+        &payment_agreement,
+        &ctx.accounts.config,
+        to_receiver,
+        ctx.remaining_accounts,
+    )?;
+    emit_completed(&payment_agreement, to_receiver, false)?;
+
+    // Anything still escrowed, such as a top-up, goes back with the rent
+    payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+pub fn open_dispute(ctx: Context<DisputePaymentAgreement>, _name: String) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.signer.key() == payment_agreement.payer
+            || ctx.accounts.signer.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+    record_activity(payment_agreement)?;
+
+    require!(
+        payment_agreement.referee.is_some(),
+        ErrorCode::NoRefereeAssigned
+    );
+    require!(
+        payment_agreement.dispute_opened_at.is_none(),
+        ErrorCode::DisputeAlreadyOpen
+    );
+
+    // Once auto-completion is due the deliverable counts as accepted
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    if let Some(auto_complete_timestamp) = payment_agreement.auto_complete_timestamp {
+        require!(
+            current_timestamp < auto_complete_timestamp,
+            ErrorCode::AutoCompletePassed
+        );
+    }
+
+    payment_agreement.dispute_opened_at = Some(current_timestamp);
+
+    Ok(())
+}
+
+// Freezes the parties until referee_resume. With block_withdrawal set the
+// payer's expiry refund is held too, otherwise it stays available.
+pub fn referee_pause(
+    ctx: Context<DisputePaymentAgreement>,
+    _name: String,
+    block_withdrawal: bool,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        payment_agreement.referee == Some(ctx.accounts.signer.key()),
+        ErrorCode::Unauthorized
+    );
+    require_unsettled(payment_agreement)?;
+    require_not_paused(payment_agreement)?;
+
+    payment_agreement.is_paused = true;
+    payment_agreement.pause_blocks_withdrawal = block_withdrawal;
+
+    Ok(())
+}
+
+pub fn referee_resume(ctx: Context<DisputePaymentAgreement>, _name: String) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require!(
+        payment_agreement.referee == Some(ctx.accounts.signer.key()),
+        ErrorCode::Unauthorized
+    );
+    require!(payment_agreement.is_paused, ErrorCode::AgreementNotPaused);
+
+    payment_agreement.is_paused = false;
+    payment_agreement.pause_blocks_withdrawal = false;
+
+    Ok(())
+}
+
+// Permissionless crank that pays the receiver once the auto-complete time is
+// reached with no dispute open
+pub fn auto_complete(ctx: Context<ResolveTie>, _name: String) -> Result<()> {
+    let (transfer_amount, to_payer) = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+
+        let auto_complete_timestamp = payment_agreement
+            .auto_complete_timestamp
+            .ok_or(ErrorCode::NoAutoComplete)?;
+        require!(
+            Clock::get()?.unix_timestamp >= auto_complete_timestamp,
+            ErrorCode::AutoCompleteNotReached
+        );
+        require!(
+            payment_agreement.dispute_opened_at.is_none(),
+            ErrorCode::DisputeAlreadyOpen
+        );
+
+        require_escrow_balance(
+            &ctx.accounts.vault.to_account_info(),
+            payment_agreement.amount,
+        )?;
+        require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+        require!(
+            payment_agreement.receiver_terms_commitment.is_none()
+                || payment_agreement.receiver_terms_revealed,
+            ErrorCode::ReceiverTermsNotRevealed
+        );
+        let payout = completion_payout(payment_agreement, ctx.remaining_accounts)?;
+        let refund = payment_agreement
+            .amount
+            .checked_sub(payout)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        mark_completed(payment_agreement)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_completed(&mut ctx.accounts.stats, payout)?;
+        add_volume(&mut ctx.accounts.stats.cancelled_volume, refund)?;
+
+        let to_payer = refund
+            .checked_add(take_referee_reserve(payment_agreement))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (payout, to_payer)
+    };
+
+    let total = transfer_amount
+        .checked_add(to_payer)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    ctx.accounts.receiver.add_lamports(transfer_amount)?;
+    credit_refund(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        to_payer,
+    )?;
+
+    notify_completion(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.config,
+        transfer_amount,
+        ctx.remaining_accounts,
+    )?;
+    emit_completed(&ctx.accounts.payment_agreement, transfer_amount, false)?;
+
+    // Anything still escrowed, such as a top-up, goes back with the rent
+    ctx.accounts
+        .payment_agreement
+        .close(ctx.accounts.payer.to_account_info())?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    Ok(())
+}
+
+pub fn escalate_to_default_referee(
+    ctx: Context<DisputePaymentAgreement>,
+    _name: String,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+    let config = &ctx.accounts.config;
+
+    require!(
+        ctx.accounts.signer.key() == payment_agreement.payer
+            || ctx.accounts.signer.key() == payment_agreement.receiver,
+        ErrorCode::Unauthorized
+    );
+
+    require_pending(payment_agreement)?;
+
+    let dispute_opened_at = payment_agreement
+        .dispute_opened_at
+        .ok_or(ErrorCode::NoDisputeOpen)?;
+
+    // Only escalate once the current referee has sat on the dispute long enough
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    require!(
+        current_timestamp >= dispute_opened_at.saturating_add(config.referee_inactivity_window),
+        ErrorCode::InactivityWindowNotElapsed
+    );
+
+    let default_referee = config.default_referee.ok_or(ErrorCode::NoDefaultReferee)?;
+    require!(
+        default_referee != payment_agreement.payer,
+        ErrorCode::RefereeCannotBePayer
+    );
+    require!(
+        default_referee != payment_agreement.receiver,
+        ErrorCode::RefereeCannotBeReceiver
+    );
+
+    // The fallback arbiter gets a fresh window of its own
+    payment_agreement.referee = Some(default_referee);
+    payment_agreement.dispute_opened_at = Some(current_timestamp);
+
+    Ok(())
+}
+
+// Permissionless crank for an unresponsive receiver
+pub fn enforce_sla(ctx: Context<EnforceSla>, _name: String) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require_pending(payment_agreement)?;
+
+    let sla_deadline = payment_agreement
+        .sla_deadline
+        .ok_or(ErrorCode::NoSlaConfigured)?;
+
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    require!(current_timestamp > sla_deadline, ErrorCode::SlaNotElapsed);
+    require!(
+        !payment_agreement.receiver_approved
+            && !payment_agreement.receiver_requested_cancel
+            && !payment_agreement.delivery_submitted,
+        ErrorCode::ReceiverAlreadyResponded
+    );
+
+    // Escalate to the referee when there is one, otherwise free the payer to reclaim
+    if payment_agreement.referee.is_some() {
+        if payment_agreement.dispute_opened_at.is_none() {
+            payment_agreement.dispute_opened_at = Some(current_timestamp);
+        }
+    } else {
+        payment_agreement.sla_breached = true;
+    }
+
+    // The SLA fires once
+    payment_agreement.sla_deadline = None;
+
+    Ok(())
+}
+
+pub fn referee_intervene_complete_payment_agreement<'info>(
+    ctx: Context<'_, '_, 'info, 'info, ApprovePaymentAgreement<'info>>,
+    name: String,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    // Handle referee intervention and get necessary data
+    let (transfer_amount, referee_fee) = {
+        let payment_agreement = &mut payment_agreement;
+
+        // Check if referee exists and signer is the referee
+        require!(payment_agreement.referee.is_some(), ErrorCode::Unauthorized);
+        require!(
+            payment_agreement.referee.unwrap() == ctx.accounts.signer.key(),
+            ErrorCode::Unauthorized
+        );
+
+        // Give the parties time to settle before arbitration kicks in
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp
+                >= payment_agreement
+                    .created_at
+                    .saturating_add(ctx.accounts.config.min_seconds_before_intervention),
+            ErrorCode::TooEarlyForIntervention
+        );
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+
+        require_escrow_balance(
+            &ctx.accounts.vault.to_account_info(),
+            payment_agreement.amount,
+        )?;
+        require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+        require_no_ruling(payment_agreement)?;
+        mark_completed(payment_agreement)?;
+        payment_agreement.status = AgreementStatus::RefereeCompleted;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_completed(&mut ctx.accounts.stats, payment_agreement.amount)?;
+
+        // Dust is left in the vault for sweep_dust, so the balance inputs don't matter here
+        let breakdown = payout_breakdown(payment_agreement, true, 0, 0)?;
+        take_referee_reserve(payment_agreement);
+        (breakdown.receiver_share, breakdown.referee_fee)
+    };
+
+    // Transfer funds from escrow to receiver, less the referee's fee
+    let total = transfer_amount
+        .checked_add(referee_fee)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    ctx.accounts.receiver.add_lamports(transfer_amount)?;
+    ctx.accounts.signer.add_lamports(referee_fee)?;
+
+    if payment_agreement.mint_receipt {
+        issue_receipt(&ctx, &payment_agreement)?;
+    }
+
+    notify_completion(
+        &payment_agreement,
+        &ctx.accounts.config,
+        transfer_amount,
+        ctx.remaining_accounts,
+    )?;
+
+    emit!(RefereeIntervened {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        referee: ctx.accounts.signer.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: ctx.accounts.receiver.key(),
+        amount: transfer_amount,
+        completed: true,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    emit_completed(&payment_agreement, transfer_amount, true)?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+pub fn referee_intervene_cancel_payment_agreement(
+    ctx: Context<CancelPaymentAgreement>,
+    name: String,
+) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
     // Handle referee intervention and get necessary data
+    let (transfer_amount, referee_fee) = {
+        let payment_agreement = &mut payment_agreement;
+
+        // Check if referee exists and signer is the referee
+        require!(payment_agreement.referee.is_some(), ErrorCode::Unauthorized);
+        require!(
+            payment_agreement.referee.unwrap() == ctx.accounts.signer.key(),
+            ErrorCode::Unauthorized
+        );
+
+        // Give the parties time to settle before arbitration kicks in
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp
+                >= payment_agreement
+                    .created_at
+                    .saturating_add(ctx.accounts.config.min_seconds_before_intervention),
+            ErrorCode::TooEarlyForIntervention
+        );
+
+        // Validate that passed payer account matches stored payer
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+
+        require_pending(payment_agreement)?;
+
+        require_no_ruling(payment_agreement)?;
+        settle(payment_agreement, AgreementStatus::RefereeCancelled)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+
+        let breakdown = payout_breakdown(payment_agreement, true, 0, 0)?;
+        take_referee_reserve(payment_agreement);
+        (breakdown.receiver_share, breakdown.referee_fee)
+    };
+
+    // Return funds to payer when cancelled, less the referee's fee
+    let total = transfer_amount
+        .checked_add(referee_fee)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    credit_refund(
+        &payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        transfer_amount,
+    )?;
+    ctx.accounts.signer.add_lamports(referee_fee)?;
+
+    emit!(RefereeIntervened {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        referee: ctx.accounts.signer.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: payment_agreement.receiver,
+        amount: transfer_amount,
+        completed: false,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    emit_cancelled(&payment_agreement, transfer_amount)?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+// Lets the referee finish a cancellation only one party asked for, once the
+// other has been silent for the abandon timeout. Settles as a referee ruling.
+pub fn confirm_abandoned_cancel(ctx: Context<CancelPaymentAgreement>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    let (transfer_amount, referee_fee) = {
+        let payment_agreement = &mut payment_agreement;
+
+        require!(
+            payment_agreement.referee == Some(ctx.accounts.signer.key()),
+            ErrorCode::Unauthorized
+        );
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+
+        require_pending(payment_agreement)?;
+
+        // Both asking is a mutual cancel, which settles without the referee
+        require!(
+            payment_agreement.payer_requested_cancel != payment_agreement.receiver_requested_cancel,
+            ErrorCode::NoOneSidedCancelRequest
+        );
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp
+                >= payment_agreement
+                    .last_activity_ts
+                    .saturating_add(ctx.accounts.config.abandon_timeout),
+            ErrorCode::AbandonTimeoutNotElapsed
+        );
+
+        require_no_ruling(payment_agreement)?;
+        settle(payment_agreement, AgreementStatus::RefereeCancelled)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+
+        let breakdown = payout_breakdown(payment_agreement, true, 0, 0)?;
+        take_referee_reserve(payment_agreement);
+        (breakdown.receiver_share, breakdown.referee_fee)
+    };
+
+    // Return funds to payer, less the referee's fee
+    let total = transfer_amount
+        .checked_add(referee_fee)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    credit_refund(
+        &payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        transfer_amount,
+    )?;
+    ctx.accounts.signer.add_lamports(referee_fee)?;
+
+    emit!(RefereeIntervened {
+        payment_agreement: ctx.accounts.payment_agreement.key(),
+        referee: ctx.accounts.signer.key(),
+        payer: ctx.accounts.payer.key(),
+        receiver: payment_agreement.receiver,
+        amount: transfer_amount,
+        completed: false,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    emit_cancelled(&payment_agreement, transfer_amount)?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+pub fn referee_vote(
+    ctx: Context<RefereeVote>,
+    _name: String,
+    decision: RefereeDecision,
+) -> Result<()> {
+    let ruling = {
+        let payment_agreement = &mut ctx.accounts.payment_agreement;
+        let signer = ctx.accounts.signer.key();
+
+        let index = payment_agreement
+            .referees
+            .iter()
+            .position(|panel_referee| *panel_referee == signer)
+            .ok_or(ErrorCode::Unauthorized)?;
+
+        // Validate that passed accounts match stored accounts
+        require!(
+            ctx.accounts.payer.key() == payment_agreement.payer,
+            ErrorCode::InvalidPayer
+        );
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+
+        // Same arbitration window as a single referee
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp
+                >= payment_agreement
+                    .created_at
+                    .saturating_add(ctx.accounts.config.min_seconds_before_intervention),
+            ErrorCode::TooEarlyForIntervention
+        );
+
+        require!(
+            (payment_agreement.complete_votes_bitmap | payment_agreement.cancel_votes_bitmap)
+                & (1 << index)
+                == 0,
+            ErrorCode::AlreadyVoted
+        );
+        match decision {
+            RefereeDecision::Complete => payment_agreement.complete_votes_bitmap |= 1 << index,
+            RefereeDecision::Cancel => payment_agreement.cancel_votes_bitmap |= 1 << index,
+        }
+
+        let complete_votes = payment_agreement.complete_votes_bitmap.count_ones() as u8;
+        let cancel_votes = payment_agreement.cancel_votes_bitmap.count_ones() as u8;
+        let decided_votes = match decision {
+            RefereeDecision::Complete => complete_votes,
+            RefereeDecision::Cancel => cancel_votes,
+        };
+        let threshold_reached = decided_votes >= payment_agreement.required_votes;
+
+        emit!(RefereeVoteCast {
+            payment_agreement: payment_agreement.key(),
+            referee: signer,
+            decision,
+            complete_votes,
+            cancel_votes,
+            threshold_reached,
+        });
+
+        if threshold_reached {
+            require_no_ruling(payment_agreement)?;
+            match decision {
+                RefereeDecision::Complete => {
+                    require_escrow_balance(
+                        &ctx.accounts.vault.to_account_info(),
+                        payment_agreement.amount,
+                    )?;
+                    require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+                    mark_completed(payment_agreement)?;
+                    payment_agreement.status = AgreementStatus::RefereeCompleted;
+                    record_completed(&mut ctx.accounts.stats, payment_agreement.amount)?;
+                }
+                RefereeDecision::Cancel => {
+                    settle(payment_agreement, AgreementStatus::RefereeCancelled)?;
+                    record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+                }
+            }
+            release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+
+            Some((payment_agreement.amount, current_timestamp))
+        } else {
+            record_panel_tie(payment_agreement)?;
+            None
+        }
+    };
+
+    if let Some((amount, timestamp)) = ruling {
+        debit_escrow(&ctx.accounts.vault.to_account_info(), amount)?;
+        let recipient = match decision {
+            RefereeDecision::Complete => {
+                ctx.accounts.receiver.add_lamports(amount)?;
+                ctx.accounts.receiver.key()
+            }
+            RefereeDecision::Cancel => credit_refund(
+                &ctx.accounts.payment_agreement,
+                &ctx.accounts.payer,
+                ctx.remaining_accounts,
+                amount,
+            )?,
+        };
+
+        emit!(RefereeRulingExecuted {
+            payment_agreement: ctx.accounts.payment_agreement.key(),
+            decision,
+            recipient,
+            amount,
+            timestamp,
+        });
+
+        match decision {
+            RefereeDecision::Complete => {
+                emit_completed(&ctx.accounts.payment_agreement, amount, true)?
+            }
+            RefereeDecision::Cancel => emit_cancelled(&ctx.accounts.payment_agreement, amount)?,
+        }
+    }
+
+    Ok(())
+}
+
+// Shared by withdraw_expired_funds and its read-only query so the two never disagree
+// Total refunded once every chunk vested by `current_timestamp` is paid out
+fn vested_refund(payment_agreement: &PaymentAgreement, current_timestamp: i64) -> u64 {
+    let total = payment_agreement
+        .amount
+        .saturating_add(payment_agreement.refunded_amount);
+    let (Some(schedule), Some(refund_started_at)) = (
+        payment_agreement.expiry_refund_schedule,
+        payment_agreement.refund_started_at,
+    ) else {
+        return total;
+    };
+
+    let elapsed = current_timestamp.saturating_sub(refund_started_at).max(0);
+    let vested_chunks = (elapsed / schedule.interval_seconds)
+        .saturating_add(1)
+        .min(schedule.chunks as i64) as u128;
+
+    (total as u128 * vested_chunks / schedule.chunks as u128) as u64
+}
+
+fn expired_withdrawal_block(
+    payment_agreement: &PaymentAgreement,
+    current_timestamp: i64,
+) -> WithdrawBlockReason {
+    // A refund in progress already passed every check below on its first chunk
+    if payment_agreement.refund_started_at.is_some() {
+        if vested_refund(payment_agreement, current_timestamp) <= payment_agreement.refunded_amount
+        {
+            return WithdrawBlockReason::RefundNotVested;
+        }
+        return WithdrawBlockReason::None;
+    }
+
+    // A lapsed acceptance window or breached SLA lets the payer reclaim
+    // before the overall expiration
+    let acceptance_lapsed = match payment_agreement.acceptance_expiry {
+        Some(acceptance_expiry) => {
+            current_timestamp > acceptance_expiry && !payment_agreement.receiver_approved
+        }
+        None => false,
+    };
+
+    if !acceptance_lapsed && !payment_agreement.sla_breached {
+        match payment_agreement.expiration_timestamp {
+            None => return WithdrawBlockReason::NoExpiration,
+            Some(expiration) if !is_expired(expiration, current_timestamp) => {
+                return WithdrawBlockReason::NotExpired
+            }
+            // The receiver may still approve during the dispute window
+            Some(expiration)
+                if !is_expired(
+                    dispute_window_end(expiration, payment_agreement.dispute_window_seconds),
+                    current_timestamp,
+                ) =>
+            {
+                return WithdrawBlockReason::DisputeWindowActive
+            }
+            Some(_) => {}
+        }
+        // A plain expiry is the receiver's to settle
+        if payment_agreement.expiry_favors == ExpiryBeneficiary::Receiver {
+            return WithdrawBlockReason::ExpiryFavorsReceiver;
+        }
+    }
+    if payment_agreement.status.is_completed() {
+        return WithdrawBlockReason::AlreadyCompleted;
+    }
+    if payment_agreement.status.is_cancelled() {
+        return WithdrawBlockReason::AlreadyCancelled;
+    }
+
+    // Once the receiver has accepted, the payer can no longer claw back on expiry
+    if payment_agreement.receiver_approved || payment_agreement.delivery_submitted {
+        return WithdrawBlockReason::ReceiverApproved;
+    }
+
+    if payment_agreement.is_paused && payment_agreement.pause_blocks_withdrawal {
+        return WithdrawBlockReason::Paused;
+    }
+
+    WithdrawBlockReason::None
+}
+
+fn dispute_window_end(expiration: i64, dispute_window_seconds: u64) -> i64 {
+    expiration.saturating_add(i64::try_from(dispute_window_seconds).unwrap_or(i64::MAX))
+}
+
+fn withdraw_block_error(reason: WithdrawBlockReason) -> Option<ErrorCode> {
+    match reason {
+        WithdrawBlockReason::None => None,
+        WithdrawBlockReason::NoExpiration | WithdrawBlockReason::NotExpired => {
+            Some(ErrorCode::PaymentAgreementNotExpired)
+        }
+        WithdrawBlockReason::AlreadyCompleted => Some(ErrorCode::AgreementAlreadyCompleted),
+        WithdrawBlockReason::AlreadyCancelled => Some(ErrorCode::AgreementAlreadyCancelled),
+        WithdrawBlockReason::ReceiverApproved => Some(ErrorCode::ReceiverHasApproved),
+        WithdrawBlockReason::RefundNotVested => Some(ErrorCode::RefundNotVested),
+        WithdrawBlockReason::DisputeWindowActive => Some(ErrorCode::DisputeWindowActive),
+        WithdrawBlockReason::Paused => Some(ErrorCode::AgreementPaused),
+        WithdrawBlockReason::ExpiryFavorsReceiver => Some(ErrorCode::ExpiryFavorsReceiver),
+    }
+}
+
+pub fn withdraw_expired_funds(ctx: Context<WithdrawExpiredFunds>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::Unauthorized
+    );
+
+    refund_expired(
+        &mut payment_agreement,
+        &ctx.accounts.vault.to_account_info(),
+        &mut ctx.accounts.config,
+        &mut ctx.accounts.stats,
+        &ctx.accounts.payer.to_account_info(),
+        ctx.remaining_accounts,
+    )
+}
+
+// Refunds the payer the vested part of an expired agreement, settling it on
+// the first chunk and closing it once everything is returned. The caller has
+// checked that payer is the stored payer.
+fn refund_expired<'info>(
+    payment_agreement: &mut LoadedAgreement<'_, 'info>,
+    vault: &AccountInfo<'info>,
+    config: &mut Config,
+    stats: &mut Stats,
+    payer: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo],
+) -> Result<()> {
+    let (transfer_amount, fully_refunded, cancelled_amount) = {
+        require_native(payment_agreement)?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        if let Some(error) = withdraw_block_error(expired_withdrawal_block(
+            payment_agreement,
+            current_timestamp,
+        )) {
+            return Err(error!(error));
+        }
+
+        // The first withdrawal settles the agreement, any later chunks only pay out
+        let mut cancelled_amount = None;
+        if payment_agreement.refund_started_at.is_none() {
+            cancelled_amount = Some(payment_agreement.amount);
+            payment_agreement.refund_started_at = Some(current_timestamp);
+            settle(payment_agreement, AgreementStatus::Expired)?;
+            release_active_slot(config, stats)?;
+            increment_count(&mut stats.expired_refund_count)?;
+        }
+
+        let transfer_amount = vested_refund(payment_agreement, current_timestamp)
+            .checked_sub(payment_agreement.refunded_amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        payment_agreement.amount = payment_agreement
+            .amount
+            .checked_sub(transfer_amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        payment_agreement.refunded_amount = payment_agreement
+            .refunded_amount
+            .checked_add(transfer_amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        add_volume(&mut stats.expired_refund_volume, transfer_amount)?;
+
+        (
+            transfer_amount,
+            payment_agreement.amount == 0,
+            cancelled_amount,
+        )
+    };
+
+    debit_escrow(vault, transfer_amount)?;
+
+    // A fallback receiver is passed as the first remaining account
+    match payment_agreement.fallback_receiver {
+        Some(fallback_receiver) => {
+            let fallback = remaining_accounts
+                .first()
+                .ok_or(ErrorCode::InvalidFallbackReceiver)?;
+            require!(
+                fallback.key() == fallback_receiver,
+                ErrorCode::InvalidFallbackReceiver
+            );
+            fallback.add_lamports(transfer_amount)?;
+        }
+        None => {
+            credit_refund(
+                payment_agreement,
+                payer,
+                remaining_accounts,
+                transfer_amount,
+            )?;
+        }
+    }
+
+    // Reported once, with everything the payer is owed across all chunks
+    if let Some(amount) = cancelled_amount {
+        emit_cancelled(payment_agreement, amount)?;
+    }
+
+    if fully_refunded {
+        payment_agreement.close(payer.clone())?;
+        close_vault(vault, payer)?;
+    }
+
+    payment_agreement.exit()
+}
+
+// Settles an expired agreement in favor of the side chosen at creation. A
+// payer-favored expiry refunds exactly as withdraw_expired_funds does. A
+// receiver-favored one pays the receiver once the dispute window has passed
+// with no dispute open.
+pub fn settle_expired(ctx: Context<SettleExpired>, name: String) -> Result<()> {
+    let mut payment_agreement = load_agreement(&ctx.accounts.payment_agreement, &name)?;
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    require!(
+        ctx.accounts.receiver.key() == payment_agreement.receiver,
+        ErrorCode::InvalidReceiver
+    );
+
+    if payment_agreement.expiry_favors == ExpiryBeneficiary::Payer {
+        require!(
+            ctx.accounts.signer.key() == payment_agreement.payer,
+            ErrorCode::Unauthorized
+        );
+        return refund_expired(
+            &mut payment_agreement,
+            &ctx.accounts.vault.to_account_info(),
+            &mut ctx.accounts.config,
+            &mut ctx.accounts.stats,
+            &ctx.accounts.payer,
+            ctx.remaining_accounts,
+        );
+    }
+
+    let (transfer_amount, to_payer) = {
+        let payment_agreement = &mut payment_agreement;
+
+        require!(
+            ctx.accounts.signer.key() == payment_agreement.receiver,
+            ErrorCode::Unauthorized
+        );
+
+        require_native(payment_agreement)?;
+        require_pending(payment_agreement)?;
+        require_not_paused(payment_agreement)?;
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let expiration = payment_agreement
+            .expiration_timestamp
+            .ok_or(ErrorCode::PaymentAgreementNotExpired)?;
+        require!(
+            is_expired(expiration, current_timestamp),
+            ErrorCode::PaymentAgreementNotExpired
+        );
+        require!(
+            is_expired(
+                dispute_window_end(expiration, payment_agreement.dispute_window_seconds),
+                current_timestamp,
+            ),
+            ErrorCode::DisputeWindowActive
+        );
+        require!(
+            payment_agreement.dispute_opened_at.is_none(),
+            ErrorCode::DisputeAlreadyOpen
+        );
+
+        require_escrow_balance(
+            &ctx.accounts.vault.to_account_info(),
+            payment_agreement.amount,
+        )?;
+        require_receiver_exists(payment_agreement, &ctx.accounts.receiver)?;
+        require!(
+            payment_agreement.receiver_terms_commitment.is_none()
+                || payment_agreement.receiver_terms_revealed,
+            ErrorCode::ReceiverTermsNotRevealed
+        );
+        let payout = completion_payout(payment_agreement, ctx.remaining_accounts)?;
+        let refund = payment_agreement
+            .amount
+            .checked_sub(payout)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        mark_completed(payment_agreement)?;
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_completed(&mut ctx.accounts.stats, payout)?;
+        add_volume(&mut ctx.accounts.stats.cancelled_volume, refund)?;
+
+        let to_payer = refund
+            .checked_add(take_referee_reserve(payment_agreement))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (payout, to_payer)
+    };
+
+    let total = transfer_amount
+        .checked_add(to_payer)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    debit_escrow(&ctx.accounts.vault.to_account_info(), total)?;
+    ctx.accounts.receiver.add_lamports(transfer_amount)?;
+    credit_refund(
+        &payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        to_payer,
+    )?;
+
+    notify_completion(
+        &payment_agreement,
+        &ctx.accounts.config,
+        transfer_amount,
+        ctx.remaining_accounts,
+    )?;
+    emit_completed(&payment_agreement, transfer_amount, false)?;
+
+    // Anything still escrowed, such as a top-up, goes back with the rent
+    payment_agreement.close(ctx.accounts.payer.to_account_info())?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    payment_agreement.exit()?;
+
+    Ok(())
+}
+
+pub fn can_payer_withdraw_expired(
+    ctx: Context<ReadPaymentAgreement>,
+    name: String,
+) -> Result<WithdrawEligibility> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    let reason = expired_withdrawal_block(&payment_agreement, current_timestamp);
+
+    Ok(WithdrawEligibility {
+        can_withdraw: reason == WithdrawBlockReason::None,
+        reason,
+    })
+}
+
+pub fn check_permission(
+    ctx: Context<CheckPermission>,
+    name: String,
+    action: PermissionAction,
+    signer: Pubkey,
+) -> Result<PermissionCheck> {
+    let payment_agreement = read_agreement(
+        &ctx.accounts.payment_agreement,
+        &ctx.accounts.payer.key(),
+        &name,
+    )?;
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    let denial = permission_denial(
+        &payment_agreement,
+        &ctx.accounts.config,
+        action,
+        signer,
+        current_timestamp,
+    );
+
+    Ok(PermissionCheck {
+        allowed: denial.is_none(),
+        denial_code: denial.map_or(0, u32::from),
+    })
+}
+
+// Mirrors the guards of each instruction, in the same order, so the error
+// reported here is the one the instruction itself would fail with
+fn permission_denial(
+    payment_agreement: &PaymentAgreement,
+    config: &Config,
+    action: PermissionAction,
+    signer: Pubkey,
+    current_timestamp: i64,
+) -> Option<ErrorCode> {
+    let is_party = signer == payment_agreement.payer || signer == payment_agreement.receiver;
+    let approver_index = payment_agreement
+        .approvers
+        .iter()
+        .position(|approver| *approver == signer);
+    let terminal = if payment_agreement.status.is_completed() {
+        Some(ErrorCode::AgreementAlreadyCompleted)
+    } else if payment_agreement.status.is_cancelled() {
+        Some(ErrorCode::AgreementAlreadyCancelled)
+    } else {
+        None
+    };
+    let acceptance_lapsed = payment_agreement
+        .acceptance_expiry
+        .is_some_and(|acceptance_expiry| current_timestamp > acceptance_expiry);
+
+    match action {
+        PermissionAction::Approve => {
+            let authorized = if payment_agreement.approvers.is_empty() {
+                signer == payer_side_approver(payment_agreement)
+                    || signer == payment_agreement.receiver
+            } else {
+                approver_index.is_some()
+            };
+            if !authorized {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.is_paused {
+                return Some(ErrorCode::AgreementPaused);
+            }
+            if payment_agreement.require_ack && !payment_agreement.receiver_acknowledged {
+                return Some(ErrorCode::ReceiverHasNotAcknowledged);
+            }
+            if signer == payer_side_approver(payment_agreement)
+                && payment_agreement.amount_commitment.is_some()
+                && !payment_agreement.amount_revealed
+            {
+                return Some(ErrorCode::AmountNotRevealed);
+            }
+            if signer == payment_agreement.receiver && acceptance_lapsed {
+                return Some(ErrorCode::AcceptanceWindowLapsed);
+            }
+            if let Some(index) = approver_index {
+                if payment_agreement.rejections_bitmap & (1 << index) != 0 {
+                    return Some(ErrorCode::AlreadyVoted);
+                }
+            }
+            None
+        }
+        PermissionAction::Reject => {
+            let Some(index) = approver_index else {
+                return Some(ErrorCode::Unauthorized);
+            };
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.is_paused {
+                return Some(ErrorCode::AgreementPaused);
+            }
+            if (payment_agreement.approvals_bitmap | payment_agreement.rejections_bitmap)
+                & (1 << index)
+                != 0
+            {
+                return Some(ErrorCode::AlreadyVoted);
+            }
+            None
+        }
+        PermissionAction::Cancel => {
+            if !is_party {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.is_paused {
+                return Some(ErrorCode::AgreementPaused);
+            }
+            // Only the request that would execute the cancellation is time-gated
+            let executes = if signer == payment_agreement.payer {
+                payment_agreement.receiver_requested_cancel
+            } else {
+                payment_agreement.payer_requested_cancel
+            };
+            if let Some(min_duration_seconds) = payment_agreement.min_duration_seconds {
+                if executes
+                    && current_timestamp
+                        < payment_agreement
+                            .created_at
+                            .saturating_add(min_duration_seconds)
+                {
+                    return Some(ErrorCode::MinimumDurationNotMet);
+                }
+            }
+            None
+        }
+        PermissionAction::Withdraw => {
+            if signer != payment_agreement.payer {
+                return Some(ErrorCode::Unauthorized);
+            }
+            withdraw_block_error(expired_withdrawal_block(
+                payment_agreement,
+                current_timestamp,
+            ))
+        }
+        PermissionAction::Intervene => {
+            if payment_agreement.referee != Some(signer) {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if current_timestamp
+                < payment_agreement
+                    .created_at
+                    .saturating_add(config.min_seconds_before_intervention)
+            {
+                return Some(ErrorCode::TooEarlyForIntervention);
+            }
+            terminal
+        }
+        PermissionAction::SubmitDelivery => {
+            if signer != payment_agreement.receiver {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.is_paused {
+                return Some(ErrorCode::AgreementPaused);
+            }
+            if !payment_agreement.delivery_completion {
+                return Some(ErrorCode::DeliveryCompletionNotEnabled);
+            }
+            if payment_agreement.delivery_submitted {
+                return Some(ErrorCode::DeliveryAlreadySubmitted);
+            }
+            if acceptance_lapsed {
+                return Some(ErrorCode::AcceptanceWindowLapsed);
+            }
+            None
+        }
+        PermissionAction::RevealPayerAmount => {
+            if signer != payment_agreement.payer {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.amount_commitment.is_none() {
+                return Some(ErrorCode::NoAmountCommitment);
+            }
+            if payment_agreement.amount_revealed {
+                return Some(ErrorCode::AmountAlreadyRevealed);
+            }
+            if !payment_agreement.receiver_approved
+                && payment_agreement.receiver_terms_commitment.is_none()
+            {
+                return Some(ErrorCode::ReceiverHasNotAccepted);
+            }
+            None
+        }
+        PermissionAction::OpenDispute => {
+            if !is_party {
+                return Some(ErrorCode::Unauthorized);
+            }
+            if terminal.is_some() {
+                return terminal;
+            }
+            if payment_agreement.referee.is_none() {
+                return Some(ErrorCode::NoRefereeAssigned);
+            }
+            if payment_agreement.dispute_opened_at.is_some() {
+                return Some(ErrorCode::DisputeAlreadyOpen);
+            }
+            None
+        }
+    }
+}
+
+pub fn migrate_to_new_terms(
+    ctx: Context<MigrateToNewTerms>,
+    _name: String,
+    new_name: String,
+    new_receiver: Pubkey,
+    new_amount: u64,
+    new_expiration_timestamp: Option<i64>,
+) -> Result<()> {
+    let new_referee = ctx
+        .accounts
+        .new_referee
+        .as_ref()
+        .map(|referee| referee.key());
+
+    let (migrated, old_amount) = {
+        let payment_agreement = &ctx.accounts.payment_agreement;
+
+        require!(
+            ctx.accounts.receiver.key() == payment_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+
+        require_pending(payment_agreement)?;
+        require!(
+            payment_agreement.amount_commitment.is_none() || payment_agreement.amount_revealed,
+            ErrorCode::AmountNotRevealed
+        );
+
+        validate_terms(
+            &new_name,
+            payment_agreement.payer,
+            new_receiver,
+            new_referee,
+            new_expiration_timestamp,
+        )?;
+        require_wallet_receiver(
+            new_receiver,
+            ctx.accounts.receiver_account.as_deref(),
+            &None,
+        )?;
+        require!(
+            new_amount >= MIN_ESCROW_AMOUNT,
+            ErrorCode::AmountBelowMinimum
+        );
+        if let (Some(acceptance_expiry), Some(expiration)) = (
+            payment_agreement.acceptance_expiry,
+            new_expiration_timestamp,
+        ) {
+            require!(
+                acceptance_expiry <= expiration,
+                ErrorCode::AcceptanceExpiryAfterExpiration
+            );
+        }
+
+        // Settings carry over, negotiation state starts fresh
+        let mut migrated = (**payment_agreement).clone();
+        migrated.name = new_name;
+        migrated.receiver = new_receiver;
+        migrated.referee = new_referee;
+        migrated.amount = new_amount;
+        // Any reserved fee is refunded with the closed account, not carried over
+        migrated.reserved_referee_fee = 0;
+        migrated.expiration_timestamp = new_expiration_timestamp;
+        migrated.payer_approved = false;
+        migrated.receiver_approved = false;
+        migrated.payer_requested_cancel = false;
+        migrated.receiver_requested_cancel = false;
+        migrated.approvals_bitmap = 0;
+        migrated.approved_weight = 0;
+        migrated.rejections_bitmap = 0;
+        migrated.tie_detected_at = None;
+        migrated.complete_votes_bitmap = 0;
+        migrated.cancel_votes_bitmap = 0;
+        migrated.pending_release = None;
+        migrated.pending_partial_completion = None;
+        migrated.proposed_deliverable_amount = None;
+        migrated.completion_callback = None;
+        migrated.auto_complete_timestamp = None;
+        migrated.performance_fee = None;
+        migrated.reference_baseline = 0;
+        migrated.dispute_opened_at = None;
+        migrated.sla_breached = false;
+        migrated.amount_commitment = None;
+        migrated.amount_revealed = false;
+        migrated.receiver_terms_commitment = None;
+        migrated.receiver_terms_revealed = false;
+        migrated.delivery_submitted = false;
+        migrated.delivery_proof = [0; 32];
+        migrated.created_at = Clock::get()?.unix_timestamp;
+        migrated.last_activity_ts = migrated.created_at;
+        migrated.original_payer = ctx.accounts.payer.key();
+        migrated.bump = ctx.bumps.new_payment_agreement;
+        migrated.vault_bump = ctx.bumps.new_vault;
+        // Released milestones stay with the old account, the rest start unapproved
+        // and must still cover the new amount
+        migrated.milestones.retain(|milestone| !milestone.released);
+        for milestone in migrated.milestones.iter_mut() {
+            milestone.payer_approved = false;
+            milestone.receiver_approved = false;
+        }
+        if !migrated.milestones.is_empty() {
+            let amounts: Vec<u64> = migrated
+                .milestones
+                .iter()
+                .map(|milestone| milestone.amount)
+                .collect();
+            validate_milestones(&amounts, new_amount)?;
+        }
+        require_referee_coverage(&migrated)?;
+
+        (migrated, payment_agreement.amount)
+    };
+
+    ctx.accounts.new_payment_agreement.set_inner(migrated);
+
+    // Move the escrow vault-to-vault, the payer only covers an increase
+    let carried = old_amount.min(new_amount);
+    debit_escrow(&ctx.accounts.vault.to_account_info(), carried)?;
+    ctx.accounts.new_vault.add_lamports(carried)?;
+
+    if new_amount > old_amount {
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: ctx.accounts.new_vault.to_account_info(),
+                },
+            ),
+            new_amount - old_amount,
+        )?;
+    }
+
+    // The new vault must hold exactly the new amount on top of its rent
+    let new_vault = ctx.accounts.new_vault.to_account_info();
+    let minimum_balance = Rent::get()?.minimum_balance(new_vault.data_len());
+    require!(
+        minimum_balance.checked_add(new_amount) == Some(new_vault.lamports()),
+        ErrorCode::MigrationBalanceMismatch
+    );
+
+    // Any decrease returns to the payer along with both of the old accounts' rent
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    Ok(())
+}
+
+// Hands the payer role to another key. The payer is part of the PDA seed, so
+// the agreement moves to a new account with its state intact and the escrow
+// carried across. The rent of the old account and its vault goes back to the
+// old payer.
+pub fn transfer_ownership(ctx: Context<TransferOwnership>, _name: String) -> Result<()> {
+    let new_payer = ctx.accounts.new_payer.key();
+
+    let (transferred, carried) = {
+        let payment_agreement = &ctx.accounts.payment_agreement;
+
+        require_pending(payment_agreement)?;
+
+        require!(
+            new_payer != payment_agreement.payer
+                && new_payer != payment_agreement.receiver
+                && payment_agreement.referee != Some(new_payer)
+                && !payment_agreement.referees.contains(&new_payer),
+            ErrorCode::InvalidNewPayer
+        );
+
+        if payment_agreement.ownership_transfer_needs_receiver {
+            let consented = ctx
+                .accounts
+                .receiver
+                .as_ref()
+                .is_some_and(|receiver| receiver.key() == payment_agreement.receiver);
+            require!(consented, ErrorCode::ReceiverConsentRequired);
+        }
+
+        let mut transferred = (**payment_agreement).clone();
+        transferred.payer = new_payer;
+        transferred.original_payer = new_payer;
+        // A designated approver can't be the payer themselves
+        if transferred.payer_approver == Some(new_payer) {
+            transferred.payer_approver = None;
+        }
+        transferred.bump = ctx.bumps.new_payment_agreement;
+        transferred.vault_bump = ctx.bumps.new_vault;
+        transferred.last_activity_ts = Clock::get()?.unix_timestamp;
+
+        let escrowed = payment_agreement
+            .amount
+            .checked_add(payment_agreement.reserved_referee_fee)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        (transferred, escrowed)
+    };
+
+    ctx.accounts.new_payment_agreement.set_inner(transferred);
+
+    debit_escrow(&ctx.accounts.vault.to_account_info(), carried)?;
+    ctx.accounts.new_vault.add_lamports(carried)?;
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )?;
+
+    emit!(OwnershipTransferred {
+        old_payment_agreement: ctx.accounts.payment_agreement.key(),
+        new_payment_agreement: ctx.accounts.new_payment_agreement.key(),
+        old_payer: ctx.accounts.payer.key(),
+        new_payer,
+    });
+
+    Ok(())
+}
+
+// Hands the payer role to `new_payer` in place. The address stays derived from
+// original_payer, only the stored authority changes, so the escrow and the
+// agreement's address are untouched. transfer_ownership re-seeds it instead.
+pub fn transfer_payer_role(
+    ctx: Context<TransferPayerRole>,
+    _name: String,
+    new_payer: Pubkey,
+) -> Result<()> {
+    let payment_agreement = &mut ctx.accounts.payment_agreement;
+
+    require_pending(payment_agreement)?;
+
+    require!(
+        new_payer != payment_agreement.payer
+            && new_payer != payment_agreement.receiver
+            && payment_agreement.referee != Some(new_payer)
+            && !payment_agreement.referees.contains(&new_payer),
+        ErrorCode::InvalidNewPayer
+    );
+
+    if payment_agreement.ownership_transfer_needs_receiver {
+        let consented = ctx
+            .accounts
+            .receiver
+            .as_ref()
+            .is_some_and(|receiver| receiver.key() == payment_agreement.receiver);
+        require!(consented, ErrorCode::ReceiverConsentRequired);
+    }
+
+    let old_payer = payment_agreement.payer;
+    payment_agreement.payer = new_payer;
+    // A designated approver can't be the payer themselves
+    if payment_agreement.payer_approver == Some(new_payer) {
+        payment_agreement.payer_approver = None;
+    }
+    record_activity(payment_agreement)?;
+
+    emit!(PayerRoleTransferred {
+        payment_agreement: payment_agreement.key(),
+        old_payer,
+        new_payer,
+        timestamp: payment_agreement.last_activity_ts,
+    });
+
+    Ok(())
+}
+
+pub fn close_terminal_agreements_batch(ctx: Context<CloseTerminalAgreementsBatch>) -> Result<u32> {
+    require!(
+        ctx.remaining_accounts.len() <= MAX_BATCH_CLOSE * 2,
+        ErrorCode::BatchTooLarge
+    );
+    let pairs = ctx.remaining_accounts.chunks_exact(2);
+    require!(
+        pairs.remainder().is_empty(),
+        ErrorCode::InvalidAgreementAccount
+    );
+
+    let payer = &ctx.accounts.payer;
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(ctx.remaining_accounts.len() / 2);
+    let mut closed: u32 = 0;
+
+    for accounts in pairs {
+        let (account_info, vault) = (&accounts[0], &accounts[1]);
+        if seen.contains(account_info.key) {
+            continue;
+        }
+        seen.push(account_info.key());
+
+        require!(
+            account_info.owner == &crate::ID,
+            ErrorCode::InvalidAgreementAccount
+        );
+
+        let payment_agreement = {
+            let data = account_info.try_borrow_data()?;
+            PaymentAgreement::try_deserialize(&mut &data[..])?
+        };
+
+        require!(
+            payment_agreement.payer == payer.key(),
+            ErrorCode::Unauthorized
+        );
+
+        // Pending agreements and unfinished expiry refunds still hold escrow,
+        // leave them alone
+        if !is_closable(&payment_agreement) {
+            continue;
+        }
+
+        // Drain the account so the runtime reclaims it, and wipe the data so
+        // it cannot be reused later in this transaction
+        let lamports = account_info.lamports();
+        account_info.sub_lamports(lamports)?;
+        payer.add_lamports(lamports)?;
+        account_info.try_borrow_mut_data()?.fill(0);
+
+        let (vault_address, _) =
+            Pubkey::find_program_address(&[VAULT_SEED, account_info.key.as_ref()], &crate::ID);
+        require_keys_eq!(vault.key(), vault_address, ErrorCode::InvalidVault);
+        close_vault(vault, payer)?;
+
+        closed += 1;
+    }
+
+    Ok(closed)
+}
+
+// Reclaims the rent of a single settled agreement and its vault, the close
+// constraint sends everything left in the account to the payer
+pub fn close_settled_agreement(ctx: Context<CloseSettledAgreement>, _name: String) -> Result<()> {
+    let payment_agreement = &ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::Unauthorized
+    );
+
+    require!(
+        is_closable(payment_agreement),
+        ErrorCode::AgreementNotSettled
+    );
+
+    close_vault(
+        &ctx.accounts.vault.to_account_info(),
+        &ctx.accounts.payer.to_account_info(),
+    )
+}
+
+// Settled, and not part way through a gradual expiry refund that still owes
+// chunks, possibly to a fallback receiver
+fn is_closable(payment_agreement: &PaymentAgreement) -> bool {
+    payment_agreement.status != AgreementStatus::Pending
+        && (payment_agreement.refund_started_at.is_none() || payment_agreement.amount == 0)
+}
+
+// Break-glass refund: returns the full balance to the payer regardless of status
+pub fn admin_emergency_refund(ctx: Context<AdminEmergencyRefund>, _name: String) -> Result<()> {
+    let payment_agreement = &ctx.accounts.payment_agreement;
+
+    require!(
+        ctx.accounts.payer.key() == payment_agreement.payer,
+        ErrorCode::InvalidPayer
+    );
+    // Closing the agreement would strand the tokens its PDA owns
+    require_native(payment_agreement)?;
+
+    // A pending agreement still holds an active slot
+    if payment_agreement.status == AgreementStatus::Pending {
+        release_active_slot(&mut ctx.accounts.config, &mut ctx.accounts.stats)?;
+        record_cancelled(&mut ctx.accounts.stats, payment_agreement.amount)?;
+        emit_cancelled(payment_agreement, payment_agreement.amount)?;
+    }
+
+    emit!(EmergencyRefund {
+        payment_agreement: payment_agreement.key(),
+        payer: payment_agreement.payer,
+        admin: ctx.accounts.admin.key(),
+        refunded_lamports: payment_agreement
+            .to_account_info()
+            .lamports()
+            .checked_add(ctx.accounts.vault.lamports())
+            .ok_or(ErrorCode::ArithmeticOverflow)?,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    // The escrow goes back to whoever funded it. The close constraint and
+    // close_vault then return the rent of both accounts to the payer.
+    let vault = ctx.accounts.vault.to_account_info();
+    let escrowed = vault
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(vault.data_len()));
+    debit_escrow(&vault, escrowed)?;
+    credit_refund(
+        payment_agreement,
+        &ctx.accounts.payer,
+        ctx.remaining_accounts,
+        escrowed,
+    )?;
+    close_vault(&vault, &ctx.accounts.payer)
+}
+
+pub fn create_crowdfund_agreement(
+    ctx: Context<CreateCrowdfundAgreement>,
+    name: String,
+    receiver: Pubkey,
+    target_amount: u64,
+    expiration_timestamp: i64,
+) -> Result<()> {
+    require!(!name.is_empty() && name.len() <= 32, ErrorCode::InvalidName);
+    require!(target_amount > 0, ErrorCode::InvalidAmount);
+
+    let current_timestamp = Clock::get()?.unix_timestamp;
+    require!(
+        expiration_timestamp > current_timestamp,
+        ErrorCode::ExpirationMustBeInFuture
+    );
+
+    let crowdfund_agreement = &mut ctx.accounts.crowdfund_agreement;
+
+    crowdfund_agreement.name = name;
+    crowdfund_agreement.creator = ctx.accounts.creator.key();
+    crowdfund_agreement.receiver = receiver;
+    crowdfund_agreement.target_amount = target_amount;
+    crowdfund_agreement.total_contributed = 0;
+    crowdfund_agreement.expiration_timestamp = expiration_timestamp;
+    crowdfund_agreement.contributions = Vec::new();
+    crowdfund_agreement.is_completed = false;
+
+    Ok(())
+}
+
+pub fn contribute(ctx: Context<Contribute>, _name: String, amount: u64) -> Result<()> {
+    require!(amount > 0, ErrorCode::InvalidAmount);
+
+    {
+        let crowdfund_agreement = &ctx.accounts.crowdfund_agreement;
+
+        require!(
+            ctx.accounts.receiver.key() == crowdfund_agreement.receiver,
+            ErrorCode::InvalidReceiver
+        );
+        require!(
+            !crowdfund_agreement.is_completed,
+            ErrorCode::AgreementAlreadyCompleted
+        );
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp <= crowdfund_agreement.expiration_timestamp,
+            ErrorCode::FundingPeriodEnded
+        );
+    }
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.contributor.to_account_info(),
+                to: ctx.accounts.crowdfund_agreement.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    // Record the contribution and check whether the target has been reached
+    let (should_complete, transfer_amount) = {
+        let crowdfund_agreement = &mut ctx.accounts.crowdfund_agreement;
+        let contributor = ctx.accounts.contributor.key();
+
+        match crowdfund_agreement
+            .contributions
+            .iter_mut()
+            .find(|contribution| contribution.contributor == contributor)
+        {
+            Some(contribution) => {
+                contribution.amount = contribution
+                    .amount
+                    .checked_add(amount)
+                    .ok_or(ErrorCode::AmountOverflow)?;
+            }
+            None => {
+                require!(
+                    crowdfund_agreement.contributions.len() < MAX_CONTRIBUTORS,
+                    ErrorCode::ContributorLimitReached
+                );
+                crowdfund_agreement.contributions.push(Contribution {
+                    contributor,
+                    amount,
+                });
+            }
+        }
+
+        crowdfund_agreement.total_contributed = crowdfund_agreement
+            .total_contributed
+            .checked_add(amount)
+            .ok_or(ErrorCode::AmountOverflow)?;
+
+        let should_complete =
+            crowdfund_agreement.total_contributed >= crowdfund_agreement.target_amount;
+
+        if should_complete {
+            crowdfund_agreement.is_completed = true;
+        }
+
+        (should_complete, crowdfund_agreement.total_contributed)
+    };
+
+    // Pay out everything collected once the target is met
+    if should_complete {
+        debit_escrow(
+            &ctx.accounts.crowdfund_agreement.to_account_info(),
+            transfer_amount,
+        )?;
+        ctx.accounts.receiver.add_lamports(transfer_amount)?;
+    }
+
+    Ok(())
+}
+
+pub fn reclaim_contribution(ctx: Context<ReclaimContribution>, _name: String) -> Result<()> {
     let transfer_amount = {
-        let payment_agreement = &mut ctx.accounts.payment_agreement;
+        let crowdfund_agreement = &mut ctx.accounts.crowdfund_agreement;
+
+        require!(
+            !crowdfund_agreement.is_completed,
+            ErrorCode::AgreementAlreadyCompleted
+        );
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        require!(
+            current_timestamp > crowdfund_agreement.expiration_timestamp,
+            ErrorCode::PaymentAgreementNotExpired
+        );
+
+        let contributor = ctx.accounts.contributor.key();
+        let contribution = crowdfund_agreement
+            .contributions
+            .iter_mut()
+            .find(|contribution| contribution.contributor == contributor)
+            .ok_or(ErrorCode::NothingToReclaim)?;
+
+        let amount = contribution.amount;
+        require!(amount > 0, ErrorCode::NothingToReclaim);
+        contribution.amount = 0;
+
+        crowdfund_agreement.total_contributed = crowdfund_agreement
+            .total_contributed
+            .checked_sub(amount)
+            .ok_or(ErrorCode::AmountOverflow)?;
+
+        amount
+    };
+
+    // Return this contributor's share only
+    debit_escrow(
+        &ctx.accounts.crowdfund_agreement.to_account_info(),
+        transfer_amount,
+    )?;
+    ctx.accounts.contributor.add_lamports(transfer_amount)?;
+
+    Ok(())
+}
+
+pub fn create_split_payment_agreement(
+    ctx: Context<CreateSplitPaymentAgreement>,
+    name: String,
+    amount: u64,
+    shares: Vec<SplitShare>,
+) -> Result<()> {
+    require!(!name.is_empty() && name.len() <= 32, ErrorCode::InvalidName);
+    require!(amount > 0, ErrorCode::InvalidAmount);
 
-        // Check if referee exists and signer is the referee
-        require!(payment_agreement.referee.is_some(), ErrorCode::Unauthorized);
+    let payer = ctx.accounts.payer.key();
+    validate_split_shares(&shares, payer, amount)?;
+
+    let split_payment_agreement = &mut ctx.accounts.split_payment_agreement;
+    split_payment_agreement.name = name;
+    split_payment_agreement.payer = payer;
+    split_payment_agreement.amount = amount;
+    split_payment_agreement.shares = shares;
+    split_payment_agreement.paid_bitmap = 0;
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: ctx.accounts.split_payment_agreement.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    Ok(())
+}
+
+fn validate_split_shares(shares: &[SplitShare], payer: Pubkey, amount: u64) -> Result<()> {
+    require!(
+        !shares.is_empty() && shares.len() <= MAX_SPLIT_RECEIVERS,
+        ErrorCode::InvalidSplitReceivers
+    );
+
+    let mut total: u64 = 0;
+    for (index, share) in shares.iter().enumerate() {
         require!(
-            payment_agreement.referee.unwrap() == ctx.accounts.signer.key(),
-            ErrorCode::Unauthorized
+            share.receiver != payer
+                && !shares[..index]
+                    .iter()
+                    .any(|earlier| earlier.receiver == share.receiver),
+            ErrorCode::InvalidSplitReceivers
         );
+        require!(share.amount > 0, ErrorCode::SplitSumMismatch);
+        total = total
+            .checked_add(share.amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    require!(total == amount, ErrorCode::SplitSumMismatch);
 
-        // Validate that passed accounts match stored accounts
+    Ok(())
+}
+
+pub fn complete_split_payment_agreement(
+    ctx: Context<CompleteSplitPaymentAgreement>,
+    _name: String,
+) -> Result<()> {
+    let shares = ctx.accounts.split_payment_agreement.shares.clone();
+    require!(
+        ctx.remaining_accounts.len() == shares.len(),
+        ErrorCode::InvalidReceiver
+    );
+
+    let paid_bitmap = ctx.accounts.split_payment_agreement.paid_bitmap;
+    for (index, (share, receiver)) in shares.iter().zip(ctx.remaining_accounts.iter()).enumerate() {
+        require!(receiver.key() == share.receiver, ErrorCode::InvalidReceiver);
+        // Shares already paid by settle_split_range are skipped
+        if paid_bitmap & (1 << index) != 0 {
+            continue;
+        }
+        debit_escrow(
+            &ctx.accounts.split_payment_agreement.to_account_info(),
+            share.amount,
+        )?;
+        receiver.add_lamports(share.amount)?;
+    }
+
+    // The close constraint returns the rent to the payer
+    Ok(())
+}
+
+pub fn settle_split_range(
+    ctx: Context<SettleSplitRange>,
+    _name: String,
+    start: u32,
+    end: u32,
+) -> Result<()> {
+    let (start, end) = (start as usize, end as usize);
+    let split_payment_agreement = &mut ctx.accounts.split_payment_agreement;
+    require!(
+        start < end && end <= split_payment_agreement.shares.len(),
+        ErrorCode::InvalidSplitRange
+    );
+    require!(
+        ctx.remaining_accounts.len() == end - start,
+        ErrorCode::InvalidReceiver
+    );
+
+    for (index, receiver) in (start..end).zip(ctx.remaining_accounts.iter()) {
+        let share = split_payment_agreement.shares[index];
+        require!(receiver.key() == share.receiver, ErrorCode::InvalidReceiver);
         require!(
-            ctx.accounts.payer.key() == payment_agreement.payer,
-            ErrorCode::InvalidPayer
+            split_payment_agreement.paid_bitmap & (1 << index) == 0,
+            ErrorCode::SplitShareAlreadyPaid
         );
+        split_payment_agreement.paid_bitmap |= 1 << index;
+        debit_escrow(&split_payment_agreement.to_account_info(), share.amount)?;
+        receiver.add_lamports(share.amount)?;
+    }
+
+    // The account stays open; complete_split_payment_agreement closes it
+    // once the remaining shares, if any, are paid
+    Ok(())
+}
+
+pub fn create_recurring_agreement(
+    ctx: Context<CreateRecurringAgreement>,
+    name: String,
+    receiver: Pubkey,
+    per_period_amount: u64,
+    interval_seconds: u64,
+    num_periods: u32,
+) -> Result<()> {
+    require!(!name.is_empty() && name.len() <= 32, ErrorCode::InvalidName);
+    require!(
+        per_period_amount > 0 && interval_seconds > 0 && num_periods > 0,
+        ErrorCode::InvalidRecurringTerms
+    );
+
+    let payer = ctx.accounts.payer.key();
+    require!(receiver != payer, ErrorCode::InvalidReceiver);
+
+    let total = per_period_amount
+        .checked_mul(u64::from(num_periods))
+        .ok_or(ErrorCode::AmountOverflow)?;
+    require!(total >= MIN_ESCROW_AMOUNT, ErrorCode::AmountBelowMinimum);
+
+    let recurring_agreement = &mut ctx.accounts.recurring_agreement;
+    recurring_agreement.name = name;
+    recurring_agreement.payer = payer;
+    recurring_agreement.receiver = receiver;
+    recurring_agreement.per_period_amount = per_period_amount;
+    recurring_agreement.interval_seconds = interval_seconds;
+    recurring_agreement.num_periods = num_periods;
+    recurring_agreement.periods_claimed = 0;
+    recurring_agreement.last_claim_ts = Clock::get()?.unix_timestamp;
+
+    system_program::transfer(
+        CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: ctx.accounts.recurring_agreement.to_account_info(),
+            },
+        ),
+        total,
+    )?;
+
+    Ok(())
+}
+
+fn period_elapsed(current_timestamp: i64, last_claim_ts: i64, interval_seconds: u64) -> bool {
+    u64::try_from(current_timestamp.saturating_sub(last_claim_ts))
+        .is_ok_and(|elapsed| elapsed >= interval_seconds)
+}
+
+pub fn claim_period(ctx: Context<ClaimPeriod>, _name: String) -> Result<()> {
+    let (transfer_amount, finished) = {
+        let recurring_agreement = &mut ctx.accounts.recurring_agreement;
+
         require!(
-            ctx.accounts.receiver.key() == payment_agreement.receiver,
-            ErrorCode::InvalidReceiver
+            ctx.accounts.receiver.key() == recurring_agreement.receiver,
+            ErrorCode::Unauthorized
         );
-
         require!(
-            !payment_agreement.is_completed,
+            recurring_agreement.periods_claimed < recurring_agreement.num_periods,
             ErrorCode::AgreementAlreadyCompleted
         );
+
+        let current_timestamp = Clock::get()?.unix_timestamp;
         require!(
-            !payment_agreement.is_cancelled,
-            ErrorCode::AgreementAlreadyCancelled
+            period_elapsed(
+                current_timestamp,
+                recurring_agreement.last_claim_ts,
+                recurring_agreement.interval_seconds
+            ),
+            ErrorCode::PeriodNotElapsed
         );
 
-        payment_agreement.is_completed = true;
-        payment_agreement.is_referee_intervened = true;
+        recurring_agreement.periods_claimed = recurring_agreement
+            .periods_claimed
+            .checked_add(1)
+            .ok_or(ErrorCode::CounterOverflow)?;
+        recurring_agreement.last_claim_ts = current_timestamp;
 
-        payment_agreement.amount
+        (
+            recurring_agreement.per_period_amount,
+            recurring_agreement.periods_claimed == recurring_agreement.num_periods,
+        )
     };
 
-    // Transfer funds from escrow to receiver
-    ctx.accounts
-        .payment_agreement
-        .sub_lamports(transfer_amount)?;
+    debit_escrow(
+        &ctx.accounts.recurring_agreement.to_account_info(),
+        transfer_amount,
+    )?;
     ctx.accounts.receiver.add_lamports(transfer_amount)?;
 
+    // Only the rent, and anything sent on top of the escrow, is left for the payer
+    if finished {
+        ctx.accounts
+            .recurring_agreement
+            .close(ctx.accounts.payer.to_account_info())?;
+    }
+
     Ok(())
 }
 
-pub fn referee_intervene_cancel_payment_agreement(
-    ctx: Context<CancelPaymentAgreement>,
+// The payer stops the schedule and takes back the unclaimed periods. A period
+// that has already elapsed is owed, so it goes to the receiver first.
+pub fn cancel_recurring_agreement(
+    ctx: Context<CancelRecurringAgreement>,
     _name: String,
 ) -> Result<()> {
-    // Handle referee intervention and get necessary data
-    let transfer_amount = {
-        let payment_agreement = &mut ctx.accounts.payment_agreement;
+    let owed = {
+        let recurring_agreement = &ctx.accounts.recurring_agreement;
 
-        // Check if referee exists and signer is the referee
-        require!(payment_agreement.referee.is_some(), ErrorCode::Unauthorized);
         require!(
-            payment_agreement.referee.unwrap() == ctx.accounts.signer.key(),
-            ErrorCode::Unauthorized
+            ctx.accounts.receiver.key() == recurring_agreement.receiver,
+            ErrorCode::InvalidReceiver
         );
 
-        // Validate that passed payer account matches stored payer
-        require!(
-            ctx.accounts.payer.key() == payment_agreement.payer,
-            ErrorCode::InvalidPayer
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        if period_elapsed(
+            current_timestamp,
+            recurring_agreement.last_claim_ts,
+            recurring_agreement.interval_seconds,
+        ) {
+            recurring_agreement.per_period_amount
+        } else {
+            0
+        }
+    };
+
+    debit_escrow(&ctx.accounts.recurring_agreement.to_account_info(), owed)?;
+    ctx.accounts.receiver.add_lamports(owed)?;
+
+    // The close constraint refunds the remaining periods and the rent to the payer
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{scoped_name_key, NONCE_SEED_MARKER};
+
+    fn serialized(agreement: &PaymentAgreement) -> Vec<u8> {
+        let mut data = Vec::new();
+        agreement.try_serialize(&mut data).unwrap();
+        data
+    }
+
+    // An account written by an older version: the current serialization
+    // without the fields added since, in an allocation of the older size
+    // whose unused tail holds stale bytes
+    fn older_layout(agreement: &PaymentAgreement, missing_bytes: usize) -> Vec<u8> {
+        let mut data = serialized(agreement);
+        data.truncate(data.len() - missing_bytes);
+        data.resize(8 + PaymentAgreement::INIT_SPACE - missing_bytes, 0xff);
+        data
+    }
+
+    fn sample_agreement(version: u8) -> PaymentAgreement {
+        PaymentAgreement {
+            name: "invoice".to_string(),
+            amount: 1_000,
+            memo: Some("March".to_string()),
+            created_at: 1_700_000_000,
+            version,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v1_account() {
+        let v1 = older_layout(
+            &sample_agreement(0),
+            1 + V3_FIELDS_SPACE
+                + V4_FIELDS_SPACE
+                + V5_FIELDS_SPACE
+                + V6_FIELDS_SPACE
+                + V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
         );
 
-        require!(
-            !payment_agreement.is_completed,
-            ErrorCode::AgreementAlreadyCompleted
+        let upgraded = upgrade_agreement_data(&v1).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.name, "invoice");
+        assert_eq!(upgraded.amount, 1_000);
+        assert_eq!(upgraded.memo.as_deref(), Some("March"));
+        assert_eq!(upgraded.fee_basis_points, 0);
+        assert_eq!(upgraded.fee_collector, Pubkey::default());
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v2_account() {
+        let v2 = older_layout(
+            &sample_agreement(2),
+            V3_FIELDS_SPACE
+                + V4_FIELDS_SPACE
+                + V5_FIELDS_SPACE
+                + V6_FIELDS_SPACE
+                + V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
         );
-        require!(
-            !payment_agreement.is_cancelled,
-            ErrorCode::AgreementAlreadyCancelled
+
+        let upgraded = upgrade_agreement_data(&v2).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.amount, 1_000);
+        assert_eq!(upgraded.fee_basis_points, 0);
+        assert_eq!(upgraded.fee_collector, Pubkey::default());
+        assert!(!upgraded.is_paused);
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v3_account() {
+        let v3 = PaymentAgreement {
+            fee_basis_points: 250,
+            ..sample_agreement(3)
+        };
+        // The stale 0xff tail lands on the new bools, which borsh rejects as is
+        let v3 = older_layout(
+            &v3,
+            V4_FIELDS_SPACE
+                + V5_FIELDS_SPACE
+                + V6_FIELDS_SPACE
+                + V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
         );
 
-        payment_agreement.is_cancelled = true;
-        payment_agreement.is_referee_intervened = true;
+        let upgraded = upgrade_agreement_data(&v3).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.fee_basis_points, 250);
+        assert!(!upgraded.is_paused);
+        assert!(!upgraded.pause_blocks_withdrawal);
+        assert!(!upgraded.require_ack);
+    }
 
-        payment_agreement.amount
-    };
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v4_account() {
+        let v4 = PaymentAgreement {
+            is_paused: true,
+            ..sample_agreement(4)
+        };
+        let v4 = older_layout(
+            &v4,
+            V5_FIELDS_SPACE
+                + V6_FIELDS_SPACE
+                + V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
+        );
 
-    // Return funds to payer when cancelled
-    ctx.accounts
-        .payment_agreement
-        .sub_lamports(transfer_amount)?;
-    ctx.accounts.payer.add_lamports(transfer_amount)?;
+        let upgraded = upgrade_agreement_data(&v4).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert!(upgraded.is_paused);
+        assert!(!upgraded.require_ack);
+        assert!(!upgraded.receiver_acknowledged);
+    }
 
-    Ok(())
-}
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v5_account() {
+        let v5 = PaymentAgreement {
+            receiver_acknowledged: true,
+            ..sample_agreement(5)
+        };
+        let v5 = older_layout(
+            &v5,
+            V6_FIELDS_SPACE
+                + V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
+        );
 
-pub fn withdraw_expired_funds(ctx: Context<WithdrawExpiredFunds>, _name: String) -> Result<()> {
-    let payment_agreement = &ctx.accounts.payment_agreement;
+        let upgraded = upgrade_agreement_data(&v5).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert!(upgraded.receiver_acknowledged);
+        // Inactivity counts from creation rather than the stale padding
+        assert_eq!(upgraded.last_activity_ts, 1_700_000_000);
+    }
 
-    require!(
-        ctx.accounts.payer.key() == payment_agreement.payer,
-        ErrorCode::Unauthorized
-    );
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v6_account() {
+        let v6 = PaymentAgreement {
+            last_activity_ts: 1_700_000_500,
+            ..sample_agreement(6)
+        };
+        let v6 = older_layout(
+            &v6,
+            V7_FIELDS_SPACE
+                + V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
+        );
 
-    require!(
-        payment_agreement.expiration_timestamp.is_some(),
-        ErrorCode::PaymentAgreementNotExpired
-    );
+        let upgraded = upgrade_agreement_data(&v6).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.last_activity_ts, 1_700_000_500);
+        assert_eq!(upgraded.document_hash, [0; 32]);
+    }
 
-    let current_timestamp = Clock::get()?.unix_timestamp;
-    let expiration = payment_agreement.expiration_timestamp.unwrap();
-    require!(
-        current_timestamp > expiration,
-        ErrorCode::PaymentAgreementNotExpired
-    );
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v7_account() {
+        let v7 = PaymentAgreement {
+            document_hash: [7; 32],
+            ..sample_agreement(7)
+        };
+        // The stale 0xff padding is not a valid ExpiryBeneficiary
+        let v7 = older_layout(
+            &v7,
+            V8_FIELDS_SPACE
+                + V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
+        );
 
-    require!(
-        !payment_agreement.is_completed,
-        ErrorCode::AgreementAlreadyCompleted
-    );
-    require!(
-        !payment_agreement.is_cancelled,
-        ErrorCode::AgreementAlreadyCancelled
-    );
+        let upgraded = upgrade_agreement_data(&v7).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.document_hash, [7; 32]);
+        assert_eq!(upgraded.expiry_favors, ExpiryBeneficiary::Payer);
+    }
 
-    let transfer_amount = payment_agreement.amount;
-    ctx.accounts
-        .payment_agreement
-        .sub_lamports(transfer_amount)?;
-    ctx.accounts.payer.add_lamports(transfer_amount)?;
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v8_account() {
+        let v8 = PaymentAgreement {
+            expiry_favors: ExpiryBeneficiary::Receiver,
+            ..sample_agreement(8)
+        };
+        let v8 = older_layout(
+            &v8,
+            V9_FIELDS_SPACE
+                + V10_FIELDS_SPACE
+                + V11_FIELDS_SPACE
+                + V12_FIELDS_SPACE
+                + V13_FIELDS_SPACE,
+        );
 
-    Ok(())
+        let upgraded = upgrade_agreement_data(&v8).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.expiry_favors, ExpiryBeneficiary::Receiver);
+        assert_eq!(upgraded.completion_mode, CompletionMode::MutualApproval);
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v9_account() {
+        let v9 = PaymentAgreement {
+            completion_mode: CompletionMode::PayerRelease,
+            ..sample_agreement(9)
+        };
+        let v9 = older_layout(
+            &v9,
+            V10_FIELDS_SPACE + V11_FIELDS_SPACE + V12_FIELDS_SPACE + V13_FIELDS_SPACE,
+        );
+
+        let upgraded = upgrade_agreement_data(&v9).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.completion_mode, CompletionMode::PayerRelease);
+        // The stale padding would otherwise move the agreement off its address
+        assert_eq!(upgraded.nonce, 0);
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v10_account() {
+        let v10 = PaymentAgreement {
+            nonce: 7,
+            ..sample_agreement(10)
+        };
+        let v10 = older_layout(&v10, V11_FIELDS_SPACE + V12_FIELDS_SPACE + V13_FIELDS_SPACE);
+
+        let upgraded = upgrade_agreement_data(&v10).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.nonce, 7);
+        // Left for migrate_agreement to set once it has created the vault
+        assert_eq!(upgraded.vault_bump, 0);
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v11_account() {
+        let v11 = PaymentAgreement {
+            vault_bump: 254,
+            ..sample_agreement(11)
+        };
+        let v11 = older_layout(&v11, V12_FIELDS_SPACE + V13_FIELDS_SPACE);
+
+        let upgraded = upgrade_agreement_data(&v11).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.vault_bump, 254);
+        assert_eq!(upgraded.refund_to, Pubkey::default());
+    }
+
+    #[test]
+    fn upgrade_agreement_data_migrates_a_v12_account() {
+        let payer = Pubkey::new_unique();
+        let v12 = PaymentAgreement {
+            payer,
+            ..sample_agreement(12)
+        };
+        let v12 = older_layout(&v12, V13_FIELDS_SPACE);
+
+        let upgraded = upgrade_agreement_data(&v12).unwrap().unwrap();
+        assert_eq!(upgraded.version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(upgraded.original_payer, payer);
+    }
+
+    #[test]
+    fn upgrade_agreement_data_leaves_current_accounts_alone() {
+        let agreement = PaymentAgreement {
+            version: CURRENT_SCHEMA_VERSION,
+            ..Default::default()
+        };
+        let mut current = serialized(&agreement);
+        current.resize(8 + PaymentAgreement::INIT_SPACE, 0);
+
+        assert!(upgrade_agreement_data(&current).unwrap().is_none());
+    }
+
+    #[test]
+    fn upgrade_agreement_data_rejects_other_accounts() {
+        let data = vec![0u8; 8 + PaymentAgreement::INIT_SPACE - 1];
+        assert!(upgrade_agreement_data(&data).is_err());
+    }
+
+    #[test]
+    fn integrity_status_is_healthy_when_fully_funded() {
+        let status = integrity_status(1_500, 500, 1_000);
+        assert!(status.rent_exempt);
+        assert_eq!(status.shortfall, 0);
+    }
+
+    #[test]
+    fn integrity_status_reports_the_exact_shortfall() {
+        // Partially debited, still rent-exempt but missing 300 of the amount
+        let status = integrity_status(1_200, 500, 1_000);
+        assert!(status.rent_exempt);
+        assert_eq!(status.shortfall, 300);
+
+        // Drained below the rent minimum
+        let status = integrity_status(200, 500, 1_000);
+        assert!(!status.rent_exempt);
+        assert_eq!(status.shortfall, 1_300);
+    }
+
+    #[test]
+    fn an_underfunded_escrow_is_not_debited() {
+        let key = Pubkey::new_unique();
+        let mut lamports = 1_200;
+        let mut data = [0u8; 8];
+        let escrow = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &crate::ID,
+            false,
+            0,
+        );
+
+        // Paying 1_000 would leave 200 against a 500 rent minimum
+        assert_eq!(
+            debit_above_rent(&escrow, 500, 1_000).err(),
+            Some(ErrorCode::EscrowBalanceMismatch.into())
+        );
+        assert_eq!(escrow.lamports(), 1_200);
+
+        assert!(debit_above_rent(&escrow, 500, 700).is_ok());
+        assert_eq!(escrow.lamports(), 500);
+    }
+
+    #[test]
+    fn funding_source_refunds_go_to_the_owner() {
+        let (payer_key, owner_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut payer_lamports, mut owner_lamports) = (0, 0);
+        let (mut payer_data, mut owner_data) = ([0u8; 0], [0u8; 0]);
+        let system = system_program::ID;
+        let payer = AccountInfo::new(
+            &payer_key,
+            false,
+            true,
+            &mut payer_lamports,
+            &mut payer_data,
+            &system,
+            false,
+            0,
+        );
+        let owner = AccountInfo::new(
+            &owner_key,
+            false,
+            true,
+            &mut owner_lamports,
+            &mut owner_data,
+            &system,
+            false,
+            0,
+        );
+        let mut agreement = sample_agreement(CURRENT_SCHEMA_VERSION);
+
+        // Without a recorded owner the payer is refunded
+        assert_eq!(credit_refund(&agreement, &payer, &[], 100), Ok(payer_key));
+
+        // With one, the owner must be passed and is refunded instead
+        agreement.refund_to = owner_key;
+        assert_eq!(
+            credit_refund(&agreement, &payer, &[], 100).err(),
+            Some(ErrorCode::MissingRefundAccount.into())
+        );
+        assert_eq!(
+            credit_refund(&agreement, &payer, std::slice::from_ref(&owner), 250),
+            Ok(owner_key)
+        );
+        assert_eq!(payer.lamports(), 100);
+        assert_eq!(owner.lamports(), 250);
+    }
+    #[test]
+    fn a_loaded_agreement_is_written_back_on_exit() {
+        let payer = Pubkey::new_unique();
+        let (key, bump) = Pubkey::find_program_address(
+            &[
+                PAYMENT_AGREEMENT_SEED,
+                payer.as_ref(),
+                b"invoice",
+                &nonce_seed(0),
+            ],
+            &crate::ID,
+        );
+        let mut agreement = sample_agreement(CURRENT_SCHEMA_VERSION);
+        agreement.bump = bump;
+        agreement.original_payer = payer;
+        // Since handed to another payer, the address still derives from the first
+        agreement.payer = Pubkey::new_unique();
+        let mut data = serialized(&agreement);
+        data.resize(8 + PaymentAgreement::INIT_SPACE, 0);
+        let mut lamports = 1_000;
+        let info = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &crate::ID,
+            false,
+            0,
+        );
+
+        // A name that doesn't derive the account is refused
+        assert!(load_agreement(&info, "other").is_err());
+
+        let mut loaded = load_agreement(&info, "invoice").unwrap();
+        loaded.amount = 400;
+        loaded.exit().unwrap();
+
+        let written = read_agreement(&info, &payer, "invoice").unwrap();
+        assert_eq!(written.amount, 400);
+        assert_eq!(written.memo, agreement.memo);
+    }
+
+    #[test]
+    fn an_agreement_owned_elsewhere_is_not_loaded() {
+        let key = Pubkey::new_unique();
+        let system_program = System::id();
+        let mut data = serialized(&sample_agreement(CURRENT_SCHEMA_VERSION));
+        let mut lamports = 1_000;
+        let foreign = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &system_program,
+            false,
+            0,
+        );
+        assert_eq!(
+            load_agreement(&foreign, "invoice").err(),
+            Some(ErrorCode::InvalidAgreementAccount.into())
+        );
+
+        // A closed agreement still reads as not initialized
+        foreign.sub_lamports(1_000).unwrap();
+        assert_eq!(
+            load_agreement(&foreign, "invoice").err(),
+            Some(anchor_lang::error::ErrorCode::AccountNotInitialized.into())
+        );
+    }
+
+    #[test]
+    fn expiry_is_inclusive_at_the_boundary_second() {
+        assert!(!is_expired(1_000, 999));
+        // Withdrawable at, and rejected for creation at, the exact second
+        assert!(is_expired(1_000, 1_000));
+        assert!(is_expired(1_000, 1_001));
+    }
+
+    #[test]
+    fn every_terminal_status_is_either_completed_or_cancelled() {
+        use AgreementStatus::*;
+        assert!(!Pending.is_completed() && !Pending.is_cancelled());
+        for status in [Completed, RefereeCompleted] {
+            assert!(status.is_completed() && !status.is_cancelled());
+        }
+        for status in [Cancelled, RefereeCancelled, Expired] {
+            assert!(status.is_cancelled() && !status.is_completed());
+        }
+    }
+
+    #[test]
+    fn a_settled_agreement_cannot_take_a_referee_ruling() {
+        use AgreementStatus::*;
+        let mut payment_agreement = PaymentAgreement::default();
+        assert!(require_no_ruling(&payment_agreement).is_ok());
+        for status in [Completed, RefereeCompleted, Cancelled, RefereeCancelled] {
+            payment_agreement.status = status;
+            assert!(require_no_ruling(&payment_agreement).is_err());
+        }
+    }
+
+    #[test]
+    fn a_completion_payout_requires_a_completed_agreement() {
+        use AgreementStatus::*;
+        let mut payment_agreement = PaymentAgreement {
+            payer_approved: true,
+            ..Default::default()
+        };
+        assert!(require_completed(&payment_agreement).is_err());
+        for status in [Cancelled, RefereeCancelled] {
+            payment_agreement.status = status;
+            assert!(require_completed(&payment_agreement).is_err());
+        }
+        payment_agreement.status = Completed;
+        assert!(require_completed(&payment_agreement).is_ok());
+    }
+
+    #[test]
+    fn performance_fee_is_a_share_of_growth_capped_by_the_escrow() {
+        // 10% of 5_000 growth
+        assert_eq!(performance_fee_amount(15_000, 10_000, 1_000, 1_000), 500);
+        // 10% of 50_000 growth exceeds the 1_000 escrowed
+        assert_eq!(performance_fee_amount(60_000, 10_000, 1_000, 1_000), 1_000);
+        // No growth, or a loss, owes nothing
+        assert_eq!(performance_fee_amount(10_000, 10_000, 1_000, 1_000), 0);
+        assert_eq!(performance_fee_amount(4_000, 10_000, 1_000, 1_000), 0);
+        // Rounded down, and full growth at 10_000 bps
+        assert_eq!(performance_fee_amount(10_019, 10_000, 500, 1_000), 0);
+        assert_eq!(performance_fee_amount(10_700, 10_000, 10_000, 1_000), 700);
+    }
+
+    #[test]
+    fn fees_near_u64_max_error_instead_of_wrapping() {
+        assert_eq!(protocol_fee_amount(u64::MAX, 10_000).unwrap(), u64::MAX);
+        assert_eq!(protocol_fee_amount(u64::MAX, 5_000).unwrap(), u64::MAX / 2);
+        assert_eq!(
+            protocol_fee_amount(u64::MAX, 10_001).err(),
+            Some(ErrorCode::ArithmeticOverflow.into())
+        );
+        assert_eq!(
+            protocol_fee_amount(u64::MAX, u16::MAX).err(),
+            Some(ErrorCode::ArithmeticOverflow.into())
+        );
+
+        let mut payment_agreement = PaymentAgreement {
+            amount: u64::MAX,
+            referee_fee: Some(RefereeFee::Bps { bps: 20_000 }),
+            ..Default::default()
+        };
+        assert_eq!(
+            referee_fee_amount(&payment_agreement).err(),
+            Some(ErrorCode::ArithmeticOverflow.into())
+        );
+        assert_eq!(
+            payout_breakdown(&payment_agreement, true, u64::MAX, 0).err(),
+            Some(ErrorCode::ArithmeticOverflow.into())
+        );
+
+        // A fee above 100% can never be deducted from the receiver's share
+        payment_agreement.referee_fee = None;
+        payment_agreement.fee_basis_points = 10_001;
+        assert_eq!(
+            payout_breakdown(&payment_agreement, false, u64::MAX, 0).err(),
+            Some(ErrorCode::ArithmeticOverflow.into())
+        );
+
+        payment_agreement.fee_basis_points = 10_000;
+        let breakdown = payout_breakdown(&payment_agreement, false, u64::MAX, 0).unwrap();
+        assert_eq!(breakdown.receiver_share, 0);
+        assert_eq!(breakdown.platform_fee, u64::MAX);
+    }
+
+    #[test]
+    fn a_period_elapses_after_a_full_interval() {
+        assert!(!period_elapsed(1_059, 1_000, 60));
+        assert!(period_elapsed(1_060, 1_000, 60));
+        assert!(period_elapsed(5_000, 1_000, 60));
+        // A clock behind the last claim never counts as elapsed
+        assert!(!period_elapsed(999, 1_000, 0));
+    }
+
+    #[test]
+    fn an_expiration_at_exactly_the_minimum_lifetime_is_allowed() {
+        let created_at = 1_700_000_000;
+        assert!(meets_min_lifetime(created_at + 3_600, created_at, 3_600));
+        assert!(!meets_min_lifetime(created_at + 3_599, created_at, 3_600));
+        assert!(meets_min_lifetime(created_at + 1, created_at, 0));
+        assert!(meets_min_lifetime(i64::MAX, created_at, i64::MAX));
+    }
+
+    #[test]
+    fn agreement_pda_derives_from_the_exported_seed() {
+        let payer = Pubkey::new_from_array([7; 32]);
+        let (address, bump) = Pubkey::find_program_address(
+            &[PAYMENT_AGREEMENT_SEED, payer.as_ref(), b"invoice-1"],
+            &crate::ID,
+        );
+
+        let expected = "C9pxPK9WNwGqNKGM1VWZpNUfF66Ke7M4EcERisG4kasP";
+        assert_eq!(address, expected.parse::<Pubkey>().unwrap());
+        assert_eq!(bump, 253);
+    }
+
+    #[test]
+    fn nonce_zero_keeps_the_address_without_a_nonce() {
+        let payer = Pubkey::new_from_array([7; 32]);
+        let address = |nonce: u64| {
+            Pubkey::find_program_address(
+                &[
+                    PAYMENT_AGREEMENT_SEED,
+                    payer.as_ref(),
+                    b"invoice-1",
+                    &nonce_seed(nonce),
+                ],
+                &crate::ID,
+            )
+            .0
+        };
+
+        let expected = "C9pxPK9WNwGqNKGM1VWZpNUfF66Ke7M4EcERisG4kasP";
+        assert_eq!(address(0), expected.parse::<Pubkey>().unwrap());
+        assert_ne!(address(1), address(0));
+        assert_ne!(address(1), address(2));
+    }
+
+    #[test]
+    fn nonce_seed_cannot_be_spelled_by_a_name() {
+        // Without the marker [payer, "x", le(n)] would derive the same address
+        // as [payer, "x" + le(n)] with nonce 0
+        let nonce = u64::from_le_bytes(*b"-invoice");
+        let seed = nonce_seed(nonce);
+        assert_eq!(seed[0], NONCE_SEED_MARKER);
+        assert_eq!(&seed[1..], b"-invoice");
+
+        let spelled = [b"march".as_slice(), &seed].concat();
+        assert!(std::str::from_utf8(&spelled).is_err());
+    }
+
+    #[test]
+    fn scoped_name_key_separates_nonces() {
+        let payer = Pubkey::new_from_array([7; 32]);
+        assert_ne!(
+            scoped_name_key(&payer, "invoice", 1),
+            scoped_name_key(&payer, "invoice", 2)
+        );
+        assert_ne!(
+            scoped_name_key(&payer, "invoice", 0),
+            scoped_name_key(&Pubkey::new_from_array([8; 32]), "invoice", 0)
+        );
+    }
 }
@@ -1,8 +1,15 @@
 use anchor_lang::prelude::*;
 
 pub mod account;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod instructions;
 
+use account::{
+    AgreementOptions, AgreementParams, AgreementSummary, ConfigUpdate, IntegrityStatus,
+    PayoutBreakdown, PdaInfo, PermissionAction, PermissionCheck, RefereeDecision, SplitShare,
+    WithdrawEligibility,
+};
 use instructions::*;
 
 declare_id!("9phLBf73k3dpX1BhLVWMLGcZEQ1cV3KCFCQV7MkkSwYQ");
@@ -11,23 +18,155 @@ declare_id!("9phLBf73k3dpX1BhLVWMLGcZEQ1cV3KCFCQV7MkkSwYQ");
 pub mod escrow_payment {
     use super::*;
 
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        max_total_active: u64,
+        treasury: Pubkey,
+    ) -> Result<()> {
+        instructions::initialize_config(ctx, max_total_active, treasury)
+    }
+
+    pub fn initialize_stats(ctx: Context<InitializeStats>) -> Result<()> {
+        instructions::initialize_stats(ctx)
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, update: ConfigUpdate) -> Result<()> {
+        instructions::update_config(ctx, update)
+    }
+
+    pub fn sweep_dust(ctx: Context<SweepDust>) -> Result<u64> {
+        instructions::sweep_dust(ctx)
+    }
+
+    pub fn initialize_receipt_mint(ctx: Context<InitializeReceiptMint>) -> Result<()> {
+        instructions::initialize_receipt_mint(ctx)
+    }
+
     pub fn create_payment_agreement(
         ctx: Context<CreatePaymentAgreement>,
         name: String,
         receiver: Pubkey,
         amount: u64,
         expiration_timestamp: Option<i64>,
+        options: Option<AgreementOptions>,
     ) -> Result<()> {
-        instructions::create_payment_agreement(ctx, name, receiver, amount, expiration_timestamp)
+        instructions::create_payment_agreement(
+            ctx,
+            name,
+            receiver,
+            amount,
+            expiration_timestamp,
+            options,
+        )
     }
 
-    pub fn approve_payment_agreement(
-        ctx: Context<ApprovePaymentAgreement>,
+    pub fn create_token_payment_agreement(
+        ctx: Context<CreateTokenPaymentAgreement>,
+        name: String,
+        receiver: Pubkey,
+        amount: u64,
+        expiration_timestamp: Option<i64>,
+    ) -> Result<()> {
+        instructions::create_token_payment_agreement(
+            ctx,
+            name,
+            receiver,
+            amount,
+            expiration_timestamp,
+        )
+    }
+
+    pub fn approve_token_payment_agreement(
+        ctx: Context<ApproveTokenPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::approve_token_payment_agreement(ctx, name)
+    }
+
+    pub fn cancel_token_payment_agreement(
+        ctx: Context<RefundTokenPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::cancel_token_payment_agreement(ctx, name)
+    }
+
+    pub fn withdraw_expired_token_funds(
+        ctx: Context<RefundTokenPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::withdraw_expired_token_funds(ctx, name)
+    }
+
+    pub fn create_payment_agreement_from_source(
+        ctx: Context<CreatePaymentAgreementFromSource>,
+        name: String,
+        receiver: Pubkey,
+        amount: u64,
+        expiration_timestamp: Option<i64>,
+        options: Option<AgreementOptions>,
+    ) -> Result<()> {
+        instructions::create_payment_agreement_from_source(
+            ctx,
+            name,
+            receiver,
+            amount,
+            expiration_timestamp,
+            options,
+        )
+    }
+
+    pub fn open_funding_source(
+        ctx: Context<OpenFundingSource>,
+        deposit: u64,
+        delegate: Pubkey,
+        allowance: u64,
+    ) -> Result<()> {
+        instructions::open_funding_source(ctx, deposit, delegate, allowance)
+    }
+
+    pub fn close_funding_source(ctx: Context<CloseFundingSource>) -> Result<()> {
+        instructions::close_funding_source(ctx)
+    }
+
+    pub fn create_payment_agreements_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CreatePaymentAgreementsBatch<'info>>,
+        agreements: Vec<AgreementParams>,
+    ) -> Result<()> {
+        instructions::create_payment_agreements_batch(ctx, agreements)
+    }
+
+    pub fn approve_payment_agreement<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ApprovePaymentAgreement<'info>>,
         name: String,
     ) -> Result<()> {
         instructions::approve_payment_agreement(ctx, name)
     }
 
+    pub fn release_partial_payment(
+        ctx: Context<ApprovePaymentAgreement>,
+        name: String,
+        release_index: u32,
+        amount: u64,
+    ) -> Result<()> {
+        instructions::release_partial_payment(ctx, name, release_index, amount)
+    }
+
+    pub fn approve_milestone(
+        ctx: Context<ApprovePaymentAgreement>,
+        name: String,
+        index: u32,
+    ) -> Result<()> {
+        instructions::approve_milestone(ctx, name, index)
+    }
+
+    pub fn complete_partial_hold_rest(
+        ctx: Context<ApprovePaymentAgreement>,
+        name: String,
+        immediate_amount: u64,
+    ) -> Result<()> {
+        instructions::complete_partial_hold_rest(ctx, name, immediate_amount)
+    }
+
     pub fn cancel_payment_agreement(
         ctx: Context<CancelPaymentAgreement>,
         name: String,
@@ -35,6 +174,169 @@ pub mod escrow_payment {
         instructions::cancel_payment_agreement(ctx, name)
     }
 
+    pub fn decline_payment_agreement(
+        ctx: Context<CancelPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::decline_payment_agreement(ctx, name)
+    }
+
+    pub fn reject_payment_agreement(
+        ctx: Context<CancelPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::reject_payment_agreement(ctx, name)
+    }
+
+    pub fn resolve_tie(ctx: Context<ResolveTie>, name: String) -> Result<()> {
+        instructions::resolve_tie(ctx, name)
+    }
+
+    pub fn reset_to_pending(ctx: Context<ResetPaymentAgreement>, name: String) -> Result<()> {
+        instructions::reset_to_pending(ctx, name)
+    }
+
+    pub fn set_approval_mode(
+        ctx: Context<SetApprovalMode>,
+        name: String,
+        approvers: Vec<Pubkey>,
+        approval_threshold: u8,
+    ) -> Result<()> {
+        instructions::set_approval_mode(ctx, name, approvers, approval_threshold)
+    }
+
+    pub fn change_referee(
+        ctx: Context<ChangeReferee>,
+        name: String,
+        new_referee: Option<Pubkey>,
+    ) -> Result<()> {
+        instructions::change_referee(ctx, name, new_referee)
+    }
+
+    pub fn update_document_hash(
+        ctx: Context<UpdateDocumentHash>,
+        name: String,
+        document_hash: Option<[u8; 32]>,
+    ) -> Result<()> {
+        instructions::update_document_hash(ctx, name, document_hash)
+    }
+
+    pub fn reveal_payer_amount(
+        ctx: Context<RevealPayerAmount>,
+        name: String,
+        amount: u64,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        instructions::reveal_payer_amount(ctx, name, amount, salt)
+    }
+
+    pub fn extend_expiration(
+        ctx: Context<ExtendExpiration>,
+        name: String,
+        new_expiration_timestamp: i64,
+    ) -> Result<()> {
+        instructions::extend_expiration(ctx, name, new_expiration_timestamp)
+    }
+
+    pub fn increase_payment_amount(
+        ctx: Context<TopUpPaymentAgreement>,
+        name: String,
+        delta: u64,
+    ) -> Result<()> {
+        instructions::increase_payment_amount(ctx, name, delta)
+    }
+
+    pub fn decrease_payment_amount(
+        ctx: Context<TopUpPaymentAgreement>,
+        name: String,
+        new_amount: u64,
+    ) -> Result<()> {
+        instructions::decrease_payment_amount(ctx, name, new_amount)
+    }
+
+    pub fn commit_receiver_terms(
+        ctx: Context<SealedReceiverTerms>,
+        name: String,
+        commitment: [u8; 32],
+    ) -> Result<()> {
+        instructions::commit_receiver_terms(ctx, name, commitment)
+    }
+
+    pub fn reveal_receiver_terms(
+        ctx: Context<SealedReceiverTerms>,
+        name: String,
+        min_amount: u64,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        instructions::reveal_receiver_terms(ctx, name, min_amount, salt)
+    }
+
+    pub fn submit_delivery(
+        ctx: Context<SubmitDelivery>,
+        name: String,
+        delivery_proof: [u8; 32],
+    ) -> Result<()> {
+        instructions::submit_delivery(ctx, name, delivery_proof)
+    }
+
+    pub fn receiver_acknowledge(ctx: Context<SubmitDelivery>, name: String) -> Result<()> {
+        instructions::receiver_acknowledge(ctx, name)
+    }
+
+    pub fn prove_and_approve<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ProveAndApprove<'info>>,
+        name: String,
+    ) -> Result<()> {
+        instructions::prove_and_approve(ctx, name)
+    }
+
+    pub fn partial_accept(
+        ctx: Context<SubmitDelivery>,
+        name: String,
+        deliverable_amount: u64,
+    ) -> Result<()> {
+        instructions::partial_accept(ctx, name, deliverable_amount)
+    }
+
+    pub fn confirm_partial_accept(
+        ctx: Context<ApprovePaymentAgreement>,
+        name: String,
+        deliverable_amount: u64,
+    ) -> Result<()> {
+        instructions::confirm_partial_accept(ctx, name, deliverable_amount)
+    }
+
+    pub fn open_dispute(ctx: Context<DisputePaymentAgreement>, name: String) -> Result<()> {
+        instructions::open_dispute(ctx, name)
+    }
+
+    pub fn referee_pause(
+        ctx: Context<DisputePaymentAgreement>,
+        name: String,
+        block_withdrawal: bool,
+    ) -> Result<()> {
+        instructions::referee_pause(ctx, name, block_withdrawal)
+    }
+
+    pub fn referee_resume(ctx: Context<DisputePaymentAgreement>, name: String) -> Result<()> {
+        instructions::referee_resume(ctx, name)
+    }
+
+    pub fn auto_complete(ctx: Context<ResolveTie>, name: String) -> Result<()> {
+        instructions::auto_complete(ctx, name)
+    }
+
+    pub fn escalate_to_default_referee(
+        ctx: Context<DisputePaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::escalate_to_default_referee(ctx, name)
+    }
+
+    pub fn enforce_sla(ctx: Context<EnforceSla>, name: String) -> Result<()> {
+        instructions::enforce_sla(ctx, name)
+    }
+
     pub fn referee_intervene_cancel_payment_agreement(
         ctx: Context<CancelPaymentAgreement>,
         name: String,
@@ -42,17 +344,207 @@ pub mod escrow_payment {
         instructions::referee_intervene_cancel_payment_agreement(ctx, name)
     }
 
-    pub fn referee_intervene_complete_payment_agreement(
-        ctx: Context<ApprovePaymentAgreement>,
+    pub fn confirm_abandoned_cancel(
+        ctx: Context<CancelPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::confirm_abandoned_cancel(ctx, name)
+    }
+
+    pub fn referee_intervene_complete_payment_agreement<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ApprovePaymentAgreement<'info>>,
         name: String,
     ) -> Result<()> {
         instructions::referee_intervene_complete_payment_agreement(ctx, name)
     }
 
+    pub fn referee_vote(
+        ctx: Context<RefereeVote>,
+        name: String,
+        decision: RefereeDecision,
+    ) -> Result<()> {
+        instructions::referee_vote(ctx, name, decision)
+    }
+
     pub fn withdraw_expired_funds(
         ctx: Context<WithdrawExpiredFunds>,
         name: String,
     ) -> Result<()> {
         instructions::withdraw_expired_funds(ctx, name)
     }
+
+    pub fn settle_expired(ctx: Context<SettleExpired>, name: String) -> Result<()> {
+        instructions::settle_expired(ctx, name)
+    }
+
+    pub fn can_payer_withdraw_expired(
+        ctx: Context<ReadPaymentAgreement>,
+        name: String,
+    ) -> Result<WithdrawEligibility> {
+        instructions::can_payer_withdraw_expired(ctx, name)
+    }
+
+    pub fn get_pda_info(ctx: Context<ReadPaymentAgreement>, name: String) -> Result<PdaInfo> {
+        instructions::get_pda_info(ctx, name)
+    }
+
+    pub fn migrate_agreement(ctx: Context<MigrateAgreement>) -> Result<()> {
+        instructions::migrate_agreement(ctx)
+    }
+
+    pub fn get_agreement_summary(
+        ctx: Context<ReadPaymentAgreement>,
+        name: String,
+    ) -> Result<AgreementSummary> {
+        instructions::get_agreement_summary(ctx, name)
+    }
+
+    pub fn check_permission(
+        ctx: Context<CheckPermission>,
+        name: String,
+        action: PermissionAction,
+        signer: Pubkey,
+    ) -> Result<PermissionCheck> {
+        instructions::check_permission(ctx, name, action, signer)
+    }
+
+    pub fn verify_integrity(
+        ctx: Context<ReadPaymentAgreement>,
+        name: String,
+    ) -> Result<IntegrityStatus> {
+        instructions::verify_integrity(ctx, name)
+    }
+
+    pub fn get_payout_breakdown(
+        ctx: Context<ReadPaymentAgreement>,
+        name: String,
+        via_referee: bool,
+    ) -> Result<PayoutBreakdown> {
+        instructions::get_payout_breakdown(ctx, name, via_referee)
+    }
+
+    pub fn migrate_to_new_terms(
+        ctx: Context<MigrateToNewTerms>,
+        name: String,
+        new_name: String,
+        new_receiver: Pubkey,
+        new_amount: u64,
+        new_expiration_timestamp: Option<i64>,
+    ) -> Result<()> {
+        instructions::migrate_to_new_terms(
+            ctx,
+            name,
+            new_name,
+            new_receiver,
+            new_amount,
+            new_expiration_timestamp,
+        )
+    }
+
+    pub fn transfer_ownership(ctx: Context<TransferOwnership>, name: String) -> Result<()> {
+        instructions::transfer_ownership(ctx, name)
+    }
+
+    pub fn transfer_payer_role(
+        ctx: Context<TransferPayerRole>,
+        name: String,
+        new_payer: Pubkey,
+    ) -> Result<()> {
+        instructions::transfer_payer_role(ctx, name, new_payer)
+    }
+
+    pub fn close_terminal_agreements_batch(
+        ctx: Context<CloseTerminalAgreementsBatch>,
+    ) -> Result<u32> {
+        instructions::close_terminal_agreements_batch(ctx)
+    }
+
+    pub fn close_settled_agreement(
+        ctx: Context<CloseSettledAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::close_settled_agreement(ctx, name)
+    }
+
+    pub fn admin_emergency_refund(ctx: Context<AdminEmergencyRefund>, name: String) -> Result<()> {
+        instructions::admin_emergency_refund(ctx, name)
+    }
+
+    pub fn create_crowdfund_agreement(
+        ctx: Context<CreateCrowdfundAgreement>,
+        name: String,
+        receiver: Pubkey,
+        target_amount: u64,
+        expiration_timestamp: i64,
+    ) -> Result<()> {
+        instructions::create_crowdfund_agreement(
+            ctx,
+            name,
+            receiver,
+            target_amount,
+            expiration_timestamp,
+        )
+    }
+
+    pub fn contribute(ctx: Context<Contribute>, name: String, amount: u64) -> Result<()> {
+        instructions::contribute(ctx, name, amount)
+    }
+
+    pub fn reclaim_contribution(ctx: Context<ReclaimContribution>, name: String) -> Result<()> {
+        instructions::reclaim_contribution(ctx, name)
+    }
+
+    pub fn create_split_payment_agreement(
+        ctx: Context<CreateSplitPaymentAgreement>,
+        name: String,
+        amount: u64,
+        shares: Vec<SplitShare>,
+    ) -> Result<()> {
+        instructions::create_split_payment_agreement(ctx, name, amount, shares)
+    }
+
+    pub fn complete_split_payment_agreement(
+        ctx: Context<CompleteSplitPaymentAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::complete_split_payment_agreement(ctx, name)
+    }
+
+    pub fn settle_split_range(
+        ctx: Context<SettleSplitRange>,
+        name: String,
+        start: u32,
+        end: u32,
+    ) -> Result<()> {
+        instructions::settle_split_range(ctx, name, start, end)
+    }
+
+    pub fn create_recurring_agreement(
+        ctx: Context<CreateRecurringAgreement>,
+        name: String,
+        receiver: Pubkey,
+        per_period_amount: u64,
+        interval_seconds: u64,
+        num_periods: u32,
+    ) -> Result<()> {
+        instructions::create_recurring_agreement(
+            ctx,
+            name,
+            receiver,
+            per_period_amount,
+            interval_seconds,
+            num_periods,
+        )
+    }
+
+    pub fn claim_period(ctx: Context<ClaimPeriod>, name: String) -> Result<()> {
+        instructions::claim_period(ctx, name)
+    }
+
+    pub fn cancel_recurring_agreement(
+        ctx: Context<CancelRecurringAgreement>,
+        name: String,
+    ) -> Result<()> {
+        instructions::cancel_recurring_agreement(ctx, name)
+    }
 }
@@ -1,5 +1,6 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
+import { getAssociatedTokenAddressSync, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import { EscrowPayment } from "../target/types/escrow_payment";
 import Idl from "../target/idl/escrow_payment.json";
 
@@ -7,6 +8,19 @@ export type PaymentAgreement = Awaited<
   ReturnType<Program<EscrowPayment>["account"]["paymentAgreement"]["fetch"]>
 >;
 
+export type AgreementOptions = Parameters<
+  Program<EscrowPayment>["methods"]["createPaymentAgreement"]
+>[4];
+
+// The agreement seed prefix as exported by the program, so the derivation
+// below can't drift from the one the program checks
+export const PAYMENT_AGREEMENT_SEED = Buffer.from(
+  JSON.parse(
+    Idl.constants.find((constant) => constant.name === "PAYMENT_AGREEMENT_SEED")
+      .value
+  )
+);
+
 export class EscrowPaymentSDK {
   private program: Program<EscrowPayment>;
   private connection: anchor.web3.Connection;
@@ -60,6 +74,26 @@ export class EscrowPaymentSDK {
     return agreements;
   }
 
+  // Names are only unique per payer and nonce, so lookups always take both
+  async getAgreementByName(
+    payerPublicKey: anchor.web3.PublicKey,
+    name: string,
+    nonce = 0
+  ): Promise<PaymentAgreement | null> {
+    return this.program.account.paymentAgreement.fetchNullable(
+      this.getPaymentAgreementPDA(payerPublicKey, name, nonce)
+    );
+  }
+
+  // Key for client-side indexes of agreements, never key by name alone
+  static scopedAgreementKey(
+    payerPublicKey: anchor.web3.PublicKey,
+    name: string,
+    nonce = 0
+  ): string {
+    return `${payerPublicKey.toBase58()}/${nonce}/${name}`;
+  }
+
   createPaymentAgreementTransaction({
     name,
     payer,
@@ -67,6 +101,7 @@ export class EscrowPaymentSDK {
     referee,
     amount,
     expirationTimestamp,
+    options,
   }: {
     name: string;
     payer: anchor.web3.PublicKey;
@@ -74,11 +109,18 @@ export class EscrowPaymentSDK {
     referee?: anchor.web3.PublicKey;
     amount: anchor.BN;
     expirationTimestamp?: anchor.BN;
+    options?: AgreementOptions;
   }) {
     const accounts = {
-      paymentAgreement: this.getPaymentAgreementPDA(payer, name),
+      paymentAgreement: this.getPaymentAgreementPDA(
+        payer,
+        name,
+        options?.nonce?.toNumber() ?? 0
+      ),
       payer: payer,
       referee: referee || null,
+      // Checked to be a wallet unless options.allowProgramReceiver is set
+      receiverAccount: receiver,
       systemProgram: anchor.web3.SystemProgram.programId,
     };
 
@@ -88,9 +130,15 @@ export class EscrowPaymentSDK {
           name,
           receiver,
           amount,
-          expirationTimestamp || null
+          expirationTimestamp || null,
+          options || null
         )
         .accounts(accounts)
+        .remainingAccounts(
+          options?.performanceFee
+            ? this.referenceAccountMeta(options.performanceFee.referenceAccount)
+            : []
+        )
         .transaction(),
       agreementPda: accounts.paymentAgreement,
     };
@@ -104,8 +152,9 @@ export class EscrowPaymentSDK {
     paymentAgreement: PaymentAgreement;
   }) {
     const paymentAgreementPDA = this.getPaymentAgreementPDA(
-      paymentAgreement.payer,
-      paymentAgreement.name
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
     );
 
     const accounts = {
@@ -120,6 +169,27 @@ export class EscrowPaymentSDK {
       transaction: this.program.methods
         .approvePaymentAgreement(paymentAgreement.name)
         .accounts(accounts)
+        // The reference, fee collector and receipt accounts go first, the
+        // callback forwards whatever follows it
+        .remainingAccounts([
+          ...(paymentAgreement.performanceFee
+            ? this.referenceAccountMeta(
+                paymentAgreement.performanceFee.referenceAccount
+              )
+            : []),
+          ...(paymentAgreement.feeBasisPoints > 0
+            ? [
+                {
+                  pubkey: paymentAgreement.feeCollector,
+                  isWritable: true,
+                  isSigner: false,
+                },
+              ]
+            : []),
+          ...this.receiptAccounts(paymentAgreement),
+          ...this.refundAccounts(paymentAgreement),
+          ...this.completionCallbackAccounts(paymentAgreement),
+        ])
         .transaction(),
     };
   }
@@ -132,8 +202,9 @@ export class EscrowPaymentSDK {
     paymentAgreement: PaymentAgreement;
   }) {
     const paymentAgreementPDA = this.getPaymentAgreementPDA(
-      paymentAgreement.payer,
-      paymentAgreement.name
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
     );
 
     const accounts = {
@@ -147,6 +218,46 @@ export class EscrowPaymentSDK {
       transaction: this.program.methods
         .cancelPaymentAgreement(paymentAgreement.name)
         .accounts(accounts)
+        // The receiver keeps any non-refundable deposit on cancellation
+        .remainingAccounts([
+          ...(paymentAgreement.nonrefundableDeposit.gtn(0)
+            ? [
+                {
+                  pubkey: paymentAgreement.receiver,
+                  isWritable: true,
+                  isSigner: false,
+                },
+              ]
+            : []),
+          ...this.refundAccounts(paymentAgreement),
+        ])
+        .transaction(),
+    };
+  }
+
+  async declinePaymentAgreementTransaction({
+    paymentAgreement,
+  }: {
+    paymentAgreement: PaymentAgreement;
+  }) {
+    const paymentAgreementPDA = this.getPaymentAgreementPDA(
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
+    );
+
+    const accounts = {
+      paymentAgreement: paymentAgreementPDA,
+      signer: paymentAgreement.receiver,
+      payer: paymentAgreement.payer,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+
+    return {
+      transaction: this.program.methods
+        .declinePaymentAgreement(paymentAgreement.name)
+        .accounts(accounts)
+        .remainingAccounts(this.refundAccounts(paymentAgreement))
         .transaction(),
     };
   }
@@ -157,8 +268,9 @@ export class EscrowPaymentSDK {
     paymentAgreement: PaymentAgreement;
   }) {
     const paymentAgreementPDA = this.getPaymentAgreementPDA(
-      paymentAgreement.payer,
-      paymentAgreement.name
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
     );
 
     const accounts = {
@@ -173,6 +285,7 @@ export class EscrowPaymentSDK {
       transaction: this.program.methods
         .refereeInterveneCompletePaymentAgreement(paymentAgreement.name)
         .accounts(accounts)
+        .remainingAccounts(this.completionCallbackAccounts(paymentAgreement))
         .transaction(),
     };
   }
@@ -183,8 +296,9 @@ export class EscrowPaymentSDK {
     paymentAgreement: PaymentAgreement;
   }) {
     const paymentAgreementPDA = this.getPaymentAgreementPDA(
-      paymentAgreement.payer,
-      paymentAgreement.name
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
     );
 
     const accounts = {
@@ -198,6 +312,7 @@ export class EscrowPaymentSDK {
       transaction: this.program.methods
         .refereeInterveneCancelPaymentAgreement(paymentAgreement.name)
         .accounts(accounts)
+        .remainingAccounts(this.refundAccounts(paymentAgreement))
         .transaction(),
     };
   }
@@ -208,8 +323,9 @@ export class EscrowPaymentSDK {
     paymentAgreement: PaymentAgreement;
   }) {
     const paymentAgreementPDA = this.getPaymentAgreementPDA(
-      paymentAgreement.payer,
-      paymentAgreement.name
+      paymentAgreement.originalPayer,
+      paymentAgreement.name,
+      paymentAgreement.nonce.toNumber()
     );
 
     const accounts = {
@@ -223,13 +339,94 @@ export class EscrowPaymentSDK {
       transaction: this.program.methods
         .withdrawExpiredFunds(paymentAgreement.name)
         .accounts(accounts)
+        // Expired refunds go to the fallback receiver when one is set
+        .remainingAccounts(
+          paymentAgreement.fallbackReceiver
+            ? [
+                {
+                  pubkey: paymentAgreement.fallbackReceiver,
+                  isWritable: true,
+                  isSigner: false,
+                },
+              ]
+            : this.refundAccounts(paymentAgreement)
+        )
         .transaction(),
     };
   }
 
-  private getPaymentAgreementPDA(payer: anchor.web3.PublicKey, name: string) {
+  // Completing an agreement with a callback needs the callback program passed along
+  private completionCallbackAccounts(paymentAgreement: PaymentAgreement) {
+    return paymentAgreement.completionCallback
+      ? [
+          {
+            pubkey: paymentAgreement.completionCallback,
+            isWritable: false,
+            isSigner: false,
+          },
+        ]
+      : [];
+  }
+
+  // The program mints the completion receipt to the payer's associated token
+  // account, finding each of these by key
+  private receiptAccounts(paymentAgreement: PaymentAgreement) {
+    if (!paymentAgreement.mintReceipt) {
+      return [];
+    }
+
+    const receiptMint = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("receipt_mint")],
+      this.program.programId
+    )[0];
+    return [
+      { pubkey: receiptMint, isWritable: true, isSigner: false },
+      {
+        pubkey: getAssociatedTokenAddressSync(receiptMint, paymentAgreement.payer),
+        isWritable: true,
+        isSigner: false,
+      },
+      { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+    ];
+  }
+
+  // Agreements funded from a FundingSource refund its owner, found by key
+  private refundAccounts(paymentAgreement: PaymentAgreement) {
+    return paymentAgreement.refundTo.equals(anchor.web3.PublicKey.default)
+      ? []
+      : [
+          {
+            pubkey: paymentAgreement.refundTo,
+            isWritable: true,
+            isSigner: false,
+          },
+        ];
+  }
+
+  // Performance-fee agreements read the reference account's balance
+  private referenceAccountMeta(referenceAccount: anchor.web3.PublicKey) {
+    return [{ pubkey: referenceAccount, isWritable: false, isSigner: false }];
+  }
+
+  // Nonce 0 adds no seed, matching agreements created without a nonce. The
+  // payer is the original one, transfer_payer_role leaves the address alone.
+  private getPaymentAgreementPDA(
+    payer: anchor.web3.PublicKey,
+    name: string,
+    nonce = 0
+  ) {
+    const seeds = [PAYMENT_AGREEMENT_SEED, payer.toBuffer(), Buffer.from(name)];
+    if (nonce !== 0) {
+      // The 0xff marker keeps a name from spelling out another name's nonce
+      seeds.push(
+        Buffer.concat([
+          Buffer.from([0xff]),
+          new anchor.BN(nonce).toArrayLike(Buffer, "le", 8),
+        ])
+      );
+    }
     return anchor.web3.PublicKey.findProgramAddressSync(
-      [Buffer.from("payment_agreement"), payer.toBuffer(), Buffer.from(name)],
+      seeds,
       this.program.programId
     )[0];
   }
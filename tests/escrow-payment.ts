@@ -7,7 +7,16 @@ import {
   SystemProgram,
   LAMPORTS_PER_SOL,
 } from "@solana/web3.js";
+import {
+  TOKEN_PROGRAM_ID,
+  createAssociatedTokenAccount,
+  createMint,
+  getAccount,
+  getAssociatedTokenAddressSync,
+  mintTo,
+} from "@solana/spl-token";
 import { assert } from "chai";
+import { createHash } from "crypto";
 
 describe("escrow-payment", () => {
   // Configure the client to use the local cluster.
@@ -16,6 +25,20 @@ describe("escrow-payment", () => {
 
   const program = anchor.workspace.escrowPayment as Program<EscrowPayment>;
 
+  // Read from the IDL rather than copied, so a changed seed fails loudly
+  const PAYMENT_AGREEMENT_SEED = Buffer.from(
+    JSON.parse(
+      program.idl.constants.find(
+        (constant) => constant.name === "PAYMENT_AGREEMENT_SEED"
+      ).value
+    )
+  );
+  const VAULT_SEED = Buffer.from(
+    JSON.parse(
+      program.idl.constants.find((constant) => constant.name === "VAULT_SEED").value
+    )
+  );
+
   // Test accounts
   let payer: Keypair;
   let receiver: Keypair;
@@ -26,6 +49,68 @@ describe("escrow-payment", () => {
   const paymentName = "test-payment";
   const paymentAmount = 1 * LAMPORTS_PER_SOL; // 1 SOL
 
+  // Global config PDA shared by every agreement
+  const configPDA = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    program.programId
+  )[0];
+
+  // Program-wide usage totals
+  const statsPDA = PublicKey.findProgramAddressSync(
+    [Buffer.from("stats")],
+    program.programId
+  )[0];
+
+  const programDataPDA = PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+  )[0];
+
+  // Receives swept dust, fresh for every run
+  const treasury = Keypair.generate();
+
+  before(async () => {
+    // Fund the treasury so small sweeps keep it rent-exempt
+    await provider.connection.requestAirdrop(
+      treasury.publicKey,
+      LAMPORTS_PER_SOL
+    );
+
+    // The config only needs to be initialized once per validator
+    const existing = await provider.connection.getAccountInfo(configPDA);
+    if (!existing) {
+      await program.methods
+        .initializeConfig(new anchor.BN(0), treasury.publicKey)
+        .accounts({
+          config: configPDA,
+          admin: provider.wallet.publicKey,
+          program: program.programId,
+          programData: programDataPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+
+    // Stats are initialized alongside the config
+    if (!(await provider.connection.getAccountInfo(statsPDA))) {
+      await program.methods
+        .initializeStats()
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    }
+
+    // Let referees act immediately and agreements expire within seconds
+    // unless a suite opts into the windows
+    await program.methods
+      .updateConfig({
+        minSecondsBeforeIntervention: new anchor.BN(0),
+        minAgreementLifetime: new anchor.BN(0),
+        treasury: treasury.publicKey,
+      })
+      .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+      .rpc();
+  });
+
   beforeEach(async () => {
     // Create fresh keypairs for each test
     payer = Keypair.generate();
@@ -56,23 +141,61 @@ describe("escrow-payment", () => {
   });
 
   // Helper function to get PDA
-  function getPaymentAgreementPDA(payer: PublicKey, name: string) {
+  // Nonce 0 adds no seed, matching agreements created without a nonce
+  function getPaymentAgreementPDA(payer: PublicKey, name: string, nonce = 0) {
+    const seeds = [PAYMENT_AGREEMENT_SEED, payer.toBuffer(), Buffer.from(name)];
+    if (nonce !== 0) {
+      // The 0xff marker keeps a name from spelling out another name's nonce
+      seeds.push(
+        Buffer.concat([
+          Buffer.from([0xff]),
+          new anchor.BN(nonce).toArrayLike(Buffer, "le", 8),
+        ])
+      );
+    }
+    return PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+  }
+
+  function getVaultPDA(paymentAgreement: PublicKey) {
     return PublicKey.findProgramAddressSync(
-      [Buffer.from("payment_agreement"), payer.toBuffer(), Buffer.from(name)],
+      [VAULT_SEED, paymentAgreement.toBuffer()],
       program.programId
     )[0];
   }
 
+  // What the vault holds on top of its own rent, which is everything still
+  // escrowed for the agreement
+  async function escrowedLamports(paymentAgreement: PublicKey) {
+    const vault = await provider.connection.getAccountInfo(
+      getVaultPDA(paymentAgreement)
+    );
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(0);
+    return vault.lamports - rent;
+  }
+
+  // The rent an agreement returns to the payer when it closes, its own and
+  // its vault's
+  async function closingRent(paymentAgreement: PublicKey) {
+    const account = await provider.connection.getAccountInfo(paymentAgreement);
+    return (
+      (await provider.connection.getMinimumBalanceForRentExemption(
+        account.data.length
+      )) + (await provider.connection.getMinimumBalanceForRentExemption(0))
+    );
+  }
+
   // Helper function to create accounts for createPaymentAgreement instruction
   function getCreatePaymentAgreementAccounts(
     payerKey: PublicKey,
     name: string,
-    refereeKey?: PublicKey
+    refereeKey?: PublicKey,
+    receiverKey?: PublicKey
   ) {
     return {
       paymentAgreement: getPaymentAgreementPDA(payerKey, name),
       payer: payerKey,
       referee: refereeKey || null,
+      receiverAccount: receiverKey || receiver.publicKey,
       systemProgram: SystemProgram.programId,
     };
   }
@@ -128,7 +251,8 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
-          null // no expiration
+          null, // no expiration
+          null
         )
         .accounts(accounts)
         .signers([payer])
@@ -154,8 +278,7 @@ describe("escrow-payment", () => {
       );
       assert.equal(paymentAgreement.payerApproved, false);
       assert.equal(paymentAgreement.receiverApproved, false);
-      assert.equal(paymentAgreement.isCompleted, false);
-      assert.equal(paymentAgreement.isCancelled, false);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
       assert.equal(paymentAgreement.referee, null);
     });
 
@@ -171,6 +294,7 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(accounts)
@@ -198,7 +322,8 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
-          new anchor.BN(futureTimestamp)
+          new anchor.BN(futureTimestamp),
+          null
         )
         .accounts(accounts)
         .signers([payer])
@@ -216,7 +341,9 @@ describe("escrow-payment", () => {
     it("Should fail when payer is same as receiver", async () => {
       const accounts = getCreatePaymentAgreementAccounts(
         payer.publicKey,
-        paymentName
+        paymentName,
+        undefined,
+        payer.publicKey
       );
 
       try {
@@ -225,6 +352,7 @@ describe("escrow-payment", () => {
             paymentName,
             payer.publicKey, // Same as payer
             new anchor.BN(paymentAmount),
+            null,
             null
           )
           .accounts(accounts)
@@ -250,6 +378,7 @@ describe("escrow-payment", () => {
             paymentName,
             receiver.publicKey,
             new anchor.BN(paymentAmount),
+            null,
             null
           )
           .accounts(accounts)
@@ -279,6 +408,7 @@ describe("escrow-payment", () => {
             paymentName,
             receiver.publicKey, // Same as receiver
             new anchor.BN(paymentAmount),
+            null,
             null
           )
           .accounts(accounts)
@@ -304,7 +434,8 @@ describe("escrow-payment", () => {
             paymentName,
             receiver.publicKey,
             new anchor.BN(paymentAmount),
-            new anchor.BN(pastTimestamp)
+            new anchor.BN(pastTimestamp),
+            null
           )
           .accounts(accounts)
           .signers([payer])
@@ -337,6 +468,7 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(accounts)
@@ -364,7 +496,7 @@ describe("escrow-payment", () => {
       );
       assert.equal(paymentAgreement.payerApproved, true);
       assert.equal(paymentAgreement.receiverApproved, false);
-      assert.equal(paymentAgreement.isCompleted, false);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
     });
 
     it("Should allow receiver to approve", async () => {
@@ -387,9 +519,44 @@ describe("escrow-payment", () => {
       );
       assert.equal(paymentAgreement.payerApproved, false);
       assert.equal(paymentAgreement.receiverApproved, true);
-      assert.equal(paymentAgreement.isCompleted, false);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
     });
 
+    for (const [party, getSigner] of [
+      ["payer", () => payer],
+      ["receiver", () => receiver],
+    ] as const) {
+      it(`Should reject a second approval by the ${party}`, async () => {
+        const signer = getSigner();
+        const approve = () =>
+          program.methods
+            .approvePaymentAgreement(paymentName)
+            .accounts(
+              getApprovePaymentAgreementAccounts(
+                payer.publicKey,
+                receiver.publicKey,
+                signer.publicKey,
+                paymentName
+              )
+            )
+            .signers([signer])
+            .rpc();
+
+        await approve();
+        try {
+          await approve();
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "AlreadyApproved");
+        }
+
+        const paymentAgreement = await program.account.paymentAgreement.fetch(
+          paymentAgreementPDA
+        );
+        assert.deepEqual(paymentAgreement.status, { pending: {} });
+      });
+    }
+
     it("Should complete payment when both parties approve", async () => {
       const receiverBalanceBefore = await provider.connection.getBalance(
         receiver.publicKey
@@ -425,19 +592,57 @@ describe("escrow-payment", () => {
         .signers([receiver])
         .rpc();
 
-      const paymentAgreement = await program.account.paymentAgreement.fetch(
-        paymentAgreementPDA
-      );
       const receiverBalanceAfter = await provider.connection.getBalance(
         receiver.publicKey
       );
 
-      assert.equal(paymentAgreement.payerApproved, true);
-      assert.equal(paymentAgreement.receiverApproved, true);
-      assert.equal(paymentAgreement.isCompleted, true);
       assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
     });
 
+    it("Should close the agreement and return its rent to the payer", async () => {
+      const rent = await closingRent(paymentAgreementPDA);
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      // Only the rent comes back, the escrowed amount went to the receiver
+      assert.equal(payerBalanceAfter - payerBalanceBefore, rent);
+      assert.equal(await provider.connection.getBalance(paymentAgreementPDA), 0);
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.isNull(
+        await provider.connection.getAccountInfo(getVaultPDA(paymentAgreementPDA))
+      );
+    });
+
     it("Should fail when unauthorized user tries to approve", async () => {
       try {
         const accounts = {
@@ -502,6 +707,7 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(accounts)
@@ -528,7 +734,7 @@ describe("escrow-payment", () => {
       );
       assert.equal(paymentAgreement.payerRequestedCancel, true);
       assert.equal(paymentAgreement.receiverRequestedCancel, false);
-      assert.equal(paymentAgreement.isCancelled, false);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
     });
 
     it("Should cancel and refund when both parties request cancellation", async () => {
@@ -557,6 +763,8 @@ describe("escrow-payment", () => {
         systemProgram: SystemProgram.programId,
       };
 
+      const rent = await closingRent(paymentAgreementPDA);
+
       // Receiver requests cancellation (this should trigger refund)
       await program.methods
         .cancelPaymentAgreement(paymentName)
@@ -564,17 +772,13 @@ describe("escrow-payment", () => {
         .signers([receiver])
         .rpc();
 
-      const paymentAgreement = await program.account.paymentAgreement.fetch(
-        paymentAgreementPDA
-      );
       const payerBalanceAfter = await provider.connection.getBalance(
         payer.publicKey
       );
 
-      assert.equal(paymentAgreement.payerRequestedCancel, true);
-      assert.equal(paymentAgreement.receiverRequestedCancel, true);
-      assert.equal(paymentAgreement.isCancelled, true);
-      // Note: Balance check would need to account for transaction fees
+      // The escrow and the rent both come back, once, with the account closed
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.equal(payerBalanceAfter - payerBalanceBefore, paymentAmount + rent);
     });
   });
 
@@ -595,6 +799,7 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(accounts)
@@ -628,11 +833,88 @@ describe("escrow-payment", () => {
         receiver.publicKey
       );
 
-      assert.equal(paymentAgreement.isCompleted, true);
-      assert.equal(paymentAgreement.isRefereeIntervened, true);
+      assert.deepEqual(paymentAgreement.status, { refereeCompleted: {} });
       assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
     });
 
+    it("Should not let the referee rule twice", async () => {
+      const completeAccounts = getApprovePaymentAgreementAccounts(
+        payer.publicKey,
+        receiver.publicKey,
+        referee.publicKey,
+        paymentName
+      );
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(completeAccounts)
+        .signers([referee])
+        .rpc();
+
+      try {
+        await program.methods
+          .refereeInterveneCompletePaymentAgreement(paymentName)
+          .accounts(completeAccounts)
+          .signers([referee])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementAlreadyCompleted");
+      }
+
+      try {
+        await program.methods
+          .refereeInterveneCancelPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              referee.publicKey,
+              paymentName
+            )
+          )
+          .signers([referee])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementAlreadyCompleted");
+      }
+    });
+
+    it("Should not let the referee act after mutual completion", async () => {
+      for (const signer of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      // Mutual completion closes the account, so there is nothing left to rule on
+      try {
+        await program.methods
+          .refereeInterveneCompletePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              referee.publicKey,
+              paymentName
+            )
+          )
+          .signers([referee])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AccountNotInitialized");
+      }
+    });
+
     it("Should allow referee to cancel payment", async () => {
       const payerBalanceBefore = await provider.connection.getBalance(
         payer.publicKey
@@ -658,8 +940,7 @@ describe("escrow-payment", () => {
         payer.publicKey
       );
 
-      assert.equal(paymentAgreement.isCancelled, true);
-      assert.equal(paymentAgreement.isRefereeIntervened, true);
+      assert.deepEqual(paymentAgreement.status, { refereeCancelled: {} });
       // Note: Balance check would need to account for transaction fees
     });
 
@@ -707,7 +988,8 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
-          new anchor.BN(shortExpirationTime)
+          new anchor.BN(shortExpirationTime),
+          null
         )
         .accounts(accounts)
         .signers([payer])
@@ -760,7 +1042,8 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
-          new anchor.BN(futureExpirationTime)
+          new anchor.BN(futureExpirationTime),
+          null
         )
         .accounts(accounts)
         .signers([payer])
@@ -804,7 +1087,8 @@ describe("escrow-payment", () => {
     //       receiver.publicKey,
     //       new anchor.BN(paymentAmount),
     //       new anchor.BN(shortExpirationTime)
-    //     )
+    //,
+    null     )
     //     .accounts(accounts)
     //     .signers([payer])
     //     .rpc();
@@ -846,7 +1130,8 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
-          null // No expiration
+          null, // No expiration
+          null
         )
         .accounts(accounts)
         .signers([payer])
@@ -891,6 +1176,7 @@ describe("escrow-payment", () => {
           paymentName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(accounts)
@@ -898,7 +1184,7 @@ describe("escrow-payment", () => {
         .rpc();
     });
 
-    it("Should fail to approve already completed agreement", async () => {
+    it("Should fail to approve an agreement closed on completion", async () => {
       const payer_accounts = {
         paymentAgreement: paymentAgreementPDA,
         signer: payer.publicKey,
@@ -937,7 +1223,63 @@ describe("escrow-payment", () => {
 
         assert.fail("Should have failed");
       } catch (error) {
-        assert.include(error.message, "AgreementAlreadyCompleted");
+        assert.include(error.message, "AccountNotInitialized");
+      }
+    });
+
+    it("Should reject an agreement account owned by another program", async () => {
+      // A funded wallet is owned by the system program, standing in for a
+      // PDA that belongs to a different deployment.
+      const foreignAccount = maliciousUser.publicKey;
+
+      try {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts({
+            paymentAgreement: foreignAccount,
+            signer: payer.publicKey,
+            payer: payer.publicKey,
+            receiver: receiver.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidAgreementAccount");
+      }
+    });
+
+    it("Should reject a foreign-owned agreement account in a read-only view", async () => {
+      try {
+        await program.methods
+          .getAgreementSummary(paymentName)
+          .accounts({
+            paymentAgreement: maliciousUser.publicKey,
+            payer: payer.publicKey,
+          })
+          .view();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidAgreementAccount");
+      }
+    });
+
+    it("Should reject an agreement read under another name", async () => {
+      try {
+        await program.methods
+          .getAgreementSummary("other-name")
+          .accounts({
+            paymentAgreement: paymentAgreementPDA,
+            payer: payer.publicKey,
+          })
+          .view();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ConstraintSeeds");
       }
     });
 
@@ -953,7 +1295,9 @@ describe("escrow-payment", () => {
 
       const receiver_create_accounts = getCreatePaymentAgreementAccounts(
         receiver.publicKey,
-        sameName
+        sameName,
+        undefined,
+        payer.publicKey
       );
 
       // Create agreement with payer
@@ -962,6 +1306,7 @@ describe("escrow-payment", () => {
           sameName,
           receiver.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(payer_create_accounts)
@@ -974,6 +1319,7 @@ describe("escrow-payment", () => {
           sameName,
           payer.publicKey,
           new anchor.BN(paymentAmount),
+          null,
           null
         )
         .accounts(receiver_create_accounts)
@@ -987,4 +1333,8354 @@ describe("escrow-payment", () => {
       assert.equal(agreement2.payer.toString(), receiver.publicKey.toString());
     });
   });
+
+  describe("Crowdfund Agreements", () => {
+    const crowdfundName = "test-crowdfund";
+    const targetAmount = 2 * LAMPORTS_PER_SOL;
+
+    function getCrowdfundPDA(creator: PublicKey, name: string) {
+      return PublicKey.findProgramAddressSync(
+        [Buffer.from("crowdfund"), creator.toBuffer(), Buffer.from(name)],
+        program.programId
+      )[0];
+    }
+
+    async function createCrowdfund(expiration: number) {
+      await program.methods
+        .createCrowdfundAgreement(
+          crowdfundName,
+          receiver.publicKey,
+          new anchor.BN(targetAmount),
+          new anchor.BN(expiration)
+        )
+        .accounts({
+          crowdfundAgreement: getCrowdfundPDA(payer.publicKey, crowdfundName),
+          creator: payer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    async function contribute(contributor: Keypair, amount: number) {
+      await program.methods
+        .contribute(crowdfundName, new anchor.BN(amount))
+        .accounts({
+          crowdfundAgreement: getCrowdfundPDA(payer.publicKey, crowdfundName),
+          contributor: contributor.publicKey,
+          creator: payer.publicKey,
+          receiver: receiver.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([contributor])
+        .rpc();
+    }
+
+    it("Should pay the receiver once the target is reached", async () => {
+      await createCrowdfund(Math.floor(Date.now() / 1000) + 3600);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      await contribute(payer, LAMPORTS_PER_SOL);
+      await contribute(referee, LAMPORTS_PER_SOL);
+
+      const crowdfund = await program.account.crowdfundAgreement.fetch(
+        getCrowdfundPDA(payer.publicKey, crowdfundName)
+      );
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(crowdfund.isCompleted, true);
+      assert.equal(crowdfund.contributions.length, 2);
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, targetAmount);
+    });
+
+    it("Should let contributors reclaim after a partially funded expiry", async () => {
+      await createCrowdfund(Math.floor(Date.now() / 1000) + 3);
+
+      await contribute(payer, LAMPORTS_PER_SOL / 2);
+      await contribute(referee, LAMPORTS_PER_SOL / 4);
+
+      // Wait for the funding period to end
+      await new Promise((resolve) => setTimeout(resolve, 5000));
+
+      const refereeBalanceBefore = await provider.connection.getBalance(
+        referee.publicKey
+      );
+
+      await program.methods
+        .reclaimContribution(crowdfundName)
+        .accounts({
+          crowdfundAgreement: getCrowdfundPDA(payer.publicKey, crowdfundName),
+          contributor: referee.publicKey,
+          creator: payer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([referee])
+        .rpc();
+
+      const refereeBalanceAfter = await provider.connection.getBalance(
+        referee.publicKey
+      );
+      const crowdfund = await program.account.crowdfundAgreement.fetch(
+        getCrowdfundPDA(payer.publicKey, crowdfundName)
+      );
+
+      // Only the referee's own contribution comes back, minus the tx fee
+      assert.approximately(
+        refereeBalanceAfter - refereeBalanceBefore,
+        LAMPORTS_PER_SOL / 4,
+        10000
+      );
+      assert.equal(
+        crowdfund.totalContributed.toString(),
+        (LAMPORTS_PER_SOL / 2).toString()
+      );
+
+      // A second reclaim has nothing left to return
+      try {
+        await program.methods
+          .reclaimContribution(crowdfundName)
+          .accounts({
+            crowdfundAgreement: getCrowdfundPDA(payer.publicKey, crowdfundName),
+            contributor: referee.publicKey,
+            creator: payer.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([referee])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "NothingToReclaim");
+      }
+    });
+  });
+
+  describe("Global Active Agreement Cap", () => {
+    afterEach(async () => {
+      // Restore unlimited creation for the remaining suites
+      await program.methods
+        .updateConfig({ maxTotalActive: new anchor.BN(0) })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    });
+
+    it("Should reject creation once the cap is reached", async () => {
+      const config = await program.account.config.fetch(configPDA);
+      const cap = config.totalActiveAgreements.addn(1);
+
+      await program.methods
+        .updateConfig({ maxTotalActive: cap })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      // This one takes the last free slot
+      await program.methods
+        .createPaymentAgreement(
+          "cap-1",
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, "cap-1"))
+        .signers([payer])
+        .rpc();
+
+      const updated = await program.account.config.fetch(configPDA);
+      assert.equal(
+        updated.totalActiveAgreements.toString(),
+        cap.toString()
+      );
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            "cap-2",
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, "cap-2"))
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "GlobalCapReached");
+      }
+    });
+
+    it("Should free a slot when an agreement completes", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const before = await program.account.config.fetch(configPDA);
+
+      for (const signer of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      const after = await program.account.config.fetch(configPDA);
+      assert.equal(
+        after.totalActiveAgreements.toString(),
+        before.totalActiveAgreements.subn(1).toString()
+      );
+    });
+
+    it("Should reject config updates from non-admins", async () => {
+      try {
+        await program.methods
+          .updateConfig({ maxTotalActive: new anchor.BN(1) })
+          .accounts({ config: configPDA, admin: maliciousUser.publicKey })
+          .signers([maliciousUser])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Reset To Pending", () => {
+    let paymentAgreementPDA: PublicKey;
+
+    beforeEach(async () => {
+      const accounts = getCreatePaymentAgreementAccounts(
+        payer.publicKey,
+        paymentName
+      );
+      paymentAgreementPDA = accounts.paymentAgreement;
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(accounts)
+        .signers([payer])
+        .rpc();
+
+      // Payer approves by accident
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should clear approvals when both parties co-sign", async () => {
+      const balanceBefore = await provider.connection.getBalance(
+        paymentAgreementPDA
+      );
+
+      await program.methods
+        .resetToPending(paymentName)
+        .accounts({
+          paymentAgreement: paymentAgreementPDA,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+        })
+        .signers([payer, receiver])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        paymentAgreementPDA
+      );
+      const balanceAfter = await provider.connection.getBalance(
+        paymentAgreementPDA
+      );
+
+      assert.equal(paymentAgreement.payerApproved, false);
+      assert.equal(paymentAgreement.receiverApproved, false);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+      assert.equal(balanceAfter, balanceBefore);
+    });
+
+    it("Should fail when the receiver is not the stored receiver", async () => {
+      try {
+        await program.methods
+          .resetToPending(paymentName)
+          .accounts({
+            paymentAgreement: paymentAgreementPDA,
+            payer: payer.publicKey,
+            receiver: maliciousUser.publicKey,
+          })
+          .signers([payer, maliciousUser])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidReceiver");
+      }
+    });
+  });
+
+  describe("Expired Withdrawal Eligibility", () => {
+    function getEligibilityAccounts(name: string) {
+      return {
+        paymentAgreement: getPaymentAgreementPDA(payer.publicKey, name),
+        payer: payer.publicKey,
+      };
+    }
+
+    it("Should report not expired before the expiration", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const eligibility = await program.methods
+        .canPayerWithdrawExpired(paymentName)
+        .accounts(getEligibilityAccounts(paymentName))
+        .view();
+
+      assert.equal(eligibility.canWithdraw, false);
+      assert.deepEqual(eligibility.reason, { notExpired: {} });
+    });
+
+    it("Should report the receiver approval block after expiry", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+
+      // Wait for expiration
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      const eligibility = await program.methods
+        .canPayerWithdrawExpired(paymentName)
+        .accounts(getEligibilityAccounts(paymentName))
+        .view();
+
+      assert.equal(eligibility.canWithdraw, false);
+      assert.deepEqual(eligibility.reason, { receiverApproved: {} });
+
+      try {
+        await program.methods
+          .withdrawExpiredFunds(paymentName)
+          .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReceiverHasApproved");
+      }
+    });
+  });
+
+  describe("Referee Intervention Window", () => {
+    const windowSeconds = 3;
+
+    beforeEach(async () => {
+      await program.methods
+        .updateConfig({
+          minSecondsBeforeIntervention: new anchor.BN(windowSeconds),
+        })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    afterEach(async () => {
+      await program.methods
+        .updateConfig({ minSecondsBeforeIntervention: new anchor.BN(0) })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    });
+
+    it("Should block the referee until the window has elapsed", async () => {
+      const accounts = getApprovePaymentAgreementAccounts(
+        payer.publicKey,
+        receiver.publicKey,
+        referee.publicKey,
+        paymentName
+      );
+
+      try {
+        await program.methods
+          .refereeInterveneCompletePaymentAgreement(paymentName)
+          .accounts(accounts)
+          .signers([referee])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "TooEarlyForIntervention");
+      }
+
+      // Wait until just past created_at + window
+      await new Promise((resolve) =>
+        setTimeout(resolve, (windowSeconds + 1) * 1000)
+      );
+
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(accounts)
+        .signers([referee])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        accounts.paymentAgreement
+      );
+      assert.deepEqual(paymentAgreement.status, { refereeCompleted: {} });
+      assert.isAbove(paymentAgreement.createdAt.toNumber(), 0);
+    });
+  });
+
+  describe("Acceptance Expiry", () => {
+    it("Should reject acceptance expiry after the overall expiration", async () => {
+      const now = Math.floor(Date.now() / 1000);
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            new anchor.BN(now + 3600),
+            { acceptanceExpiry: new anchor.BN(now + 7200) }
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(payer.publicKey, paymentName)
+          )
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "AcceptanceExpiryAfterExpiration");
+      }
+    });
+
+    it("Should let the payer reclaim once the acceptance window lapses", async () => {
+      const now = Math.floor(Date.now() / 1000);
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(now + 3600),
+          { acceptanceExpiry: new anchor.BN(now + 2) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      // Wait for the acceptance window to lapse
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      try {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              receiver.publicKey,
+              paymentName
+            )
+          )
+          .signers([receiver])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "AcceptanceWindowLapsed");
+      }
+
+      // The overall expiration is still an hour away
+      await program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const accountInfo = await provider.connection.getAccountInfo(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isNull(accountInfo);
+    });
+
+    it("Should reject an acceptance expiry that has already passed", async () => {
+      const now = Math.floor(Date.now() / 1000);
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            new anchor.BN(now + 3600),
+            { acceptanceExpiry: new anchor.BN(now - 60) }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ExpirationMustBeInFuture");
+      }
+    });
+
+    it("Should let the payer reclaim a lapsed offer that never expires", async () => {
+      const now = Math.floor(Date.now() / 1000);
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { acceptanceExpiry: new anchor.BN(now + 2) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      const payerBalanceBefore = await provider.connection.getBalance(payer.publicKey);
+      await program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+      const payerBalanceAfter = await provider.connection.getBalance(payer.publicKey);
+
+      // The escrow plus the account's rent comes back
+      assert.isAtLeast(payerBalanceAfter - payerBalanceBefore, paymentAmount);
+    });
+  });
+
+  describe("Dust Sweep", () => {
+    let paymentAgreementPDA: PublicKey;
+    const dust = 12345;
+
+    beforeEach(async () => {
+      paymentAgreementPDA = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    async function sendDust() {
+      const tx = new anchor.web3.Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: payer.publicKey,
+          toPubkey: paymentAgreementPDA,
+          lamports: dust,
+        })
+      );
+      await provider.sendAndConfirm(tx, [payer]);
+    }
+
+    function sweep(admin: Keypair | null) {
+      const builder = program.methods
+        .sweepDust()
+        .accounts({
+          config: configPDA,
+          admin: admin ? admin.publicKey : provider.wallet.publicKey,
+          treasury: treasury.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: paymentAgreementPDA, isWritable: true, isSigner: false },
+          {
+            pubkey: getVaultPDA(paymentAgreementPDA),
+            isWritable: true,
+            isSigner: false,
+          },
+        ]);
+      return admin ? builder.signers([admin]).rpc() : builder.rpc();
+    }
+
+    it("Should sweep dust from a settled agreement to the treasury", async () => {
+      // A referee ruling leaves the settled agreement open for sweeping
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+      await sendDust();
+
+      const treasuryBefore = await provider.connection.getBalance(
+        treasury.publicKey
+      );
+      await sweep(null);
+      const treasuryAfter = await provider.connection.getBalance(
+        treasury.publicKey
+      );
+
+      assert.equal(treasuryAfter - treasuryBefore, dust);
+    });
+
+    it("Should refuse to sweep an active escrow", async () => {
+      await sendDust();
+
+      try {
+        await sweep(null);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "AgreementNotSettled");
+      }
+    });
+
+    it("Should reject sweeps from non-admins", async () => {
+      try {
+        await sweep(maliciousUser);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("N-of-M Approvers", () => {
+    async function createWithApprovers(approvers: PublicKey[], threshold: number) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { approvers, approvalThreshold: threshold }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    async function approve(signer: Keypair) {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should complete only once the threshold is met", async () => {
+      await createWithApprovers(
+        [payer.publicKey, receiver.publicKey, referee.publicKey],
+        2
+      );
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      await approve(payer);
+      let paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+      assert.equal(paymentAgreement.approvalsBitmap, 0b001);
+
+      await approve(referee);
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+    });
+
+    it("Should refund and close once enough approvers reject", async () => {
+      await createWithApprovers(
+        [payer.publicKey, receiver.publicKey, referee.publicKey],
+        2
+      );
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const rent = await closingRent(pda);
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      for (const signer of [receiver, referee]) {
+        await program.methods
+          .rejectPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      assert.equal(payerBalanceAfter - payerBalanceBefore, paymentAmount + rent);
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+      assert.isNull(await provider.connection.getAccountInfo(getVaultPDA(pda)));
+    });
+
+    it("Should reject approvals from outside the approver set", async () => {
+      await createWithApprovers([payer.publicKey, referee.publicKey], 2);
+
+      try {
+        await approve(receiver);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should reject a threshold above the approver count", async () => {
+      try {
+        await createWithApprovers([payer.publicKey, referee.publicKey], 3);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidApprovalThreshold");
+      }
+    });
+  });
+
+  describe("Default Referee Escalation", () => {
+    const fallbackReferee = Keypair.generate();
+
+    function getDisputeAccounts(signerKey: PublicKey) {
+      return {
+        paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+        signer: signerKey,
+        payer: payer.publicKey,
+        config: configPDA,
+      };
+    }
+
+    beforeEach(async () => {
+      await program.methods
+        .updateConfig({
+          defaultReferee: fallbackReferee.publicKey,
+          refereeInactivityWindow: new anchor.BN(2),
+        })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .openDispute(paymentName)
+        .accounts(getDisputeAccounts(receiver.publicKey))
+        .signers([receiver])
+        .rpc();
+    });
+
+    it("Should reject escalation before the inactivity window", async () => {
+      try {
+        await program.methods
+          .escalateToDefaultReferee(paymentName)
+          .accounts(getDisputeAccounts(payer.publicKey))
+          .signers([payer])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InactivityWindowNotElapsed");
+      }
+    });
+
+    it("Should hand the dispute to the default referee after inactivity", async () => {
+      // Wait out the inactivity window
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      await program.methods
+        .escalateToDefaultReferee(paymentName)
+        .accounts(getDisputeAccounts(payer.publicKey))
+        .signers([payer])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(
+        paymentAgreement.referee.toString(),
+        fallbackReferee.publicKey.toString()
+      );
+    });
+  });
+
+  describe("Completion Receipts", () => {
+    const receiptMintPDA = PublicKey.findProgramAddressSync(
+      [Buffer.from("receipt_mint")],
+      program.programId
+    )[0];
+    let payerTokenAccount: PublicKey;
+
+    before(async () => {
+      const existing = await provider.connection.getAccountInfo(receiptMintPDA);
+      if (!existing) {
+        await program.methods
+          .initializeReceiptMint()
+          .accounts({
+            config: configPDA,
+            admin: provider.wallet.publicKey,
+            receiptMint: receiptMintPDA,
+          })
+          .rpc();
+      }
+    });
+
+    beforeEach(async () => {
+      payerTokenAccount = await createAssociatedTokenAccount(
+        provider.connection,
+        payer,
+        receiptMintPDA,
+        payer.publicKey
+      );
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { mintReceipt: true }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    async function approveWithReceipt(signer: Keypair) {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .remainingAccounts([
+          { pubkey: receiptMintPDA, isWritable: true, isSigner: false },
+          { pubkey: payerTokenAccount, isWritable: true, isSigner: false },
+          { pubkey: TOKEN_PROGRAM_ID, isWritable: false, isSigner: false },
+        ])
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should mint a receipt to the payer only on completion", async () => {
+      await approveWithReceipt(payer);
+
+      let tokenAccount = await getAccount(provider.connection, payerTokenAccount);
+      assert.equal(tokenAccount.amount.toString(), "0");
+
+      await approveWithReceipt(receiver);
+
+      tokenAccount = await getAccount(provider.connection, payerTokenAccount);
+      assert.equal(tokenAccount.amount.toString(), "1");
+    });
+  });
+
+  describe("Approval Mode Changes", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function setApprovalMode(approvers: PublicKey[], threshold: number) {
+      return program.methods
+        .setApprovalMode(paymentName, approvers, threshold)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+        })
+        .signers([payer, receiver])
+        .rpc();
+    }
+
+    it("Should switch modes while no approvals exist", async () => {
+      await setApprovalMode([payer.publicKey, referee.publicKey], 1);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.approvers.length, 2);
+      assert.equal(paymentAgreement.approvalThreshold, 1);
+    });
+
+    it("Should reject a mode change mid-approval", async () => {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      // A 1-of-2 mode would otherwise complete on the existing payer approval
+      try {
+        await setApprovalMode([payer.publicKey, referee.publicKey], 1);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "CannotChangeModeAfterApproval");
+      }
+    });
+  });
+
+  describe("PDA Info", () => {
+    it("Should return the seeds and canonical bump", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const [pda, bump] = PublicKey.findProgramAddressSync(
+        [Buffer.from("payment_agreement"), payer.publicKey.toBuffer(), Buffer.from(paymentName)],
+        program.programId
+      );
+
+      const info = await program.methods
+        .getPdaInfo(paymentName)
+        .accounts({ paymentAgreement: pda, payer: payer.publicKey })
+        .view();
+
+      assert.equal(Buffer.from(info.seedPrefix).toString(), "payment_agreement");
+      assert.isTrue(Buffer.from(info.seedPrefix).equals(PAYMENT_AGREEMENT_SEED));
+      assert.equal(info.payer.toString(), payer.publicKey.toString());
+      assert.equal(info.name, paymentName);
+      assert.equal(info.bump, bump);
+    });
+  });
+
+  describe("Receiver Response SLA", () => {
+    const slaSeconds = 2;
+
+    function enforceSla() {
+      return program.methods
+        .enforceSla(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .rpc();
+    }
+
+    async function createWithSla(refereeKey?: PublicKey) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { slaSeconds: new anchor.BN(slaSeconds) }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            refereeKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should reject enforcement before the SLA elapses", async () => {
+      await createWithSla();
+
+      try {
+        await enforceSla();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "SlaNotElapsed");
+      }
+    });
+
+    it("Should open a dispute when a referee exists", async () => {
+      await createWithSla(referee.publicKey);
+      await new Promise((resolve) => setTimeout(resolve, (slaSeconds + 1) * 1000));
+
+      await enforceSla();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isNotNull(paymentAgreement.disputeOpenedAt);
+      assert.equal(paymentAgreement.slaBreached, false);
+    });
+
+    it("Should let the payer reclaim when no referee exists", async () => {
+      await createWithSla();
+      await new Promise((resolve) => setTimeout(resolve, (slaSeconds + 1) * 1000));
+
+      await enforceSla();
+
+      await program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const accountInfo = await provider.connection.getAccountInfo(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isNull(accountInfo);
+    });
+  });
+
+  describe("Batch Close Terminal Agreements", () => {
+    const names = ["batch-a", "batch-b", "batch-c"];
+
+    beforeEach(async () => {
+      for (const name of names) {
+        await program.methods
+          .createPaymentAgreement(
+            name,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(payer.publicKey, name, referee.publicKey)
+          )
+          .signers([payer])
+          .rpc();
+      }
+
+      // Settle the first two, leave the last one pending. A referee cancel
+      // keeps the account open, unlike a decline or a mutual cancel.
+      for (const name of names.slice(0, 2)) {
+        await program.methods
+          .refereeInterveneCancelPaymentAgreement(name)
+          .accounts(
+            getCancelPaymentAgreementAccounts(payer.publicKey, referee.publicKey, name)
+          )
+          .signers([referee])
+          .rpc();
+      }
+    });
+
+    it("Should close only terminal agreements and skip duplicates", async () => {
+      const pdas = names.map((name) => getPaymentAgreementPDA(payer.publicKey, name));
+      const remaining = [...pdas, pdas[0]]
+        .flatMap((pda) => [pda, getVaultPDA(pda)])
+        .map((pubkey) => ({ pubkey, isWritable: true, isSigner: false }));
+
+      const closed = await program.methods
+        .closeTerminalAgreementsBatch()
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(remaining)
+        .signers([payer])
+        .view();
+      assert.equal(closed, 2);
+
+      await program.methods
+        .closeTerminalAgreementsBatch()
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(remaining)
+        .signers([payer])
+        .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(pdas[0]));
+      assert.isNull(await provider.connection.getAccountInfo(pdas[1]));
+      assert.isNotNull(await provider.connection.getAccountInfo(pdas[2]));
+      assert.isNull(await provider.connection.getAccountInfo(getVaultPDA(pdas[0])));
+      assert.isNotNull(
+        await provider.connection.getAccountInfo(getVaultPDA(pdas[2]))
+      );
+    });
+  });
+
+  describe("Admin Emergency Refund", () => {
+    let paymentAgreementPDA: PublicKey;
+
+    beforeEach(async () => {
+      const accounts = getCreatePaymentAgreementAccounts(
+        payer.publicKey,
+        paymentName
+      );
+      paymentAgreementPDA = accounts.paymentAgreement;
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(accounts)
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should refund the full balance to the payer and close", async () => {
+      const pdaBalance =
+        (await provider.connection.getBalance(paymentAgreementPDA)) +
+        (await provider.connection.getBalance(getVaultPDA(paymentAgreementPDA)));
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      await program.methods
+        .adminEmergencyRefund(paymentName)
+        .accounts({
+          paymentAgreement: paymentAgreementPDA,
+          payer: payer.publicKey,
+          config: configPDA,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      assert.equal(payerBalanceAfter - payerBalanceBefore, pdaBalance);
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.isNull(
+        await provider.connection.getAccountInfo(getVaultPDA(paymentAgreementPDA))
+      );
+    });
+
+    it("Should reject non-admins", async () => {
+      try {
+        await program.methods
+          .adminEmergencyRefund(paymentName)
+          .accounts({
+            paymentAgreement: paymentAgreementPDA,
+            payer: payer.publicKey,
+            config: configPDA,
+            admin: maliciousUser.publicKey,
+          })
+          .signers([maliciousUser])
+          .rpc();
+
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Hidden Amount Commitments", () => {
+    const salt = Buffer.alloc(32, 7);
+
+    function commitTo(amount: number) {
+      const amountBytes = new anchor.BN(amount).toArrayLike(Buffer, "le", 8);
+      return Array.from(
+        createHash("sha256").update(Buffer.concat([amountBytes, salt])).digest()
+      );
+    }
+
+    function reveal(amount: number) {
+      return program.methods
+        .revealPayerAmount(paymentName, new anchor.BN(amount), Array.from(salt))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    async function approve(signer: Keypair) {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(0),
+          null,
+          { amountCommitment: commitTo(paymentAmount) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should fund and complete after a matching reveal", async () => {
+      await approve(receiver);
+      await reveal(paymentAmount);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.amount.toString(), paymentAmount.toString());
+      assert.equal(paymentAgreement.amountRevealed, true);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(payer);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+    });
+
+    it("Should reject a reveal that does not match the commitment", async () => {
+      await approve(receiver);
+
+      try {
+        await reveal(paymentAmount + 1);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "CommitmentMismatch");
+      }
+    });
+
+    it("Should reject a reveal before the receiver accepts", async () => {
+      try {
+        await reveal(paymentAmount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReceiverHasNotAccepted");
+      }
+    });
+  });
+
+  describe("Referee Fees", () => {
+    async function createWithFee(amount: number, refereeFee: any) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          null,
+          { refereeFee }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    async function interveneComplete() {
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      return receiverBalanceAfter - receiverBalanceBefore;
+    }
+
+    it("Should deduct a percentage fee before paying the receiver", async () => {
+      await createWithFee(paymentAmount, { bps: { bps: 500 } });
+
+      const received = await interveneComplete();
+      assert.equal(received, paymentAmount * 0.95);
+    });
+
+    it("Should round a percentage fee down for small amounts", async () => {
+      // 1% of 1000050 lamports is 10000.5, the referee gets 10000
+      await createWithFee(1_000_050, { bps: { bps: 100 } });
+
+      const received = await interveneComplete();
+      assert.equal(received, 990050);
+    });
+
+    it("Should deduct a fixed fee before paying the receiver", async () => {
+      const fee = 0.1 * LAMPORTS_PER_SOL;
+      await createWithFee(paymentAmount, { fixed: { lamports: new anchor.BN(fee) } });
+
+      const received = await interveneComplete();
+      assert.equal(received, paymentAmount - fee);
+    });
+
+    async function refereeBalanceChange(intervene: () => Promise<unknown>) {
+      const refereeBalanceBefore = await provider.connection.getBalance(
+        referee.publicKey
+      );
+      await intervene();
+      const refereeBalanceAfter = await provider.connection.getBalance(
+        referee.publicKey
+      );
+      return refereeBalanceAfter - refereeBalanceBefore;
+    }
+
+    it("Should pay the fixed fee to the referee on completion", async () => {
+      const fee = 0.1 * LAMPORTS_PER_SOL;
+      await createWithFee(paymentAmount, { fixed: { lamports: new anchor.BN(fee) } });
+
+      assert.equal(await refereeBalanceChange(interveneComplete), fee);
+    });
+
+    it("Should pay the fixed fee to the referee on cancellation", async () => {
+      const fee = 0.1 * LAMPORTS_PER_SOL;
+      await createWithFee(paymentAmount, { fixed: { lamports: new anchor.BN(fee) } });
+
+      const change = await refereeBalanceChange(() =>
+        program.methods
+          .refereeInterveneCancelPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              referee.publicKey,
+              paymentName
+            )
+          )
+          .signers([referee])
+          .rpc()
+      );
+      assert.equal(change, fee);
+    });
+
+    it("Should reject a fixed fee that is not below the amount", async () => {
+      try {
+        await createWithFee(paymentAmount, {
+          fixed: { lamports: new anchor.BN(paymentAmount) },
+        });
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "RefereeFeeTooLarge");
+      }
+    });
+
+    it("Should reject a percentage fee above the maximum", async () => {
+      try {
+        await createWithFee(paymentAmount, { bps: { bps: 2001 } });
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidRefereeFee");
+      }
+    });
+  });
+
+  describe("Payout Breakdown", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { refereeFee: { bps: { bps: 1000 } } }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function getBreakdown(viaReferee: boolean) {
+      return program.methods
+        .getPayoutBreakdown(paymentName, viaReferee)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+    }
+
+    it("Should pay the full amount to the receiver on mutual completion", async () => {
+      const breakdown = await getBreakdown(false);
+
+      assert.equal(breakdown.receiverShare.toNumber(), paymentAmount);
+      assert.equal(breakdown.refereeFee.toNumber(), 0);
+      assert.equal(breakdown.platformFee.toNumber(), 0);
+      assert.equal(breakdown.dust.toNumber(), 0);
+    });
+
+    it("Should itemize the referee fee and match the actual payout", async () => {
+      const breakdown = await getBreakdown(true);
+      assert.equal(breakdown.refereeFee.toNumber(), paymentAmount * 0.1);
+      assert.equal(breakdown.receiverShare.toNumber(), paymentAmount * 0.9);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(
+        receiverBalanceAfter - receiverBalanceBefore,
+        breakdown.receiverShare.toNumber()
+      );
+    });
+  });
+
+  describe("Minimum Escrow Duration", () => {
+    const minDurationSeconds = 3;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { minDurationSeconds: new anchor.BN(minDurationSeconds) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function receiverCancel() {
+      return program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+    }
+
+    it("Should reject cancellation before the minimum duration", async () => {
+      try {
+        await receiverCancel();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "MinimumDurationNotMet");
+      }
+    });
+
+    it("Should cancel once the minimum duration has passed", async () => {
+      await new Promise((resolve) =>
+        setTimeout(resolve, (minDurationSeconds + 1) * 1000)
+      );
+
+      await receiverCancel();
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+  });
+
+  describe("Delivery Submission", () => {
+    const deliveryProof = Array.from(Buffer.alloc(32, 9));
+
+    async function createAgreement(deliveryCompletion: boolean) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { deliveryCompletion }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function submitDelivery() {
+      return program.methods
+        .submitDelivery(paymentName, deliveryProof)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          receiver: receiver.publicKey,
+          payer: payer.publicKey,
+        })
+        .signers([receiver])
+        .rpc();
+    }
+
+    it("Should let the payer alone complete after delivery", async () => {
+      await createAgreement(true);
+      await submitDelivery();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.deepEqual(paymentAgreement.deliveryProof, deliveryProof);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+    });
+
+    it("Should reject delivery when the flag is not set", async () => {
+      await createAgreement(false);
+
+      try {
+        await submitDelivery();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "DeliveryCompletionNotEnabled");
+      }
+    });
+  });
+
+  describe("Cross-Payer Name Isolation", () => {
+    let otherPayer: Keypair;
+
+    beforeEach(async () => {
+      otherPayer = Keypair.generate();
+      await provider.connection.requestAirdrop(
+        otherPayer.publicKey,
+        5 * LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      // Both payers use the same name
+      for (const agreementPayer of [payer, otherPayer]) {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(agreementPayer.publicKey, paymentName)
+          )
+          .signers([agreementPayer])
+          .rpc();
+      }
+    });
+
+    it("Should keep same-named agreements of different payers separate", async () => {
+      const ownPDA = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const otherPDA = getPaymentAgreementPDA(otherPayer.publicKey, paymentName);
+      assert.notEqual(ownPDA.toString(), otherPDA.toString());
+
+      // Approving one leaves the other untouched
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            otherPayer.publicKey,
+            receiver.publicKey,
+            otherPayer.publicKey,
+            paymentName
+          )
+        )
+        .signers([otherPayer])
+        .rpc();
+
+      const own = await program.account.paymentAgreement.fetch(ownPDA);
+      const other = await program.account.paymentAgreement.fetch(otherPDA);
+      assert.equal(own.payer.toString(), payer.publicKey.toString());
+      assert.equal(own.payerApproved, false);
+      assert.equal(other.payerApproved, true);
+    });
+
+    it("Should not let another payer act on an agreement by reusing its name", async () => {
+      try {
+        await program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            signer: otherPayer.publicKey,
+            payer: otherPayer.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([otherPayer])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Migrate To New Terms", () => {
+    const newName = "test-payment-v2";
+    let newReceiver: Keypair;
+
+    beforeEach(async () => {
+      newReceiver = Keypair.generate();
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function migrate(newAmount: number, signers: Keypair[] = [payer, receiver]) {
+      return program.methods
+        .migrateToNewTerms(
+          paymentName,
+          newName,
+          newReceiver.publicKey,
+          new anchor.BN(newAmount),
+          null
+        )
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          newPaymentAgreement: getPaymentAgreementPDA(payer.publicKey, newName),
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          newReferee: null,
+          receiverAccount: newReceiver.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers(signers)
+        .rpc();
+    }
+
+    it("Should reject migrating to a program-owned receiver", async () => {
+      try {
+        await program.methods
+          .migrateToNewTerms(
+            paymentName,
+            newName,
+            configPDA,
+            new anchor.BN(paymentAmount),
+            null
+          )
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            newPaymentAgreement: getPaymentAgreementPDA(payer.publicKey, newName),
+            payer: payer.publicKey,
+            receiver: receiver.publicKey,
+            newReferee: null,
+            receiverAccount: configPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([payer, receiver])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotOnCurve");
+      }
+    });
+
+    it("Should carry the escrowed balance over to the new agreement", async () => {
+      await migrate(paymentAmount);
+
+      const newPDA = getPaymentAgreementPDA(payer.publicKey, newName);
+      assert.equal(await escrowedLamports(newPDA), paymentAmount);
+
+      const migrated = await program.account.paymentAgreement.fetch(newPDA);
+      assert.equal(migrated.receiver.toString(), newReceiver.publicKey.toString());
+      assert.equal(migrated.amount.toString(), paymentAmount.toString());
+
+      const oldPDA = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      assert.isNull(await provider.connection.getAccountInfo(oldPDA));
+      assert.isNull(await provider.connection.getAccountInfo(getVaultPDA(oldPDA)));
+    });
+
+    it("Should fund an increased amount from the payer", async () => {
+      await migrate(paymentAmount * 2);
+
+      const newPDA = getPaymentAgreementPDA(payer.publicKey, newName);
+      assert.equal(await escrowedLamports(newPDA), paymentAmount * 2);
+    });
+
+    it("Should require the receiver to sign", async () => {
+      try {
+        await migrate(paymentAmount, [payer]);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Signature verification failed");
+      }
+    });
+  });
+
+  describe("Receiver Existence Check", () => {
+    let closedReceiver: Keypair;
+
+    beforeEach(() => {
+      // Never funded, so the account does not exist on-chain
+      closedReceiver = Keypair.generate();
+    });
+
+    async function createAndApprove(requireReceiverExists: boolean) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          closedReceiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { requireReceiverExists, deliveryCompletion: true }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            undefined,
+            closedReceiver.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      // The receiver can't pay fees, so deliver through the payer-only path
+      await provider.connection.requestAirdrop(
+        closedReceiver.publicKey,
+        LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+      await program.methods
+        .submitDelivery(paymentName, Array.from(Buffer.alloc(32)))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          receiver: closedReceiver.publicKey,
+          payer: payer.publicKey,
+        })
+        .signers([closedReceiver])
+        .rpc();
+
+      // Drain the receiver so it no longer exists at payout
+      const balance = await provider.connection.getBalance(
+        closedReceiver.publicKey
+      );
+      const drain = new anchor.web3.Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: closedReceiver.publicKey,
+          toPubkey: payer.publicKey,
+          lamports: balance - 5000,
+        })
+      );
+      await anchor.web3.sendAndConfirmTransaction(provider.connection, drain, [
+        closedReceiver,
+      ]);
+
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            closedReceiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should recreate a closed receiver by default", async () => {
+      await createAndApprove(false);
+
+      const balance = await provider.connection.getBalance(
+        closedReceiver.publicKey
+      );
+      assert.equal(balance, paymentAmount);
+    });
+
+    it("Should reject payout to a closed receiver when required", async () => {
+      try {
+        await createAndApprove(true);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReceiverDoesNotExist");
+      }
+    });
+  });
+
+  describe("Tie-Break Resolution", () => {
+    before(async () => {
+      await program.methods
+        .updateConfig({ tieBreakTimeout: new anchor.BN(0) })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    });
+
+    // Payer and receiver are the two approvers, both are needed either way
+    async function createTiedAgreement(tieBreak: any) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount + 1),
+          null,
+          {
+            approvers: [payer.publicKey, receiver.publicKey],
+            approvalThreshold: 2,
+            tieBreak,
+          }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .rejectPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+    }
+
+    // Returns the lamports each side received from the escrow, not counting
+    // the rent the payer gets back when a settling resolution closes it
+    async function resolveTie() {
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const rent = await closingRent(pda);
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      const receiverBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      await program.methods
+        .resolveTie(paymentName)
+        .accounts({
+          paymentAgreement: pda,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          config: configPDA,
+        })
+        .rpc();
+
+      const payerAfter = await provider.connection.getBalance(payer.publicKey);
+      const receiverAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const closed = (await provider.connection.getAccountInfo(pda)) === null;
+      return {
+        toPayer: payerAfter - payerBefore - (closed ? rent : 0),
+        toReceiver: receiverAfter - receiverBefore,
+        closed,
+      };
+    }
+
+    it("Should record the tie once every approver has voted", async () => {
+      await createTiedAgreement({ favorPayer: {} });
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isNotNull(paymentAgreement.tieDetectedAt);
+    });
+
+    it("Should refund the payer when the tie favors the payer", async () => {
+      await createTiedAgreement({ favorPayer: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toPayer, paymentAmount + 1);
+      assert.equal(toReceiver, 0);
+      assert.isTrue(closed);
+    });
+
+    it("Should pay the receiver when the tie favors the receiver", async () => {
+      await createTiedAgreement({ favorReceiver: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toPayer, 0);
+      assert.equal(toReceiver, paymentAmount + 1);
+      assert.isTrue(closed);
+    });
+
+    it("Should split 50/50 with the odd lamport going to the payer", async () => {
+      await createTiedAgreement({ split5050: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toReceiver, paymentAmount / 2);
+      assert.equal(toPayer, paymentAmount / 2 + 1);
+      assert.isTrue(closed);
+    });
+
+    it("Should clear the votes when unanimity is required", async () => {
+      await createTiedAgreement({ requireUnanimity: {} });
+
+      await resolveTie();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.approvalsBitmap, 0);
+      assert.equal(paymentAgreement.rejectionsBitmap, 0);
+      assert.isNull(paymentAgreement.tieDetectedAt);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    // A two-referee panel that needs both votes, split one each way
+    async function createPanelTie(tieBreak: any) {
+      const panel = [Keypair.generate(), Keypair.generate()];
+      for (const panelReferee of panel) {
+        await provider.connection.requestAirdrop(
+          panelReferee.publicKey,
+          LAMPORTS_PER_SOL
+        );
+      }
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount + 1),
+          null,
+          {
+            referees: panel.map((panelReferee) => panelReferee.publicKey),
+            requiredVotes: 2,
+            tieBreak,
+          }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const decisions = [{ complete: {} }, { cancel: {} }];
+      for (const [index, panelReferee] of panel.entries()) {
+        await program.methods
+          .refereeVote(paymentName, decisions[index])
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            signer: panelReferee.publicKey,
+            payer: payer.publicKey,
+            receiver: receiver.publicKey,
+            config: configPDA,
+          })
+          .signers([panelReferee])
+          .rpc();
+      }
+    }
+
+    it("Should record the tie once every panel referee has voted", async () => {
+      await createPanelTie({ favorPayer: {} });
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isNotNull(paymentAgreement.tieDetectedAt);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should refund the payer when a panel tie favors the payer", async () => {
+      await createPanelTie({ favorPayer: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toPayer, paymentAmount + 1);
+      assert.equal(toReceiver, 0);
+      assert.isTrue(closed);
+    });
+
+    it("Should pay the receiver when a panel tie favors the receiver", async () => {
+      await createPanelTie({ favorReceiver: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toPayer, 0);
+      assert.equal(toReceiver, paymentAmount + 1);
+      assert.isTrue(closed);
+    });
+
+    it("Should split a panel tie 50/50 with the odd lamport going to the payer", async () => {
+      await createPanelTie({ split5050: {} });
+
+      const { toPayer, toReceiver, closed } = await resolveTie();
+      assert.equal(toReceiver, paymentAmount / 2);
+      assert.equal(toPayer, paymentAmount / 2 + 1);
+      assert.isTrue(closed);
+    });
+
+    it("Should clear the panel votes when unanimity is required", async () => {
+      await createPanelTie({ requireUnanimity: {} });
+
+      await resolveTie();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.completeVotesBitmap, 0);
+      assert.equal(paymentAgreement.cancelVotesBitmap, 0);
+      assert.isNull(paymentAgreement.tieDetectedAt);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+  });
+
+  describe("Permission Checks", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function checkPermission(action: any, signer: PublicKey) {
+      return program.methods
+        .checkPermission(paymentName, action, signer)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          config: configPDA,
+        })
+        .view();
+    }
+
+    function errorCode(name: string) {
+      return program.idl.errors.find(
+        (error) => error.name.toLowerCase() === name.toLowerCase()
+      ).code;
+    }
+
+    it("Should allow the parties to approve and deny outsiders", async () => {
+      const payerCheck = await checkPermission({ approve: {} }, payer.publicKey);
+      assert.equal(payerCheck.allowed, true);
+      assert.equal(payerCheck.denialCode, 0);
+
+      const outsiderCheck = await checkPermission(
+        { approve: {} },
+        maliciousUser.publicKey
+      );
+      assert.equal(outsiderCheck.allowed, false);
+      assert.equal(outsiderCheck.denialCode, errorCode("Unauthorized"));
+    });
+
+    it("Should report the same denial as the instruction", async () => {
+      const check = await checkPermission({ withdraw: {} }, payer.publicKey);
+      assert.equal(check.allowed, false);
+      assert.equal(check.denialCode, errorCode("PaymentAgreementNotExpired"));
+
+      try {
+        await program.methods
+          .withdrawExpiredFunds(paymentName)
+          .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "PaymentAgreementNotExpired");
+      }
+    });
+
+    it("Should deny every action once the agreement is settled", async () => {
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+
+      const check = await checkPermission({ cancel: {} }, receiver.publicKey);
+      assert.equal(check.allowed, false);
+      assert.equal(check.denialCode, errorCode("AgreementAlreadyCompleted"));
+    });
+  });
+
+  describe("Pull Funding From Source", () => {
+    let custodian: Keypair;
+    let fundingSourcePDA: PublicKey;
+
+    beforeEach(async () => {
+      custodian = Keypair.generate();
+      await provider.connection.requestAirdrop(
+        custodian.publicKey,
+        5 * LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      fundingSourcePDA = PublicKey.findProgramAddressSync(
+        [Buffer.from("funding_source"), custodian.publicKey.toBuffer()],
+        program.programId
+      )[0];
+
+      // The payer may pull up to one payment from the custodian's source
+      await program.methods
+        .openFundingSource(
+          new anchor.BN(2 * paymentAmount),
+          payer.publicKey,
+          new anchor.BN(paymentAmount)
+        )
+        .accounts({
+          fundingSource: fundingSourcePDA,
+          owner: custodian.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([custodian])
+        .rpc();
+    });
+
+    function createFromSource(name: string, delegate: Keypair = payer) {
+      return program.methods
+        .createPaymentAgreementFromSource(
+          name,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(delegate.publicKey, name),
+          payer: delegate.publicKey,
+          fundingSource: fundingSourcePDA,
+          referee: null,
+          receiverAccount: receiver.publicKey,
+          config: configPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([delegate])
+        .rpc();
+    }
+
+    it("Should pull exactly the amount from the source into the escrow", async () => {
+      const sourceBalanceBefore = await provider.connection.getBalance(
+        fundingSourcePDA
+      );
+
+      await createFromSource(paymentName);
+
+      const sourceBalanceAfter = await provider.connection.getBalance(
+        fundingSourcePDA
+      );
+      assert.equal(sourceBalanceBefore - sourceBalanceAfter, paymentAmount);
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      assert.equal(await escrowedLamports(pda), paymentAmount);
+
+      const fundingSource = await program.account.fundingSource.fetch(
+        fundingSourcePDA
+      );
+      assert.equal(fundingSource.allowance.toNumber(), 0);
+    });
+
+    it("Should reject pulls beyond the allowance", async () => {
+      await createFromSource(paymentName);
+
+      try {
+        await createFromSource("second-payment");
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "FundingAllowanceExceeded");
+      }
+    });
+
+    it("Should reject a signer that is not the delegate", async () => {
+      try {
+        await createFromSource(paymentName, maliciousUser);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidFundingDelegate");
+      }
+    });
+
+    it("Should refund a cancelled agreement to the source owner", async () => {
+      await createFromSource(paymentName);
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      assert.equal(
+        paymentAgreement.refundTo.toBase58(),
+        custodian.publicKey.toBase58()
+      );
+
+      const cancel = (signer: Keypair) =>
+        program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts({
+            paymentAgreement: pda,
+            signer: signer.publicKey,
+            payer: payer.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([
+            { pubkey: custodian.publicKey, isWritable: true, isSigner: false },
+          ])
+          .signers([signer])
+          .rpc();
+
+      const ownerBalanceBefore = await provider.connection.getBalance(
+        custodian.publicKey
+      );
+      await cancel(payer);
+      await cancel(receiver);
+
+      const ownerBalanceAfter = await provider.connection.getBalance(
+        custodian.publicKey
+      );
+      assert.equal(ownerBalanceAfter - ownerBalanceBefore, paymentAmount);
+    });
+
+    it("Should reject a refund without the source owner's account", async () => {
+      await createFromSource(paymentName);
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      try {
+        await program.methods
+          .declinePaymentAgreement(paymentName)
+          .accounts({
+            paymentAgreement: pda,
+            signer: receiver.publicKey,
+            payer: payer.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([receiver])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "MissingRefundAccount");
+      }
+    });
+  });
+
+  describe("Sealed-Bid Commit-Reveal", () => {
+    const payerSalt = Buffer.alloc(32, 1);
+    const receiverSalt = Buffer.alloc(32, 2);
+
+    function commitTo(value: number, salt: Buffer) {
+      const valueBytes = new anchor.BN(value).toArrayLike(Buffer, "le", 8);
+      return Array.from(
+        createHash("sha256").update(Buffer.concat([valueBytes, salt])).digest()
+      );
+    }
+
+    function receiverTermsAccounts() {
+      return {
+        paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+        receiver: receiver.publicKey,
+        payer: payer.publicKey,
+      };
+    }
+
+    async function runSealedBid(minAmount: number) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(0),
+          null,
+          { amountCommitment: commitTo(paymentAmount, payerSalt) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .commitReceiverTerms(paymentName, commitTo(minAmount, receiverSalt))
+        .accounts(receiverTermsAccounts())
+        .signers([receiver])
+        .rpc();
+
+      await program.methods
+        .revealPayerAmount(
+          paymentName,
+          new anchor.BN(paymentAmount),
+          Array.from(payerSalt)
+        )
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .revealReceiverTerms(
+          paymentName,
+          new anchor.BN(minAmount),
+          Array.from(receiverSalt)
+        )
+        .accounts(receiverTermsAccounts())
+        .signers([receiver])
+        .rpc();
+    }
+
+    async function approve(signer: Keypair) {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should complete after both sides reveal matching commitments", async () => {
+      await runSealedBid(paymentAmount / 2);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.receiverTermsRevealed, true);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(payer);
+      await approve(receiver);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      // The receiver paid the approval fee
+      assert.approximately(
+        receiverBalanceAfter - receiverBalanceBefore,
+        paymentAmount,
+        10000
+      );
+    });
+
+    it("Should reject a receiver reveal below their sealed minimum", async () => {
+      try {
+        await runSealedBid(paymentAmount * 2);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "SealedTermsNotMet");
+      }
+    });
+
+    it("Should block completion until the receiver reveals", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(0),
+          null,
+          { amountCommitment: commitTo(paymentAmount, payerSalt) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+      await program.methods
+        .commitReceiverTerms(paymentName, commitTo(1, receiverSalt))
+        .accounts(receiverTermsAccounts())
+        .signers([receiver])
+        .rpc();
+      await program.methods
+        .revealPayerAmount(
+          paymentName,
+          new anchor.BN(paymentAmount),
+          Array.from(payerSalt)
+        )
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([payer])
+        .rpc();
+
+      await approve(payer);
+      try {
+        await approve(receiver);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReceiverTermsNotRevealed");
+      }
+    });
+  });
+
+  describe("Single-Transaction Settlement Ordering", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should reject a cancel bundled after a completing approval", async () => {
+      const completeIx = await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .instruction();
+      const cancelIx = await program.methods
+        .refereeInterveneCancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .instruction();
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      const transaction = new anchor.web3.Transaction().add(completeIx, cancelIx);
+      try {
+        await provider.sendAndConfirm(transaction, [receiver, referee]);
+        assert.fail("Should have failed");
+      } catch (error) {
+        // The completing approval closed the agreement under the cancel
+        assert.include(
+          error.logs ? error.logs.join("\n") : error.message,
+          "AccountNotInitialized"
+        );
+      }
+
+      // The whole transaction rolled back, nothing moved
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+      assert.equal(
+        await provider.connection.getBalance(receiver.publicKey),
+        receiverBalanceBefore
+      );
+    });
+  });
+
+  describe("Relative Expiration", () => {
+    const thirtyDays = 30 * 24 * 60 * 60;
+
+    it("Should convert a duration to an absolute expiration", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { expirationDurationSeconds: new anchor.BN(thirtyDays) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(
+        paymentAgreement.expirationTimestamp.toNumber(),
+        paymentAgreement.createdAt.toNumber() + thirtyDays
+      );
+    });
+
+    it("Should reject both a duration and an absolute expiration", async () => {
+      const expiration = Math.floor(Date.now() / 1000) + thirtyDays;
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            new anchor.BN(expiration),
+            { expirationDurationSeconds: new anchor.BN(thirtyDays) }
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(payer.publicKey, paymentName)
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ConflictingExpiration");
+      }
+    });
+  });
+
+  describe("Referee Panel Vote Events", () => {
+    let panel: Keypair[];
+
+    beforeEach(async () => {
+      panel = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+      for (const panelReferee of panel) {
+        await provider.connection.requestAirdrop(
+          panelReferee.publicKey,
+          LAMPORTS_PER_SOL
+        );
+      }
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          {
+            referees: panel.map((panelReferee) => panelReferee.publicKey),
+            requiredVotes: 2,
+          }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    // Casts a vote and returns the events it emitted
+    async function vote(panelReferee: Keypair, decision: any) {
+      const signature = await program.methods
+        .refereeVote(paymentName, decision)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          signer: panelReferee.publicKey,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          config: configPDA,
+        })
+        .signers([panelReferee])
+        .rpc({ commitment: "confirmed" });
+
+      const transaction = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const parser = new anchor.EventParser(program.programId, program.coder);
+      return Array.from(parser.parseLogs(transaction.meta.logMessages));
+    }
+
+    it("Should emit the running tally with every vote", async () => {
+      const events = await vote(panel[0], { cancel: {} });
+
+      assert.equal(events.length, 1);
+      assert.equal(events[0].name, "refereeVoteCast");
+      assert.equal(events[0].data.completeVotes, 0);
+      assert.equal(events[0].data.cancelVotes, 1);
+      assert.equal(events[0].data.thresholdReached, false);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.cancelVotesBitmap, 1);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should emit the ruling once the majority is reached", async () => {
+      await vote(panel[0], { complete: {} });
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const events = await vote(panel[2], { complete: {} });
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      const voteCast = events.find((event) => event.name === "refereeVoteCast");
+      assert.equal(voteCast.data.completeVotes, 2);
+      assert.equal(voteCast.data.thresholdReached, true);
+
+      const ruling = events.find(
+        (event) => event.name === "refereeRulingExecuted"
+      );
+      assert.equal(ruling.data.recipient.toString(), receiver.publicKey.toString());
+      assert.equal(ruling.data.amount.toNumber(), paymentAmount);
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.completeVotesBitmap, 0b101);
+      assert.deepEqual(paymentAgreement.status, { refereeCompleted: {} });
+    });
+
+    it("Should not let a referee vote twice, even the other way", async () => {
+      await vote(panel[0], { complete: {} });
+
+      try {
+        await vote(panel[0], { cancel: {} });
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AlreadyVoted");
+      }
+    });
+
+    it("Should reject votes once the ruling has settled the agreement", async () => {
+      await vote(panel[0], { cancel: {} });
+      await vote(panel[1], { cancel: {} });
+
+      try {
+        await vote(panel[2], { complete: {} });
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementAlreadyCancelled");
+      }
+    });
+  });
+
+  describe("Idempotent Creation", () => {
+    async function create(amount: number, idempotent: boolean | null, options = {}) {
+      // Let the blockhash move on so a retry isn't deduplicated as the same transaction
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          null,
+          idempotent === null ? null : { idempotent, ...options }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should treat a matching retry as a no-op", async () => {
+      await create(paymentAmount, true);
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const escrowBefore = await escrowedLamports(pda);
+
+      await create(paymentAmount, true);
+
+      // Nothing was escrowed twice
+      assert.equal(await escrowedLamports(pda), escrowBefore);
+    });
+
+    it("Should reject a retry with different terms", async () => {
+      await create(paymentAmount, true);
+
+      try {
+        await create(paymentAmount * 2, true);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ConflictingExistingAgreement");
+      }
+    });
+
+    it("Should reject a retry that only changes an option", async () => {
+      await create(paymentAmount, true, { documentHash: Array(32).fill(1) });
+
+      try {
+        await create(paymentAmount, true, { documentHash: Array(32).fill(2) });
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ConflictingExistingAgreement");
+      }
+
+      try {
+        await create(paymentAmount, true, {
+          documentHash: Array(32).fill(1),
+          completionMode: { payerRelease: {} },
+        });
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ConflictingExistingAgreement");
+      }
+
+      // The same options again are still a no-op
+      await create(paymentAmount, true, { documentHash: Array(32).fill(1) });
+    });
+
+    it("Should still reject re-creation without the idempotent flag", async () => {
+      await create(paymentAmount, null);
+
+      try {
+        await create(paymentAmount, null);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "AgreementAlreadyExists");
+      }
+    });
+  });
+
+  describe("Partial Releases", () => {
+    const releaseAmount = paymentAmount / 4;
+
+    async function createAgreement(perReleaseDualApproval: boolean) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { perReleaseDualApproval }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function release(signer: Keypair, releaseIndex: number, amount: number) {
+      return program.methods
+        .releasePartialPayment(paymentName, releaseIndex, new anchor.BN(amount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should let the payer release part of the escrow alone", async () => {
+      await createAgreement(false);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await release(payer, 0, releaseAmount);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, releaseAmount);
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount - releaseAmount);
+      assert.equal(paymentAgreement.releasedAmount.toNumber(), releaseAmount);
+      assert.equal(paymentAgreement.releaseCount, 1);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should complete and close once everything is released", async () => {
+      await createAgreement(false);
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      await release(payer, 0, releaseAmount);
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await release(payer, 1, paymentAmount - releaseAmount);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(
+        receiverBalanceAfter - receiverBalanceBefore,
+        paymentAmount - releaseAmount
+      );
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+    });
+
+    it("Should not let the receiver release without dual approval", async () => {
+      await createAgreement(false);
+
+      try {
+        await release(receiver, 0, releaseAmount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should hold a release until both parties approve it", async () => {
+      await createAgreement(true);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await release(payer, 0, releaseAmount);
+
+      let paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(
+        await provider.connection.getBalance(receiver.publicKey),
+        receiverBalanceBefore
+      );
+      assert.equal(paymentAgreement.pendingRelease.payerApproved, true);
+      assert.equal(paymentAgreement.pendingRelease.receiverApproved, false);
+      assert.equal(paymentAgreement.releaseCount, 0);
+
+      await release(receiver, 0, releaseAmount);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      // The receiver paid the fee for the second approval
+      assert.isAbove(receiverBalanceAfter - receiverBalanceBefore, releaseAmount - 10000);
+      assert.equal(paymentAgreement.releasedAmount.toNumber(), releaseAmount);
+      assert.equal(paymentAgreement.releaseCount, 1);
+      assert.isNull(paymentAgreement.pendingRelease);
+    });
+
+    it("Should reject an approval for a different release amount", async () => {
+      await createAgreement(true);
+      await release(payer, 0, releaseAmount);
+
+      try {
+        await release(receiver, 0, releaseAmount * 2);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReleaseAmountMismatch");
+      }
+    });
+
+    it("Should reject an approval for a stale release index", async () => {
+      await createAgreement(false);
+      await release(payer, 0, releaseAmount);
+
+      try {
+        await release(payer, 0, releaseAmount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReleaseIndexMismatch");
+      }
+    });
+  });
+
+  describe("Completion Slot", () => {
+    it("Should record the slot of the completing transaction", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.completionSlot.toNumber(), 0);
+
+      for (const approver of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              approver.publicKey,
+              paymentName
+            )
+          )
+          .signers([approver])
+          .rpc({ commitment: "confirmed" });
+      }
+
+      const signatures = await provider.connection.getSignaturesForAddress(
+        getPaymentAgreementPDA(payer.publicKey, paymentName),
+        { limit: 1 },
+        "confirmed"
+      );
+      const transaction = await provider.connection.getTransaction(
+        signatures[0].signature,
+        { commitment: "confirmed", maxSupportedTransactionVersion: 0 }
+      );
+
+      // The completing approval closes the account, the event carries the slot
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+
+      const parser = new anchor.EventParser(program.programId, program.coder);
+      const events = Array.from(parser.parseLogs(transaction.meta.logMessages));
+      const completed = events.filter((event) => event.name === "agreementCompleted");
+      assert.lengthOf(completed, 1);
+      assert.equal(completed[0].data.completionSlot.toNumber(), transaction.slot);
+    });
+  });
+
+  describe("Proof Token Approval", () => {
+    // The referee keypair stands in for the carrier holding the proof token
+    let proofMint: PublicKey;
+    let carrierTokenAccount: PublicKey;
+
+    beforeEach(async () => {
+      proofMint = await createMint(
+        provider.connection,
+        referee,
+        referee.publicKey,
+        null,
+        0
+      );
+      carrierTokenAccount = await createAssociatedTokenAccount(
+        provider.connection,
+        referee,
+        proofMint,
+        referee.publicKey
+      );
+      await mintTo(
+        provider.connection,
+        referee,
+        proofMint,
+        carrierTokenAccount,
+        referee,
+        2
+      );
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { proofTokenMint: proofMint }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function proveAndApprove(mint: PublicKey, tokenAccount: PublicKey) {
+      const paymentAgreement = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      return program.methods
+        .proveAndApprove(paymentName)
+        .accounts({
+          paymentAgreement,
+          depositor: referee.publicKey,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          proofMint: mint,
+          depositorTokenAccount: tokenAccount,
+          proofVault: PublicKey.findProgramAddressSync(
+            [Buffer.from("proof_vault"), paymentAgreement.toBuffer()],
+            program.programId
+          )[0],
+          config: configPDA,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([referee])
+        .rpc();
+    }
+
+    it("Should complete when the proof arrives after the payer approves", async () => {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await proveAndApprove(proofMint, carrierTokenAccount);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      // Completing closes the agreement, the proof stays in the vault
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+
+      const carrierAccount = await getAccount(
+        provider.connection,
+        carrierTokenAccount
+      );
+      assert.equal(Number(carrierAccount.amount), 1);
+    });
+
+    it("Should reject a second proof deposit", async () => {
+      await proveAndApprove(proofMint, carrierTokenAccount);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.receiverApproved, true);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+
+      try {
+        await proveAndApprove(proofMint, carrierTokenAccount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ProofAlreadyUsed");
+      }
+    });
+
+    it("Should reject a token from a different mint", async () => {
+      const otherMint = await createMint(
+        provider.connection,
+        referee,
+        referee.publicKey,
+        null,
+        0
+      );
+      const otherTokenAccount = await createAssociatedTokenAccount(
+        provider.connection,
+        referee,
+        otherMint,
+        referee.publicKey
+      );
+      await mintTo(
+        provider.connection,
+        referee,
+        otherMint,
+        otherTokenAccount,
+        referee,
+        1
+      );
+
+      try {
+        await proveAndApprove(otherMint, otherTokenAccount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidProofToken");
+      }
+    });
+  });
+
+  describe("Release Schedule", () => {
+    const releaseAmount = paymentAmount / 4;
+
+    async function createAgreement(schedule: number[]) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { allowedReleaseTimestamps: schedule.map((ts) => new anchor.BN(ts)) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function release(releaseIndex: number) {
+      return program.methods
+        .releasePartialPayment(paymentName, releaseIndex, new anchor.BN(releaseAmount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should release chunks whose time has come and hold later ones", async () => {
+      const now = Math.floor(Date.now() / 1000);
+      await createAgreement([now - 100, now + 3600]);
+
+      await release(0);
+
+      try {
+        await release(1);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReleaseTooEarly");
+      }
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.releaseCount, 1);
+      assert.equal(paymentAgreement.releasedAmount.toNumber(), releaseAmount);
+    });
+
+    it("Should reject releasing out of order", async () => {
+      const now = Math.floor(Date.now() / 1000);
+      await createAgreement([now - 200, now - 100]);
+
+      try {
+        await release(1);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "ReleaseIndexMismatch");
+      }
+    });
+
+    it("Should reject a schedule that is not ascending", async () => {
+      const now = Math.floor(Date.now() / 1000);
+
+      try {
+        await createAgreement([now + 3600, now - 100]);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidReleaseSchedule");
+      }
+    });
+  });
+
+  describe("Non-Refundable Deposit", () => {
+    const deposit = paymentAmount / 10;
+
+    async function createAgreement(nonrefundableDeposit: number) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { nonrefundableDeposit: new anchor.BN(nonrefundableDeposit) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function cancel(signer: Keypair) {
+      return program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(payer.publicKey, signer.publicKey, paymentName)
+        )
+        .remainingAccounts([
+          { pubkey: receiver.publicKey, isWritable: true, isSigner: false },
+        ])
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should pay the deposit to the receiver on mutual cancellation", async () => {
+      await createAgreement(deposit);
+      await cancel(receiver);
+
+      const rent = await closingRent(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await cancel(payer);
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, deposit);
+      // The payer also paid the transaction fee, and gets the rent back
+      assert.isAbove(
+        payerBalanceAfter - payerBalanceBefore,
+        paymentAmount - deposit + rent - 10000
+      );
+      assert.isAtMost(
+        payerBalanceAfter - payerBalanceBefore,
+        paymentAmount - deposit + rent
+      );
+    });
+
+    it("Should refund everything when there is no deposit", async () => {
+      await createAgreement(0);
+      await cancel(receiver);
+
+      const rent = await closingRent(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      await cancel(payer);
+
+      assert.equal(
+        await provider.connection.getBalance(receiver.publicKey),
+        receiverBalanceBefore
+      );
+      assert.isAbove(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBalanceBefore,
+        paymentAmount + rent - 10000
+      );
+    });
+
+    it("Should reject a deposit that is not less than the amount", async () => {
+      try {
+        await createAgreement(paymentAmount);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidDeposit");
+      }
+    });
+  });
+
+  describe("Integrity Check", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function verifyIntegrity() {
+      return program.methods
+        .verifyIntegrity(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+    }
+
+    it("Should report a freshly funded escrow as healthy", async () => {
+      const status = await verifyIntegrity();
+
+      assert.equal(status.rentExempt, true);
+      assert.equal(status.outstandingAmount.toNumber(), paymentAmount);
+      assert.equal(status.shortfall.toNumber(), 0);
+      assert.equal(
+        status.balance.toNumber(),
+        status.rentExemptMinimum.toNumber() + paymentAmount
+      );
+    });
+
+    it("Should only count what is still owed after a partial release", async () => {
+      await program.methods
+        .releasePartialPayment(paymentName, 0, new anchor.BN(paymentAmount / 2))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      const status = await verifyIntegrity();
+      assert.equal(status.outstandingAmount.toNumber(), paymentAmount / 2);
+      assert.equal(status.shortfall.toNumber(), 0);
+    });
+  });
+
+  describe("Designated Payer Approver", () => {
+    // The referee keypair stands in for the organization's approver
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { payerApprover: referee.publicKey }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should not let the funder approve when an approver is set", async () => {
+      try {
+        await approve(payer);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should complete with the designated approver and the receiver", async () => {
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(referee);
+      await approve(receiver);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+      // The receiver paid the fee for its own approval
+      assert.isAbove(receiverBalanceAfter - receiverBalanceBefore, paymentAmount - 10000);
+    });
+
+    it("Should reject the receiver as the payer approver", async () => {
+      const otherName = "receiver-as-approver";
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            otherName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { payerApprover: receiver.publicKey }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, otherName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidPayerApprover");
+      }
+    });
+  });
+
+  describe("Gradual Expiry Refund", () => {
+    function withdraw() {
+      return program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should refund in chunks and close only once fully refunded", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+          { expiryRefundSchedule: { chunks: 2, intervalSeconds: new anchor.BN(3) } }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      // Wait for expiration
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      await withdraw();
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      assert.equal(paymentAgreement.refundedAmount.toNumber(), paymentAmount / 2);
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount / 2);
+      assert.deepEqual(paymentAgreement.status, { expired: {} });
+
+      // The second chunk vests one interval after the first withdrawal
+      try {
+        await withdraw();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "RefundNotVested");
+      }
+
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      await withdraw();
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      assert.isNull(await program.account.paymentAgreement.fetchNullable(pda));
+      // The last chunk plus the reclaimed rent, less the fee
+      assert.isAbove(payerBalanceAfter - payerBalanceBefore, paymentAmount / 2);
+    });
+
+    it("Should skip an unfinished refund in a batch close", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+          { expiryRefundSchedule: { chunks: 2, intervalSeconds: new anchor.BN(3) } }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+      await withdraw();
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const remaining = [pda, getVaultPDA(pda)].map((pubkey) => ({
+        pubkey,
+        isWritable: true,
+        isSigner: false,
+      }));
+      const closed = await program.methods
+        .closeTerminalAgreementsBatch()
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(remaining)
+        .signers([payer])
+        .view();
+      assert.equal(closed, 0);
+
+      await program.methods
+        .closeTerminalAgreementsBatch()
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(remaining)
+        .signers([payer])
+        .rpc();
+
+      // The second chunk is still owed, so the escrow stays put
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      assert.deepEqual(paymentAgreement.status, { expired: {} });
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount / 2);
+    });
+
+    it("Should reject a schedule without chunks", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            new anchor.BN(Math.floor(Date.now() / 1000) + 60),
+            { expiryRefundSchedule: { chunks: 0, intervalSeconds: new anchor.BN(3) } }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidRefundSchedule");
+      }
+    });
+  });
+
+  describe("Program Statistics", () => {
+    async function create(name: string) {
+      await program.methods
+        .createPaymentAgreement(
+          name,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, name))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should track volume and counts across a sequence of operations", async () => {
+      const before = await program.account.stats.fetch(statsPDA);
+
+      // One agreement completes, one is cancelled, one stays active
+      await create("stats-complete");
+      await create("stats-cancel");
+      await create("stats-active");
+
+      for (const approver of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement("stats-complete")
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              approver.publicKey,
+              "stats-complete"
+            )
+          )
+          .signers([approver])
+          .rpc();
+      }
+      for (const canceller of [payer, receiver]) {
+        await program.methods
+          .cancelPaymentAgreement("stats-cancel")
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              canceller.publicKey,
+              "stats-cancel"
+            )
+          )
+          .signers([canceller])
+          .rpc();
+      }
+
+      const after = await program.account.stats.fetch(statsPDA);
+      assert.equal(after.createdCount.sub(before.createdCount).toNumber(), 3);
+      assert.equal(
+        after.createdVolume.sub(before.createdVolume).toNumber(),
+        3 * paymentAmount
+      );
+      assert.equal(after.completedCount.sub(before.completedCount).toNumber(), 1);
+      assert.equal(
+        after.completedVolume.sub(before.completedVolume).toNumber(),
+        paymentAmount
+      );
+      assert.equal(after.cancelledCount.sub(before.cancelledCount).toNumber(), 1);
+      assert.equal(
+        after.cancelledVolume.sub(before.cancelledVolume).toNumber(),
+        paymentAmount
+      );
+      assert.equal(after.activeCount.sub(before.activeCount).toNumber(), 1);
+    });
+  });
+
+  describe("Weighted Approvals", () => {
+    async function createAgreement(
+      payerWeight: number,
+      receiverWeight: number,
+      weightThreshold: number
+    ) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          {
+            approverWeights: [
+              { approver: payer.publicKey, weight: payerWeight },
+              { approver: receiver.publicKey, weight: receiverWeight },
+            ],
+            weightThreshold,
+          }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function fetchAgreement() {
+      return program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+    }
+
+    // Completion closes the agreement
+    async function assertCompleted() {
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    }
+
+    it("Should let a heavy enough approver complete alone", async () => {
+      await createAgreement(2, 1, 2);
+      await approve(payer);
+
+      await assertCompleted();
+    });
+
+    it("Should not let a light approver complete alone", async () => {
+      await createAgreement(2, 1, 2);
+      await approve(receiver);
+
+      const paymentAgreement = await fetchAgreement();
+      assert.equal(paymentAgreement.approvedWeight, 1);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should complete once the combined weight meets the threshold", async () => {
+      await createAgreement(1, 1, 2);
+
+      await approve(payer);
+      assert.deepEqual((await fetchAgreement()).status, { pending: {} });
+
+      await approve(receiver);
+      await assertCompleted();
+    });
+
+    it("Should reject a threshold above the total weight", async () => {
+      try {
+        await createAgreement(1, 1, 3);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidWeightThreshold");
+      }
+    });
+
+    it("Should reject a zero weight", async () => {
+      try {
+        await createAgreement(0, 1, 1);
+        assert.fail("Should have failed");
+      } catch (error) {
+        assert.include(error.message, "InvalidApproverWeights");
+      }
+    });
+  });
+
+  describe("Reserved Referee Fee", () => {
+    const fee = paymentAmount / 20;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          {
+            refereeFee: { fixed: { lamports: new anchor.BN(fee) } },
+            reserveRefereeFee: true,
+          }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should escrow the fee on top of the amount", async () => {
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+
+      assert.equal(paymentAgreement.reservedRefereeFee.toNumber(), fee);
+      assert.equal(await escrowedLamports(pda), paymentAmount + fee);
+    });
+
+    it("Should return the reserve to the payer on completion without intervention", async () => {
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const rent = await closingRent(pda);
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+
+      // Closing on completion returns the rent along with the reserve
+      assert.equal(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBalanceBefore,
+        fee + rent
+      );
+      // The receiver paid the fee for its own approval
+      assert.isAbove(
+        (await provider.connection.getBalance(receiver.publicKey)) -
+          receiverBalanceBefore,
+        paymentAmount - 10000
+      );
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+    });
+
+    it("Should pay the reserve to the referee on intervention and the full amount to the receiver", async () => {
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const refereeBalanceBefore = await provider.connection.getBalance(
+        referee.publicKey
+      );
+
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) -
+          receiverBalanceBefore,
+        paymentAmount
+      );
+      // The referee also paid the transaction fee
+      assert.isAbove(
+        (await provider.connection.getBalance(referee.publicKey)) -
+          refereeBalanceBefore,
+        fee - 10000
+      );
+    });
+  });
+
+  describe("Partial Acceptance", () => {
+    const deliverableAmount = paymentAmount * 0.6;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function partialAccept(amount: number) {
+      return program.methods
+        .partialAccept(paymentName, new anchor.BN(amount))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          receiver: receiver.publicKey,
+          payer: payer.publicKey,
+        })
+        .signers([receiver])
+        .rpc();
+    }
+
+    function confirmPartialAccept(amount: number) {
+      return program.methods
+        .confirmPartialAccept(paymentName, new anchor.BN(amount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should pay the deliverable amount and refund the rest exactly", async () => {
+      await partialAccept(deliverableAmount);
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const escrowBefore =
+        (await provider.connection.getBalance(pda)) +
+        (await provider.connection.getBalance(getVaultPDA(pda)));
+      const rent = await closingRent(pda);
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      const signature = await confirmPartialAccept(deliverableAmount);
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      const escrowAfter =
+        (await provider.connection.getBalance(pda)) +
+        (await provider.connection.getBalance(getVaultPDA(pda)));
+
+      // The payer also gets the rent back when the agreement closes
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, deliverableAmount);
+      assert.equal(
+        payerBalanceAfter - payerBalanceBefore + tx.meta.fee,
+        paymentAmount - deliverableAmount + rent
+      );
+      assert.equal(escrowBefore - escrowAfter, paymentAmount + rent);
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+    });
+
+    it("Should reject a deliverable amount above the escrowed amount", async () => {
+      try {
+        await partialAccept(paymentAmount + 1);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidDeliverableAmount");
+      }
+    });
+
+    it("Should reject a zero deliverable amount", async () => {
+      try {
+        await partialAccept(0);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidDeliverableAmount");
+      }
+    });
+
+    it("Should require a proposal before the payer can confirm", async () => {
+      try {
+        await confirmPartialAccept(deliverableAmount);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "NoPartialAcceptance");
+      }
+    });
+
+    it("Should reject a confirmation for different terms", async () => {
+      await partialAccept(deliverableAmount);
+
+      try {
+        await confirmPartialAccept(deliverableAmount - 1);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "DeliverableAmountMismatch");
+      }
+    });
+
+    it("Should not let the receiver confirm their own proposal", async () => {
+      await partialAccept(deliverableAmount);
+
+      try {
+        await program.methods
+          .confirmPartialAccept(paymentName, new anchor.BN(deliverableAmount))
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              receiver.publicKey,
+              paymentName
+            )
+          )
+          .signers([receiver])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Completion Callback", () => {
+    const callbackProgramId = anchor.workspace.completionCallback.programId;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { completionCallback: callbackProgramId }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    afterEach(async () => {
+      await program.methods
+        .updateConfig({ callbackFailuresFatal: false })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    });
+
+    function receiverApproval(remainingAccounts: anchor.web3.AccountMeta[]) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .remainingAccounts(remainingAccounts)
+        .signers([receiver])
+        .rpc();
+    }
+
+    async function logsOf(signature: string) {
+      const tx = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      return tx.meta.logMessages.join("\n");
+    }
+
+    it("Should pass the completion notice to the callback program", async () => {
+      const signature = await receiverApproval([
+        { pubkey: callbackProgramId, isWritable: false, isSigner: false },
+      ]);
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const logs = await logsOf(signature);
+      assert.include(logs, `Program ${callbackProgramId.toBase58()} invoke [2]`);
+      assert.include(
+        logs,
+        `Relayed completion of ${pda.toBase58()} for ${paymentAmount} lamports`
+      );
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+    });
+
+    it("Should log and still pay out when the callback is unavailable", async () => {
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      const signature = await receiverApproval([]);
+
+      const logs = await logsOf(signature);
+      assert.include(logs, "unavailable, skipping");
+      assert.notInclude(logs, `Program ${callbackProgramId.toBase58()} invoke`);
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+
+      // The receiver paid the fee for their own approval
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      assert.isAbove(
+        receiverBalanceAfter - receiverBalanceBefore,
+        paymentAmount - 0.01 * LAMPORTS_PER_SOL
+      );
+    });
+
+    it("Should block completion on an unavailable callback when failures are fatal", async () => {
+      await program.methods
+        .updateConfig({ callbackFailuresFatal: true })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      try {
+        await receiverApproval([]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "CompletionCallbackUnavailable");
+      }
+
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should not treat a non-executable account as the callback", async () => {
+      await program.methods
+        .updateConfig({ callbackFailuresFatal: true })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+
+      try {
+        await receiverApproval([
+          { pubkey: maliciousUser.publicKey, isWritable: false, isSigner: false },
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "CompletionCallbackUnavailable");
+      }
+    });
+  });
+
+  describe("Escrow Balance Invariant", () => {
+    const pdaOf = () => getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+    // Lamports above rent must always equal what is still owed out of escrow
+    async function assertEscrowMatchesState() {
+      const pda = pdaOf();
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+      const accountInfo = await provider.connection.getAccountInfo(pda);
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        accountInfo.data.length
+      );
+
+      // The vault holds the escrow, the agreement only its own rent
+      assert.equal(
+        await escrowedLamports(pda),
+        paymentAgreement.amount.toNumber() +
+          paymentAgreement.reservedRefereeFee.toNumber()
+      );
+      assert.equal(accountInfo.lamports, rent);
+      assert.equal(
+        paymentAgreement.amount.toNumber(),
+        paymentAmount - paymentAgreement.releasedAmount.toNumber()
+      );
+    }
+
+    function release(releaseIndex: number, amount: number) {
+      return program.methods
+        .releasePartialPayment(paymentName, releaseIndex, new anchor.BN(amount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should hold exactly the outstanding amount across partial releases", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+      await assertEscrowMatchesState();
+
+      await release(0, paymentAmount / 4);
+      await assertEscrowMatchesState();
+
+      await release(1, paymentAmount / 2);
+      await assertEscrowMatchesState();
+    });
+
+    it("Should keep a reserved referee fee on top of the outstanding amount", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          {
+            refereeFee: { fixed: { lamports: new anchor.BN(paymentAmount / 20) } },
+            reserveRefereeFee: true,
+          }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+      await assertEscrowMatchesState();
+
+      await release(0, paymentAmount / 4);
+      await assertEscrowMatchesState();
+    });
+  });
+
+  describe("Partial Completion With Held Remainder", () => {
+    const immediateAmount = paymentAmount / 2;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function completePartial(signer: Keypair, amount: number) {
+      return program.methods
+        .completePartialHoldRest(paymentName, new anchor.BN(amount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function cancel(signer: Keypair) {
+      return program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should pay the immediate portion once both parties agree", async () => {
+      await completePartial(receiver, immediateAmount);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await completePartial(payer, immediateAmount);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, immediateAmount);
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount - immediateAmount);
+      assert.equal(paymentAgreement.releasedAmount.toNumber(), immediateAmount);
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+      assert.isFalse(paymentAgreement.payerApproved);
+      assert.isFalse(paymentAgreement.receiverApproved);
+    });
+
+    it("Should release the held remainder on a second approval round", async () => {
+      await completePartial(receiver, immediateAmount);
+      await completePartial(payer, immediateAmount);
+
+      await approve(receiver);
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(payer);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(
+        receiverBalanceAfter - receiverBalanceBefore,
+        paymentAmount - immediateAmount
+      );
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should refund the held remainder on mutual cancellation", async () => {
+      await completePartial(receiver, immediateAmount);
+      await completePartial(payer, immediateAmount);
+
+      await cancel(payer);
+      const rent = await closingRent(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      await cancel(receiver);
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      assert.equal(
+        payerBalanceAfter - payerBalanceBefore,
+        paymentAmount - immediateAmount + rent
+      );
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should reject an immediate portion equal to the whole amount", async () => {
+      try {
+        await completePartial(payer, paymentAmount);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidImmediateAmount");
+      }
+    });
+
+    it("Should reject a second party agreeing to a different split", async () => {
+      await completePartial(payer, immediateAmount);
+
+      try {
+        await completePartial(receiver, immediateAmount / 2);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReleaseAmountMismatch");
+      }
+    });
+
+    it("Should not let a third party agree to the split", async () => {
+      try {
+        await completePartial(maliciousUser, immediateAmount);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Ownership Transfer", () => {
+    // The unused referee keypair is already funded, so it stands in as the new payer
+    let newPayer: Keypair;
+
+    async function createAgreement(options = null) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          options
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function transferOwnership(to: PublicKey, withReceiver: boolean) {
+      return program.methods
+        .transferOwnership(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          newPaymentAgreement: getPaymentAgreementPDA(to, paymentName),
+          payer: payer.publicKey,
+          newPayer: to,
+          receiver: withReceiver ? receiver.publicKey : null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers(withReceiver ? [payer, receiver] : [payer])
+        .rpc();
+    }
+
+    beforeEach(() => {
+      newPayer = referee;
+    });
+
+    it("Should move funds and state to a PDA seeded by the new payer", async () => {
+      await createAgreement();
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+
+      const oldPda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const before = await program.account.paymentAgreement.fetch(oldPda);
+
+      await transferOwnership(newPayer.publicKey, false);
+
+      const newPda = getPaymentAgreementPDA(newPayer.publicKey, paymentName);
+      const after = await program.account.paymentAgreement.fetch(newPda);
+
+      assert.isNull(await provider.connection.getAccountInfo(oldPda));
+      assert.isNull(await provider.connection.getAccountInfo(getVaultPDA(oldPda)));
+      assert.equal(await escrowedLamports(newPda), paymentAmount);
+      assert.equal(after.payer.toBase58(), newPayer.publicKey.toBase58());
+      assert.equal(after.name, before.name);
+      assert.equal(after.receiver.toBase58(), before.receiver.toBase58());
+      assert.equal(after.amount.toNumber(), before.amount.toNumber());
+      assert.equal(after.createdAt.toNumber(), before.createdAt.toNumber());
+      assert.equal(after.receiverApproved, before.receiverApproved);
+    });
+
+    it("Should let the new payer complete the agreement", async () => {
+      await createAgreement();
+      await transferOwnership(newPayer.publicKey, false);
+
+      for (const signer of [newPayer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              newPayer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(newPayer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should reject transferring to the receiver", async () => {
+      await createAgreement();
+
+      try {
+        await transferOwnership(receiver.publicKey, false);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidNewPayer");
+      }
+    });
+
+    it("Should reject transferring to the referee", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      try {
+        await transferOwnership(referee.publicKey, false);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidNewPayer");
+      }
+    });
+
+    it("Should let the new payer cancel and lock out the old payer", async () => {
+      await createAgreement();
+      await transferOwnership(newPayer.publicKey, false);
+
+      // The old payer's PDA is gone, so the old key has nothing to act on
+      try {
+        await program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              payer.publicKey,
+              paymentName
+            )
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AccountNotInitialized");
+      }
+
+      const newPayerBalanceBefore = await provider.connection.getBalance(
+        newPayer.publicKey
+      );
+      for (const signer of [newPayer, receiver]) {
+        await program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              newPayer.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(newPayer.publicKey, paymentName)
+        )
+      );
+      // The escrow and the rent both go to the new payer
+      assert.isAbove(
+        (await provider.connection.getBalance(newPayer.publicKey)) -
+          newPayerBalanceBefore,
+        paymentAmount
+      );
+    });
+
+    it("Should require the receiver's consent when the agreement asks for it", async () => {
+      await createAgreement({ ownershipTransferNeedsReceiver: true });
+
+      try {
+        await transferOwnership(newPayer.publicKey, false);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverConsentRequired");
+      }
+
+      await transferOwnership(newPayer.publicKey, true);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(newPayer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.payer.toBase58(), newPayer.publicKey.toBase58());
+    });
+  });
+
+  describe("Payer Role Transfer", () => {
+    // The unused referee keypair is already funded, so it stands in as the new payer
+    let newPayer: Keypair;
+    let paymentAgreementPDA: PublicKey;
+
+    beforeEach(async () => {
+      newPayer = referee;
+      paymentAgreementPDA = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function transferPayerRole(from: Keypair, to: PublicKey) {
+      return program.methods
+        .transferPayerRole(paymentName, to)
+        .accounts({
+          paymentAgreement: paymentAgreementPDA,
+          payer: from.publicKey,
+          receiver: null,
+        })
+        .signers([from])
+        .rpc();
+    }
+
+    it("Should update the payer in place at an unchanged address", async () => {
+      await transferPayerRole(payer, newPayer.publicKey);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        paymentAgreementPDA
+      );
+      assert.equal(paymentAgreement.payer.toBase58(), newPayer.publicKey.toBase58());
+      assert.equal(
+        paymentAgreement.originalPayer.toBase58(),
+        payer.publicKey.toBase58()
+      );
+      assert.equal(await escrowedLamports(paymentAgreementPDA), paymentAmount);
+    });
+
+    it("Should let the new payer approve", async () => {
+      await transferPayerRole(payer, newPayer.publicKey);
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      // Still derived from the original payer, the new one signs and is paid back
+      for (const signer of [newPayer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts({
+            ...getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            ),
+            payer: newPayer.publicKey,
+          })
+          .signers([signer])
+          .rpc();
+      }
+
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) -
+          receiverBalanceBefore,
+        paymentAmount
+      );
+    });
+
+    it("Should let the new payer cancel and refund them", async () => {
+      await transferPayerRole(payer, newPayer.publicKey);
+      const rent = await closingRent(paymentAgreementPDA);
+      const newPayerBalanceBefore = await provider.connection.getBalance(
+        newPayer.publicKey
+      );
+
+      for (const signer of [newPayer, receiver]) {
+        await program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts({
+            ...getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              signer.publicKey,
+              paymentName
+            ),
+            payer: newPayer.publicKey,
+          })
+          .signers([signer])
+          .rpc();
+      }
+
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.equal(
+        (await provider.connection.getBalance(newPayer.publicKey)) -
+          newPayerBalanceBefore,
+        paymentAmount + rent
+      );
+    });
+
+    it("Should lock out the old payer", async () => {
+      await transferPayerRole(payer, newPayer.publicKey);
+
+      try {
+        await transferPayerRole(payer, payer.publicKey);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidPayer");
+      }
+    });
+
+    it("Should reject the receiver or the referee as the new payer", async () => {
+      try {
+        await transferPayerRole(payer, receiver.publicKey);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidNewPayer");
+      }
+
+      const refereeName = "with-referee";
+      paymentAgreementPDA = getPaymentAgreementPDA(payer.publicKey, refereeName);
+      await program.methods
+        .createPaymentAgreement(
+          refereeName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            refereeName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      try {
+        await program.methods
+          .transferPayerRole(refereeName, referee.publicKey)
+          .accounts({
+            paymentAgreement: paymentAgreementPDA,
+            payer: payer.publicKey,
+            receiver: null,
+          })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidNewPayer");
+      }
+    });
+  });
+
+  describe("Auto-Complete", () => {
+    async function createAgreement(autoCompleteInSeconds: number) {
+      const autoCompleteTimestamp =
+        Math.floor(Date.now() / 1000) + autoCompleteInSeconds;
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { autoCompleteTimestamp: new anchor.BN(autoCompleteTimestamp) }
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    function autoComplete() {
+      return program.methods
+        .autoComplete(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          config: configPDA,
+        })
+        .rpc();
+    }
+
+    function openDispute() {
+      return program.methods
+        .openDispute(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          signer: payer.publicKey,
+          payer: payer.publicKey,
+          config: configPDA,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should pay the receiver once the auto-complete time passes", async () => {
+      await createAgreement(2);
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await autoComplete();
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount);
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should not fire before the auto-complete time", async () => {
+      await createAgreement(3600);
+
+      try {
+        await autoComplete();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AutoCompleteNotReached");
+      }
+    });
+
+    it("Should be blocked by a dispute opened in time", async () => {
+      await createAgreement(2);
+      await openDispute();
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      try {
+        await autoComplete();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "DisputeAlreadyOpen");
+      }
+    });
+
+    it("Should not accept a dispute after the auto-complete time", async () => {
+      await createAgreement(2);
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      try {
+        await openDispute();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AutoCompletePassed");
+      }
+    });
+
+    it("Should require a referee to be able to object", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            {
+              autoCompleteTimestamp: new anchor.BN(
+                Math.floor(Date.now() / 1000) + 3600
+              ),
+            }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidAutoCompleteTimestamp");
+      }
+    });
+  });
+
+  describe("Lifecycle Events", () => {
+    async function eventsOf(signature: string) {
+      const transaction = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const parser = new anchor.EventParser(program.programId, program.coder);
+      return Array.from(parser.parseLogs(transaction.meta.logMessages));
+    }
+
+    function create(withReferee: boolean) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            withReferee ? referee.publicKey : undefined
+          )
+        )
+        .signers([payer])
+        .rpc({ commitment: "confirmed" });
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc({ commitment: "confirmed" });
+    }
+
+    function cancel(signer: Keypair) {
+      return program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc({ commitment: "confirmed" });
+    }
+
+    it("Should emit AgreementCreated with the terms", async () => {
+      const events = await eventsOf(await create(false));
+
+      const created = events.find((event) => event.name === "agreementCreated");
+      assert.equal(
+        created.data.paymentAgreement.toBase58(),
+        getPaymentAgreementPDA(payer.publicKey, paymentName).toBase58()
+      );
+      assert.equal(created.data.payer.toBase58(), payer.publicKey.toBase58());
+      assert.equal(created.data.receiver.toBase58(), receiver.publicKey.toBase58());
+      assert.equal(created.data.amount.toNumber(), paymentAmount);
+    });
+
+    it("Should only emit AgreementCompleted once both parties approve", async () => {
+      await create(false);
+
+      let events = await eventsOf(await approve(payer));
+      const approved = events.find((event) => event.name === "agreementApproved");
+      assert.equal(approved.data.approver.toBase58(), payer.publicKey.toBase58());
+      assert.isUndefined(events.find((event) => event.name === "agreementCompleted"));
+
+      events = await eventsOf(await approve(receiver));
+      const completed = events.find((event) => event.name === "agreementCompleted");
+      assert.equal(completed.data.amount.toNumber(), paymentAmount);
+      assert.isFalse(completed.data.refereeForced);
+    });
+
+    it("Should mark a referee completion as forced", async () => {
+      await create(true);
+
+      const signature = await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc({ commitment: "confirmed" });
+      const events = await eventsOf(signature);
+
+      const intervened = events.find((event) => event.name === "refereeIntervened");
+      assert.equal(intervened.data.referee.toBase58(), referee.publicKey.toBase58());
+      assert.isTrue(intervened.data.completed);
+
+      const completed = events.find((event) => event.name === "agreementCompleted");
+      assert.isTrue(completed.data.refereeForced);
+    });
+
+    it("Should only emit AgreementCancelled once both parties cancel", async () => {
+      await create(false);
+
+      let events = await eventsOf(await cancel(payer));
+      assert.isUndefined(events.find((event) => event.name === "agreementCancelled"));
+
+      events = await eventsOf(await cancel(receiver));
+      const cancelled = events.find((event) => event.name === "agreementCancelled");
+      assert.equal(cancelled.data.amount.toNumber(), paymentAmount);
+    });
+  });
+
+  describe("Release History Bound", () => {
+    const maxReleaseHistory = 10;
+    const releaseAmount = paymentAmount / 20;
+
+    function release(releaseIndex: number) {
+      return program.methods
+        .releasePartialPayment(paymentName, releaseIndex, new anchor.BN(releaseAmount))
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            payer.publicKey,
+            paymentName
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      for (let i = 0; i < maxReleaseHistory; i++) {
+        await release(i);
+      }
+    });
+
+    it("Should record every release up to the bound", async () => {
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+
+      assert.equal(paymentAgreement.releaseHistory.length, maxReleaseHistory);
+      for (const record of paymentAgreement.releaseHistory) {
+        assert.equal(record.amount.toNumber(), releaseAmount);
+        assert.isAbove(record.timestamp.toNumber(), 0);
+      }
+    });
+
+    it("Should reject a release past the bound with ReleaseHistoryFull", async () => {
+      try {
+        await release(maxReleaseHistory);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReleaseHistoryFull");
+      }
+    });
+
+    it("Should still settle the remainder through approval", async () => {
+      for (const signer of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+  });
+
+  describe("SPL Token Escrow", () => {
+    const tokenAmount = 400_000;
+    const mintedAmount = 1_000_000;
+    let mint: PublicKey;
+    let payerTokenAccount: PublicKey;
+    let receiverTokenAccount: PublicKey;
+
+    const pdaOf = () => getPaymentAgreementPDA(payer.publicKey, paymentName);
+    const escrowTokenAccountOf = () =>
+      getAssociatedTokenAddressSync(mint, pdaOf(), true);
+
+    beforeEach(async () => {
+      mint = await createMint(provider.connection, payer, payer.publicKey, null, 6);
+      payerTokenAccount = await createAssociatedTokenAccount(
+        provider.connection,
+        payer,
+        mint,
+        payer.publicKey
+      );
+      receiverTokenAccount = await createAssociatedTokenAccount(
+        provider.connection,
+        payer,
+        mint,
+        receiver.publicKey
+      );
+      await mintTo(
+        provider.connection,
+        payer,
+        mint,
+        payerTokenAccount,
+        payer,
+        mintedAmount
+      );
+    });
+
+    function createTokenAgreement(
+      expirationTimestamp: number | null = null,
+      receiverKey: PublicKey = receiver.publicKey
+    ) {
+      return program.methods
+        .createTokenPaymentAgreement(
+          paymentName,
+          receiverKey,
+          new anchor.BN(tokenAmount),
+          expirationTimestamp === null ? null : new anchor.BN(expirationTimestamp)
+        )
+        .accounts({
+          paymentAgreement: pdaOf(),
+          payer: payer.publicKey,
+          mint,
+          payerTokenAccount,
+          receiverAccount: receiverKey,
+          escrowTokenAccount: escrowTokenAccountOf(),
+          config: configPDA,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should reject a program-owned receiver", async () => {
+      try {
+        await createTokenAgreement(null, configPDA);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotOnCurve");
+      }
+    });
+
+    function approveToken(signer: Keypair) {
+      return program.methods
+        .approveTokenPaymentAgreement(paymentName)
+        .accounts({
+          paymentAgreement: pdaOf(),
+          signer: signer.publicKey,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          mint,
+          escrowTokenAccount: escrowTokenAccountOf(),
+          receiverTokenAccount,
+          config: configPDA,
+        })
+        .signers([signer])
+        .rpc();
+    }
+
+    function refundAccounts(signer: Keypair) {
+      return {
+        paymentAgreement: pdaOf(),
+        signer: signer.publicKey,
+        payer: payer.publicKey,
+        mint,
+        escrowTokenAccount: escrowTokenAccountOf(),
+        payerTokenAccount,
+        config: configPDA,
+      };
+    }
+
+    it("Should escrow the tokens in an account owned by the agreement PDA", async () => {
+      await createTokenAgreement();
+
+      const escrow = await getAccount(provider.connection, escrowTokenAccountOf());
+      assert.equal(Number(escrow.amount), tokenAmount);
+      assert.equal(escrow.owner.toBase58(), pdaOf().toBase58());
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pdaOf());
+      assert.equal(paymentAgreement.mint.toBase58(), mint.toBase58());
+      assert.equal(paymentAgreement.amount.toNumber(), tokenAmount);
+    });
+
+    it("Should pay the receiver in tokens and close the escrow account", async () => {
+      await createTokenAgreement();
+      await approveToken(payer);
+      await approveToken(receiver);
+
+      const receiverAccount = await getAccount(
+        provider.connection,
+        receiverTokenAccount
+      );
+      assert.equal(Number(receiverAccount.amount), tokenAmount);
+      assert.isNull(
+        await provider.connection.getAccountInfo(escrowTokenAccountOf())
+      );
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pdaOf());
+      assert.deepEqual(paymentAgreement.status, { completed: {} });
+    });
+
+    it("Should refund the tokens on mutual cancellation", async () => {
+      await createTokenAgreement();
+
+      for (const signer of [payer, receiver]) {
+        await program.methods
+          .cancelTokenPaymentAgreement(paymentName)
+          .accounts(refundAccounts(signer))
+          .signers([signer])
+          .rpc();
+      }
+
+      const payerAccount = await getAccount(provider.connection, payerTokenAccount);
+      assert.equal(Number(payerAccount.amount), mintedAmount);
+      assert.isNull(
+        await provider.connection.getAccountInfo(escrowTokenAccountOf())
+      );
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(pdaOf());
+      assert.deepEqual(paymentAgreement.status, { cancelled: {} });
+    });
+
+    it("Should refund the tokens and close the agreement after expiry", async () => {
+      await createTokenAgreement(Math.floor(Date.now() / 1000) + 2);
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      await program.methods
+        .withdrawExpiredTokenFunds(paymentName)
+        .accounts(refundAccounts(payer))
+        .signers([payer])
+        .rpc();
+
+      const payerAccount = await getAccount(provider.connection, payerTokenAccount);
+      assert.equal(Number(payerAccount.amount), mintedAmount);
+      assert.isNull(await provider.connection.getAccountInfo(pdaOf()));
+    });
+
+    it("Should reject the native instructions on a token agreement", async () => {
+      await createTokenAgreement();
+
+      try {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              payer.publicKey,
+              paymentName
+            )
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "WrongInstructionForAsset");
+      }
+    });
+
+    it("Should not let a third party approve", async () => {
+      await createTokenAgreement();
+
+      try {
+        await approveToken(maliciousUser);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Performance Fee", () => {
+    // 10% of the reference account's growth, capped at the escrowed amount
+    const feeBps = 1_000;
+
+    function referenceMeta() {
+      return [{ pubkey: referee.publicKey, isWritable: false, isSigner: false }];
+    }
+
+    async function createAgreement() {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { performanceFee: { feeBps, referenceAccount: referee.publicKey } }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .remainingAccounts(referenceMeta())
+        .signers([payer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .remainingAccounts(referenceMeta())
+        .signers([signer])
+        .rpc();
+    }
+
+    async function moveReferenceBalance(lamports: number) {
+      const transfer =
+        lamports > 0
+          ? SystemProgram.transfer({
+              fromPubkey: provider.wallet.publicKey,
+              toPubkey: referee.publicKey,
+              lamports,
+            })
+          : SystemProgram.transfer({
+              fromPubkey: referee.publicKey,
+              toPubkey: treasury.publicKey,
+              lamports: -lamports,
+            });
+      const tx = new anchor.web3.Transaction().add(transfer);
+      await provider.sendAndConfirm(tx, lamports > 0 ? [] : [referee]);
+    }
+
+    // Approves from both sides and returns what each party received out of
+    // the escrow, leaving out the rent returned when the agreement closes
+    async function settle() {
+      const rent = await closingRent(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      await approve(payer);
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      const receiverBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(receiver);
+      return {
+        toPayer:
+          (await provider.connection.getBalance(payer.publicKey)) - payerBefore - rent,
+        toReceiver:
+          (await provider.connection.getBalance(receiver.publicKey)) -
+          receiverBefore,
+      };
+    }
+
+    it("Should capture the reference balance at creation", async () => {
+      const balance = await provider.connection.getBalance(referee.publicKey);
+      await createAgreement();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.referenceBaseline.toNumber(), balance);
+      assert.equal(paymentAgreement.performanceFee.feeBps, feeBps);
+    });
+
+    it("Should pay the fee on the growth and refund the rest", async () => {
+      await createAgreement();
+      await moveReferenceBalance(LAMPORTS_PER_SOL / 2);
+
+      const { toPayer, toReceiver } = await settle();
+      assert.equal(toReceiver, LAMPORTS_PER_SOL / 20);
+      assert.equal(toPayer, paymentAmount - LAMPORTS_PER_SOL / 20);
+    });
+
+    it("Should cap the payout at the escrowed amount", async () => {
+      await createAgreement();
+      // 10% of 15 SOL is more than the 1 SOL escrowed
+      await moveReferenceBalance(15 * LAMPORTS_PER_SOL);
+
+      const { toPayer, toReceiver } = await settle();
+      assert.equal(toReceiver, paymentAmount);
+      assert.equal(toPayer, 0);
+    });
+
+    it("Should pay nothing when the reference balance fell", async () => {
+      await createAgreement();
+      await moveReferenceBalance(-LAMPORTS_PER_SOL / 2);
+
+      const { toPayer, toReceiver } = await settle();
+      assert.equal(toReceiver, 0);
+      assert.equal(toPayer, paymentAmount);
+    });
+
+    it("Should require the reference account to settle", async () => {
+      await createAgreement();
+      await approve(payer);
+
+      try {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              receiver.publicKey,
+              paymentName
+            )
+          )
+          .signers([receiver])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "MissingReferenceAccount");
+      }
+    });
+
+    it("Should reject a missing reference account or invalid fee at creation", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { performanceFee: { feeBps, referenceAccount: referee.publicKey } }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "MissingReferenceAccount");
+      }
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { performanceFee: { feeBps: 0, referenceAccount: referee.publicKey } }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .remainingAccounts(referenceMeta())
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidPerformanceFee");
+      }
+    });
+  });
+
+  describe("Dispute Window", () => {
+    const disputeWindowSeconds = 4;
+
+    async function createAgreement() {
+      const now = Math.floor(Date.now() / 1000);
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(now + 2),
+          { disputeWindowSeconds: new anchor.BN(disputeWindowSeconds) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function withdraw() {
+      return program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should block withdrawal until the window after expiration elapses", async () => {
+      await createAgreement();
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      const eligibility = await program.methods
+        .canPayerWithdrawExpired(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+      assert.equal(eligibility.canWithdraw, false);
+      assert.deepEqual(eligibility.reason, { disputeWindowActive: {} });
+
+      try {
+        await withdraw();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "DisputeWindowActive");
+      }
+
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+      await withdraw();
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should let the receiver approve during the window", async () => {
+      await createAgreement();
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+
+      // Once accepted the payer can no longer claw back, even after the window
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+      try {
+        await withdraw();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverHasApproved");
+      }
+    });
+  });
+
+  describe("Top Up", () => {
+    const topUp = paymentAmount / 2;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function increase(signer: Keypair, delta: number) {
+      return program.methods
+        .increasePaymentAmount(paymentName, new anchor.BN(delta))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: signer.publicKey,
+        })
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should pay the receiver the topped-up total on approval", async () => {
+      await increase(payer, topUp);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount + topUp);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      for (const signer of [payer, receiver]) {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              signer.publicKey,
+              paymentName
+            )
+          )
+          .signers([signer])
+          .rpc();
+      }
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, paymentAmount + topUp);
+    });
+
+    it("Should only let the payer top up", async () => {
+      // The agreement PDA is seeded by the payer, so another signer misses it
+      try {
+        await increase(maliciousUser, topUp);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.match(error.message, /ConstraintSeeds|AccountNotInitialized/);
+      }
+    });
+
+    it("Should reject a top-up once the agreement is cancelled", async () => {
+      await program.methods
+        .declinePaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(payer.publicKey, receiver.publicKey, paymentName)
+        )
+        .signers([receiver])
+        .rpc();
+
+      // Declining closed the agreement
+      try {
+        await increase(payer, topUp);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AccountNotInitialized");
+      }
+    });
+
+    it("Should reject a top-up that overflows the amount", async () => {
+      try {
+        await program.methods
+          .increasePaymentAmount(paymentName, new anchor.BN("18446744073709551615"))
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            payer: payer.publicKey,
+          })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AmountOverflow");
+      }
+    });
+  });
+
+  describe("Decrease Amount", () => {
+    const reducedAmount = paymentAmount / 2;
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function decrease(newAmount: number) {
+      return program.methods
+        .decreasePaymentAmount(paymentName, new anchor.BN(newAmount))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should refund the difference while the agreement is pending", async () => {
+      const payerBalanceBefore = await provider.connection.getBalance(payer.publicKey);
+      await decrease(reducedAmount);
+      const payerBalanceAfter = await provider.connection.getBalance(payer.publicKey);
+
+      assert.equal(payerBalanceAfter - payerBalanceBefore, paymentAmount - reducedAmount);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.amount.toNumber(), reducedAmount);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approve(payer);
+      await approve(receiver);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+
+      assert.equal(receiverBalanceAfter - receiverBalanceBefore, reducedAmount);
+    });
+
+    it("Should reject a decrease once the receiver has approved", async () => {
+      await approve(receiver);
+
+      try {
+        await decrease(reducedAmount);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverHasApproved");
+      }
+    });
+
+    it("Should reject a decrease below the minimum escrow amount", async () => {
+      try {
+        await decrease(1);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AmountBelowMinimum");
+      }
+    });
+
+    it("Should reject an amount that is not lower than the current one", async () => {
+      try {
+        await decrease(paymentAmount);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidAmount");
+      }
+    });
+  });
+
+  describe("Agreement Summary", () => {
+    function getSummary() {
+      return program.methods
+        .getAgreementSummary(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+    }
+
+    it("Should summarize an agreement without an expiration", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+
+      const summary = await getSummary();
+      assert.deepEqual(summary.status, { pending: {} });
+      assert.equal(summary.amount.toNumber(), paymentAmount);
+      assert.equal(summary.payer.toBase58(), payer.publicKey.toBase58());
+      assert.equal(summary.receiver.toBase58(), receiver.publicKey.toBase58());
+      assert.equal(summary.referee.toBase58(), referee.publicKey.toBase58());
+      assert.isFalse(summary.isExpired);
+    });
+
+    it("Should report expiry once the expiration passes", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      assert.isFalse((await getSummary()).isExpired);
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+      const summary = await getSummary();
+      assert.isTrue(summary.isExpired);
+      assert.isNull(summary.referee);
+    });
+  });
+
+  describe("Creation Funding Check", () => {
+    it("Should fail cleanly when the payer only holds the amount", async () => {
+      const underfundedPayer = Keypair.generate();
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          SystemProgram.transfer({
+            fromPubkey: provider.wallet.publicKey,
+            toPubkey: underfundedPayer.publicKey,
+            lamports: paymentAmount,
+          })
+        )
+      );
+
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(underfundedPayer.publicKey, paymentName)
+          )
+          .signers([underfundedPayer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InsufficientFundsForAmountAndRent");
+      }
+
+      // Nothing was created and the payer kept everything
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(underfundedPayer.publicKey, paymentName)
+        )
+      );
+      assert.equal(
+        await provider.connection.getBalance(underfundedPayer.publicKey),
+        paymentAmount
+      );
+    });
+  });
+
+  describe("Split Payment Agreements", () => {
+    const splitPDA = () =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("split_payment"), payer.publicKey.toBuffer(), Buffer.from(paymentName)],
+        program.programId
+      )[0];
+
+    function createSplit(amount: number, shares: [PublicKey, number][]) {
+      return program.methods
+        .createSplitPaymentAgreement(
+          paymentName,
+          new anchor.BN(amount),
+          shares.map(([receiver, share]) => ({ receiver, amount: new anchor.BN(share) }))
+        )
+        .accounts({ payer: payer.publicKey })
+        .signers([payer])
+        .rpc();
+    }
+
+    function completeSplit(receivers: PublicKey[]) {
+      return program.methods
+        .completeSplitPaymentAgreement(paymentName)
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(
+          receivers.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false }))
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should pay every receiver its share and close the escrow", async () => {
+      const contractorShare = (paymentAmount * 3) / 4;
+      const subcontractorShare = paymentAmount / 4;
+      await createSplit(paymentAmount, [
+        [receiver.publicKey, contractorShare],
+        [referee.publicKey, subcontractorShare],
+      ]);
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      const refereeBefore = await provider.connection.getBalance(referee.publicKey);
+      await completeSplit([receiver.publicKey, referee.publicKey]);
+
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        contractorShare
+      );
+      assert.equal(
+        (await provider.connection.getBalance(referee.publicKey)) - refereeBefore,
+        subcontractorShare
+      );
+      assert.isNull(await provider.connection.getAccountInfo(splitPDA()));
+    });
+
+    function settleSplitRange(start: number, end: number, receivers: PublicKey[]) {
+      return program.methods
+        .settleSplitRange(paymentName, start, end)
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts(
+          receivers
+            .slice(start, end)
+            .map((pubkey) => ({ pubkey, isWritable: true, isSigner: false }))
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should pay ten receivers in two ranges and then close the escrow", async () => {
+      const receivers = Array.from({ length: 10 }, () => Keypair.generate().publicKey);
+      const share = paymentAmount / 10;
+      await createSplit(
+        paymentAmount,
+        receivers.map((pubkey) => [pubkey, share] as [PublicKey, number])
+      );
+
+      await settleSplitRange(0, 5, receivers);
+      let escrow = await program.account.splitPaymentAgreement.fetch(splitPDA());
+      assert.equal(escrow.paidBitmap, 0b0000011111);
+
+      await settleSplitRange(5, 10, receivers);
+      escrow = await program.account.splitPaymentAgreement.fetch(splitPDA());
+      assert.equal(escrow.paidBitmap, 0b1111111111);
+
+      let total = 0;
+      for (const pubkey of receivers) {
+        const balance = await provider.connection.getBalance(pubkey);
+        assert.equal(balance, share);
+        total += balance;
+      }
+      assert.equal(total, paymentAmount);
+
+      try {
+        await settleSplitRange(3, 4, receivers);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "SplitShareAlreadyPaid");
+      }
+
+      // Every share is paid, so completing only closes the account
+      await completeSplit(receivers);
+      assert.isNull(await provider.connection.getAccountInfo(splitPDA()));
+    });
+
+    it("Should reject shares that do not sum to the amount", async () => {
+      try {
+        await createSplit(paymentAmount, [
+          [receiver.publicKey, paymentAmount / 2],
+          [referee.publicKey, paymentAmount / 4],
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "SplitSumMismatch");
+      }
+    });
+
+    it("Should reject the payer as a receiver", async () => {
+      try {
+        await createSplit(paymentAmount, [
+          [receiver.publicKey, paymentAmount / 2],
+          [payer.publicKey, paymentAmount / 2],
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidSplitReceivers");
+      }
+    });
+
+    it("Should reject receivers passed out of order", async () => {
+      await createSplit(paymentAmount, [
+        [receiver.publicKey, paymentAmount / 2],
+        [referee.publicKey, paymentAmount / 2],
+      ]);
+
+      try {
+        await completeSplit([referee.publicKey, receiver.publicKey]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidReceiver");
+      }
+    });
+  });
+
+  describe("Extend Expiration", () => {
+    async function createAgreement(expirationTimestamp: anchor.BN | null) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          expirationTimestamp,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function extend(signer: Keypair, newExpiration: number) {
+      return program.methods
+        .extendExpiration(paymentName, new anchor.BN(newExpiration))
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: signer.publicKey,
+        })
+        .signers([signer])
+        .rpc();
+    }
+
+    function fetchExpiration() {
+      return program.account.paymentAgreement
+        .fetch(getPaymentAgreementPDA(payer.publicKey, paymentName))
+        .then((paymentAgreement) => paymentAgreement.expirationTimestamp);
+    }
+
+    it("Should set an expiration on an agreement without one", async () => {
+      await createAgreement(null);
+      const newExpiration = Math.floor(Date.now() / 1000) + 3600;
+
+      await extend(payer, newExpiration);
+
+      assert.equal((await fetchExpiration()).toNumber(), newExpiration);
+    });
+
+    it("Should push the expiration later", async () => {
+      const now = Math.floor(Date.now() / 1000);
+      await createAgreement(new anchor.BN(now + 3600));
+
+      await extend(payer, now + 7200);
+
+      assert.equal((await fetchExpiration()).toNumber(), now + 7200);
+    });
+
+    it("Should reject an earlier or equal timestamp", async () => {
+      const now = Math.floor(Date.now() / 1000);
+      await createAgreement(new anchor.BN(now + 3600));
+
+      for (const newExpiration of [now + 1800, now + 3600]) {
+        try {
+          await extend(payer, newExpiration);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "ExpirationMustBeInFuture");
+        }
+      }
+      assert.equal((await fetchExpiration()).toNumber(), now + 3600);
+    });
+
+    it("Should reject a timestamp in the past when none was set", async () => {
+      await createAgreement(null);
+
+      try {
+        await extend(payer, Math.floor(Date.now() / 1000) - 60);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ExpirationMustBeInFuture");
+      }
+    });
+  });
+
+  describe("Receiver Decline", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function decline(signer: Keypair) {
+      return program.methods
+        .declinePaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should refund the payer and close in a single instruction", async () => {
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const rent = await closingRent(pda);
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      await decline(receiver);
+
+      const payerBalanceAfter = await provider.connection.getBalance(
+        payer.publicKey
+      );
+      assert.equal(payerBalanceAfter - payerBalanceBefore, paymentAmount + rent);
+      assert.isNull(await provider.connection.getAccountInfo(pda));
+      assert.isNull(await provider.connection.getAccountInfo(getVaultPDA(pda)));
+    });
+
+    it("Should only let the receiver decline", async () => {
+      for (const signer of [payer, maliciousUser]) {
+        try {
+          await decline(signer);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "Unauthorized");
+        }
+      }
+    });
+
+    it("Should not decline a settled agreement", async () => {
+      await decline(receiver);
+
+      try {
+        await decline(receiver);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AccountNotInitialized");
+      }
+    });
+  });
+
+  describe("Memo", () => {
+    function createWithMemo(memo: string) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { memo }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should store the memo and expose it in the summary", async () => {
+      const memo = "Invoice #1042: design work for March, net 30";
+      await createWithMemo(memo);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.memo, memo);
+
+      const summary = await program.methods
+        .getAgreementSummary(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+      assert.equal(summary.memo, memo);
+    });
+
+    it("Should accept a memo of exactly 200 bytes", async () => {
+      await createWithMemo("m".repeat(200));
+    });
+
+    it("Should reject a memo longer than 200 bytes", async () => {
+      // Multi-byte characters count by their encoded length
+      try {
+        await createWithMemo("é".repeat(101));
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidMemo");
+      }
+    });
+  });
+
+  describe("Mandatory Referee Threshold", () => {
+    // Must match MANDATORY_REFEREE_THRESHOLD in account.rs
+    const threshold = 100 * LAMPORTS_PER_SOL;
+
+    beforeEach(async () => {
+      await provider.connection.requestAirdrop(
+        payer.publicKey,
+        2 * threshold
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    });
+
+    function create(amount: number, withReferee: boolean) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            withReferee ? referee.publicKey : undefined
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should keep the referee optional at exactly the threshold", async () => {
+      await create(threshold, false);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.referee, null);
+    });
+
+    it("Should require a referee one lamport above the threshold", async () => {
+      try {
+        await create(threshold + 1, false);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "RefereeRequiredForLargeAmount");
+      }
+
+      await create(threshold + 1, true);
+    });
+
+    it("Should not let a top-up cross the threshold without a referee", async () => {
+      await create(threshold, false);
+
+      try {
+        await program.methods
+          .increasePaymentAmount(paymentName, new anchor.BN(1))
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            payer: payer.publicKey,
+          })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "RefereeRequiredForLargeAmount");
+      }
+    });
+  });
+
+  describe("Milestones", () => {
+    const milestoneAmounts = [0.2, 0.3, 0.5].map((sol) => sol * LAMPORTS_PER_SOL);
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { milestones: milestoneAmounts.map((amount) => new anchor.BN(amount)) }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function approveMilestone(signer: Keypair, index: number) {
+      return program.methods
+        .approveMilestone(paymentName, index)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    async function releaseMilestone(index: number) {
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      await approveMilestone(payer, index);
+      await approveMilestone(receiver, index);
+      const receiverBalanceAfter = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      return receiverBalanceAfter - receiverBalanceBefore;
+    }
+
+    it("Should only release a milestone once both parties approve", async () => {
+      await approveMilestone(payer, 1);
+
+      let paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isTrue(paymentAgreement.milestones[1].payerApproved);
+      assert.isFalse(paymentAgreement.milestones[1].released);
+      assert.equal(paymentAgreement.amount.toNumber(), paymentAmount);
+
+      await approveMilestone(receiver, 1);
+
+      paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isTrue(paymentAgreement.milestones[1].released);
+      assert.equal(paymentAgreement.releasedAmount.toNumber(), milestoneAmounts[1]);
+      assert.equal(
+        paymentAgreement.amount.toNumber(),
+        paymentAmount - milestoneAmounts[1]
+      );
+      assert.deepEqual(paymentAgreement.status, { pending: {} });
+    });
+
+    it("Should pay each milestone and close after the last one", async () => {
+      for (const [index, amount] of milestoneAmounts.entries()) {
+        assert.equal(await releaseMilestone(index), amount);
+      }
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should not release a milestone twice", async () => {
+      await releaseMilestone(0);
+
+      try {
+        await approveMilestone(payer, 0);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "MilestoneAlreadyReleased");
+      }
+    });
+
+    it("Should reject an unknown milestone index", async () => {
+      try {
+        await approveMilestone(payer, milestoneAmounts.length);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidMilestoneIndex");
+      }
+    });
+
+    it("Should reject milestones that do not add up to the amount", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            "milestone-mismatch",
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { milestones: [new anchor.BN(paymentAmount / 2)] }
+          )
+          .accounts(
+            getCreatePaymentAgreementAccounts(payer.publicKey, "milestone-mismatch")
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidMilestones");
+      }
+    });
+  });
+
+  describe("Fallback Receiver", () => {
+    let fallback: Keypair;
+
+    beforeEach(() => {
+      fallback = Keypair.generate();
+    });
+
+    async function createExpiring(fallbackReceiver: PublicKey | null) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + 2),
+          { fallbackReceiver }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+    }
+
+    function withdraw(remainingAccounts: PublicKey[]) {
+      return program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .remainingAccounts(
+          remainingAccounts.map((pubkey) => ({
+            pubkey,
+            isWritable: true,
+            isSigner: false,
+          }))
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should send the expired escrow to the fallback receiver", async () => {
+      await createExpiring(fallback.publicKey);
+      const rent = await closingRent(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      await withdraw([fallback.publicKey]);
+
+      assert.equal(
+        await provider.connection.getBalance(fallback.publicKey),
+        paymentAmount
+      );
+      // Only the rent comes back to the payer
+      assert.equal(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBalanceBefore,
+        rent
+      );
+    });
+
+    it("Should refund the payer when no fallback is set", async () => {
+      await createExpiring(null);
+      const payerBalanceBefore = await provider.connection.getBalance(
+        payer.publicKey
+      );
+
+      await withdraw([]);
+
+      assert.isAbove(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBalanceBefore,
+        paymentAmount
+      );
+    });
+
+    it("Should reject a missing or mismatched fallback account", async () => {
+      await createExpiring(fallback.publicKey);
+
+      for (const remainingAccounts of [[], [maliciousUser.publicKey]]) {
+        try {
+          await withdraw(remainingAccounts);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "InvalidFallbackReceiver");
+        }
+      }
+    });
+
+    it("Should only let the payer withdraw", async () => {
+      await createExpiring(fallback.publicKey);
+
+      try {
+        await program.methods
+          .withdrawExpiredFunds(paymentName)
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            payer: fallback.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([
+            { pubkey: fallback.publicKey, isWritable: true, isSigner: false },
+          ])
+          .signers([fallback])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+  });
+
+  describe("Settlement Timestamps", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function getSummary() {
+      return program.methods
+        .getAgreementSummary(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+    }
+
+    it("Should report created_at and no settled_at while pending", async () => {
+      const summary = await getSummary();
+
+      assert.approximately(
+        summary.createdAt.toNumber(),
+        Math.floor(Date.now() / 1000),
+        30
+      );
+      assert.isNull(summary.settledAt);
+    });
+
+    it("Should stamp settled_at when the agreement reaches a terminal status", async () => {
+      // A referee cancel leaves the account open to read back
+      const signature = await program.methods
+        .refereeInterveneCancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(
+            payer.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc({ commitment: "confirmed" });
+
+      const summary = await getSummary();
+      assert.isNotNull(summary.settledAt);
+      assert.isAtLeast(summary.settledAt.toNumber(), summary.createdAt.toNumber());
+
+      const transaction = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const parser = new anchor.EventParser(program.programId, program.coder);
+      const cancelled = Array.from(
+        parser.parseLogs(transaction.meta.logMessages)
+      ).find((event) => event.name === "agreementCancelled");
+      assert.equal(
+        cancelled.data.createdAt.toNumber(),
+        summary.createdAt.toNumber()
+      );
+      assert.equal(
+        cancelled.data.timestamp.toNumber(),
+        summary.settledAt.toNumber()
+      );
+    });
+  });
+
+  describe("Schema Version", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should create agreements at the current schema version", async () => {
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.version, 13);
+    });
+
+    it("Should leave a current agreement untouched when anyone migrates it", async () => {
+      const pda = getPaymentAgreementPDA(payer.publicKey, paymentName);
+      const before = await provider.connection.getAccountInfo(pda);
+
+      await program.methods
+        .migrateAgreement()
+        .accounts({ paymentAgreement: pda, feePayer: maliciousUser.publicKey })
+        .signers([maliciousUser])
+        .rpc();
+
+      const after = await provider.connection.getAccountInfo(pda);
+      assert.isTrue(after.data.equals(before.data));
+      assert.equal(after.lamports, before.lamports);
+    });
+
+    it("Should reject accounts that are not payment agreements", async () => {
+      try {
+        await program.methods
+          .migrateAgreement()
+          .accounts({ paymentAgreement: statsPDA, feePayer: payer.publicKey })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidAgreementAccount");
+      }
+    });
+  });
+
+  describe("Minimum Escrow Amount", () => {
+    // Must match MIN_ESCROW_AMOUNT in account.rs
+    const minEscrowAmount = 1_000_000;
+
+    function create(name: string, amount: number) {
+      return program.methods
+        .createPaymentAgreement(
+          name,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, name))
+        .signers([payer])
+        .rpc();
+    }
+
+    for (const amount of [0, minEscrowAmount - 1]) {
+      it(`Should reject an amount of ${amount} lamports`, async () => {
+        try {
+          await create(paymentName, amount);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "AmountBelowMinimum");
+        }
+      });
+    }
+
+    it("Should accept the minimum and anything above it", async () => {
+      await create("min-exact", minEscrowAmount);
+      await create("min-plus-one", minEscrowAmount + 1);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, "min-exact")
+      );
+      assert.equal(paymentAgreement.amount.toNumber(), minEscrowAmount);
+    });
+  });
+
+  describe("Protocol Fee", () => {
+    // Not a round number, so the fee has a fractional lamport to drop
+    const amount = 1_000_003;
+    const feeBasisPoints = 250;
+    let feeCollector: Keypair;
+
+    beforeEach(async () => {
+      feeCollector = Keypair.generate();
+      await provider.connection.requestAirdrop(
+        feeCollector.publicKey,
+        LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          null,
+          { feeBasisPoints, feeCollector: feeCollector.publicKey }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function approve(signer: Keypair, remainingAccounts: PublicKey[]) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .remainingAccounts(
+          remainingAccounts.map((pubkey) => ({
+            pubkey,
+            isWritable: true,
+            isSigner: false,
+          }))
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should floor the fee and split the amount between receiver and collector", async () => {
+      await approve(payer, []);
+
+      const receiverBalanceBefore = await provider.connection.getBalance(
+        receiver.publicKey
+      );
+      const collectorBalanceBefore = await provider.connection.getBalance(
+        feeCollector.publicKey
+      );
+      await approve(receiver, [feeCollector.publicKey]);
+      const received =
+        (await provider.connection.getBalance(receiver.publicKey)) -
+        receiverBalanceBefore;
+      const collected =
+        (await provider.connection.getBalance(feeCollector.publicKey)) -
+        collectorBalanceBefore;
+
+      // 2.5% of 1000003 is 25000.075
+      assert.equal(collected, 25000);
+      assert.equal(received, amount - 25000);
+      assert.equal(received + collected, amount);
+    });
+
+    it("Should itemize the fee in the payout breakdown", async () => {
+      const breakdown = await program.methods
+        .getPayoutBreakdown(paymentName, false)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+
+      assert.equal(breakdown.platformFee.toNumber(), 25000);
+      assert.equal(breakdown.receiverShare.toNumber(), amount - 25000);
+    });
+
+    it("Should require the stored fee collector to complete", async () => {
+      await approve(payer, []);
+
+      for (const remainingAccounts of [[], [maliciousUser.publicKey]]) {
+        try {
+          await approve(receiver, remainingAccounts);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "InvalidFeeCollector");
+        }
+      }
+    });
+
+    it("Should reject a fee above 10000 basis points", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            "fee-too-large",
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { feeBasisPoints: 10001, feeCollector: feeCollector.publicKey }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, "fee-too-large"))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidProtocolFee");
+      }
+    });
+  });
+
+  describe("Recurring Agreements", () => {
+    const perPeriodAmount = paymentAmount / 4;
+    const intervalSeconds = 2;
+
+    const recurringPDA = () =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("recurring"), payer.publicKey.toBuffer(), Buffer.from(paymentName)],
+        program.programId
+      )[0];
+
+    function createRecurring(numPeriods: number, amount = perPeriodAmount) {
+      return program.methods
+        .createRecurringAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(amount),
+          new anchor.BN(intervalSeconds),
+          numPeriods
+        )
+        .accounts({ payer: payer.publicKey })
+        .signers([payer])
+        .rpc();
+    }
+
+    function claimPeriod(signer: Keypair) {
+      return program.methods
+        .claimPeriod(paymentName)
+        .accounts({ receiver: signer.publicKey, payer: payer.publicKey })
+        .signers([signer])
+        .rpc();
+    }
+
+    const waitForPeriod = () =>
+      new Promise((resolve) => setTimeout(resolve, (intervalSeconds + 1) * 1000));
+
+    it("Should fund every period up front", async () => {
+      await createRecurring(3);
+
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(
+        (await provider.connection.getAccountInfo(recurringPDA())).data.length
+      );
+      assert.equal(
+        await provider.connection.getBalance(recurringPDA()),
+        rent + perPeriodAmount * 3
+      );
+    });
+
+    it("Should pay one period per interval and close after the last", async () => {
+      await createRecurring(2);
+
+      try {
+        await claimPeriod(receiver);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "PeriodNotElapsed");
+      }
+
+      await waitForPeriod();
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      await claimPeriod(receiver);
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        perPeriodAmount
+      );
+
+      let recurring = await program.account.recurringAgreement.fetch(recurringPDA());
+      assert.equal(recurring.periodsClaimed, 1);
+
+      // The interval restarts from the claim
+      try {
+        await claimPeriod(receiver);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "PeriodNotElapsed");
+      }
+
+      await waitForPeriod();
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      const rent = await provider.connection.getBalance(recurringPDA()) - perPeriodAmount;
+      await claimPeriod(receiver);
+
+      assert.isNull(await provider.connection.getAccountInfo(recurringPDA()));
+      assert.equal(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBefore,
+        rent
+      );
+    });
+
+    it("Should only let the receiver claim", async () => {
+      await createRecurring(2);
+      await waitForPeriod();
+
+      try {
+        await claimPeriod(maliciousUser);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should refund the unclaimed periods to the payer on cancellation", async () => {
+      await createRecurring(3);
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      const escrowBalance = await provider.connection.getBalance(recurringPDA());
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      await program.methods
+        .cancelRecurringAgreement(paymentName)
+        .accounts({ payer: payer.publicKey, receiver: receiver.publicKey })
+        .signers([payer])
+        .rpc();
+
+      assert.isNull(await provider.connection.getAccountInfo(recurringPDA()));
+      // No period had elapsed, so everything goes back to the payer
+      assert.equal(
+        await provider.connection.getBalance(receiver.publicKey),
+        receiverBefore
+      );
+      assert.equal(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBefore,
+        escrowBalance
+      );
+    });
+
+    it("Should pay an elapsed period to the receiver before refunding", async () => {
+      await createRecurring(3);
+      await waitForPeriod();
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      const escrowBalance = await provider.connection.getBalance(recurringPDA());
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      await program.methods
+        .cancelRecurringAgreement(paymentName)
+        .accounts({ payer: payer.publicKey, receiver: receiver.publicKey })
+        .signers([payer])
+        .rpc();
+
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        perPeriodAmount
+      );
+      assert.equal(
+        (await provider.connection.getBalance(payer.publicKey)) - payerBefore,
+        escrowBalance - perPeriodAmount
+      );
+    });
+
+    it("Should reject a schedule without periods", async () => {
+      try {
+        await createRecurring(0);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidRecurringTerms");
+      }
+    });
+  });
+
+  describe("Referee Pause", () => {
+    async function createAgreement(expiration: number | null = null) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          expiration === null ? null : new anchor.BN(expiration),
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    }
+
+    function pauseAccounts(signer: Keypair) {
+      return {
+        paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+        signer: signer.publicKey,
+        payer: payer.publicKey,
+        config: configPDA,
+      };
+    }
+
+    function pause(blockWithdrawal: boolean, signer = referee) {
+      return program.methods
+        .refereePause(paymentName, blockWithdrawal)
+        .accounts(pauseAccounts(signer))
+        .signers([signer])
+        .rpc();
+    }
+
+    function resume(signer = referee) {
+      return program.methods
+        .refereeResume(paymentName)
+        .accounts(pauseAccounts(signer))
+        .signers([signer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function withdrawExpired() {
+      return program.methods
+        .withdrawExpiredFunds(paymentName)
+        .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should block approvals and cancellations until resumed", async () => {
+      await createAgreement();
+      await pause(false);
+
+      try {
+        await approve(payer);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementPaused");
+      }
+
+      try {
+        await program.methods
+          .cancelPaymentAgreement(paymentName)
+          .accounts(
+            getCancelPaymentAgreementAccounts(
+              payer.publicKey,
+              payer.publicKey,
+              paymentName
+            )
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementPaused");
+      }
+
+      await resume();
+      await approve(payer);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isFalse(paymentAgreement.isPaused);
+      assert.isTrue(paymentAgreement.payerApproved);
+    });
+
+    it("Should still let the referee rule while paused", async () => {
+      await createAgreement();
+      await pause(true);
+
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            referee.publicKey,
+            paymentName
+          )
+        )
+        .signers([referee])
+        .rpc();
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.deepEqual(paymentAgreement.status, { refereeCompleted: {} });
+    });
+
+    it("Should hold expiry refunds only when the referee asks for it", async () => {
+      await createAgreement(Math.floor(Date.now() / 1000) + 2);
+      await pause(true);
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      try {
+        await withdrawExpired();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementPaused");
+      }
+
+      // Re-pausing without the hold leaves the refund available
+      await resume();
+      await pause(false);
+      await withdrawExpired();
+    });
+
+    it("Should only let the referee pause and resume", async () => {
+      await createAgreement();
+
+      for (const signer of [payer, receiver]) {
+        try {
+          await pause(false, signer);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "Unauthorized");
+        }
+      }
+
+      try {
+        await resume();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementNotPaused");
+      }
+    });
+  });
+
+  describe("Receiver Acknowledgement", () => {
+    async function createAgreement(requireAck: boolean | null) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          requireAck === null ? null : { requireAck }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function acknowledge(signer: Keypair) {
+      return program.methods
+        .receiverAcknowledge(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          receiver: signer.publicKey,
+          payer: payer.publicKey,
+        })
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should block both approvals until the receiver acknowledges", async () => {
+      await createAgreement(true);
+
+      for (const signer of [payer, receiver]) {
+        try {
+          await approve(signer);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "ReceiverHasNotAcknowledged");
+        }
+      }
+
+      await acknowledge(receiver);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.isTrue(paymentAgreement.receiverAcknowledged);
+
+      await approve(payer);
+      await approve(receiver);
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should approve without acknowledgement when it isn't required", async () => {
+      await createAgreement(null);
+
+      await approve(payer);
+      await approve(receiver);
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should block partial releases until the receiver acknowledges", async () => {
+      await createAgreement(true);
+
+      const release = () =>
+        program.methods
+          .releasePartialPayment(paymentName, 0, new anchor.BN(paymentAmount / 2))
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              receiver.publicKey,
+              payer.publicKey,
+              paymentName
+            )
+          )
+          .signers([payer])
+          .rpc();
+
+      try {
+        await release();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverHasNotAcknowledged");
+      }
+
+      await acknowledge(receiver);
+      await release();
+    });
+
+    it("Should only accept the acknowledgement from the receiver", async () => {
+      await createAgreement(true);
+
+      for (const signer of [payer, maliciousUser]) {
+        try {
+          await acknowledge(signer);
+          assert.fail("Should have thrown an error");
+        } catch (error) {
+          assert.include(error.message, "Unauthorized");
+        }
+      }
+    });
+  });
+
+  describe("Batch Create", () => {
+    function createBatch(entries: { name: string; receiver: PublicKey; amount: number }[]) {
+      return program.methods
+        .createPaymentAgreementsBatch(
+          entries.map(({ name, receiver, amount }) => ({
+            name,
+            receiver,
+            amount: new anchor.BN(amount),
+            expirationTimestamp: null,
+          }))
+        )
+        .accounts({ payer: payer.publicKey })
+        .remainingAccounts([
+          ...entries.map(({ name }) => ({
+            pubkey: getPaymentAgreementPDA(payer.publicKey, name),
+            isWritable: true,
+            isSigner: false,
+          })),
+          ...entries.map(({ name }) => ({
+            pubkey: getVaultPDA(getPaymentAgreementPDA(payer.publicKey, name)),
+            isWritable: true,
+            isSigner: false,
+          })),
+          ...entries.map(({ receiver }) => ({
+            pubkey: receiver,
+            isWritable: false,
+            isSigner: false,
+          })),
+        ])
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should create and fund every agreement in one call", async () => {
+      const entries = [
+        { name: "payroll-1", receiver: receiver.publicKey, amount: paymentAmount / 2 },
+        { name: "payroll-2", receiver: referee.publicKey, amount: paymentAmount / 4 },
+        { name: "payroll-3", receiver: maliciousUser.publicKey, amount: paymentAmount / 8 },
+      ];
+      await createBatch(entries);
+
+      for (const { name, receiver: entryReceiver, amount } of entries) {
+        const pda = getPaymentAgreementPDA(payer.publicKey, name);
+        const paymentAgreement = await program.account.paymentAgreement.fetch(pda);
+        assert.equal(paymentAgreement.name, name);
+        assert.equal(paymentAgreement.receiver.toBase58(), entryReceiver.toBase58());
+        assert.equal(paymentAgreement.amount.toNumber(), amount);
+        assert.deepEqual(paymentAgreement.status, { pending: {} });
+
+        assert.equal(await escrowedLamports(pda), amount);
+      }
+    });
+
+    it("Should roll back the whole batch when one entry is invalid", async () => {
+      try {
+        await createBatch([
+          { name: "payroll-ok", receiver: receiver.publicKey, amount: paymentAmount },
+          { name: "payroll-self", receiver: payer.publicKey, amount: paymentAmount },
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "PayerCannotBeReceiver");
+      }
+
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, "payroll-ok")
+        )
+      );
+    });
+
+    it("Should reject a name repeated within the batch", async () => {
+      try {
+        await createBatch([
+          { name: "payroll-dup", receiver: receiver.publicKey, amount: paymentAmount / 2 },
+          { name: "payroll-dup", receiver: receiver.publicKey, amount: paymentAmount / 2 },
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementAlreadyExists");
+      }
+    });
+
+    it("Should reject an off-curve receiver in the batch", async () => {
+      try {
+        await createBatch([
+          { name: "payroll-pda", receiver: statsPDA, amount: paymentAmount },
+        ]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotOnCurve");
+      }
+    });
+  });
+
+  describe("Change Referee", () => {
+    let newReferee: Keypair;
+
+    beforeEach(async () => {
+      newReferee = Keypair.generate();
+
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function changeReferee(
+      newKey: PublicKey | null,
+      receiverKey = receiver.publicKey,
+      signers = [payer, receiver]
+    ) {
+      return program.methods
+        .changeReferee(paymentName, newKey)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          receiver: receiverKey,
+        })
+        .signers(signers)
+        .rpc();
+    }
+
+    it("Should hand the agreement to a new referee when both parties sign", async () => {
+      await changeReferee(newReferee.publicKey);
+
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(paymentAgreement.referee.toBase58(), newReferee.publicKey.toBase58());
+
+      // The replaced referee can no longer rule
+      try {
+        await program.methods
+          .refereeInterveneCompletePaymentAgreement(paymentName)
+          .accounts({
+            paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+            signer: referee.publicKey,
+            payer: payer.publicKey,
+            receiver: receiver.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([referee])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should reject a change signed by the payer alone", async () => {
+      try {
+        await changeReferee(newReferee.publicKey, payer.publicKey, [payer]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidReceiver");
+      }
+    });
+
+    it("Should not let either party become the referee", async () => {
+      try {
+        await changeReferee(receiver.publicKey);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "RefereeCannotBeReceiver");
+      }
+    });
+
+    it("Should reject a change once a party has approved", async () => {
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            receiver.publicKey,
+            paymentName
+          )
+        )
+        .signers([receiver])
+        .rpc();
+
+      try {
+        await changeReferee(newReferee.publicKey);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "RefereeChangeAfterApproval");
+      }
+    });
+  });
+
+  describe("Key Mismatch Logs", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    it("Should log the provided and stored receiver on a mismatch", async () => {
+      try {
+        await program.methods
+          .approvePaymentAgreement(paymentName)
+          .accounts(
+            getApprovePaymentAgreementAccounts(
+              payer.publicKey,
+              maliciousUser.publicKey,
+              payer.publicKey,
+              paymentName
+            )
+          )
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidReceiver");
+
+        const logs: string[] = error.logs ?? [];
+        const left = logs.indexOf("Program log: Left:");
+        const right = logs.indexOf("Program log: Right:");
+        assert.isAtLeast(left, 0);
+        assert.include(logs[left + 1], maliciousUser.publicKey.toBase58());
+        assert.isAtLeast(right, 0);
+        assert.include(logs[right + 1], receiver.publicKey.toBase58());
+      }
+    });
+  });
+
+  describe("Abandoned Cancel", () => {
+    before(async () => {
+      await program.methods
+        .updateConfig({ abandonTimeout: new anchor.BN(2) })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    });
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function requestCancel(signer: Keypair) {
+      return program.methods
+        .cancelPaymentAgreement(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(payer.publicKey, signer.publicKey, paymentName)
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    function confirm() {
+      return program.methods
+        .confirmAbandonedCancel(paymentName)
+        .accounts(
+          getCancelPaymentAgreementAccounts(payer.publicKey, referee.publicKey, paymentName)
+        )
+        .signers([referee])
+        .rpc();
+    }
+
+    it("Should refund the payer once the other party stays silent", async () => {
+      await requestCancel(payer);
+
+      try {
+        await confirm();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AbandonTimeoutNotElapsed");
+      }
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      const payerBalanceBefore = await provider.connection.getBalance(payer.publicKey);
+      await confirm();
+      const payerBalanceAfter = await provider.connection.getBalance(payer.publicKey);
+
+      assert.equal(payerBalanceAfter - payerBalanceBefore, paymentAmount);
+      const paymentAgreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.deepEqual(paymentAgreement.status, { refereeCancelled: {} });
+    });
+
+    it("Should restart the timeout on any activity", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+      // The cancel request is itself activity, so the wait starts over
+      await requestCancel(receiver);
+
+      try {
+        await confirm();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AbandonTimeoutNotElapsed");
+      }
+    });
+
+    it("Should need exactly one cancel request", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      try {
+        await confirm();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "NoOneSidedCancelRequest");
+      }
+    });
+  });
+
+  describe("Close Settled Agreement", () => {
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(
+          getCreatePaymentAgreementAccounts(
+            payer.publicKey,
+            paymentName,
+            referee.publicKey
+          )
+        )
+        .signers([payer])
+        .rpc();
+    });
+
+    function closeSettled() {
+      return program.methods
+        .closeSettledAgreement(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should close a completed agreement and refund its rent", async () => {
+      const paymentAgreementPDA = getPaymentAgreementPDA(payer.publicKey, paymentName);
+
+      // A referee ruling leaves the account open
+      await program.methods
+        .refereeInterveneCompletePaymentAgreement(paymentName)
+        .accounts({
+          paymentAgreement: paymentAgreementPDA,
+          signer: referee.publicKey,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([referee])
+        .rpc();
+
+      const rent = await closingRent(paymentAgreementPDA);
+      const payerBalanceBefore = await provider.connection.getBalance(payer.publicKey);
+      await closeSettled();
+      const payerBalanceAfter = await provider.connection.getBalance(payer.publicKey);
+
+      assert.isNull(await provider.connection.getAccountInfo(paymentAgreementPDA));
+      assert.equal(payerBalanceAfter - payerBalanceBefore, rent);
+    });
+
+    it("Should refuse to close a pending agreement", async () => {
+      try {
+        await closeSettled();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "AgreementNotSettled");
+      }
+    });
+  });
+
+  describe("Document Hash", () => {
+    const documentHash = Array.from(
+      createHash("sha256").update("signed contract v1").digest()
+    );
+    const amendedHash = Array.from(
+      createHash("sha256").update("signed contract v2").digest()
+    );
+
+    beforeEach(async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { documentHash }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+
+    function getSummary() {
+      return program.methods
+        .getAgreementSummary(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+        })
+        .view();
+    }
+
+    function updateDocumentHash(hash: number[] | null, signers = [payer, receiver]) {
+      return program.methods
+        .updateDocumentHash(paymentName, hash)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+        })
+        .signers(signers)
+        .rpc();
+    }
+
+    it("Should return the hash given at creation", async () => {
+      const summary = await getSummary();
+      assert.deepEqual(Array.from(summary.documentHash), documentHash);
+    });
+
+    it("Should rebind the agreement to an amended document", async () => {
+      await updateDocumentHash(amendedHash);
+
+      const summary = await getSummary();
+      assert.deepEqual(Array.from(summary.documentHash), amendedHash);
+    });
+
+    it("Should need the receiver's signature to change the hash", async () => {
+      try {
+        await updateDocumentHash(amendedHash, [payer]);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Signature verification failed");
+      }
+    });
+  });
+
+  describe("Program-Owned Receiver", () => {
+    function createWithReceiverAccount(receiverKey: PublicKey, options = null) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiverKey,
+          new anchor.BN(paymentAmount),
+          null,
+          options
+        )
+        .accounts({
+          ...getCreatePaymentAgreementAccounts(payer.publicKey, paymentName),
+          receiverAccount: receiverKey,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should reject a program-owned receiver by default", async () => {
+      try {
+        await createWithReceiverAccount(configPDA);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotOnCurve");
+      }
+    });
+
+    it("Should reject an uncreated PDA receiver by default", async () => {
+      // Nothing lives at this address, so it is still owned by the System Program
+      const [uncreated] = PublicKey.findProgramAddressSync(
+        [Buffer.from("uncreated")],
+        program.programId
+      );
+
+      try {
+        await createWithReceiverAccount(uncreated);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotOnCurve");
+      }
+    });
+
+    it("Should reject an on-curve receiver owned by a program", async () => {
+      // A keypair address whose account is handed to this program
+      const programOwned = Keypair.generate();
+      const space = 8;
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          SystemProgram.createAccount({
+            fromPubkey: provider.wallet.publicKey,
+            newAccountPubkey: programOwned.publicKey,
+            lamports: await provider.connection.getMinimumBalanceForRentExemption(space),
+            space,
+            programId: program.programId,
+          })
+        ),
+        [programOwned]
+      );
+
+      try {
+        await createWithReceiverAccount(programOwned.publicKey);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ReceiverNotSystemAccount");
+      }
+    });
+
+    it("Should require the receiver account unless a program receiver is allowed", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts({
+            ...getCreatePaymentAgreementAccounts(payer.publicKey, paymentName),
+            receiverAccount: null,
+          })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "MissingReceiverAccount");
+      }
+    });
+
+    it("Should accept a program-owned receiver when allowed", async () => {
+      await createWithReceiverAccount(configPDA, { allowProgramReceiver: true });
+
+      const agreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(agreement.receiver.toString(), configPDA.toString());
+    });
+
+    it("Should accept a system-owned receiver", async () => {
+      await createWithReceiverAccount(receiver.publicKey);
+    });
+
+    it("Should reject a receiver account that does not match the receiver", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            null
+          )
+          .accounts({
+            ...getCreatePaymentAgreementAccounts(payer.publicKey, paymentName),
+            receiverAccount: referee.publicKey,
+          })
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidReceiver");
+      }
+    });
+  });
+
+  describe("Settle Expired", () => {
+    async function createExpiring(expiryFavors: object) {
+      const expiration = Math.floor(Date.now() / 1000) + 3;
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(expiration),
+          { expiryFavors }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 5000));
+    }
+
+    function settleExpired(signer: Keypair) {
+      return program.methods
+        .settleExpired(paymentName)
+        .accounts({
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName),
+          signer: signer.publicKey,
+          payer: payer.publicKey,
+          receiver: receiver.publicKey,
+        })
+        .signers([signer])
+        .rpc();
+    }
+
+    it("Should pay the receiver when expiry favors them", async () => {
+      await createExpiring({ receiver: {} });
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      await settleExpired(receiver);
+      const receiverAfter = await provider.connection.getBalance(receiver.publicKey);
+
+      // The receiver also pays the transaction fee
+      assert.isAbove(receiverAfter - receiverBefore, paymentAmount - 10_000);
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should refund the payer when expiry favors them", async () => {
+      await createExpiring({ payer: {} });
+
+      const payerBefore = await provider.connection.getBalance(payer.publicKey);
+      await settleExpired(payer);
+      const payerAfter = await provider.connection.getBalance(payer.publicKey);
+
+      assert.isAbove(payerAfter - payerBefore, paymentAmount - 10_000);
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    });
+
+    it("Should not let the payer reclaim a receiver-favored expiry", async () => {
+      await createExpiring({ receiver: {} });
+
+      try {
+        await settleExpired(payer);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+
+      try {
+        await program.methods
+          .withdrawExpiredFunds(paymentName)
+          .accounts(getWithdrawExpiredFundsAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ExpiryFavorsReceiver");
+      }
+    });
+
+    it("Should not let the receiver claim a payer-favored expiry", async () => {
+      await createExpiring({ payer: {} });
+
+      try {
+        await settleExpired(receiver);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "Unauthorized");
+      }
+    });
+
+    it("Should reject a receiver-favored expiry without an expiration", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            { expiryFavors: { receiver: {} } }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidExpiryBeneficiary");
+      }
+    });
+  });
+
+  describe("Minimum Agreement Lifetime", () => {
+    function setMinLifetime(seconds: number) {
+      return program.methods
+        .updateConfig({ minAgreementLifetime: new anchor.BN(seconds) })
+        .accounts({ config: configPDA, admin: provider.wallet.publicKey })
+        .rpc();
+    }
+
+    function createExpiringIn(seconds: number) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          new anchor.BN(Math.floor(Date.now() / 1000) + seconds),
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    beforeEach(async () => {
+      await setMinLifetime(3600);
+    });
+
+    afterEach(async () => {
+      await setMinLifetime(0);
+    });
+
+    it("Should reject an expiration inside the minimum lifetime", async () => {
+      try {
+        await createExpiringIn(60);
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "ExpirationTooSoon");
+      }
+    });
+
+    it("Should accept an expiration past the minimum lifetime", async () => {
+      // Margin for the validator clock running ahead of the test
+      await createExpiringIn(3600 + 30);
+    });
+
+    it("Should not limit agreements without an expiration", async () => {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          null
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    });
+  });
+
+  describe("Completion Modes", () => {
+    async function createWithMode(completionMode: object) {
+      await program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { completionMode }
+        )
+        .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+        .signers([payer])
+        .rpc();
+    }
+
+    function approve(signer: Keypair) {
+      return program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(
+          getApprovePaymentAgreementAccounts(
+            payer.publicKey,
+            receiver.publicKey,
+            signer.publicKey,
+            paymentName
+          )
+        )
+        .signers([signer])
+        .rpc();
+    }
+
+    async function status() {
+      const agreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      return agreement.status;
+    }
+
+    // Completion closes the agreement, so a completed one has no account left
+    async function assertClosed() {
+      assert.isNull(
+        await provider.connection.getAccountInfo(
+          getPaymentAgreementPDA(payer.publicKey, paymentName)
+        )
+      );
+    }
+
+    it("Should keep requiring both approvals in mutual mode", async () => {
+      await createWithMode({ mutualApproval: {} });
+
+      await approve(receiver);
+      assert.deepEqual(await status(), { pending: {} });
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      await approve(payer);
+      await assertClosed();
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        paymentAmount
+      );
+    });
+
+    it("Should complete on the receiver's confirmation alone", async () => {
+      await createWithMode({ receiverConfirm: {} });
+
+      await approve(payer);
+      assert.deepEqual(await status(), { pending: {} });
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      await approve(receiver);
+      await assertClosed();
+      assert.isAbove(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        paymentAmount - 10_000
+      );
+    });
+
+    it("Should complete on the payer's release alone", async () => {
+      await createWithMode({ payerRelease: {} });
+
+      await approve(receiver);
+      assert.deepEqual(await status(), { pending: {} });
+
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+      await approve(payer);
+      await assertClosed();
+      assert.equal(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        paymentAmount
+      );
+    });
+
+    it("Should reject a one-sided mode with an approver set", async () => {
+      try {
+        await program.methods
+          .createPaymentAgreement(
+            paymentName,
+            receiver.publicKey,
+            new anchor.BN(paymentAmount),
+            null,
+            {
+              completionMode: { payerRelease: {} },
+              approvers: [payer.publicKey, receiver.publicKey],
+              approvalThreshold: 2,
+            }
+          )
+          .accounts(getCreatePaymentAgreementAccounts(payer.publicKey, paymentName))
+          .signers([payer])
+          .rpc();
+        assert.fail("Should have thrown an error");
+      } catch (error) {
+        assert.include(error.message, "InvalidCompletionMode");
+      }
+    });
+  });
+
+  describe("Agreement Nonce", () => {
+    function createWithNonce(nonce: number) {
+      return program.methods
+        .createPaymentAgreement(
+          paymentName,
+          receiver.publicKey,
+          new anchor.BN(paymentAmount),
+          null,
+          { nonce: new anchor.BN(nonce) }
+        )
+        .accounts({
+          ...getCreatePaymentAgreementAccounts(payer.publicKey, paymentName),
+          paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName, nonce),
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    it("Should create two agreements with the same name under different nonces", async () => {
+      await createWithNonce(1);
+      await createWithNonce(2);
+
+      const first = getPaymentAgreementPDA(payer.publicKey, paymentName, 1);
+      const second = getPaymentAgreementPDA(payer.publicKey, paymentName, 2);
+      assert.notEqual(first.toString(), second.toString());
+
+      const firstAgreement = await program.account.paymentAgreement.fetch(first);
+      const secondAgreement = await program.account.paymentAgreement.fetch(second);
+      assert.equal(firstAgreement.nonce.toNumber(), 1);
+      assert.equal(secondAgreement.nonce.toNumber(), 2);
+      assert.equal(firstAgreement.name, secondAgreement.name);
+    });
+
+    it("Should keep nonce 0 at the address without a nonce", async () => {
+      await createWithNonce(0);
+
+      const agreement = await program.account.paymentAgreement.fetch(
+        getPaymentAgreementPDA(payer.publicKey, paymentName)
+      );
+      assert.equal(agreement.nonce.toNumber(), 0);
+    });
+
+    it("Should approve an agreement found through its stored nonce", async () => {
+      await createWithNonce(3);
+      const accounts = {
+        ...getApprovePaymentAgreementAccounts(
+          payer.publicKey,
+          receiver.publicKey,
+          payer.publicKey,
+          paymentName
+        ),
+        paymentAgreement: getPaymentAgreementPDA(payer.publicKey, paymentName, 3),
+      };
+      const receiverBefore = await provider.connection.getBalance(receiver.publicKey);
+
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts(accounts)
+        .signers([payer])
+        .rpc();
+      await program.methods
+        .approvePaymentAgreement(paymentName)
+        .accounts({ ...accounts, signer: receiver.publicKey })
+        .signers([receiver])
+        .rpc();
+
+      // Completion closes the agreement, so check the payout instead
+      assert.isNull(
+        await provider.connection.getAccountInfo(accounts.paymentAgreement)
+      );
+      assert.isAbove(
+        (await provider.connection.getBalance(receiver.publicKey)) - receiverBefore,
+        paymentAmount - 10_000
+      );
+    });
+  });
 });
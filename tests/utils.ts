@@ -55,7 +55,8 @@ export class TestUtils {
         receiver,
         referee || null,
         new anchor.BN(amount),
-        expiration ? new anchor.BN(expiration) : null
+        expiration ? new anchor.BN(expiration) : null,
+        null
       )
       .accounts({
         paymentAgreement: paymentAgreementPDA,